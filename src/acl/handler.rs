@@ -0,0 +1,190 @@
+use crate::acl::Acl;
+use crate::dns::{DnsMessage, Opcode, QuestionType, ResponseCode};
+use crate::handler::{DnsHandler, DnsRequest};
+use crate::stats::Stats;
+use anyhow::Result;
+use async_trait::async_trait;
+use std::sync::Arc;
+use tracing::{debug, info};
+
+pub const STAT_ACL_ALLOWED: &str = "acl.allowed";
+pub const STAT_ACL_REFUSED: &str = "acl.refused";
+
+// what a request is asking of us, each with an access list of its own
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Query,
+
+    // Queries that need us to go and find the answer. Whether a query does depends on where
+    // the handler sits: wrapped around a resolver, every query is one.
+    Recursion,
+
+    Transfer,
+    Update,
+    Notify,
+}
+
+impl Access {
+    // the ones that apply to a request, a transfer is also a query
+    fn of(request: &DnsRequest) -> Vec<Access> {
+        let opcode = request.message.header.opcode;
+        if opcode == Opcode::Update as u8 {
+            return vec![Access::Update];
+        }
+        if opcode == Opcode::Notify as u8 {
+            return vec![Access::Notify];
+        }
+
+        let transfer = request
+            .message
+            .question()
+            .is_some_and(|q| matches!(q.qtype, QuestionType::AXFR | QuestionType::IXFR));
+        match transfer {
+            true => vec![Access::Query, Access::Transfer],
+            false => vec![Access::Query, Access::Recursion],
+        }
+    }
+}
+
+// Refuses requests from clients the access list for that kind of request doesn't allow, and
+// passes the rest on. Kinds of request without a list are open to everybody.
+#[derive(Debug)]
+pub struct AclHandler {
+    acls: Vec<(Access, Acl)>,
+    inner: Arc<dyn DnsHandler>,
+    stats: Arc<Stats>,
+}
+
+impl AclHandler {
+    pub fn new(inner: Arc<dyn DnsHandler>) -> Self {
+        Self {
+            acls: Vec::new(),
+            inner,
+            stats: Arc::new(Stats::default()),
+        }
+    }
+
+    pub fn with_acl(mut self, access: Access, acl: Acl) -> Self {
+        self.acls.retain(|(a, _)| *a != access);
+        self.acls.push((access, acl));
+        self
+    }
+
+    pub fn with_stats(mut self, stats: Arc<Stats>) -> Self {
+        self.stats = stats;
+        self
+    }
+
+    pub fn stats(&self) -> Arc<Stats> {
+        self.stats.clone()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.acls.is_empty()
+    }
+
+    // the kind of request the client isn't allowed to make, if any
+    fn refused(&self, request: &DnsRequest) -> Option<Access> {
+        let client = request.client.ip();
+        Access::of(request).into_iter().find(|access| {
+            self.acls
+                .iter()
+                .any(|(a, acl)| a == access && !acl.allows(&client))
+        })
+    }
+
+    fn check(&self, request: &DnsRequest) -> Option<DnsMessage> {
+        match self.refused(request) {
+            Some(access) => {
+                info!("refusing {access:?} from {}", request.client);
+                self.stats.increment(STAT_ACL_REFUSED);
+                let reply = request.message.clone().as_reply();
+                Some(reply.with_response_code(ResponseCode::Refused))
+            }
+            None => {
+                debug!("allowing {:?} from {}", Access::of(request), request.client);
+                self.stats.increment(STAT_ACL_ALLOWED);
+                None
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl DnsHandler for AclHandler {
+    async fn handle(&self, request: &DnsRequest) -> Result<Option<DnsMessage>> {
+        if let Some(refused) = self.check(request) {
+            return Ok(Some(refused));
+        }
+        self.inner.handle(request).await
+    }
+
+    async fn handle_stream(&self, request: &DnsRequest) -> Result<Vec<DnsMessage>> {
+        if let Some(refused) = self.check(request) {
+            return Ok(vec![refused]);
+        }
+        self.inner.handle_stream(request).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::DnsQuestion;
+    use crate::handler::{EchoHandler, Transport};
+    use bytes::Bytes;
+
+    fn request(client: &str, opcode: Opcode, qtype: QuestionType) -> DnsRequest {
+        let mut message =
+            DnsMessage::query(1, DnsQuestion::new("example.com".parse().unwrap(), qtype));
+        message.header.opcode = opcode as u8;
+        DnsRequest {
+            message,
+            client: client.parse().unwrap(),
+            transport: Transport::Tcp,
+            raw: Bytes::new(),
+            key: None,
+        }
+    }
+
+    #[test]
+    fn each_kind_of_request_has_its_own_list() {
+        let acls = AclHandler::new(Arc::new(EchoHandler))
+            .with_acl(Access::Query, "127.0.0.0/8, 10.0.0.0/8".parse().unwrap())
+            .with_acl(Access::Recursion, "127.0.0.1".parse().unwrap())
+            .with_acl(Access::Transfer, "10.0.0.2".parse().unwrap())
+            .with_acl(Access::Update, "none".parse().unwrap());
+        let refused = |client, opcode, qtype| acls.refused(&request(client, opcode, qtype));
+
+        assert_eq!(
+            refused("127.0.0.1:53", Opcode::Query, QuestionType::A),
+            None
+        );
+        assert_eq!(
+            refused("127.0.0.2:53", Opcode::Query, QuestionType::A),
+            Some(Access::Recursion)
+        );
+        assert_eq!(
+            refused("192.0.2.1:53", Opcode::Query, QuestionType::A),
+            Some(Access::Query)
+        );
+        assert_eq!(
+            refused("10.0.0.2:53", Opcode::Query, QuestionType::AXFR),
+            None
+        );
+        assert_eq!(
+            refused("10.0.0.3:53", Opcode::Query, QuestionType::IXFR),
+            Some(Access::Transfer)
+        );
+        assert_eq!(
+            refused("127.0.0.1:53", Opcode::Update, QuestionType::SOA),
+            Some(Access::Update)
+        );
+
+        // no list, no restrictions
+        assert_eq!(
+            refused("192.0.2.1:53", Opcode::Notify, QuestionType::SOA),
+            None
+        );
+    }
+}
@@ -0,0 +1,111 @@
+use crate::dns::IpPrefix;
+use anyhow::{Context, Result};
+use std::net::IpAddr;
+use std::str::FromStr;
+
+// one entry of an access list, a prefix that either lets clients in or keeps them out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct AclEntry {
+    prefix: IpPrefix,
+    allow: bool,
+}
+
+// An ordered list of CIDR blocks where the first one a client is in decides, and clients in none
+// of them are denied. A ! in front of a block denies it, so "!10.1.0.0/16, 10.0.0.0/8" lets in
+// all of 10/8 except 10.1/16. "any" and "none" are short for everything and nothing.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Acl {
+    entries: Vec<AclEntry>,
+}
+
+impl Acl {
+    pub fn any() -> Self {
+        Self::default()
+            .allow("0.0.0.0/0".parse().unwrap())
+            .allow("::/0".parse().unwrap())
+    }
+
+    pub fn none() -> Self {
+        Self::default()
+    }
+
+    pub fn allow(mut self, prefix: IpPrefix) -> Self {
+        self.entries.push(AclEntry {
+            prefix,
+            allow: true,
+        });
+        self
+    }
+
+    pub fn deny(mut self, prefix: IpPrefix) -> Self {
+        self.entries.push(AclEntry {
+            prefix,
+            allow: false,
+        });
+        self
+    }
+
+    pub fn allows(&self, client: &IpAddr) -> bool {
+        self.entries
+            .iter()
+            .find(|e| e.prefix.contains(client))
+            .is_some_and(|e| e.allow)
+    }
+}
+
+impl FromStr for Acl {
+    type Err = anyhow::Error;
+
+    // comma or semicolon separated entries
+    fn from_str(s: &str) -> Result<Self> {
+        let mut acl = Self::default();
+        for entry in s.split([',', ';']).map(str::trim).filter(|e| !e.is_empty()) {
+            acl = match entry.strip_prefix('!') {
+                Some("any") => acl.deny("0.0.0.0/0".parse()?).deny("::/0".parse()?),
+                Some(prefix) => acl.deny(prefix.trim().parse()?),
+                None if entry == "any" => acl.allow("0.0.0.0/0".parse()?).allow("::/0".parse()?),
+                None if entry == "none" => acl,
+                None => acl.allow(
+                    entry
+                        .parse()
+                        .with_context(|| format!("bad access list entry {entry}"))?,
+                ),
+            };
+        }
+        Ok(acl)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn first_matching_entry_decides() {
+        let acl: Acl = "!10.1.0.0/16, 10.0.0.0/8; 2001:db8::/32, 192.0.2.7"
+            .parse()
+            .unwrap();
+        assert!(acl.allows(&ip("10.2.3.4")));
+        assert!(!acl.allows(&ip("10.1.3.4")));
+        assert!(acl.allows(&ip("2001:db8::53")));
+        assert!(acl.allows(&ip("192.0.2.7")));
+        assert!(acl.allows(&ip("::ffff:192.0.2.7")));
+        assert!(!acl.allows(&ip("192.0.2.8")));
+
+        assert!(Acl::any().allows(&ip("203.0.113.1")));
+        assert!(Acl::any().allows(&ip("2001:db8::1")));
+        assert!(!Acl::none().allows(&ip("127.0.0.1")));
+        assert!(!"none".parse::<Acl>().unwrap().allows(&ip("127.0.0.1")));
+        assert!(
+            !"!127.0.0.2, any"
+                .parse::<Acl>()
+                .unwrap()
+                .allows(&ip("127.0.0.2"))
+        );
+        assert!("10.0.0.0/40".parse::<Acl>().is_err());
+    }
+}
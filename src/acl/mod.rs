@@ -0,0 +1,5 @@
+mod handler;
+mod list;
+
+pub use handler::*;
+pub use list::*;
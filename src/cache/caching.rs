@@ -0,0 +1,197 @@
+use crate::cache::{CachedResponse, DnsCache, Validation};
+use crate::dns::{
+    DnsMessage, DnsQuestion, Domain, EDE_STALE_ANSWER, EDE_STALE_NXDOMAIN_ANSWER, QuestionType,
+    ResponseCode,
+};
+use crate::handler::{DnsHandler, DnsRequest};
+use crate::stats::Stats;
+use anyhow::Result;
+use async_trait::async_trait;
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use tracing::{debug, info, warn};
+
+pub const STAT_CACHE_HITS: &str = "cache.hits";
+pub const STAT_CACHE_MISSES: &str = "cache.misses";
+pub const STAT_CACHE_STALE_ANSWERS: &str = "cache.stale_answers";
+pub const STAT_CACHE_PREFETCHES: &str = "cache.prefetches";
+
+type RefreshKey = (Domain, QuestionType, u16);
+
+// why we're going back upstream for something we already have
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Refresh {
+    // it's about to expire, one attempt is enough
+    Prefetch,
+
+    // we've served it stale, keep trying until upstream comes back
+    Stale,
+}
+
+// answers from the cache when it can, otherwise asks the handler it wraps and remembers the answer
+#[derive(Debug)]
+pub struct CachingHandler {
+    cache: Arc<DnsCache>,
+    inner: Arc<dyn DnsHandler>,
+
+    // the entries we're already refreshing in the background
+    refreshing: Arc<Mutex<HashSet<RefreshKey>>>,
+
+    stats: Arc<Stats>,
+}
+
+impl CachingHandler {
+    pub fn new(cache: Arc<DnsCache>, inner: Arc<dyn DnsHandler>) -> Self {
+        Self {
+            cache,
+            inner,
+            refreshing: Arc::new(Mutex::new(HashSet::new())),
+            stats: Arc::new(Stats::default()),
+        }
+    }
+
+    pub fn with_stats(mut self, stats: Arc<Stats>) -> Self {
+        self.stats = stats;
+        self
+    }
+
+    pub fn stats(&self) -> Arc<Stats> {
+        self.stats.clone()
+    }
+
+    // Go back upstream for a question without making the client wait. Prefetches get one
+    // attempt, entries we've served stale are retried until upstream answers or the entry falls
+    // out of the stale window (RFC 8767 S5).
+    fn refresh_in_background(&self, request: &DnsRequest, question: &DnsQuestion, kind: Refresh) {
+        let key = (
+            question.name.to_lowercase(),
+            question.qtype.clone(),
+            question.class,
+        );
+        if !self.refreshing.lock().unwrap().insert(key.clone()) {
+            return;
+        }
+
+        if kind == Refresh::Prefetch {
+            self.stats.increment(STAT_CACHE_PREFETCHES);
+        }
+
+        let cache = self.cache.clone();
+        let inner = self.inner.clone();
+        let refreshing = self.refreshing.clone();
+        let request = request.clone();
+        let question = question.clone();
+        tokio::spawn(async move {
+            let interval = cache.config().stale_refresh_interval;
+            loop {
+                if kind == Refresh::Stale {
+                    tokio::time::sleep(interval).await;
+                }
+
+                match inner.handle(&request).await {
+                    Ok(Some(response)) if !is_failure(&response) => {
+                        info!("refreshed {} ({kind:?})", question.name);
+                        insert(&cache, &request.message, &question, &response);
+                        break;
+                    }
+                    _ => debug!("upstream unavailable for {}", question.name),
+                }
+
+                if kind == Refresh::Prefetch
+                    || !cache.lookup_stale(&question).is_some_and(|c| c.stale)
+                {
+                    break;
+                }
+            }
+            refreshing.lock().unwrap().remove(&key);
+        });
+    }
+}
+
+fn is_failure(response: &DnsMessage) -> bool {
+    response.response_code() == ResponseCode::ServFail as u8
+}
+
+fn dnssec_ok(request: &DnsMessage) -> bool {
+    request.edns().is_some_and(|e| e.dnssec_ok)
+}
+
+// DO clients need the signatures, which only entries cached from other DO queries have
+fn usable(request: &DnsMessage, cached: &CachedResponse) -> bool {
+    !dnssec_ok(request) || cached.validation != Validation::Unchecked
+}
+
+fn insert(cache: &DnsCache, request: &DnsMessage, question: &DnsQuestion, response: &DnsMessage) {
+    if dnssec_ok(request) {
+        cache.insert_signed_response(question, response);
+    } else {
+        cache.insert_response(question, response);
+    }
+}
+
+#[async_trait]
+impl DnsHandler for CachingHandler {
+    async fn handle(&self, request: &DnsRequest) -> Result<Option<DnsMessage>> {
+        let Some(question) = request.message.question() else {
+            return self.inner.handle(request).await;
+        };
+
+        // With CD set the client validates for itself, so it gets what upstream says rather than
+        // what we validated, and we mustn't store what we didn't validate (RFC 4035 S3.2.2).
+        if request.message.header.checking_disabled() {
+            return self.inner.handle(request).await;
+        }
+
+        if let Some(cached) = self
+            .cache
+            .lookup(question)
+            .filter(|c| usable(&request.message, c))
+        {
+            debug!("cache hit for {} {:?}", question.name, question.qtype);
+            self.stats.increment(STAT_CACHE_HITS);
+            if cached.prefetch {
+                self.refresh_in_background(request, question, Refresh::Prefetch);
+            }
+            return Ok(Some(cached.into_reply(&request.message)?));
+        }
+
+        debug!("cache miss for {} {:?}", question.name, question.qtype);
+        self.stats.increment(STAT_CACHE_MISSES);
+        let response = self.inner.handle(request).await;
+
+        // when upstream can't help, an old answer beats no answer
+        let failed = match &response {
+            Ok(Some(r)) => is_failure(r),
+            Ok(None) | Err(_) => true,
+        };
+        if failed
+            && let Some(stale) = self
+                .cache
+                .lookup_stale(question)
+                .filter(|c| usable(&request.message, c))
+        {
+            warn!("upstream failed for {}, serving stale", question.name);
+            self.stats.increment(STAT_CACHE_STALE_ANSWERS);
+            self.refresh_in_background(request, question, Refresh::Stale);
+
+            let code = match stale.response_code {
+                ResponseCode::NxDomain => EDE_STALE_NXDOMAIN_ANSWER,
+                _ => EDE_STALE_ANSWER,
+            };
+            let mut reply = stale.into_reply(&request.message)?;
+
+            // only clients that speak EDNS get told why
+            if request.message.edns().is_some() {
+                reply = reply.with_extended_error(code, "")?;
+            }
+            return Ok(Some(reply));
+        }
+
+        let response = response?;
+        if let Some(response) = &response {
+            insert(&self.cache, &request.message, question, response);
+        }
+
+        Ok(response)
+    }
+}
@@ -0,0 +1,7 @@
+mod caching;
+mod clock;
+mod store;
+
+pub use caching::*;
+pub use clock::*;
+pub use store::*;
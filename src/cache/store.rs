@@ -0,0 +1,1015 @@
+use crate::cache::{Clock, SystemClock};
+use crate::dns::{
+    DnsAnswer, DnsAnswerSet, DnsMessage, DnsQuestion, Domain, QuestionType, RRset, RecordData,
+    ResponseCode,
+};
+use anyhow::Result;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+// following a CNAME chain through the cache stops after this many hops
+const MAX_CHAIN_LENGTH: usize = 8;
+
+// a rough per entry cost on top of the records themselves, covers the key, the map slots, etc.
+const ENTRY_OVERHEAD: usize = 64;
+
+#[derive(Debug, Clone)]
+pub struct CacheConfig {
+    // TTLs are clamped into [min_ttl, max_ttl] when records are cached
+    pub min_ttl: u32,
+    pub max_ttl: u32,
+
+    // negative answers are cached for at most this long (RFC 2308 S5)
+    pub max_negative_ttl: u32,
+
+    // roughly how many bytes the cache may hold before it starts evicting
+    pub max_size: usize,
+
+    // how many seconds past expiry an entry can still be served when upstream is unreachable
+    // (RFC 8767), zero turns serve-stale off
+    pub serve_stale: u32,
+
+    // the TTL stale answers are served with
+    pub stale_answer_ttl: u32,
+
+    // how often we try to refresh an entry we've served stale
+    pub stale_refresh_interval: Duration,
+
+    // an entry hit during the last `prefetch_threshold` percent of its TTL, that has been hit at
+    // least `prefetch_min_hits` times, gets refreshed before it expires. A threshold of zero
+    // turns prefetching off
+    pub prefetch_threshold: u32,
+    pub prefetch_min_hits: u64,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            min_ttl: 0,
+            max_ttl: 86400,
+            max_negative_ttl: 3600,
+            max_size: 16 * 1024 * 1024,
+            serve_stale: 86400,
+            stale_answer_ttl: 30,
+            stale_refresh_interval: Duration::from_secs(30),
+            prefetch_threshold: 10,
+            prefetch_min_hits: 3,
+        }
+    }
+}
+
+// a qtype of None means every type at that name, which is what NXDOMAIN is cached under
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct CacheKey {
+    name: Domain,
+    qtype: Option<QuestionType>,
+    class: u16,
+}
+
+impl CacheKey {
+    fn new(name: &Domain, qtype: Option<QuestionType>, class: u16) -> Self {
+        Self {
+            name: name.to_lowercase(),
+            qtype,
+            class,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum CachedData {
+    Records(RRset),
+
+    // the name doesn't exist, along with the SOA record that told us so
+    NxDomain(DnsAnswer),
+
+    // the name exists but has no records of this type
+    NoData(DnsAnswer),
+}
+
+// how far an entry's records were checked before they were cached, the weakest of an answer's
+// entries is what the answer as a whole gets
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Validation {
+    // cached from a query without DO, so whatever signatures there were didn't come with them
+    Unchecked,
+
+    // cached with any signatures they had but didn't validate, usually because the zone is unsigned
+    Insecure,
+
+    // they validated, which the response told us with AD
+    Secure,
+}
+
+#[derive(Debug)]
+struct CacheEntry {
+    data: CachedData,
+
+    // the RRSIGs over the data, and for negative answers the NSEC or NSEC3 records proving it
+    // with theirs
+    dnssec: Vec<DnsAnswer>,
+    validation: Validation,
+
+    inserted: Instant,
+    ttl: Duration,
+    size: usize,
+
+    // how many times this entry has been looked up, carried over when the entry is refreshed
+    hits: u64,
+
+    // the position of this entry in the LRU ordering
+    tick: u64,
+}
+
+impl CacheEntry {
+    fn remaining(&self, now: Instant) -> Option<Duration> {
+        let age = now.saturating_duration_since(self.inserted);
+        (age < self.ttl).then(|| self.ttl - age)
+    }
+
+    // expired, but not for longer than `stale`
+    fn is_stale(&self, now: Instant, stale: Duration) -> bool {
+        let age = now.saturating_duration_since(self.inserted);
+        age >= self.ttl && age < self.ttl + stale
+    }
+
+    // popular and close to expiring
+    fn wants_prefetch(&self, remaining: Duration, config: &CacheConfig) -> bool {
+        config.prefetch_threshold > 0
+            && self.hits >= config.prefetch_min_hits
+            && remaining.as_secs_f64()
+                <= self.ttl.as_secs_f64() * config.prefetch_threshold as f64 / 100.0
+    }
+}
+
+// an entry found by a lookup
+struct Hit {
+    data: CachedData,
+    dnssec: Vec<DnsAnswer>,
+    validation: Validation,
+    remaining: Duration,
+    prefetch: bool,
+}
+
+// how far past its expiry an entry is allowed to be
+#[derive(Debug, Clone, Copy)]
+enum Freshness {
+    Fresh,
+    Stale(Duration),
+}
+
+#[derive(Debug, Default)]
+struct CacheState {
+    entries: HashMap<CacheKey, CacheEntry>,
+
+    // least recently used first
+    lru: BTreeMap<u64, CacheKey>,
+    tick: u64,
+    size: usize,
+}
+
+impl CacheState {
+    fn touch(&mut self, key: &CacheKey) {
+        self.tick += 1;
+        let tick = self.tick;
+        if let Some(entry) = self.entries.get_mut(key) {
+            self.lru.remove(&entry.tick);
+            entry.tick = tick;
+            self.lru.insert(tick, key.clone());
+        }
+    }
+
+    fn remove(&mut self, key: &CacheKey) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.lru.remove(&entry.tick);
+            self.size -= entry.size;
+        }
+    }
+
+    fn insert(
+        &mut self,
+        key: CacheKey,
+        data: CachedData,
+        dnssec: Vec<DnsAnswer>,
+        validation: Validation,
+        inserted: Instant,
+        ttl: Duration,
+    ) {
+        let hits = self.entries.get(&key).map(|e| e.hits).unwrap_or_default();
+        self.remove(&key);
+
+        let size = ENTRY_OVERHEAD
+            + key.name.wire_len()
+            + dnssec.iter().map(record_size).sum::<usize>()
+            + match &data {
+                CachedData::Records(records) => records.iter().map(record_size).sum(),
+                CachedData::NxDomain(soa) | CachedData::NoData(soa) => record_size(soa),
+            };
+
+        self.tick += 1;
+        self.lru.insert(self.tick, key.clone());
+        self.size += size;
+        self.entries.insert(key, CacheEntry {
+            data,
+            dnssec,
+            validation,
+            inserted,
+            ttl,
+            size,
+            hits,
+            tick: self.tick,
+        });
+    }
+
+    fn evict_to(&mut self, max_size: usize) {
+        while self.size > max_size {
+            let Some((_, key)) = self.lru.pop_first() else {
+                break;
+            };
+            debug!("evicting {} {:?} from the cache", key.name, key.qtype);
+            if let Some(entry) = self.entries.remove(&key) {
+                self.size -= entry.size;
+            }
+        }
+    }
+
+    // An entry along with how long it has left. Expired entries are only returned when we're
+    // allowed to serve stale, in which case they have nothing left. Entries that are past even
+    // the stale window are dropped on the way.
+    fn get(
+        &mut self,
+        key: &CacheKey,
+        now: Instant,
+        freshness: Freshness,
+        config: &CacheConfig,
+    ) -> Option<Hit> {
+        let stale_window = Duration::from_secs(config.serve_stale.into());
+        let entry = self.entries.get_mut(key)?;
+        let remaining = match (entry.remaining(now), freshness) {
+            (Some(remaining), _) => remaining,
+            (None, Freshness::Stale(_)) if entry.is_stale(now, stale_window) => Duration::ZERO,
+            (None, _) if entry.is_stale(now, stale_window) => return None,
+            (None, _) => {
+                self.remove(key);
+                return None;
+            }
+        };
+
+        entry.hits += 1;
+        let hit = Hit {
+            data: entry.data.clone(),
+            dnssec: entry.dnssec.clone(),
+            validation: entry.validation,
+            remaining,
+            prefetch: !remaining.is_zero() && entry.wants_prefetch(remaining, config),
+        };
+        self.touch(key);
+        Some(hit)
+    }
+}
+
+fn record_size(record: &DnsAnswer) -> usize {
+    record.name.wire_len() + 10 + record.data.len()
+}
+
+// what the cache hands back, the TTLs have already been counted down
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CachedResponse {
+    pub response_code: ResponseCode,
+    pub answers: Vec<DnsAnswer>,
+    pub authority: Vec<DnsAnswer>,
+
+    // at least part of the response has expired
+    pub stale: bool,
+
+    // at least part of the response is popular and about to expire, so it's worth refreshing
+    pub prefetch: bool,
+
+    // the weakest validation of the entries the response was put together from
+    pub validation: Validation,
+}
+
+impl CachedResponse {
+    // Turn the cached response into the reply to `request`. The DNSSEC records only go to clients
+    // that set DO (RFC 4035 S3.2.1), and AD only to those that asked for it with DO or AD.
+    pub fn into_reply(mut self, request: &DnsMessage) -> Result<DnsMessage> {
+        let mut reply = request.clone().as_reply();
+        reply.header.recursion_available = true;
+
+        let dnssec_ok = request.edns().is_some_and(|e| e.dnssec_ok);
+        let wants_ad = dnssec_ok || request.header.authentic_data();
+        reply
+            .header
+            .set_authentic_data(wants_ad && self.validation == Validation::Secure);
+        if !dnssec_ok && let Some(question) = request.question() {
+            let asked_for = |r: &DnsAnswer| {
+                r.qtype == question.qtype
+                    || !matches!(
+                        r.qtype,
+                        QuestionType::RRSIG | QuestionType::NSEC | QuestionType::NSEC3
+                    )
+            };
+            self.answers.retain(asked_for);
+            self.authority.retain(asked_for);
+        }
+
+        reply
+            .with_response_code(self.response_code)
+            .with_answers(DnsAnswerSet {
+                answers: self.answers,
+            })?
+            .with_authority(DnsAnswerSet {
+                answers: self.authority,
+            })
+    }
+}
+
+// An RRset cache keyed on name, type and class. Positive answers are stored one RRset at a time
+// so that CNAME chains can be put back together from their parts, negative answers are stored per
+// RFC 2308 for as long as the SOA minimum says.
+#[derive(Debug)]
+pub struct DnsCache {
+    config: CacheConfig,
+    clock: Arc<dyn Clock>,
+    state: Mutex<CacheState>,
+}
+
+impl DnsCache {
+    pub fn new(config: CacheConfig) -> Self {
+        Self::with_clock(config, Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(config: CacheConfig, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            config,
+            clock,
+            state: Mutex::new(CacheState::default()),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // the approximate number of bytes held by the cache
+    pub fn size(&self) -> usize {
+        self.state.lock().unwrap().size
+    }
+
+    pub fn lookup(&self, question: &DnsQuestion) -> Option<CachedResponse> {
+        self.lookup_with(question, Freshness::Fresh)
+    }
+
+    // Like lookup, but entries that expired within the serve-stale window are used too. Anything
+    // that has expired is served with the stale answer TTL.
+    pub fn lookup_stale(&self, question: &DnsQuestion) -> Option<CachedResponse> {
+        if self.config.serve_stale == 0 {
+            return None;
+        }
+
+        let ttl = Duration::from_secs(self.config.stale_answer_ttl.into());
+        self.lookup_with(question, Freshness::Stale(ttl))
+    }
+
+    pub fn config(&self) -> &CacheConfig {
+        &self.config
+    }
+
+    fn lookup_with(&self, question: &DnsQuestion, freshness: Freshness) -> Option<CachedResponse> {
+        let now = self.clock.now();
+        let mut state = self.state.lock().unwrap();
+
+        let mut name = question.name.clone();
+        let mut answers = Vec::new();
+        let mut stale = false;
+        let mut prefetch = false;
+        let mut validation = Validation::Secure;
+
+        // expired entries come back with nothing left, they get the stale TTL instead
+        let mut remaining_ttl = |hit: &Hit| {
+            prefetch |= hit.prefetch;
+            validation = validation.min(hit.validation);
+            match freshness {
+                Freshness::Stale(ttl) if hit.remaining.is_zero() => {
+                    stale = true;
+                    ttl
+                }
+                _ => hit.remaining,
+            }
+        };
+
+        for _ in 0..MAX_CHAIN_LENGTH {
+            let key = CacheKey::new(&name, Some(question.qtype.clone()), question.class);
+            let hit = match state.get(&key, now, freshness, &self.config) {
+                Some(hit) => Some(hit),
+                None => {
+                    let key = CacheKey::new(&name, None, question.class);
+                    state.get(&key, now, freshness, &self.config)
+                }
+            };
+            if let Some(hit) = hit {
+                let remaining = remaining_ttl(&hit);
+                let mut response = respond(hit.data, hit.dnssec, remaining, answers);
+                response.stale = stale;
+                response.prefetch = prefetch;
+                response.validation = validation;
+                return Some(response);
+            }
+
+            // maybe we know this name is an alias
+            if question.qtype == QuestionType::CNAME {
+                return None;
+            }
+            let key = CacheKey::new(&name, Some(QuestionType::CNAME), question.class);
+            let hit = state.get(&key, now, freshness, &self.config)?;
+            let remaining = remaining_ttl(&hit);
+            let CachedData::Records(records) = hit.data else {
+                return None;
+            };
+
+            name = match records.first()?.rdata() {
+                Ok(RecordData::CNAME(target)) => target,
+                _ => return None,
+            };
+            answers.extend(with_ttl(records.into_records(), remaining));
+            answers.extend(with_ttl(hit.dnssec, remaining));
+        }
+
+        None
+    }
+
+    // cache whatever we can from the response to `question`, asked without DO
+    pub fn insert_response(&self, question: &DnsQuestion, response: &DnsMessage) {
+        self.insert_with(question, response, Validation::Unchecked);
+    }
+
+    // Cache the response to a question asked with DO, keeping the DNSSEC records with whatever
+    // they sign or prove along with whether it validated. Responses to CD queries weren't
+    // validated at all, so they mustn't end up here.
+    pub fn insert_signed_response(&self, question: &DnsQuestion, response: &DnsMessage) {
+        let validation = if response.header.authentic_data() {
+            Validation::Secure
+        } else {
+            Validation::Insecure
+        };
+        self.insert_with(question, response, validation);
+    }
+
+    fn insert_with(&self, question: &DnsQuestion, response: &DnsMessage, validation: Validation) {
+        let now = self.clock.now();
+        let response_code = response.response_code();
+        if response_code != ResponseCode::NoError as u8
+            && response_code != ResponseCode::NxDomain as u8
+        {
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap();
+
+        // Every RRset on the CNAME chain from the question gets its own entry. Anything else in
+        // the answer section is a server talking about names we didn't ask it about, and caching
+        // that would let it answer for them to every client.
+        let chain = cname_chain(question, &response.answers.answers);
+        let on_chain = |rrset: &RRset| {
+            chain.iter().any(|name| name.eq_ignore_case(rrset.name()))
+                && (*rrset.qtype() == question.qtype
+                    || question.qtype == QuestionType::ANY
+                    || *rrset.qtype() == QuestionType::CNAME)
+        };
+
+        // signatures are kept with the RRset they cover, unless they're what was asked for
+        let (signatures, records): (Vec<_>, Vec<_>) =
+            response.answers.answers.iter().cloned().partition(|a| {
+                a.qtype == QuestionType::RRSIG && question.qtype != QuestionType::RRSIG
+            });
+        let covering = |rrset: &RRset| {
+            signatures
+                .iter()
+                .filter(|s| s.name.eq_ignore_case(rrset.name()))
+                .filter(|s| match s.rdata() {
+                    Ok(RecordData::RRSIG(sig)) => {
+                        QuestionType::from(sig.type_covered) == *rrset.qtype()
+                    }
+                    _ => false,
+                })
+                .cloned()
+                .collect()
+        };
+        for rrset in RRset::group(&records) {
+            if !on_chain(&rrset) {
+                debug!(
+                    "not caching {} {:?}, it's not on the chain",
+                    rrset.name(),
+                    rrset.qtype()
+                );
+                continue;
+            }
+            let key = CacheKey::new(rrset.name(), Some(rrset.qtype().clone()), rrset.class());
+            let ttl = rrset.ttl().clamp(self.config.min_ttl, self.config.max_ttl);
+            let dnssec = covering(&rrset);
+            state.insert(
+                key,
+                CachedData::Records(rrset),
+                dnssec,
+                validation,
+                now,
+                Duration::from_secs(ttl.into()),
+            );
+        }
+
+        // the negative part applies to wherever the CNAME chain (if there was one) ended up
+        let name = chain[chain.len() - 1].clone();
+        let answered = response
+            .answers
+            .answers
+            .iter()
+            .any(|a| a.name.eq_ignore_case(&name) && a.qtype == question.qtype);
+
+        if response_code == ResponseCode::NxDomain as u8 || !answered {
+            // without an SOA we have no idea how long the negative answer is good for
+            let Some(soa) = response
+                .authority
+                .answers
+                .iter()
+                .find(|a| a.qtype == QuestionType::SOA)
+            else {
+                return;
+            };
+            let minimum = match soa.rdata() {
+                Ok(RecordData::SOA(s)) => s.minimum,
+                _ => return,
+            };
+
+            let ttl = soa
+                .ttl
+                .min(minimum)
+                .clamp(self.config.min_ttl, self.config.max_negative_ttl);
+
+            let (key, data) = if response_code == ResponseCode::NxDomain as u8 {
+                (
+                    CacheKey::new(&name, None, question.class),
+                    CachedData::NxDomain(soa.clone()),
+                )
+            } else {
+                (
+                    CacheKey::new(&name, Some(question.qtype.clone()), question.class),
+                    CachedData::NoData(soa.clone()),
+                )
+            };
+
+            // the proof that there's nothing there, and the signatures over it and the SOA
+            let dnssec = response
+                .authority
+                .answers
+                .iter()
+                .filter(|a| {
+                    matches!(
+                        a.qtype,
+                        QuestionType::RRSIG | QuestionType::NSEC | QuestionType::NSEC3
+                    )
+                })
+                .cloned()
+                .collect();
+
+            debug!("caching negative answer for {name} for {ttl}s");
+            state.insert(
+                key,
+                data,
+                dnssec,
+                validation,
+                now,
+                Duration::from_secs(ttl.into()),
+            );
+        }
+
+        state.evict_to(self.config.max_size);
+    }
+}
+
+fn respond(
+    data: CachedData,
+    dnssec: Vec<DnsAnswer>,
+    remaining: Duration,
+    mut answers: Vec<DnsAnswer>,
+) -> CachedResponse {
+    let (response_code, authority) = match data {
+        CachedData::Records(rrset) => {
+            answers.extend(with_ttl(rrset.into_records(), remaining));
+            answers.extend(with_ttl(dnssec, remaining));
+            (ResponseCode::NoError, Vec::new())
+        }
+        CachedData::NxDomain(soa) => (
+            ResponseCode::NxDomain,
+            with_ttl([vec![soa], dnssec].concat(), remaining),
+        ),
+        CachedData::NoData(soa) => (
+            ResponseCode::NoError,
+            with_ttl([vec![soa], dnssec].concat(), remaining),
+        ),
+    };
+
+    CachedResponse {
+        response_code,
+        answers,
+        authority,
+        stale: false,
+        prefetch: false,
+        validation: Validation::Secure,
+    }
+}
+
+// the records as they should be served, with the time they have left as their TTL
+fn with_ttl(records: Vec<DnsAnswer>, remaining: Duration) -> Vec<DnsAnswer> {
+    let ttl = remaining.as_secs().try_into().unwrap_or(u32::MAX);
+    records
+        .into_iter()
+        .map(|mut r| {
+            r.ttl = ttl;
+            r
+        })
+        .collect()
+}
+
+// the names along the CNAMEs in an answer section, starting from the question name
+fn cname_chain(question: &DnsQuestion, answers: &[DnsAnswer]) -> Vec<Domain> {
+    let mut chain = vec![question.name.clone()];
+    if question.qtype == QuestionType::CNAME {
+        return chain;
+    }
+
+    for _ in 0..MAX_CHAIN_LENGTH {
+        let name = &chain[chain.len() - 1];
+        let Some(target) = answers
+            .iter()
+            .filter(|a| a.qtype == QuestionType::CNAME && a.name.eq_ignore_case(name))
+            .find_map(|a| match a.rdata() {
+                Ok(RecordData::CNAME(target)) => Some(target),
+                _ => None,
+            })
+        else {
+            break;
+        };
+        chain.push(target);
+    }
+
+    chain
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::ManualClock;
+    use crate::dns::Soa;
+    use std::net::Ipv4Addr;
+
+    fn record(name: &str, ttl: u32, rdata: RecordData) -> DnsAnswer {
+        DnsAnswer::new(name.parse().unwrap(), ttl, rdata).unwrap()
+    }
+
+    fn soa(ttl: u32, minimum: u32) -> DnsAnswer {
+        record(
+            "example.com",
+            ttl,
+            RecordData::SOA(Soa {
+                mname: "ns.example.com".parse().unwrap(),
+                rname: "hostmaster.example.com".parse().unwrap(),
+                minimum,
+                ..Soa::default()
+            }),
+        )
+    }
+
+    fn question(name: &str, qtype: QuestionType) -> DnsQuestion {
+        DnsQuestion::new(name.parse().unwrap(), qtype)
+    }
+
+    fn response(
+        response_code: ResponseCode,
+        answers: Vec<DnsAnswer>,
+        authority: Vec<DnsAnswer>,
+    ) -> DnsMessage {
+        DnsMessage::default()
+            .with_response_code(response_code)
+            .with_answers(DnsAnswerSet { answers })
+            .unwrap()
+            .with_authority(DnsAnswerSet { answers: authority })
+            .unwrap()
+    }
+
+    fn cache(config: CacheConfig) -> (DnsCache, Arc<ManualClock>) {
+        let clock = Arc::new(ManualClock::default());
+        (DnsCache::with_clock(config, clock.clone()), clock)
+    }
+
+    #[test]
+    fn ttl_counts_down_and_expires() {
+        let (cache, clock) = cache(CacheConfig {
+            serve_stale: 0,
+            ..CacheConfig::default()
+        });
+        let q = question("www.example.com", QuestionType::A);
+        let a = record("www.example.com", 300, RecordData::A(Ipv4Addr::LOCALHOST));
+
+        cache.insert_response(&q, &response(ResponseCode::NoError, vec![a], vec![]));
+        assert_eq!(cache.lookup(&q).unwrap().answers[0].ttl, 300);
+
+        clock.advance(Duration::from_secs(100));
+        assert_eq!(cache.lookup(&q).unwrap().answers[0].ttl, 200);
+
+        clock.advance(Duration::from_secs(200));
+        assert!(cache.lookup(&q).is_none());
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn lookups_ignore_case() {
+        let (cache, _) = cache(CacheConfig::default());
+        let a = record("WWW.Example.com", 300, RecordData::A(Ipv4Addr::LOCALHOST));
+        cache.insert_response(
+            &question("WWW.Example.com", QuestionType::A),
+            &response(ResponseCode::NoError, vec![a], vec![]),
+        );
+
+        assert!(
+            cache
+                .lookup(&question("www.example.COM", QuestionType::A))
+                .is_some()
+        );
+        assert!(
+            cache
+                .lookup(&question("www.example.com", QuestionType::MX))
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn ttls_are_clamped() {
+        let (cache, _) = cache(CacheConfig {
+            min_ttl: 60,
+            max_ttl: 600,
+            ..CacheConfig::default()
+        });
+
+        let low = question("low.example.com", QuestionType::A);
+        let high = question("high.example.com", QuestionType::A);
+        cache.insert_response(
+            &low,
+            &response(
+                ResponseCode::NoError,
+                vec![record(
+                    "low.example.com",
+                    5,
+                    RecordData::A(Ipv4Addr::LOCALHOST),
+                )],
+                vec![],
+            ),
+        );
+        cache.insert_response(
+            &high,
+            &response(
+                ResponseCode::NoError,
+                vec![record(
+                    "high.example.com",
+                    90000,
+                    RecordData::A(Ipv4Addr::LOCALHOST),
+                )],
+                vec![],
+            ),
+        );
+
+        assert_eq!(cache.lookup(&low).unwrap().answers[0].ttl, 60);
+        assert_eq!(cache.lookup(&high).unwrap().answers[0].ttl, 600);
+    }
+
+    #[test]
+    fn nxdomain_is_cached_for_every_type_using_the_soa_minimum() {
+        let (cache, clock) = cache(CacheConfig::default());
+        let q = question("missing.example.com", QuestionType::A);
+
+        cache.insert_response(
+            &q,
+            &response(ResponseCode::NxDomain, vec![], vec![soa(3600, 30)]),
+        );
+
+        let cached = cache
+            .lookup(&question("missing.example.com", QuestionType::MX))
+            .unwrap();
+        assert_eq!(cached.response_code, ResponseCode::NxDomain);
+        assert_eq!(cached.authority[0].ttl, 30);
+
+        clock.advance(Duration::from_secs(30));
+        assert!(cache.lookup(&q).is_none());
+    }
+
+    #[test]
+    fn nodata_is_cached_per_type() {
+        let (cache, _) = cache(CacheConfig::default());
+        let q = question("www.example.com", QuestionType::MX);
+
+        cache.insert_response(
+            &q,
+            &response(ResponseCode::NoError, vec![], vec![soa(10, 300)]),
+        );
+
+        let cached = cache.lookup(&q).unwrap();
+        assert_eq!(cached.response_code, ResponseCode::NoError);
+        assert!(cached.answers.is_empty());
+        assert_eq!(cached.authority[0].ttl, 10);
+        assert!(
+            cache
+                .lookup(&question("www.example.com", QuestionType::A))
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn negative_answers_without_soa_are_not_cached() {
+        let (cache, _) = cache(CacheConfig::default());
+        let q = question("missing.example.com", QuestionType::A);
+        cache.insert_response(&q, &response(ResponseCode::NxDomain, vec![], vec![]));
+        assert!(cache.lookup(&q).is_none());
+    }
+
+    #[test]
+    fn cname_chains_are_rebuilt_from_rrsets() {
+        let (cache, _) = cache(CacheConfig::default());
+        let q = question("alias.example.com", QuestionType::A);
+        let answers = vec![
+            record(
+                "alias.example.com",
+                100,
+                RecordData::CNAME("www.example.com".parse().unwrap()),
+            ),
+            record("www.example.com", 300, RecordData::A(Ipv4Addr::LOCALHOST)),
+        ];
+        cache.insert_response(
+            &q,
+            &response(ResponseCode::NoError, answers.clone(), vec![]),
+        );
+
+        assert_eq!(cache.lookup(&q).unwrap().answers, answers);
+
+        // the target RRset is usable on its own too
+        assert!(
+            cache
+                .lookup(&question("www.example.com", QuestionType::A))
+                .is_some()
+        );
+    }
+
+    #[test]
+    fn records_off_the_chain_are_not_cached() {
+        let (cache, _) = cache(CacheConfig::default());
+        let q = question("www.evil.example", QuestionType::A);
+        let answers = vec![
+            record("www.evil.example", 300, RecordData::A(Ipv4Addr::LOCALHOST)),
+            record(
+                "www.bank.example",
+                300,
+                RecordData::A(Ipv4Addr::new(6, 6, 6, 6)),
+            ),
+            record("www.evil.example", 300, RecordData::TXT(vec!["x".into()])),
+        ];
+        cache.insert_response(&q, &response(ResponseCode::NoError, answers, vec![]));
+
+        assert!(cache.lookup(&q).is_some());
+        assert!(
+            cache
+                .lookup(&question("www.bank.example", QuestionType::A))
+                .is_none()
+        );
+        assert!(
+            cache
+                .lookup(&question("www.evil.example", QuestionType::TXT))
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn stale_entries_are_only_served_when_asked_for() {
+        let (cache, clock) = cache(CacheConfig {
+            serve_stale: 600,
+            stale_answer_ttl: 30,
+            ..CacheConfig::default()
+        });
+        let q = question("www.example.com", QuestionType::A);
+        let a = record("www.example.com", 300, RecordData::A(Ipv4Addr::LOCALHOST));
+        cache.insert_response(&q, &response(ResponseCode::NoError, vec![a], vec![]));
+
+        // still fresh, so nothing stale about it
+        let cached = cache.lookup_stale(&q).unwrap();
+        assert!(!cached.stale);
+        assert_eq!(cached.answers[0].ttl, 300);
+
+        clock.advance(Duration::from_secs(400));
+        assert!(cache.lookup(&q).is_none());
+        let cached = cache.lookup_stale(&q).unwrap();
+        assert!(cached.stale);
+        assert_eq!(cached.answers[0].ttl, 30);
+
+        // past the stale window it's gone for good
+        clock.advance(Duration::from_secs(600));
+        assert!(cache.lookup_stale(&q).is_none());
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn serve_stale_can_be_turned_off() {
+        let (cache, clock) = cache(CacheConfig {
+            serve_stale: 0,
+            ..CacheConfig::default()
+        });
+        let q = question("www.example.com", QuestionType::A);
+        let a = record("www.example.com", 300, RecordData::A(Ipv4Addr::LOCALHOST));
+        cache.insert_response(&q, &response(ResponseCode::NoError, vec![a], vec![]));
+
+        clock.advance(Duration::from_secs(301));
+        assert!(cache.lookup_stale(&q).is_none());
+    }
+
+    #[test]
+    fn popular_entries_near_expiry_want_prefetching() {
+        let (cache, clock) = cache(CacheConfig {
+            prefetch_threshold: 10,
+            prefetch_min_hits: 2,
+            ..CacheConfig::default()
+        });
+        let q = question("www.example.com", QuestionType::A);
+        let a = record("www.example.com", 100, RecordData::A(Ipv4Addr::LOCALHOST));
+        cache.insert_response(
+            &q,
+            &response(ResponseCode::NoError, vec![a.clone()], vec![]),
+        );
+
+        // popular, but not close to expiring
+        assert!(!cache.lookup(&q).unwrap().prefetch);
+        assert!(!cache.lookup(&q).unwrap().prefetch);
+
+        // in the last 10% of its TTL
+        clock.advance(Duration::from_secs(95));
+        assert!(cache.lookup(&q).unwrap().prefetch);
+
+        // refreshing keeps the hit count, so it stays popular
+        cache.insert_response(&q, &response(ResponseCode::NoError, vec![a], vec![]));
+        assert!(!cache.lookup(&q).unwrap().prefetch);
+        clock.advance(Duration::from_secs(91));
+        assert!(cache.lookup(&q).unwrap().prefetch);
+    }
+
+    #[test]
+    fn unpopular_entries_are_not_prefetched() {
+        let (cache, clock) = cache(CacheConfig {
+            prefetch_threshold: 10,
+            prefetch_min_hits: 5,
+            ..CacheConfig::default()
+        });
+        let q = question("www.example.com", QuestionType::A);
+        let a = record("www.example.com", 100, RecordData::A(Ipv4Addr::LOCALHOST));
+        cache.insert_response(&q, &response(ResponseCode::NoError, vec![a], vec![]));
+
+        clock.advance(Duration::from_secs(95));
+        assert!(!cache.lookup(&q).unwrap().prefetch);
+    }
+
+    #[test]
+    fn least_recently_used_entries_are_evicted() {
+        let a = |name: &str| record(name, 300, RecordData::A(Ipv4Addr::LOCALHOST));
+        let one = question("one.example.com", QuestionType::A);
+        let two = question("two.example.com", QuestionType::A);
+        let three = question("three.example.com", QuestionType::A);
+
+        // room for two entries, but not three
+        let (cache, _) = cache(CacheConfig {
+            max_size: 2 * (ENTRY_OVERHEAD + 2 * record_size(&a("three.example.com"))),
+            ..CacheConfig::default()
+        });
+
+        cache.insert_response(
+            &one,
+            &response(ResponseCode::NoError, vec![a("one.example.com")], vec![]),
+        );
+        cache.insert_response(
+            &two,
+            &response(ResponseCode::NoError, vec![a("two.example.com")], vec![]),
+        );
+
+        // one is now more recently used than two
+        assert!(cache.lookup(&one).is_some());
+
+        cache.insert_response(
+            &three,
+            &response(ResponseCode::NoError, vec![a("three.example.com")], vec![]),
+        );
+
+        assert_eq!(cache.len(), 2);
+        assert!(cache.lookup(&one).is_some());
+        assert!(cache.lookup(&two).is_none());
+        assert!(cache.lookup(&three).is_some());
+    }
+}
@@ -1,8 +1,9 @@
+use crate::dns::CLASS_IN;
 use crate::dns::QuestionType;
+use crate::dns::RecordData;
 use crate::dns::label::Domain;
 use crate::parse::DnsData;
 use crate::parse::LabelMap;
-use crate::parse::parse_data;
 use crate::parse::parse_u16;
 use crate::parse::parse_u32;
 use anyhow::Result;
@@ -30,16 +31,16 @@ impl DnsData for DnsAnswer {
         buf.extend_from_slice(&self.name.encode(pos, label_map)?);
 
         // qtype
-        buf.put_u16(self.qtype.clone().try_into()?);
+        buf.put_u16(self.qtype.code());
 
         // class
         buf.put_u16(self.class);
 
-        // tll -- hardcoded for now
+        // tll
         buf.put_u32(self.ttl);
 
-        // length -- each IP is 4 bytes
-        buf.put_u16(self.data.len() as u16 * 4);
+        // length
+        buf.put_u16(u16::try_from(self.data.len())?);
 
         // data
         buf.extend_from_slice(&self.data);
@@ -54,22 +55,28 @@ impl DnsData for DnsAnswer {
 
         // qtype
         let (current, qtype) = {
-            let (c, q) = parse_u16(&buf, current)?;
-            let qtype: QuestionType = q.try_into()?;
+            let (c, q) = parse_u16(buf, current)?;
+            let qtype: QuestionType = q.into();
             (c, qtype)
         };
 
         // class
-        let (current, class) = parse_u16(&buf, current)?;
+        let (current, class) = parse_u16(buf, current)?;
 
         // ttl
-        let (current, ttl) = parse_u32(&buf, current)?;
+        let (current, ttl) = parse_u32(buf, current)?;
 
         // parse the length
-        let (current, data_length) = parse_u16(&buf, current)?;
-
-        // parse the data according to the length and type
-        let (current, data) = parse_data(buf, current, data_length as usize)?;
+        let (current, data_length) = parse_u16(buf, current)?;
+
+        // parse the data according to the length and type, any compressed names in there only
+        // make sense relative to this message, so we store the uncompressed form. UPDATE uses
+        // records with no data at all to mean every record of the type (RFC 2136 S2.4).
+        let data = match data_length {
+            0 => Bytes::new(),
+            len => RecordData::decode(buf, current, len as usize, &qtype, label_map)?.encode()?,
+        };
+        let current = current + data_length as usize;
 
         Ok((current, Self {
             name,
@@ -81,6 +88,77 @@ impl DnsData for DnsAnswer {
     }
 }
 
+impl DnsAnswer {
+    pub fn new(name: Domain, ttl: u32, rdata: RecordData) -> Result<Self> {
+        Ok(Self {
+            name,
+            qtype: rdata.qtype(),
+            class: CLASS_IN,
+            ttl,
+            data: rdata.encode()?,
+        })
+    }
+
+    pub fn rdata(&self) -> Result<RecordData> {
+        RecordData::from_bytes(&self.data, &self.qtype)
+    }
+
+    // The RDATA in canonical form (RFC 4034 S6.2, as amended by RFC 6840 S5.1): the names inside
+    // the older types and the RRSIG signer are lowercased, everything else is left alone.
+    pub fn canonical_data(&self) -> Result<Bytes> {
+        if self.data.is_empty() {
+            return Ok(self.data.clone());
+        }
+
+        let rdata = match self.rdata()? {
+            RecordData::NS(d) => RecordData::NS(d.to_lowercase()),
+            RecordData::MD(d) => RecordData::MD(d.to_lowercase()),
+            RecordData::MF(d) => RecordData::MF(d.to_lowercase()),
+            RecordData::CNAME(d) => RecordData::CNAME(d.to_lowercase()),
+            RecordData::MB(d) => RecordData::MB(d.to_lowercase()),
+            RecordData::MG(d) => RecordData::MG(d.to_lowercase()),
+            RecordData::MR(d) => RecordData::MR(d.to_lowercase()),
+            RecordData::PTR(d) => RecordData::PTR(d.to_lowercase()),
+            RecordData::SOA(mut soa) => {
+                soa.mname = soa.mname.to_lowercase();
+                soa.rname = soa.rname.to_lowercase();
+                RecordData::SOA(soa)
+            }
+            RecordData::MINFO { rmailbx, emailbx } => RecordData::MINFO {
+                rmailbx: rmailbx.to_lowercase(),
+                emailbx: emailbx.to_lowercase(),
+            },
+            RecordData::MX {
+                preference,
+                exchange,
+            } => RecordData::MX {
+                preference,
+                exchange: exchange.to_lowercase(),
+            },
+            RecordData::RRSIG(mut rrsig) => {
+                rrsig.signer = rrsig.signer.to_lowercase();
+                RecordData::RRSIG(rrsig)
+            }
+            _ => return Ok(self.data.clone()),
+        };
+
+        rdata.encode()
+    }
+
+    // the whole record in canonical form: lowercase owner, no compression, canonical RDATA
+    pub fn canonical(&self) -> Result<Bytes> {
+        let data = self.canonical_data()?;
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(&self.name.canonical());
+        buf.put_u16(self.qtype.code());
+        buf.put_u16(self.class);
+        buf.put_u32(self.ttl);
+        buf.put_u16(u16::try_from(data.len())?);
+        buf.extend_from_slice(&data);
+        Ok(buf.into())
+    }
+}
+
 #[derive(Default, Debug, Clone, Hash, Eq, PartialEq)]
 pub struct DnsAnswerSet {
     pub answers: Vec<DnsAnswer>,
@@ -164,21 +242,15 @@ mod tests {
             }
 
             let class = u16::arbitrary(g);
-            let qtype = (u16::arbitrary(g) % 16) + 1;
-            let qtype: QuestionType = qtype.try_into().unwrap();
+            let rdata = RecordData::arbitrary(g);
             let ttl: u32 = (u32::arbitrary(g) % 256) + 5;
-            let mut data: Vec<u8> = Vec::new();
-
-            for _ in 0..4 {
-                data.push(u8::arbitrary(g) + 1);
-            }
 
             Self {
                 name,
                 class,
-                qtype,
+                qtype: rdata.qtype(),
                 ttl,
-                data: data.into(),
+                data: rdata.encode().unwrap(),
             }
         }
     }
@@ -199,13 +271,14 @@ mod tests {
         }
     }
 
-    // quickcheck! {
-    //     fn encode_decode_answers(h: DnsAnswerSet) -> TestResult {
-    //         let mut m: HashMap<String, usize> = HashMap::new();
-    //         let buf = h.encode(h.answers.len(), &mut m, 0).unwrap();
-    //         let (_, questions) = DnsAnswerSet::decode(&buf, 0, h.answers.len(), &mut m).unwrap();
-    //         assert_eq!(questions, h);
-    //         TestResult::passed()
-    //     }
-    // }
+    quickcheck! {
+        fn encode_decode_answers(h: DnsAnswerSet) -> TestResult {
+            let mut m: HashMap<String, usize> = HashMap::new();
+            let buf = h.encode(h.answers.len(), &mut m, 0).unwrap();
+            let (_, questions) =
+                DnsAnswerSet::decode(&buf, 0, h.answers.len(), &mut HashMap::new()).unwrap();
+            assert_eq!(questions, h);
+            TestResult::passed()
+        }
+    }
 }
@@ -1,57 +1,292 @@
 use crate::dns::DnsAnswerSet;
+use crate::dns::DnsQuestion;
 use crate::dns::DnsQuestionSet;
-use crate::dns::header::{DnsHeader, DnsPacketType};
+use crate::dns::Edns;
+use crate::dns::QuestionType;
+use crate::dns::header::{DnsHeader, DnsPacketType, ResponseCode};
+use crate::dns::{
+    DOH_ALPN, DOT_ALPN, TCP_IDLE_TIMEOUT, TLS_IDLE_TIMEOUT, quic_server_config, serve_connection,
+    serve_https_connection, serve_quic_connection,
+};
+use crate::handler::{DnsHandler, DnsRequest, EchoHandler, Transport};
 use crate::parse::DnsData;
 use crate::parse::LabelMap;
 use anyhow::{Result, ensure};
 use bytes::{Bytes, BytesMut};
+use rustls::ServerConfig;
 use std::collections::HashMap;
-use tokio::net::UdpSocket;
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, UdpSocket};
+use tokio::time::timeout;
+use tokio_rustls::TlsAcceptor;
 use tracing::debug;
 use tracing::info;
 use tracing::instrument;
+use tracing::warn;
+
+// the largest message we'll read off of a UDP socket
+pub const MAX_UDP_MESSAGE_SIZE: usize = 4096;
+
+// the most we can send over UDP to a client that didn't say it can take more (RFC 1035 S4.2.1)
+pub const MIN_UDP_MESSAGE_SIZE: usize = 512;
 
 #[derive(Debug)]
 pub struct DnsServer {
     port: u16,
-    sock: UdpSocket,
+    sock: Arc<UdpSocket>,
+
+    // TCP listens on the same address and port as UDP
+    listener: TcpListener,
+
+    // DNS over TLS, HTTPS and QUIC have addresses of their own, if they're on at all
+    tls: Option<TlsListener>,
+    https: Option<HttpsListener>,
+    quic: Option<quinn::Endpoint>,
+    tls_idle: Duration,
+
+    handler: Arc<dyn DnsHandler>,
+}
+
+#[derive(Debug)]
+struct TlsListener {
+    listener: TcpListener,
+    config: Arc<ServerConfig>,
+}
+
+// without a TLS config it's plain HTTP, for behind a reverse proxy
+#[derive(Debug)]
+struct HttpsListener {
+    listener: TcpListener,
+    config: Option<Arc<ServerConfig>>,
 }
 
 impl DnsServer {
     pub async fn build(address: &str) -> Result<Self> {
-        let sock = UdpSocket::bind(address).await?;
+        // Given port 0, UDP picks a port that's free, but it might not be free for TCP. Then we
+        // try again for another.
+        let any_port = address.parse::<SocketAddr>().is_ok_and(|a| a.port() == 0);
+        let mut attempts = 0;
+        let (sock, listener) = loop {
+            let sock = UdpSocket::bind(address).await?;
+            match TcpListener::bind(sock.local_addr()?).await {
+                Ok(listener) => break (sock, listener),
+                Err(e) if any_port && e.kind() == ErrorKind::AddrInUse && attempts < 10 => {
+                    attempts += 1;
+                }
+                Err(e) => return Err(e.into()),
+            }
+        };
 
         Ok(Self {
             port: sock.local_addr()?.port(),
-            sock,
+            sock: Arc::new(sock),
+            listener,
+            tls: None,
+            https: None,
+            quic: None,
+            tls_idle: TLS_IDLE_TIMEOUT,
+            handler: Arc::new(EchoHandler),
         })
     }
 
+    // also serve DNS over TLS (RFC 7858), on an address of its own, usually port 853
+    pub async fn with_tls(mut self, address: &str, config: Arc<ServerConfig>) -> Result<Self> {
+        let mut config = ServerConfig::clone(&config);
+        config.alpn_protocols = vec![DOT_ALPN.to_vec()];
+        self.tls = Some(TlsListener {
+            listener: TcpListener::bind(address).await?,
+            config: Arc::new(config),
+        });
+        Ok(self)
+    }
+
+    // Also serve DNS over HTTPS (RFC 8484) on an address of its own, usually port 443. Without a
+    // TLS config it's served over plain HTTP, for when a reverse proxy in front does the TLS.
+    pub async fn with_https(
+        mut self,
+        address: &str,
+        config: Option<Arc<ServerConfig>>,
+    ) -> Result<Self> {
+        let config = config.map(|config| {
+            let mut config = ServerConfig::clone(&config);
+            config.alpn_protocols = DOH_ALPN.iter().map(|p| p.to_vec()).collect();
+            Arc::new(config)
+        });
+        self.https = Some(HttpsListener {
+            listener: TcpListener::bind(address).await?,
+            config,
+        });
+        Ok(self)
+    }
+
+    // Also serve DNS over QUIC (RFC 9250) on a UDP address of its own, usually port 853. QUIC
+    // keeps track of idle connections itself, so the idle timeout has to be set before this.
+    pub fn with_quic(mut self, address: &str, config: Arc<ServerConfig>) -> Result<Self> {
+        let config = quic_server_config(&config, self.tls_idle)?;
+        self.quic = Some(quinn::Endpoint::server(config, address.parse()?)?);
+        Ok(self)
+    }
+
+    // how long a DoT, DoH or DoQ connection can go without a request, and a handshake can take
+    pub fn with_tls_idle_timeout(mut self, idle: Duration) -> Self {
+        self.tls_idle = idle;
+        self
+    }
+
+    // replace the handler that turns requests into responses, by default we just echo the
+    // request back
+    pub fn with_handler(mut self, handler: Arc<dyn DnsHandler>) -> Self {
+        self.handler = handler;
+        self
+    }
+
     pub async fn run_until_stopped(&self) -> Result<()> {
         debug!("our server is {}", self.sock.local_addr()?.to_string());
-        let mut buf = [0; 1024];
-        // somehow causing some hangin?
-        // let server_addr: String = std::env::args().collect::<Vec<String>>()[2].clone();
-        // info!("forwarding server is {server_addr}");
+        tokio::select! {
+            result = self.serve_udp() => result,
+            result = self.serve_tcp() => result,
+            result = self.serve_tls() => result,
+            result = self.serve_https() => result,
+            result = self.serve_quic() => result,
+        }
+    }
+
+    async fn serve_udp(&self) -> Result<()> {
+        let mut buf = [0; MAX_UDP_MESSAGE_SIZE];
         loop {
             let (len, addr) = self.sock.recv_from(&mut buf).await?;
             info!("got request");
 
-            // parse the request
-            let (_, req) =
-                DnsMessage::decode(&Bytes::copy_from_slice(&buf[..len]), 0, &mut HashMap::new())?;
+            // handlers might have to go talk to other servers, so each request gets its own task
+            // rather than holding up the receive loop
+            let request = Bytes::copy_from_slice(&buf[..len]);
+            let sock = self.sock.clone();
+            let handler = self.handler.clone();
+            tokio::spawn(async move {
+                match process_request(handler.as_ref(), request, addr).await {
+                    Ok(Some(reply)) => {
+                        info!("sending a response");
+                        if let Err(e) = sock.send_to(&reply, addr).await {
+                            warn!("failed to send response to {addr}: {e}");
+                        }
+                    }
+                    Ok(None) => debug!("no response for {addr}"),
+                    Err(e) => warn!("failed to handle request from {addr}: {e:#}"),
+                }
+            });
+        }
+    }
+
+    async fn serve_tcp(&self) -> Result<()> {
+        loop {
+            let (stream, addr) = self.listener.accept().await?;
+            debug!("accepted TCP connection from {addr}");
+
+            let handler = self.handler.clone();
+            tokio::spawn(async move {
+                if let Err(e) = serve_connection(
+                    stream,
+                    addr,
+                    Transport::Tcp,
+                    handler.as_ref(),
+                    TCP_IDLE_TIMEOUT,
+                )
+                .await
+                {
+                    warn!("TCP connection from {addr} failed: {e:#}");
+                }
+            });
+        }
+    }
+
+    async fn serve_tls(&self) -> Result<()> {
+        let Some(tls) = &self.tls else {
+            return std::future::pending().await;
+        };
+        let acceptor = TlsAcceptor::from(tls.config.clone());
+        loop {
+            let (stream, addr) = tls.listener.accept().await?;
+            debug!("accepted TLS connection from {addr}");
+
+            let acceptor = acceptor.clone();
+            let handler = self.handler.clone();
+            let idle = self.tls_idle;
+            tokio::spawn(async move {
+                let stream = match timeout(idle, acceptor.accept(stream)).await {
+                    Ok(Ok(stream)) => stream,
+                    Ok(Err(e)) => {
+                        debug!("TLS handshake with {addr} failed: {e}");
+                        return;
+                    }
+                    Err(_) => {
+                        debug!("TLS handshake with {addr} timed out");
+                        return;
+                    }
+                };
+                if let Err(e) =
+                    serve_connection(stream, addr, Transport::Tls, handler.as_ref(), idle).await
+                {
+                    warn!("TLS connection from {addr} failed: {e:#}");
+                }
+            });
+        }
+    }
 
-            // convert the request into a response
-            // let reply = forward_to_server(&server_addr, req).await?;
-            info!("sending a response");
+    async fn serve_https(&self) -> Result<()> {
+        let Some(https) = &self.https else {
+            return std::future::pending().await;
+        };
+        let acceptor = https.config.clone().map(TlsAcceptor::from);
+        loop {
+            let (stream, addr) = https.listener.accept().await?;
+            debug!("accepted HTTP connection from {addr}");
 
-            let _ = self
-                .sock
-                .send_to(&req.encode(0, &mut HashMap::new())?, addr)
-                .await?;
+            let acceptor = acceptor.clone();
+            let handler = self.handler.clone();
+            let idle = self.tls_idle;
+            tokio::spawn(async move {
+                let result = match acceptor {
+                    Some(acceptor) => match timeout(idle, acceptor.accept(stream)).await {
+                        Ok(Ok(stream)) => serve_https_connection(stream, addr, handler, idle).await,
+                        Ok(Err(e)) => {
+                            debug!("TLS handshake with {addr} failed: {e}");
+                            return;
+                        }
+                        Err(_) => {
+                            debug!("TLS handshake with {addr} timed out");
+                            return;
+                        }
+                    },
+                    None => serve_https_connection(stream, addr, handler, idle).await,
+                };
+                if let Err(e) = result {
+                    debug!("HTTP connection from {addr} failed: {e:#}");
+                }
+            });
+        }
+    }
 
-            // info!("send response {reply:?}");
+    async fn serve_quic(&self) -> Result<()> {
+        let Some(endpoint) = &self.quic else {
+            return std::future::pending().await;
+        };
+        while let Some(incoming) = endpoint.accept().await {
+            let handler = self.handler.clone();
+            tokio::spawn(async move {
+                let addr = incoming.remote_address();
+                match incoming.await {
+                    Ok(connection) => {
+                        debug!("accepted QUIC connection from {addr}");
+                        serve_quic_connection(connection, handler).await;
+                    }
+                    Err(e) => debug!("QUIC handshake with {addr} failed: {e}"),
+                }
+            });
         }
+        Ok(())
     }
 
     pub fn port(&self) -> u16 {
@@ -61,13 +296,106 @@ impl DnsServer {
     pub fn address(&self) -> Result<String> {
         Ok(self.sock.local_addr()?.to_string())
     }
+
+    pub fn tls_address(&self) -> Result<Option<String>> {
+        match &self.tls {
+            Some(tls) => Ok(Some(tls.listener.local_addr()?.to_string())),
+            None => Ok(None),
+        }
+    }
+
+    pub fn https_address(&self) -> Result<Option<String>> {
+        match &self.https {
+            Some(https) => Ok(Some(https.listener.local_addr()?.to_string())),
+            None => Ok(None),
+        }
+    }
+
+    pub fn quic_address(&self) -> Result<Option<String>> {
+        match &self.quic {
+            Some(endpoint) => Ok(Some(endpoint.local_addr()?.to_string())),
+            None => Ok(None),
+        }
+    }
+}
+
+// parse the request, run it through the handler and encode whatever comes back
+pub async fn process_request(
+    handler: &dyn DnsHandler,
+    buf: Bytes,
+    client: SocketAddr,
+) -> Result<Option<Bytes>> {
+    let message = match DnsMessage::decode(&buf, 0, &mut HashMap::new()) {
+        Ok((_, message)) => message,
+        Err(e) => match format_error(&buf) {
+            Some(reply) => {
+                debug!("malformed request from {client}: {e:#}");
+                return Ok(Some(reply.encode(0, &mut HashMap::new())?));
+            }
+            None => return Err(e),
+        },
+    };
+    let limit = udp_limit(&message);
+    let request = DnsRequest {
+        message,
+        client,
+        transport: Transport::Udp,
+        raw: buf,
+        key: None,
+    };
+
+    let reply = match handler.handle(&request).await {
+        Ok(Some(reply)) => reply,
+        Ok(None) => return Ok(None),
+        Err(e) => {
+            warn!("failed to handle request from {client}: {e:#}");
+            server_failure(&request.message)
+        }
+    };
+    Ok(Some(reply.encode_within(limit)?))
+}
+
+// how big a UDP reply to a request can be, 512 bytes unless its OPT offers more (RFC 6891 S6.2.5)
+fn udp_limit(request: &DnsMessage) -> usize {
+    request.edns().map_or(MIN_UDP_MESSAGE_SIZE, |edns| {
+        (edns.udp_payload_size as usize).clamp(MIN_UDP_MESSAGE_SIZE, MAX_UDP_MESSAGE_SIZE)
+    })
+}
+
+// the reply to a request that a handler failed on, so the client isn't left waiting
+pub fn server_failure(request: &DnsMessage) -> DnsMessage {
+    request
+        .clone()
+        .as_reply()
+        .with_response_code(ResponseCode::ServFail)
+}
+
+// The FORMERR for a request whose header we could read but not the rest, so the client hears
+// back rather than waiting for an answer that's never coming. Responses don't get one, or two
+// servers could end up bouncing errors back and forth.
+pub fn format_error(buf: &Bytes) -> Option<DnsMessage> {
+    if buf.len() < 12 {
+        return None;
+    }
+    let (_, request) = DnsHeader::decode(buf, 0, &mut HashMap::new()).ok()?;
+    if request.query_type == DnsPacketType::Response {
+        return None;
+    }
+
+    let mut reply = DnsMessage::default();
+    reply.header.packet_id = request.packet_id;
+    reply.header.opcode = request.opcode;
+    reply.header.recursion_desired = request.recursion_desired;
+    Some(reply.as_reply().with_response_code(ResponseCode::FormErr))
 }
 
-#[derive(Debug, Default, Eq, PartialEq)]
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
 pub struct DnsMessage {
     pub header: DnsHeader,
     pub questions: DnsQuestionSet,
     pub answers: DnsAnswerSet,
+    pub authority: DnsAnswerSet,
+    pub additional: DnsAnswerSet,
 }
 
 impl DnsData for DnsMessage {
@@ -90,6 +418,20 @@ impl DnsData for DnsMessage {
             buf.len(),
         )?);
 
+        // encode authority records
+        buf.extend_from_slice(&self.authority.encode(
+            self.header.authority_record_count as usize,
+            label_map,
+            buf.len(),
+        )?);
+
+        // encode additional records
+        buf.extend_from_slice(&self.additional.encode(
+            self.header.additional_record_count as usize,
+            label_map,
+            buf.len(),
+        )?);
+
         Ok(buf.into())
     }
 
@@ -105,17 +447,63 @@ impl DnsData for DnsMessage {
         let (current, questions) =
             DnsQuestionSet::decode(buf, current, header.question_count as usize, label_map)?;
 
+        // parse the answers
+        let (current, answers) =
+            DnsAnswerSet::decode(buf, current, header.answer_record_count as usize, label_map)?;
+
+        // parse the authority records
+        let (current, authority) = DnsAnswerSet::decode(
+            buf,
+            current,
+            header.authority_record_count as usize,
+            label_map,
+        )?;
+
+        // parse the additional records
+        let (current, additional) = DnsAnswerSet::decode(
+            buf,
+            current,
+            header.additional_record_count as usize,
+            label_map,
+        )?;
+
         Ok((current, Self {
             header,
             questions,
-            answers: DnsAnswerSet::default(),
+            answers,
+            authority,
+            additional,
         }))
     }
 }
 
 impl DnsMessage {
+    // a standard query for a single question
+    pub fn query(packet_id: u16, question: DnsQuestion) -> Self {
+        let mut message = Self::default();
+        message.header.packet_id = packet_id;
+        message.header.question_count = 1;
+        message.questions.questions.push(question);
+        message
+    }
+
+    // Turn a query into the start of its reply. The client's additional section, its OPT with
+    // any cookie or other options included, isn't ours to send back, so it goes. A client that
+    // spoke EDNS gets an OPT of our own instead, with the DO bit echoed (RFC 3225 S3).
     pub fn as_reply(mut self) -> Self {
+        let edns = self.edns();
         self.header.query_type = DnsPacketType::Response;
+        self.additional = DnsAnswerSet::default();
+        self.header.additional_record_count = 0;
+
+        let opt = edns.map(|edns| Edns {
+            dnssec_ok: edns.dnssec_ok,
+            ..Edns::default()
+        });
+        if let Some(Ok(opt)) = opt.map(|opt| opt.to_record()) {
+            self.additional.answers.push(opt);
+            self.header.additional_record_count = 1;
+        }
         self
     }
 
@@ -124,6 +512,60 @@ impl DnsMessage {
         self.answers = answer;
         Ok(self)
     }
+
+    pub fn with_authority(mut self, authority: DnsAnswerSet) -> Result<Self> {
+        self.header.authority_record_count = authority.answers.len().try_into()?;
+        self.authority = authority;
+        Ok(self)
+    }
+
+    pub fn with_additional(mut self, additional: DnsAnswerSet) -> Result<Self> {
+        self.header.additional_record_count = additional.answers.len().try_into()?;
+        self.additional = additional;
+        Ok(self)
+    }
+
+    pub fn with_response_code(mut self, response_code: ResponseCode) -> Self {
+        self.header.response_code = response_code as u8;
+        self
+    }
+
+    // Encode a reply to go over UDP in at most `limit` bytes. One that doesn't fit is sent with TC
+    // set so the client asks again over TCP, and loses its additional records, then its authority
+    // and then its answers until it does fit. The OPT stays, without it the client would think we
+    // don't speak EDNS.
+    pub fn encode_within(mut self, limit: usize) -> Result<Bytes> {
+        let buf = self.encode(0, &mut HashMap::new())?;
+        if buf.len() <= limit {
+            return Ok(buf);
+        }
+
+        self.header.truncation = true;
+        let mut additional = self.additional.clone();
+        additional.answers.retain(|a| a.qtype == QuestionType::OPT);
+        self = self.with_additional(additional)?;
+        let buf = self.encode(0, &mut HashMap::new())?;
+        if buf.len() <= limit {
+            return Ok(buf);
+        }
+
+        self = self.with_authority(DnsAnswerSet::default())?;
+        let buf = self.encode(0, &mut HashMap::new())?;
+        if buf.len() <= limit {
+            return Ok(buf);
+        }
+
+        self.with_answers(DnsAnswerSet::default())?
+            .encode(0, &mut HashMap::new())
+    }
+
+    pub fn response_code(&self) -> u8 {
+        self.header.response_code
+    }
+
+    pub fn question(&self) -> Option<&DnsQuestion> {
+        self.questions.questions.first()
+    }
 }
 
 pub async fn send_request(addr: &str, buf: Bytes) -> Result<Bytes> {
@@ -138,7 +580,7 @@ pub async fn send_request(addr: &str, buf: Bytes) -> Result<Bytes> {
     debug!("sent {n} bytes to {addr}");
 
     // receive response
-    let mut buf: [u8; 256] = [0; 256];
+    let mut buf = [0; MAX_UDP_MESSAGE_SIZE];
     let resp = current_sock.recv(&mut buf).await?;
 
     debug!("read {resp} bytes");
@@ -0,0 +1,243 @@
+use crate::dns::{DnsAnswer, DnsMessage, Domain, QuestionType, RecordData};
+use crate::parse::{parse_data, parse_u16};
+use anyhow::{Result, ensure};
+use bytes::{BufMut, Bytes, BytesMut};
+
+// the payload size we advertise, the DNS flag day 2020 recommendation
+pub const DEFAULT_UDP_PAYLOAD_SIZE: u16 = 1232;
+
+// EDNS option codes
+pub const EDNS_OPTION_EXTENDED_ERROR: u16 = 15;
+
+// Extended DNS Error codes (RFC 8914 S4)
+pub const EDE_OTHER: u16 = 0;
+pub const EDE_UNSUPPORTED_DNSKEY_ALGORITHM: u16 = 1;
+pub const EDE_UNSUPPORTED_DS_DIGEST_TYPE: u16 = 2;
+pub const EDE_STALE_ANSWER: u16 = 3;
+pub const EDE_DNSSEC_BOGUS: u16 = 6;
+pub const EDE_SIGNATURE_EXPIRED: u16 = 7;
+pub const EDE_SIGNATURE_NOT_YET_VALID: u16 = 8;
+pub const EDE_DNSKEY_MISSING: u16 = 9;
+pub const EDE_RRSIGS_MISSING: u16 = 10;
+pub const EDE_NSEC_MISSING: u16 = 12;
+pub const EDE_BLOCKED: u16 = 15;
+pub const EDE_FILTERED: u16 = 17;
+pub const EDE_STALE_NXDOMAIN_ANSWER: u16 = 19;
+
+#[derive(Clone, Debug, Hash, Eq, PartialEq)]
+pub struct EdnsOption {
+    pub code: u16,
+    pub data: Bytes,
+}
+
+impl EdnsOption {
+    pub fn extended_error(info_code: u16, extra_text: &str) -> Self {
+        let mut data = BytesMut::new();
+        data.put_u16(info_code);
+        data.extend_from_slice(extra_text.as_bytes());
+        Self {
+            code: EDNS_OPTION_EXTENDED_ERROR,
+            data: data.into(),
+        }
+    }
+
+    // the INFO-CODE of an Extended DNS Error option
+    pub fn extended_error_code(&self) -> Option<u16> {
+        if self.code != EDNS_OPTION_EXTENDED_ERROR || self.data.len() < 2 {
+            return None;
+        }
+        Some(u16::from_be_bytes([self.data[0], self.data[1]]))
+    }
+}
+
+// OPT RDATA is just a list of {code, length, data} options
+pub fn decode_options(buf: &Bytes, pos: usize, len: usize) -> Result<(usize, Vec<EdnsOption>)> {
+    let end = pos + len;
+    let mut options = Vec::new();
+    let mut current = pos;
+
+    while current < end {
+        let (c, code) = parse_u16(buf, current)?;
+        let (c, length) = parse_u16(buf, c)?;
+        let (c, data) = parse_data(buf, c, length as usize)?;
+        ensure!(c <= end, "EDNS option runs past the end of the record");
+        options.push(EdnsOption { code, data });
+        current = c;
+    }
+
+    Ok((current, options))
+}
+
+pub fn encode_options(options: &[EdnsOption], buf: &mut BytesMut) -> Result<()> {
+    for option in options {
+        buf.put_u16(option.code);
+        buf.put_u16(u16::try_from(option.data.len())?);
+        buf.extend_from_slice(&option.data);
+    }
+    Ok(())
+}
+
+// The OPT pseudo-record (RFC 6891 S6.1.2) reuses the fields of a normal record for other things:
+// the class is the UDP payload size and the TTL holds the extended RCODE, version and flags.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Edns {
+    pub udp_payload_size: u16,
+    pub extended_rcode: u8,
+    pub version: u8,
+    pub dnssec_ok: bool,
+    pub options: Vec<EdnsOption>,
+}
+
+impl Default for Edns {
+    fn default() -> Self {
+        Self {
+            udp_payload_size: DEFAULT_UDP_PAYLOAD_SIZE,
+            extended_rcode: 0,
+            version: 0,
+            dnssec_ok: false,
+            options: Vec::new(),
+        }
+    }
+}
+
+impl Edns {
+    pub fn from_record(record: &DnsAnswer) -> Result<Self> {
+        ensure!(record.qtype == QuestionType::OPT, "not an OPT record");
+        let options = match record.rdata()? {
+            RecordData::OPT(options) => options,
+            _ => Vec::new(),
+        };
+
+        Ok(Self {
+            udp_payload_size: record.class,
+            extended_rcode: (record.ttl >> 24) as u8,
+            version: (record.ttl >> 16) as u8,
+            dnssec_ok: (record.ttl >> 15) & 0x1 == 1,
+            options,
+        })
+    }
+
+    pub fn to_record(&self) -> Result<DnsAnswer> {
+        let mut ttl = (self.extended_rcode as u32) << 24 | (self.version as u32) << 16;
+        if self.dnssec_ok {
+            ttl |= 1 << 15;
+        }
+
+        Ok(DnsAnswer {
+            name: Domain::root(),
+            qtype: QuestionType::OPT,
+            class: self.udp_payload_size,
+            ttl,
+            data: RecordData::OPT(self.options.clone()).encode()?,
+        })
+    }
+
+    pub fn extended_errors(&self) -> Vec<u16> {
+        self.options
+            .iter()
+            .filter_map(|o| o.extended_error_code())
+            .collect()
+    }
+}
+
+impl DnsMessage {
+    pub fn edns(&self) -> Option<Edns> {
+        self.additional
+            .answers
+            .iter()
+            .find(|a| a.qtype == QuestionType::OPT)
+            .and_then(|a| Edns::from_record(a).ok())
+    }
+
+    // replace the OPT record (if any) with `edns`
+    pub fn with_edns(mut self, edns: Edns) -> Result<Self> {
+        let mut additional = self.additional.clone();
+        additional.answers.retain(|a| a.qtype != QuestionType::OPT);
+        additional.answers.push(edns.to_record()?);
+        self = self.with_additional(additional)?;
+        Ok(self)
+    }
+
+    // attach an Extended DNS Error, adding an OPT record if there isn't one already
+    pub fn with_extended_error(self, info_code: u16, extra_text: &str) -> Result<Self> {
+        let mut edns = self.edns().unwrap_or_default();
+        edns.options
+            .push(EdnsOption::extended_error(info_code, extra_text));
+        self.with_edns(edns)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::{DnsQuestion, QuestionType};
+    use crate::parse::DnsData;
+    use std::collections::HashMap;
+
+    #[test]
+    fn extended_errors_survive_the_wire() {
+        let message = DnsMessage::query(
+            1,
+            DnsQuestion::new("example.com".parse().unwrap(), QuestionType::A),
+        )
+        .with_edns(Edns {
+            dnssec_ok: true,
+            ..Edns::default()
+        })
+        .unwrap()
+        .with_extended_error(EDE_STALE_ANSWER, "upstream unreachable")
+        .unwrap();
+
+        let buf = message.encode(0, &mut HashMap::new()).unwrap();
+        let (_, decoded) = DnsMessage::decode(&buf, 0, &mut HashMap::new()).unwrap();
+        let edns = decoded.edns().unwrap();
+
+        assert_eq!(decoded.additional.answers.len(), 1);
+        assert!(edns.dnssec_ok);
+        assert_eq!(edns.udp_payload_size, DEFAULT_UDP_PAYLOAD_SIZE);
+        assert_eq!(edns.extended_errors(), vec![EDE_STALE_ANSWER]);
+    }
+
+    #[test]
+    fn replies_get_our_opt_rather_than_the_clients() {
+        let cookie = EdnsOption {
+            code: 10,
+            data: Bytes::from_static(&[1, 2, 3, 4, 5, 6, 7, 8]),
+        };
+        let query = DnsMessage::query(
+            1,
+            DnsQuestion::new("example.com".parse().unwrap(), QuestionType::A),
+        )
+        .with_edns(Edns {
+            udp_payload_size: 4096,
+            dnssec_ok: true,
+            options: vec![cookie],
+            ..Edns::default()
+        })
+        .unwrap();
+        let mut additional = query.additional.clone();
+        additional.answers.push(
+            DnsAnswer::new(
+                "ns.example.com".parse().unwrap(),
+                300,
+                RecordData::A([192, 0, 2, 1].into()),
+            )
+            .unwrap(),
+        );
+        let query = query.with_additional(additional).unwrap();
+
+        let reply = query.as_reply();
+        assert_eq!(reply.header.additional_record_count, 1);
+        let edns = reply.edns().unwrap();
+        assert!(edns.dnssec_ok);
+        assert!(edns.options.is_empty());
+        assert_eq!(edns.udp_payload_size, DEFAULT_UDP_PAYLOAD_SIZE);
+
+        // and no OPT at all for a client that didn't send one
+        let query = DnsMessage::query(
+            2,
+            DnsQuestion::new("example.com".parse().unwrap(), QuestionType::A),
+        );
+        assert!(query.as_reply().additional.answers.is_empty());
+    }
+}
@@ -10,6 +10,40 @@ pub enum DnsPacketType {
     Response = 1,
 }
 
+// OPCODE values (RFC 1035 S4.1.1, RFC 1996, RFC 2136)
+#[derive(Debug, PartialEq, Eq, Default, Clone, Copy)]
+pub enum Opcode {
+    #[default]
+    Query = 0,
+    IQuery = 1,
+    Status = 2,
+    Notify = 4,
+    Update = 5,
+}
+
+// RCODE values (RFC 1035 S4.1.1)
+#[derive(Debug, PartialEq, Eq, Default, Clone, Copy)]
+pub enum ResponseCode {
+    #[default]
+    NoError = 0,
+    FormErr = 1,
+    ServFail = 2,
+    NxDomain = 3,
+    NotImp = 4,
+    Refused = 5,
+
+    // UPDATE (RFC 2136 S2.2)
+    YxDomain = 6,
+    YxRrset = 7,
+    NxRrset = 8,
+    NotAuth = 9,
+    NotZone = 10,
+}
+
+// DNSSEC took two of the reserved bits (RFC 4035 S3.2)
+const RESERVED_AUTHENTIC_DATA: u8 = 0x2;
+const RESERVED_CHECKING_DISABLED: u8 = 0x1;
+
 #[derive(Debug, Default, Clone, Eq, PartialEq)]
 pub struct DnsHeader {
     pub packet_id: u16,
@@ -27,6 +61,34 @@ pub struct DnsHeader {
     pub additional_record_count: u16,
 }
 
+impl DnsHeader {
+    // AD, set by a validating resolver when everything in the response checked out
+    pub fn authentic_data(&self) -> bool {
+        self.reserved & RESERVED_AUTHENTIC_DATA != 0
+    }
+
+    pub fn set_authentic_data(&mut self, on: bool) {
+        self.set_reserved(RESERVED_AUTHENTIC_DATA, on);
+    }
+
+    // CD, the client wants the data even if it doesn't validate
+    pub fn checking_disabled(&self) -> bool {
+        self.reserved & RESERVED_CHECKING_DISABLED != 0
+    }
+
+    pub fn set_checking_disabled(&mut self, on: bool) {
+        self.set_reserved(RESERVED_CHECKING_DISABLED, on);
+    }
+
+    fn set_reserved(&mut self, bit: u8, on: bool) {
+        if on {
+            self.reserved |= bit;
+        } else {
+            self.reserved &= !bit;
+        }
+    }
+}
+
 impl DnsData for DnsHeader {
     #[instrument(name = "Encoding DNS Header", skip_all, parent = None)]
     fn encode(&self, pos: usize, _: LabelMap) -> Result<Bytes> {
@@ -119,7 +181,7 @@ impl DnsData for DnsHeader {
             }
         };
 
-        let recursion_desired: bool = match (buf[2] >> 0) & 0x1 {
+        let recursion_desired: bool = match buf[2] & 0x1 {
             0 => false,
             1 => true,
             x => {
@@ -0,0 +1,425 @@
+use crate::dns::{
+    DnsAnswerSet, DnsMessage, DnsQuestion, Edns, QuestionType, RecordData, ResponseCode,
+    format_error, server_failure,
+};
+use crate::handler::{DnsHandler, DnsRequest, Transport};
+use crate::parse::DnsData;
+use crate::zone::parse_type;
+use anyhow::{Context, Result, anyhow};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::body::Incoming;
+use hyper::header::{CACHE_CONTROL, CONTENT_TYPE};
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::{TokioExecutor, TokioIo, TokioTimer};
+use hyper_util::server::conn::auto;
+use serde_json::{Value, json};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::time::{Instant, sleep, sleep_until};
+use tracing::{debug, warn};
+
+// where DNS over HTTPS requests go (RFC 8484 S4.1 leaves it up to us, this is what everyone uses)
+pub const DOH_PATH: &str = "/dns-query";
+
+pub const DNS_MESSAGE: &str = "application/dns-message";
+
+// the JSON format popularised by Google and Cloudflare, handy for poking at with curl
+pub const DNS_JSON: &str = "application/dns-json";
+
+// HTTP/2 is preferred but HTTP/1.1 works too (RFC 8484 S5.2)
+pub const DOH_ALPN: [&[u8]; 2] = [b"h2", b"http/1.1"];
+
+// the biggest POST body we'll read, which is the biggest a DNS message can be
+const MAX_BODY_SIZE: usize = 65535;
+
+// Answer DNS over HTTPS requests on a connection, in whichever HTTP version the client speaks.
+// A connection that goes `idle` without a request is shut down, whichever version it is. Over
+// HTTP/1.1 that includes a client slow to send its headers, and over HTTP/2 one that stops
+// answering pings.
+pub async fn serve_https_connection<S>(
+    stream: S,
+    client: SocketAddr,
+    handler: Arc<dyn DnsHandler>,
+    idle: Duration,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let activity = Arc::new(Activity::default());
+    let service = service_fn({
+        let activity = activity.clone();
+        move |request| {
+            let handler = handler.clone();
+            let busy = activity.start();
+            async move {
+                let response = respond(request, client, handler.as_ref()).await;
+                drop(busy);
+                Ok::<_, Infallible>(response)
+            }
+        }
+    });
+
+    let mut builder = auto::Builder::new(TokioExecutor::new());
+    builder
+        .http1()
+        .timer(TokioTimer::new())
+        .header_read_timeout(idle);
+    builder
+        .http2()
+        .timer(TokioTimer::new())
+        .keep_alive_interval(idle)
+        .keep_alive_timeout(idle);
+
+    let connection = builder.serve_connection(TokioIo::new(stream), service);
+    tokio::pin!(connection);
+    tokio::select! {
+        result = connection.as_mut() => return result.map_err(|e| anyhow!(e)),
+        _ = activity.idle_for(idle) => debug!("closing idle HTTP connection from {client}"),
+    }
+    connection.as_mut().graceful_shutdown();
+    connection.await.map_err(|e| anyhow!(e))
+}
+
+// how many requests a connection has in progress, and since when that's been the case
+#[derive(Debug)]
+struct Activity {
+    state: Mutex<(usize, Instant)>,
+}
+
+impl Default for Activity {
+    fn default() -> Self {
+        Self {
+            state: Mutex::new((0, Instant::now())),
+        }
+    }
+}
+
+impl Activity {
+    // a request has started, it's finished when the guard is dropped
+    fn start(self: &Arc<Self>) -> Busy {
+        let mut state = self.state.lock().unwrap();
+        *state = (state.0 + 1, Instant::now());
+        Busy(self.clone())
+    }
+
+    // returns once there have been no requests in progress for `idle`
+    async fn idle_for(&self, idle: Duration) {
+        loop {
+            let (requests, since) = *self.state.lock().unwrap();
+            match requests {
+                0 if since.elapsed() >= idle => return,
+                0 => sleep_until(since + idle).await,
+                _ => sleep(idle).await,
+            }
+        }
+    }
+}
+
+struct Busy(Arc<Activity>);
+
+impl Drop for Busy {
+    fn drop(&mut self) {
+        let mut state = self.0.state.lock().unwrap();
+        *state = (state.0 - 1, Instant::now());
+    }
+}
+
+async fn respond(
+    request: Request<Incoming>,
+    client: SocketAddr,
+    handler: &dyn DnsHandler,
+) -> Response<Full<Bytes>> {
+    match answer(request, client, handler).await {
+        Ok(response) => response,
+        Err(e) => {
+            warn!("failed to answer DoH request from {client}: {e:#}");
+            status(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+async fn answer(
+    request: Request<Incoming>,
+    client: SocketAddr,
+    handler: &dyn DnsHandler,
+) -> Result<Response<Full<Bytes>>> {
+    if request.uri().path() != DOH_PATH {
+        return Ok(status(StatusCode::NOT_FOUND));
+    }
+    let params = query_params(request.uri().query().unwrap_or_default());
+
+    // a GET with a name rather than a message is for the JSON API
+    let json = request.method() == Method::GET && params.contains_key("name");
+    let raw: Bytes = match *request.method() {
+        Method::GET if json => match json_query(&params) {
+            Ok(query) => query.encode(0, &mut HashMap::new())?,
+            Err(e) => {
+                debug!("bad JSON API request from {client}: {e:#}");
+                return Ok(status(StatusCode::BAD_REQUEST));
+            }
+        },
+        Method::GET => {
+            let dns = params.get("dns").map(|dns| dns.trim_end_matches('='));
+            let Some(Ok(raw)) = dns.map(|dns| URL_SAFE_NO_PAD.decode(dns)) else {
+                return Ok(status(StatusCode::BAD_REQUEST));
+            };
+            raw.into()
+        }
+        Method::POST => {
+            let content_type = request.headers().get(CONTENT_TYPE);
+            if content_type.is_none_or(|t| t != DNS_MESSAGE) {
+                return Ok(status(StatusCode::UNSUPPORTED_MEDIA_TYPE));
+            }
+            let Ok(body) = Limited::new(request.into_body(), MAX_BODY_SIZE)
+                .collect()
+                .await
+            else {
+                return Ok(status(StatusCode::PAYLOAD_TOO_LARGE));
+            };
+            body.to_bytes()
+        }
+        _ => return Ok(status(StatusCode::METHOD_NOT_ALLOWED)),
+    };
+
+    // Like everywhere else a query we can't make sense of gets a FORMERR and one we fail on a
+    // SERVFAIL. Only a message without so much as a header leaves nothing to answer.
+    let reply = match DnsMessage::decode(&raw, 0, &mut HashMap::new()) {
+        Ok((_, message)) => {
+            let request = DnsRequest {
+                message,
+                client,
+                transport: Transport::Https,
+                raw,
+                key: None,
+            };
+            match handler.handle(&request).await {
+                Ok(Some(reply)) => reply,
+                // there's no way to not answer an HTTP request, so the closest thing to it
+                Ok(None) => return Ok(status(StatusCode::SERVICE_UNAVAILABLE)),
+                Err(e) => {
+                    warn!("failed to handle request from {client}: {e:#}");
+                    server_failure(&request.message)
+                }
+            }
+        }
+        Err(e) => format_error(&raw).ok_or(e)?,
+    };
+
+    let mut response = Response::builder().status(StatusCode::OK);
+    if let Some(max_age) = max_age(&reply) {
+        response = response.header(CACHE_CONTROL, format!("max-age={max_age}"));
+    }
+    let response = match json {
+        true => response
+            .header(CONTENT_TYPE, DNS_JSON)
+            .body(Full::new(to_json(&reply).to_string().into()))?,
+        false => response
+            .header(CONTENT_TYPE, DNS_MESSAGE)
+            .body(Full::new(reply.encode(0, &mut HashMap::new())?))?,
+    };
+    Ok(response)
+}
+
+fn status(code: StatusCode) -> Response<Full<Bytes>> {
+    let mut response = Response::new(Full::new(Bytes::new()));
+    *response.status_mut() = code;
+    response
+}
+
+// How long the response can be cached: the smallest TTL in the answers, or for a negative answer
+// as long as the SOA says it can be (RFC 8484 S5.1, RFC 2308 S5). Errors don't get cached.
+fn max_age(response: &DnsMessage) -> Option<u32> {
+    let code = response.response_code();
+    if code != ResponseCode::NoError as u8 && code != ResponseCode::NxDomain as u8 {
+        return None;
+    }
+    if let Some(ttl) = response.answers.answers.iter().map(|a| a.ttl).min() {
+        return Some(ttl);
+    }
+    let soa = response
+        .authority
+        .answers
+        .iter()
+        .find(|a| a.qtype == QuestionType::SOA)?;
+    match soa.rdata() {
+        Ok(RecordData::SOA(data)) => Some(soa.ttl.min(data.minimum)),
+        _ => Some(soa.ttl),
+    }
+}
+
+// the query in a JSON API request: name, type as a mnemonic or number, and the cd and do flags
+fn json_query(params: &HashMap<String, String>) -> Result<DnsMessage> {
+    let name = params.get("name").context("no name")?.parse()?;
+    let qtype = match params.get("type") {
+        Some(t) => match t.parse::<u16>() {
+            Ok(code) => code.into(),
+            Err(_) => parse_type(t)?,
+        },
+        None => QuestionType::A,
+    };
+    let flag = |name: &str| params.get(name).is_some_and(|v| v == "1" || v == "true");
+
+    let mut message = DnsMessage::query(0, DnsQuestion::new(name, qtype));
+    message.header.recursion_desired = true;
+    message.header.set_checking_disabled(flag("cd"));
+    if flag("do") {
+        message = message.with_edns(Edns {
+            dnssec_ok: true,
+            ..Edns::default()
+        })?;
+    }
+    Ok(message)
+}
+
+fn to_json(response: &DnsMessage) -> Value {
+    let records = |set: &DnsAnswerSet| -> Vec<Value> {
+        set.answers
+            .iter()
+            .filter(|a| a.qtype != QuestionType::OPT)
+            .map(|a| {
+                json!({
+                    "name": a.name.to_string(),
+                    "type": a.qtype.code(),
+                    "TTL": a.ttl,
+                    "data": a.rdata().map(|r| r.to_string()).unwrap_or_default(),
+                })
+            })
+            .collect()
+    };
+
+    let header = &response.header;
+    let questions: Vec<Value> = response
+        .questions
+        .questions
+        .iter()
+        .map(|q| json!({ "name": q.name.to_string(), "type": q.qtype.code() }))
+        .collect();
+    let mut body = json!({
+        "Status": header.response_code,
+        "TC": header.truncation,
+        "RD": header.recursion_desired,
+        "RA": header.recursion_available,
+        "AD": header.authentic_data(),
+        "CD": header.checking_disabled(),
+        "Question": questions,
+    });
+    for (section, records) in [
+        ("Answer", records(&response.answers)),
+        ("Authority", records(&response.authority)),
+        ("Additional", records(&response.additional)),
+    ] {
+        if !records.is_empty() {
+            body[section] = Value::Array(records);
+        }
+    }
+    body
+}
+
+// the parameters in a query string, percent decoded
+fn query_params(query: &str) -> HashMap<String, String> {
+    query
+        .split('&')
+        .filter_map(|param| param.split_once('='))
+        .map(|(k, v)| (percent_decode(k), percent_decode(v)))
+        .collect()
+}
+
+fn percent_decode(s: &str) -> String {
+    let mut out = Vec::with_capacity(s.len());
+    let mut bytes = s.bytes();
+    while let Some(b) = bytes.next() {
+        match b {
+            b'+' => out.push(b' '),
+            b'%' => {
+                let hex: Vec<u8> = bytes.by_ref().take(2).collect();
+                let byte = std::str::from_utf8(&hex)
+                    .ok()
+                    .filter(|h| h.len() == 2)
+                    .and_then(|h| u8::from_str_radix(h, 16).ok());
+                match byte {
+                    Some(byte) => out.push(byte),
+                    None => {
+                        out.push(b'%');
+                        out.extend_from_slice(&hex);
+                    }
+                }
+            }
+            b => out.push(b),
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::{DnsAnswer, Soa};
+    use std::net::Ipv4Addr;
+
+    fn record(name: &str, ttl: u32, rdata: RecordData) -> DnsAnswer {
+        DnsAnswer::new(name.parse().unwrap(), ttl, rdata).unwrap()
+    }
+
+    #[test]
+    fn max_age_is_the_smallest_ttl() {
+        let query = DnsMessage::query(
+            0,
+            DnsQuestion::new("www.example.com".parse().unwrap(), QuestionType::A),
+        );
+        let answers = DnsAnswerSet {
+            answers: vec![
+                record(
+                    "www.example.com",
+                    300,
+                    RecordData::A(Ipv4Addr::new(192, 0, 2, 1)),
+                ),
+                record(
+                    "www.example.com",
+                    60,
+                    RecordData::A(Ipv4Addr::new(192, 0, 2, 2)),
+                ),
+            ],
+        };
+        let reply = query.clone().as_reply().with_answers(answers).unwrap();
+        assert_eq!(max_age(&reply), Some(60));
+
+        let soa = record(
+            "example.com",
+            3600,
+            RecordData::SOA(Soa {
+                minimum: 30,
+                ..Soa::default()
+            }),
+        );
+        let negative = query
+            .clone()
+            .as_reply()
+            .with_response_code(ResponseCode::NxDomain)
+            .with_authority(DnsAnswerSet { answers: vec![soa] })
+            .unwrap();
+        assert_eq!(max_age(&negative), Some(30));
+
+        let failed = query.as_reply().with_response_code(ResponseCode::ServFail);
+        assert_eq!(max_age(&failed), None);
+    }
+
+    #[test]
+    fn query_strings_are_decoded() {
+        let params = query_params("name=www.example.com&type=AAAA&x=a%20b%2&cd=1");
+        assert_eq!(params["name"], "www.example.com");
+        assert_eq!(params["x"], "a b%2");
+
+        let query = json_query(&params).unwrap();
+        assert_eq!(query.question().unwrap().qtype, QuestionType::AAAA);
+        assert!(query.header.checking_disabled());
+        assert!(query.edns().is_none());
+    }
+}
@@ -1,16 +1,28 @@
 use crate::parse::DnsData;
 use crate::parse::LabelMap;
 use crate::parse::parse_string;
+use crate::parse::parse_u8;
 use crate::parse::parse_u16;
-use anyhow::Context;
 use anyhow::Result;
 use anyhow::ensure;
 use bytes::BufMut;
 use bytes::Bytes;
 use bytes::BytesMut;
+use std::cmp::Ordering;
+use std::fmt;
+use std::str::FromStr;
 use tracing::debug;
 use tracing::instrument;
 
+// labels are limited to 63 octets, the top two bits of the length octet are reserved for pointers
+pub const MAX_LABEL_LENGTH: usize = 63;
+
+// pointers only have 14 bits for the offset
+const MAX_POINTER_OFFSET: usize = 0x3fff;
+
+// a name that points back into itself (or a chain of names that do) would otherwise loop forever
+const MAX_POINTER_DEPTH: usize = 64;
+
 pub enum LabelByte {
     Pointer,
     Null,
@@ -19,12 +31,15 @@ pub enum LabelByte {
 
 impl LabelByte {
     pub fn from_byte(buf: &Bytes, pos: usize) -> Result<Self> {
-        let (_, b) = parse_u16(buf, pos)?;
+        let (_, b) = parse_u8(buf, pos)?;
 
         match b {
-            x if x == 0x00 => Ok(Self::Null),
-            x if (x >> 14) & 0x3 == 3 => Ok(Self::Pointer),
-            _ => Ok(Self::Length),
+            0x00 => Ok(Self::Null),
+            x if (x >> 6) & 0x3 == 3 => Ok(Self::Pointer),
+            x if (x >> 6) & 0x3 == 0 => Ok(Self::Length),
+            x => Err(anyhow::Error::msg(format!(
+                "Unsupported label type in byte {x:#04x}"
+            ))),
         }
     }
 }
@@ -57,6 +72,148 @@ pub struct Domain {
 #[derive(Debug, Default, Clone, PartialEq, Eq, Hash)]
 pub struct Label(pub String);
 
+impl Domain {
+    pub fn root() -> Self {
+        Self::default()
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.labels.is_empty()
+    }
+
+    // the domain with the leftmost label removed, None for the root
+    pub fn parent(&self) -> Option<Self> {
+        if self.is_root() {
+            return None;
+        }
+
+        Some(Self {
+            labels: self.labels[1..].to_vec(),
+        })
+    }
+
+    // a new domain with `label` prepended, e.g. "www" + "example.com" = "www.example.com"
+    pub fn prepend(&self, label: &str) -> Self {
+        let mut labels = vec![Label(label.to_string())];
+        labels.extend(self.labels.iter().cloned());
+        Self { labels }
+    }
+
+    // domain names compare case-insensitively (RFC 4343), so anything used as a key should go
+    // through here first
+    pub fn to_lowercase(&self) -> Self {
+        Self {
+            labels: self
+                .labels
+                .iter()
+                .map(|l| Label(l.0.to_ascii_lowercase()))
+                .collect(),
+        }
+    }
+
+    pub fn eq_ignore_case(&self, other: &Domain) -> bool {
+        self.labels.len() == other.labels.len()
+            && self
+                .labels
+                .iter()
+                .zip(other.labels.iter())
+                .all(|(a, b)| a.0.eq_ignore_ascii_case(&b.0))
+    }
+
+    // true if we are equal to, or underneath, `other`
+    pub fn is_subdomain_of(&self, other: &Domain) -> bool {
+        if other.labels.len() > self.labels.len() {
+            return false;
+        }
+
+        self.labels
+            .iter()
+            .rev()
+            .zip(other.labels.iter().rev())
+            .all(|(a, b)| a.0.eq_ignore_ascii_case(&b.0))
+    }
+
+    // the length of the name on the wire without compression
+    pub fn wire_len(&self) -> usize {
+        self.labels.iter().map(|l| l.0.len() + 1).sum::<usize>() + 1
+    }
+
+    // the canonical wire form (RFC 4034 S6.2), lowercase and uncompressed
+    pub fn canonical(&self) -> Bytes {
+        let mut buf = BytesMut::with_capacity(self.wire_len());
+        for label in &self.labels {
+            buf.put_u8(label.0.len() as u8);
+            buf.extend_from_slice(label.0.to_ascii_lowercase().as_bytes());
+        }
+        buf.put_u8(0);
+        buf.into()
+    }
+
+    // Canonical name order (RFC 4034 S6.1): labels are compared from the root down as lowercase
+    // octet strings, and a name sorts before the names underneath it.
+    pub fn canonical_cmp(&self, other: &Domain) -> Ordering {
+        for (a, b) in self.labels.iter().rev().zip(other.labels.iter().rev()) {
+            let order =
+                a.0.to_ascii_lowercase()
+                    .as_bytes()
+                    .cmp(b.0.to_ascii_lowercase().as_bytes());
+            if order != Ordering::Equal {
+                return order;
+            }
+        }
+        self.labels.len().cmp(&other.labels.len())
+    }
+
+    pub fn is_wildcard(&self) -> bool {
+        self.labels.first().is_some_and(|l| l.0 == "*")
+    }
+
+    // the name with only its rightmost `n` labels
+    pub fn suffix(&self, n: usize) -> Self {
+        Self {
+            labels: self.labels[self.labels.len().saturating_sub(n)..].to_vec(),
+        }
+    }
+}
+
+impl fmt::Display for Domain {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_root() {
+            return write!(f, ".");
+        }
+
+        for label in &self.labels {
+            write!(f, "{}.", label.0)?;
+        }
+
+        Ok(())
+    }
+}
+
+// parses names in presentation format, the trailing dot is optional and "." (or "") is the root
+impl FromStr for Domain {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.strip_suffix('.').unwrap_or(s);
+        if s.is_empty() {
+            return Ok(Self::root());
+        }
+
+        let mut labels = Vec::new();
+        for l in s.split('.') {
+            ensure!(!l.is_empty(), "empty label in domain {s}");
+            ensure!(
+                l.len() <= MAX_LABEL_LENGTH,
+                "label {l} is longer than {MAX_LABEL_LENGTH} characters"
+            );
+            labels.push(Label(l.to_string()));
+        }
+
+        Ok(Self { labels })
+    }
+}
+
 impl DnsData for Domain {
     #[instrument(name = "Encoding Label", skip_all)]
     fn encode(&self, pos: usize, label_map: LabelMap) -> Result<Bytes> {
@@ -94,30 +251,29 @@ impl DnsData for Domain {
                         .ok_or(anyhow::Error::msg("Label num too large"))?;
 
                     // before we insert anything inside of the buffer, we want to store our
-                    // location in the domain map
+                    // location in the domain map, as long as a pointer can actually reach it
                     let loc = pos + buf.len();
-                    debug!(
-                        label = label,
-                        offset = loc,
-                        "label not found in map, inserting"
-                    );
-                    label_map.insert(label, loc);
+                    if loc <= MAX_POINTER_OFFSET {
+                        debug!(
+                            label = label,
+                            offset = loc,
+                            "label not found in map, inserting"
+                        );
+                        label_map.insert(label, loc);
+                    }
 
                     // first, we put the length of the string
-                    let len = {
-                        let len = l.0.len();
-                        ensure!(len < 0xcfff as usize, "label should be smaller than 0xcfff");
-                        let len = u16::try_from(len).with_context(
-                            || "couldn't convert length into u16 while encoding label",
-                        )? & 0xcfff as u16;
-                        len
-                    };
+                    let len = l.0.len();
+                    ensure!(
+                        len > 0 && len <= MAX_LABEL_LENGTH,
+                        "label should be between 1 and {MAX_LABEL_LENGTH} bytes"
+                    );
 
                     debug!(
                         position = buf.len() + pos,
                         "encoding {l:?} with length {len}"
                     );
-                    buf.put_u16(len);
+                    buf.put_u8(len as u8);
 
                     // then we put the string
                     buf.extend_from_slice(l.0.as_bytes());
@@ -126,126 +282,153 @@ impl DnsData for Domain {
                     // to signal the end of the label
                     if label_num == self.labels.len() - 1 {
                         debug!(
-                            label = "0x0000",
+                            label = "0x00",
                             offset = pos + buf.len(),
                             "last label, inserting null byte"
                         );
-                        buf.put_u16(0x0000);
+                        buf.put_u8(0x00);
                     }
                 }
             }
         }
 
+        // the root domain has no labels, it is just the null byte
+        if self.is_root() {
+            buf.put_u8(0x00);
+        }
+
         Ok(buf.into())
     }
 
     #[instrument(name = "Decoding Label", skip_all, ret)]
     fn decode(buf: &Bytes, pos: usize, label_map: LabelMap) -> Result<(usize, Self)> {
-        // where relative to pos are we in the buffer
-        let mut current = pos;
-
-        let mut res = Domain::default();
-
-        // we want to keep track of where different domains are encoded, but at decoding time, we
-        // can't tell where the domain ends until after we have either a pointer, or a null byte
-        let mut offsets: Vec<(String, usize)> = Vec::new();
-
-        // the domain ends when we reach either a pointer, or a null byte
-        loop {
-            debug!(offset = pos + current, "checking type");
-            match LabelByte::from_byte(buf, current)? {
-                // if we've hit the null, return the label set so far and move the cursor 1 past
-                // the null byte
-                LabelByte::Null => {
-                    // update the map with the labels
-                    for i in 0..offsets.len() {
-                        let offset = offsets[i].1;
-
-                        let x = offsets
-                            .iter()
-                            .skip(i)
-                            .map(|(s, _)| s.as_str())
-                            .collect::<Vec<&str>>()
-                            .join(".");
+        decode_domain(buf, pos, label_map, 0)
+    }
+}
 
-                        if label_map.get(&x).is_none() {
-                            debug!("inserting entry {x} with offset {offset}");
-                            label_map.insert(x, offset);
-                        }
+fn decode_domain(
+    buf: &Bytes,
+    pos: usize,
+    label_map: LabelMap,
+    depth: usize,
+) -> Result<(usize, Domain)> {
+    ensure!(depth <= MAX_POINTER_DEPTH, "too many compression pointers");
+
+    // where relative to pos are we in the buffer
+    let mut current = pos;
+
+    let mut res = Domain::default();
+
+    // we want to keep track of where different domains are encoded, but at decoding time, we
+    // can't tell where the domain ends until after we have either a pointer, or a null byte
+    let mut offsets: Vec<(String, usize)> = Vec::new();
+
+    // the domain ends when we reach either a pointer, or a null byte
+    loop {
+        debug!(offset = pos + current, "checking type");
+        match LabelByte::from_byte(buf, current)? {
+            // if we've hit the null, return the label set so far and move the cursor 1 past
+            // the null byte
+            LabelByte::Null => {
+                // update the map with the labels
+                for i in 0..offsets.len() {
+                    let offset = offsets[i].1;
+
+                    let x = offsets
+                        .iter()
+                        .skip(i)
+                        .map(|(s, _)| s.as_str())
+                        .collect::<Vec<&str>>()
+                        .join(".");
+
+                    if label_map.get(&x).is_none() {
+                        debug!("inserting entry {x} with offset {offset}");
+                        label_map.insert(x, offset);
                     }
-
-                    break Ok((current + 2, res));
                 }
 
-                // if we hit a pointer, we'll add the labels to our result set
-                // and return that
-                LabelByte::Pointer => {
-                    let (c, offset) = {
-                        let (c, pointer) = parse_u16(buf, current)?;
-                        let offset = pointer & 0x3fff;
-                        debug!(offset = offset, pointer = pointer, "pointer found");
-                        (c, offset)
-                    };
-
-                    // get the domain
-                    let domain = {
-                        let d = label_map
-                            .iter()
-                            .find(|(_, o)| **o == offset as usize)
-                            .ok_or(anyhow::Error::msg(
-                                "Encountered a label with an offset not located in the map",
-                            ))?;
-                        d.0.to_string()
-                    };
-
-                    // update our label map
-                    for i in 0..offsets.len() {
-                        let offset = offsets[i].1;
-
-                        let mut x = offsets
+                break Ok((current + 1, res));
+            }
+
+            // if we hit a pointer, we'll add the labels to our result set
+            // and return that
+            LabelByte::Pointer => {
+                let (c, offset) = {
+                    let (c, pointer) = parse_u16(buf, current)?;
+                    let offset = pointer & 0x3fff;
+                    debug!(offset = offset, pointer = pointer, "pointer found");
+                    (c, offset)
+                };
+
+                // get the domain, pointers have to point backwards
+                ensure!(
+                    (offset as usize) < current,
+                    "compression pointer {offset} does not point backwards"
+                );
+                let domain = match label_map.iter().find(|(_, o)| **o == offset as usize) {
+                    Some(d) => d.0.to_string(),
+
+                    // the name lives somewhere we haven't decoded through the map yet (for
+                    // example inside the RDATA of an earlier record), so decode it in place
+                    None => {
+                        let (_, d) = decode_domain(buf, offset as usize, label_map, depth + 1)?;
+                        d.labels
                             .iter()
-                            .skip(i)
-                            .map(|(s, _)| s.as_str())
+                            .map(|x| x.0.as_str())
                             .collect::<Vec<&str>>()
-                            .join(".");
+                            .join(".")
+                    }
+                };
+
+                // update our label map
+                for i in 0..offsets.len() {
+                    let offset = offsets[i].1;
+
+                    let mut x = offsets
+                        .iter()
+                        .skip(i)
+                        .map(|(s, _)| s.as_str())
+                        .collect::<Vec<&str>>()
+                        .join(".");
 
+                    if !domain.is_empty() {
                         x.push_str(&format!(".{}", domain.as_str()));
+                    }
 
-                        if label_map.get(&x).is_none() {
-                            debug!("inserting entry {x} with offset {offset}");
-                            label_map.insert(x, offset);
-                        }
+                    if label_map.get(&x).is_none() {
+                        debug!("inserting entry {x} with offset {offset}");
+                        label_map.insert(x, offset);
                     }
+                }
 
-                    // add them to our set
+                // add them to our set
+                if !domain.is_empty() {
                     res.labels
-                        .extend(domain.clone().split(".").map(|x| Label(x.to_string())));
-
-                    // a pointer ends the domain decoding
-                    // no need to add +1 because there is no null byte
-                    current = c;
-                    break Ok((current, res));
+                        .extend(domain.split(".").map(|x| Label(x.to_string())));
                 }
 
-                // if its a length, we'll decode this label
-                LabelByte::Length => {
-                    // parse the label
-                    let (c, s) = parse_string(buf, current)?;
+                // a pointer ends the domain decoding
+                // no need to add +1 because there is no null byte
+                current = c;
+                break Ok((current, res));
+            }
 
-                    // keep track of this label location within the buffer,
-                    // it will later be used to update the label map
-                    offsets.push((s.clone(), current));
+            // if its a length, we'll decode this label
+            LabelByte::Length => {
+                // parse the label
+                let (c, s) = parse_string(buf, current)?;
 
-                    // add the label to our results
-                    res.labels.push(Label(s));
+                // keep track of this label location within the buffer,
+                // it will later be used to update the label map
+                offsets.push((s.clone(), current));
 
-                    // update the current pointer
-                    current = c;
-                }
+                // add the label to our results
+                res.labels.push(Label(s));
+
+                // update the current pointer
+                current = c;
             }
         }
-
-        // update the
     }
 }
 
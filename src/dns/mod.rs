@@ -1,13 +1,32 @@
 mod answer;
+#[allow(clippy::module_inception)]
 mod dns;
+mod edns;
 mod header;
+mod https;
 mod label;
+mod prefix;
 mod question;
 mod question_type;
+mod quic;
+mod rdata;
+mod reverse;
+mod rrset;
+mod tcp;
+mod tls;
 
 pub use answer::*;
 pub use dns::*;
+pub use edns::*;
 pub use header::*;
+pub use https::*;
 pub use label::*;
+pub use prefix::*;
 pub use question::*;
 pub use question_type::QuestionType;
+pub use quic::*;
+pub use rdata::*;
+pub use reverse::*;
+pub use rrset::*;
+pub use tcp::*;
+pub use tls::*;
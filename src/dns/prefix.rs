@@ -0,0 +1,130 @@
+use anyhow::{Context, Result, ensure};
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+// An address and how many of its leading bits matter, i.e. a CIDR block like 192.0.2.0/24.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct IpPrefix {
+    address: IpAddr,
+    len: u8,
+}
+
+impl IpPrefix {
+    pub fn new(address: IpAddr, len: u8) -> Result<Self> {
+        let max = max_len(&address);
+        ensure!(len <= max, "prefix length {len} is longer than {max}");
+        Ok(Self {
+            address: mask(address, len),
+            len,
+        })
+    }
+
+    // a prefix matching just the one address
+    pub fn host(address: IpAddr) -> Self {
+        Self {
+            len: max_len(&address),
+            address,
+        }
+    }
+
+    pub fn address(&self) -> IpAddr {
+        self.address
+    }
+
+    pub fn prefix_len(&self) -> u8 {
+        self.len
+    }
+
+    // IPv4 addresses mapped into IPv6 (::ffff:a.b.c.d) match IPv4 prefixes
+    pub fn contains(&self, address: &IpAddr) -> bool {
+        let address = address.to_canonical();
+        match (self.address, address) {
+            (IpAddr::V4(_), IpAddr::V4(_)) | (IpAddr::V6(_), IpAddr::V6(_)) => {
+                mask(address, self.len) == self.address
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpPrefix {
+    type Err = anyhow::Error;
+
+    // an address with an optional /length, a bare address is a single host
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        match s.split_once('/') {
+            Some((address, len)) => {
+                let address: IpAddr = address
+                    .parse()
+                    .with_context(|| format!("bad address in {s}"))?;
+                let len: u8 = len
+                    .parse()
+                    .with_context(|| format!("bad prefix length in {s}"))?;
+                Self::new(address, len)
+            }
+            None => Ok(Self::host(
+                s.parse().with_context(|| format!("bad address {s}"))?,
+            )),
+        }
+    }
+}
+
+impl fmt::Display for IpPrefix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.address, self.len)
+    }
+}
+
+fn max_len(address: &IpAddr) -> u8 {
+    match address {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
+
+// the address with everything after the first `len` bits cleared
+fn mask(address: IpAddr, len: u8) -> IpAddr {
+    match address {
+        IpAddr::V4(a) => {
+            let bits = u32::from(a);
+            let mask = u32::MAX.checked_shl(32 - len as u32).unwrap_or(0);
+            IpAddr::V4((bits & mask).into())
+        }
+        IpAddr::V6(a) => {
+            let bits = u128::from(a);
+            let mask = u128::MAX.checked_shl(128 - len as u32).unwrap_or(0);
+            IpAddr::V6((bits & mask).into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_addresses_inside_the_prefix() {
+        let prefix: IpPrefix = "192.0.2.77/24".parse().unwrap();
+        assert_eq!(prefix.to_string(), "192.0.2.0/24");
+        assert!(prefix.contains(&"192.0.2.1".parse().unwrap()));
+        assert!(prefix.contains(&"::ffff:192.0.2.1".parse().unwrap()));
+        assert!(!prefix.contains(&"192.0.3.1".parse().unwrap()));
+        assert!(!prefix.contains(&"2001:db8::1".parse().unwrap()));
+
+        let prefix: IpPrefix = "2001:db8::/32".parse().unwrap();
+        assert!(prefix.contains(&"2001:db8:1::1".parse().unwrap()));
+        assert!(!prefix.contains(&"2001:db9::1".parse().unwrap()));
+
+        let everything: IpPrefix = "0.0.0.0/0".parse().unwrap();
+        assert!(everything.contains(&"203.0.113.9".parse().unwrap()));
+
+        let host: IpPrefix = "10.1.2.3".parse().unwrap();
+        assert_eq!(host.prefix_len(), 32);
+        assert!(!host.contains(&"10.1.2.4".parse().unwrap()));
+
+        assert!("10.0.0.0/33".parse::<IpPrefix>().is_err());
+        assert!("example.com/8".parse::<IpPrefix>().is_err());
+    }
+}
@@ -9,6 +9,13 @@ use bytes::{BufMut, Bytes, BytesMut};
 use tracing::info;
 use tracing::instrument;
 
+// the internet class, the only one anyone really uses
+pub const CLASS_IN: u16 = 1;
+
+// the pseudo classes UPDATE uses to say what to delete or check for (RFC 2136 S2.4)
+pub const CLASS_NONE: u16 = 254;
+pub const CLASS_ANY: u16 = 255;
+
 #[derive(Default, Clone, Debug, Eq, PartialEq)]
 pub struct DnsQuestion {
     pub name: Domain,
@@ -25,7 +32,7 @@ impl DnsData for DnsQuestion {
         buf.extend_from_slice(&self.name.encode(pos, label_map)?);
 
         // type
-        buf.put_u16(self.qtype.clone().try_into()?);
+        buf.put_u16(self.qtype.code());
 
         // class
         buf.put_u16(self.class);
@@ -40,7 +47,7 @@ impl DnsData for DnsQuestion {
         // question type
         let (current, qtype) = {
             let (c, q) = parse_u16(buf, current)?;
-            (c, q.try_into()?)
+            (c, q.into())
         };
 
         // class type
@@ -50,6 +57,16 @@ impl DnsData for DnsQuestion {
     }
 }
 
+impl DnsQuestion {
+    pub fn new(name: Domain, qtype: QuestionType) -> Self {
+        Self {
+            name,
+            qtype,
+            class: CLASS_IN,
+        }
+    }
+}
+
 #[derive(Default, Debug, Clone, Eq, PartialEq)]
 pub struct DnsQuestionSet {
     pub questions: Vec<DnsQuestion>,
@@ -107,7 +124,7 @@ mod tests {
             let name: Domain = Domain::arbitrary(g);
             let class = u16::arbitrary(g);
             let qtype = (u16::arbitrary(g) % 16) + 1;
-            let qtype: QuestionType = qtype.try_into().unwrap();
+            let qtype: QuestionType = qtype.into();
 
             Self { name, class, qtype }
         }
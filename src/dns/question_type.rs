@@ -1,5 +1,3 @@
-use anyhow::{Error, Result};
-
 // TYPE            value and meaning
 // A               1 a host address
 // NS              2 an authoritative name server
@@ -17,7 +15,23 @@ use anyhow::{Error, Result};
 // MINFO           14 mailbox or mail list information
 // MX              15 mail exchange
 // TXT             16 text strings
+// AAAA            28 an IPv6 host address (RFC 3596)
+// OPT             41 EDNS(0) pseudo-record (RFC 6891)
+// DS              43 delegation signer (RFC 4034)
+// RRSIG           46 signature over an RRset (RFC 4034)
+// NSEC            47 next secure record, authenticated denial (RFC 4034)
+// DNSKEY          48 zone signing public key (RFC 4034)
+// NSEC3           50 hashed authenticated denial (RFC 5155)
+// NSEC3PARAM      51 NSEC3 parameters of a zone (RFC 5155)
+// TSIG            250 transaction signature (RFC 8945), never stored in a zone
+// IXFR            251 incremental zone transfer (RFC 1995), questions only
+// AXFR            252 full zone transfer (RFC 5936), questions only
+// ANY             255 all records (RFC 1035 calls it *), questions and UPDATE only
+//
+// Any other type is kept by its number, so records of types we don't know about can still be
+// passed along as opaque data (RFC 3597).
 #[derive(Clone, Debug, Default, Eq, PartialEq, Hash)]
+#[repr(u16)]
 pub enum QuestionType {
     #[default]
     A = 1,
@@ -36,13 +50,24 @@ pub enum QuestionType {
     MINFO = 14,
     MX = 15,
     TXT = 16,
+    AAAA = 28,
+    OPT = 41,
+    DS = 43,
+    RRSIG = 46,
+    NSEC = 47,
+    DNSKEY = 48,
+    NSEC3 = 50,
+    NSEC3PARAM = 51,
+    TSIG = 250,
+    IXFR = 251,
+    AXFR = 252,
+    ANY = 255,
+    Unknown(u16),
 }
 
-impl TryInto<QuestionType> for u16 {
-    type Error = anyhow::Error;
-
-    fn try_into(self) -> Result<QuestionType, Self::Error> {
-        Ok(match self {
+impl From<u16> for QuestionType {
+    fn from(code: u16) -> Self {
+        match code {
             1 => QuestionType::A,
             2 => QuestionType::NS,
             3 => QuestionType::MD,
@@ -59,16 +84,27 @@ impl TryInto<QuestionType> for u16 {
             14 => QuestionType::MINFO,
             15 => QuestionType::MX,
             16 => QuestionType::TXT,
-            _ => return Err(Error::msg(format!("Invalid QuestionType: {}", self))),
-        })
+            28 => QuestionType::AAAA,
+            41 => QuestionType::OPT,
+            43 => QuestionType::DS,
+            46 => QuestionType::RRSIG,
+            47 => QuestionType::NSEC,
+            48 => QuestionType::DNSKEY,
+            50 => QuestionType::NSEC3,
+            51 => QuestionType::NSEC3PARAM,
+            250 => QuestionType::TSIG,
+            251 => QuestionType::IXFR,
+            252 => QuestionType::AXFR,
+            255 => QuestionType::ANY,
+            other => QuestionType::Unknown(other),
+        }
     }
 }
 
-impl TryInto<u16> for QuestionType {
-    type Error = anyhow::Error;
-
-    fn try_into(self) -> Result<u16, Self::Error> {
-        Ok(match self {
+impl QuestionType {
+    // the TYPE number, for the wire and the places that deal in raw type codes like NSEC bitmaps
+    pub fn code(&self) -> u16 {
+        match self {
             QuestionType::A => 1,
             QuestionType::NS => 2,
             QuestionType::MD => 3,
@@ -85,6 +121,27 @@ impl TryInto<u16> for QuestionType {
             QuestionType::MINFO => 14,
             QuestionType::MX => 15,
             QuestionType::TXT => 16,
-        })
+            QuestionType::AAAA => 28,
+            QuestionType::OPT => 41,
+            QuestionType::DS => 43,
+            QuestionType::RRSIG => 46,
+            QuestionType::NSEC => 47,
+            QuestionType::DNSKEY => 48,
+            QuestionType::NSEC3 => 50,
+            QuestionType::NSEC3PARAM => 51,
+            QuestionType::TSIG => 250,
+            QuestionType::IXFR => 251,
+            QuestionType::AXFR => 252,
+            QuestionType::ANY => 255,
+            QuestionType::Unknown(code) => *code,
+        }
+    }
+
+    // the mnemonic, or TYPEnnn for the ones we don't know (RFC 3597 S5)
+    pub fn name(&self) -> String {
+        match self {
+            QuestionType::Unknown(code) => format!("TYPE{code}"),
+            known => format!("{known:?}"),
+        }
     }
 }
@@ -0,0 +1,165 @@
+use crate::dns::{DnsMessage, format_error, read_frame, server_failure, write_frame};
+use crate::handler::{DnsHandler, DnsRequest, Transport};
+use crate::parse::DnsData;
+use anyhow::{Result, anyhow, bail, ensure};
+use bytes::Bytes;
+use quinn::crypto::rustls::QuicServerConfig;
+use quinn::{Connection, IdleTimeout, RecvStream, SendStream, TransportConfig, VarInt};
+use rustls::ServerConfig;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::debug;
+use tracing::warn;
+
+// where DNS over QUIC is served unless we're told otherwise (RFC 9250 S4.1.1)
+pub const DOQ_PORT: u16 = 853;
+
+pub const DOQ_ALPN: &[u8] = b"doq";
+
+// the error codes DoQ closes connections and resets streams with (RFC 9250 S4.3)
+pub const DOQ_NO_ERROR: u32 = 0x0;
+pub const DOQ_INTERNAL_ERROR: u32 = 0x1;
+pub const DOQ_PROTOCOL_ERROR: u32 = 0x2;
+pub const DOQ_REQUEST_CANCELLED: u32 = 0x3;
+pub const DOQ_EXCESSIVE_LOAD: u32 = 0x4;
+pub const DOQ_UNSPECIFIED_ERROR: u32 = 0x5;
+
+// how a query on a stream went wrong, which decides what happens to the connection
+enum StreamError {
+    // the client broke the protocol, so the whole connection goes
+    Protocol(anyhow::Error),
+
+    // we couldn't answer, which only costs the client this query
+    Internal(anyhow::Error),
+}
+
+// A QUIC server config for DoQ from the TLS one. The idle timeout is QUIC's own, so quiet
+// connections go away without us having to watch them.
+pub fn quic_server_config(tls: &ServerConfig, idle: Duration) -> Result<quinn::ServerConfig> {
+    let mut tls = tls.clone();
+    tls.alpn_protocols = vec![DOQ_ALPN.to_vec()];
+    let mut config = quinn::ServerConfig::with_crypto(Arc::new(QuicServerConfig::try_from(tls)?));
+
+    let mut transport = TransportConfig::default();
+    transport.max_idle_timeout(Some(IdleTimeout::try_from(idle)?));
+    config.transport_config(Arc::new(transport));
+    Ok(config)
+}
+
+// Answer queries on a DoQ connection until the client closes it or it times out. Every query
+// gets a bidirectional stream of its own (RFC 9250 S4.2).
+pub async fn serve_quic_connection(connection: Connection, handler: Arc<dyn DnsHandler>) {
+    let client = connection.remote_address();
+    loop {
+        let (send, recv) = match connection.accept_bi().await {
+            Ok(streams) => streams,
+            Err(e) => {
+                debug!("QUIC connection from {client} closed: {e}");
+                return;
+            }
+        };
+
+        let connection = connection.clone();
+        let handler = handler.clone();
+        tokio::spawn(async move {
+            serve_stream(connection, send, recv, handler.as_ref()).await;
+        });
+    }
+}
+
+async fn serve_stream(
+    connection: Connection,
+    mut send: SendStream,
+    mut recv: RecvStream,
+    handler: &dyn DnsHandler,
+) {
+    let client = connection.remote_address();
+    match answer(&connection, &mut recv, handler).await {
+        Ok(replies) if replies.is_empty() => {
+            // the handler chose not to answer, and the stream has to end somehow
+            let _ = send.reset(VarInt::from_u32(DOQ_REQUEST_CANCELLED));
+        }
+        Ok(replies) => {
+            for reply in replies {
+                if let Err(e) = write_frame(&mut send, &reply).await {
+                    debug!("failed to send DoQ response to {client}: {e:#}");
+                    return;
+                }
+            }
+            let _ = send.finish();
+        }
+        Err(StreamError::Internal(e)) => {
+            debug!("failed to answer DoQ query from {client}: {e:#}");
+            let _ = send.reset(VarInt::from_u32(DOQ_INTERNAL_ERROR));
+        }
+        Err(StreamError::Protocol(e)) => {
+            debug!("DoQ protocol error from {client}: {e:#}");
+            connection.close(VarInt::from_u32(DOQ_PROTOCOL_ERROR), b"");
+        }
+    }
+}
+
+// the encoded responses to the one query on a stream
+async fn answer(
+    connection: &Connection,
+    recv: &mut RecvStream,
+    handler: &dyn DnsHandler,
+) -> Result<Vec<Bytes>, StreamError> {
+    let raw = read_query(recv).await.map_err(StreamError::Protocol)?;
+
+    // a query we can't make sense of gets a FORMERR, as long as it has a header to answer
+    let (message, malformed) = match DnsMessage::decode(&raw, 0, &mut HashMap::new()) {
+        Ok((_, message)) => (message, false),
+        Err(e) => (
+            format_error(&raw).ok_or(e).map_err(StreamError::Protocol)?,
+            true,
+        ),
+    };
+
+    // Message IDs are pointless when every query has its own stream, so they have to be zero,
+    // and the response's is too since it's a copy of the query's (RFC 9250 S4.2.1)
+    if message.header.packet_id != 0 {
+        return Err(StreamError::Protocol(anyhow!(
+            "message ID {} isn't zero",
+            message.header.packet_id
+        )));
+    }
+    if malformed {
+        let reply = message.encode(0, &mut HashMap::new());
+        return reply
+            .map(|reply| vec![reply])
+            .map_err(StreamError::Internal);
+    }
+
+    let request = DnsRequest {
+        message,
+        client: connection.remote_address(),
+        transport: Transport::Quic,
+        raw,
+        key: None,
+    };
+    let replies = match handler.handle_stream(&request).await {
+        Ok(replies) => replies,
+        Err(e) => {
+            warn!("failed to handle request from {}: {e:#}", request.client);
+            vec![server_failure(&request.message)]
+        }
+    };
+    replies
+        .iter()
+        .map(|reply| reply.encode(0, &mut HashMap::new()))
+        .collect::<Result<_>>()
+        .map_err(StreamError::Internal)
+}
+
+// The query is length prefixed like over TCP, and the client has to finish the stream after it
+// (RFC 9250 S4.2).
+async fn read_query(recv: &mut RecvStream) -> Result<Bytes> {
+    let Some(raw) = read_frame(recv).await? else {
+        bail!("stream finished without a query");
+    };
+    let rest = recv.read_to_end(0).await?;
+    ensure!(rest.is_empty(), "more than one message on a stream");
+    Ok(raw)
+}
@@ -0,0 +1,985 @@
+use crate::dns::QuestionType;
+use crate::dns::edns::{EdnsOption, decode_options, encode_options};
+use crate::dns::label::Domain;
+use crate::dnssec::base32hex_encode;
+use crate::parse::DnsData;
+use crate::parse::LabelMap;
+use crate::parse::parse_data;
+use crate::parse::parse_string;
+use crate::parse::parse_u8;
+use crate::parse::parse_u16;
+use crate::parse::parse_u32;
+use anyhow::Result;
+use anyhow::bail;
+use anyhow::ensure;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use bytes::{BufMut, Bytes, BytesMut};
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+// SOA RDATA (RFC 1035 S3.3.13)
+#[derive(Clone, Debug, Default, Hash, Eq, PartialEq)]
+pub struct Soa {
+    pub mname: Domain,
+    pub rname: Domain,
+    pub serial: u32,
+    pub refresh: u32,
+    pub retry: u32,
+    pub expire: u32,
+    pub minimum: u32,
+}
+
+// TSIG RDATA (RFC 8945 S4.2). The time is a 48 bit count of seconds since the epoch.
+#[derive(Clone, Debug, Default, Hash, Eq, PartialEq)]
+pub struct Tsig {
+    pub algorithm: Domain,
+    pub time_signed: u64,
+    pub fudge: u16,
+    pub mac: Bytes,
+    pub original_id: u16,
+    pub error: u16,
+    pub other: Bytes,
+}
+
+// DS RDATA (RFC 4034 S5.1), a digest of a child zone's DNSKEY held in the parent
+#[derive(Clone, Debug, Default, Hash, Eq, PartialEq)]
+pub struct Ds {
+    pub key_tag: u16,
+    pub algorithm: u8,
+    pub digest_type: u8,
+    pub digest: Bytes,
+}
+
+// DNSKEY RDATA (RFC 4034 S2.1)
+#[derive(Clone, Debug, Default, Hash, Eq, PartialEq)]
+pub struct DnsKey {
+    pub flags: u16,
+    pub protocol: u8,
+    pub algorithm: u8,
+    pub public_key: Bytes,
+}
+
+// RRSIG RDATA (RFC 4034 S3.1). The type covered is kept as a number since it can be a type we
+// don't otherwise know about, the times are seconds since the epoch modulo 2^32.
+#[derive(Clone, Debug, Default, Hash, Eq, PartialEq)]
+pub struct Rrsig {
+    pub type_covered: u16,
+    pub algorithm: u8,
+    pub labels: u8,
+    pub original_ttl: u32,
+    pub expiration: u32,
+    pub inception: u32,
+    pub key_tag: u16,
+    pub signer: Domain,
+    pub signature: Bytes,
+}
+
+// NSEC RDATA (RFC 4034 S4.1), the types are the ones present at the owner name
+#[derive(Clone, Debug, Default, Hash, Eq, PartialEq)]
+pub struct Nsec {
+    pub next: Domain,
+    pub types: Vec<u16>,
+}
+
+// NSEC3 RDATA (RFC 5155 S3.2), the next owner is the raw hash rather than its base32 label
+#[derive(Clone, Debug, Default, Hash, Eq, PartialEq)]
+pub struct Nsec3 {
+    pub hash_algorithm: u8,
+    pub flags: u8,
+    pub iterations: u16,
+    pub salt: Bytes,
+    pub next_hashed: Bytes,
+    pub types: Vec<u16>,
+}
+
+// NSEC3PARAM RDATA (RFC 5155 S4.2)
+#[derive(Clone, Debug, Default, Hash, Eq, PartialEq)]
+pub struct Nsec3Param {
+    pub hash_algorithm: u8,
+    pub flags: u8,
+    pub iterations: u16,
+    pub salt: Bytes,
+}
+
+// The typed form of the RDATA of a record. DnsAnswer keeps the raw bytes around since that is
+// what goes on the wire, this is what you get when you need to look inside them.
+//
+// Any names inside of the RDATA may be compressed when they come off the wire, so decoding needs
+// the full message buffer and the label map. Encoding never compresses, which keeps the RDATA
+// self-contained no matter which message it ends up in.
+#[derive(Clone, Debug, Hash, Eq, PartialEq)]
+pub enum RecordData {
+    A(Ipv4Addr),
+    NS(Domain),
+    MD(Domain),
+    MF(Domain),
+    CNAME(Domain),
+    SOA(Soa),
+    MB(Domain),
+    MG(Domain),
+    MR(Domain),
+    NULL(Bytes),
+    WKS {
+        address: Ipv4Addr,
+        protocol: u8,
+        bitmap: Bytes,
+    },
+    PTR(Domain),
+    HINFO {
+        cpu: String,
+        os: String,
+    },
+    MINFO {
+        rmailbx: Domain,
+        emailbx: Domain,
+    },
+    MX {
+        preference: u16,
+        exchange: Domain,
+    },
+    TXT(Vec<String>),
+    AAAA(Ipv6Addr),
+    OPT(Vec<EdnsOption>),
+    DS(Ds),
+    RRSIG(Rrsig),
+    NSEC(Nsec),
+    DNSKEY(DnsKey),
+    NSEC3(Nsec3),
+    NSEC3PARAM(Nsec3Param),
+    TSIG(Tsig),
+
+    // RDATA we can't look inside, either of a type we don't know or TXT strings that aren't
+    // UTF-8, passed along as it came (RFC 3597)
+    Unknown(u16, Bytes),
+}
+
+impl RecordData {
+    pub fn qtype(&self) -> QuestionType {
+        match self {
+            RecordData::A(_) => QuestionType::A,
+            RecordData::NS(_) => QuestionType::NS,
+            RecordData::MD(_) => QuestionType::MD,
+            RecordData::MF(_) => QuestionType::MF,
+            RecordData::CNAME(_) => QuestionType::CNAME,
+            RecordData::SOA(_) => QuestionType::SOA,
+            RecordData::MB(_) => QuestionType::MB,
+            RecordData::MG(_) => QuestionType::MG,
+            RecordData::MR(_) => QuestionType::MR,
+            RecordData::NULL(_) => QuestionType::NULL,
+            RecordData::WKS { .. } => QuestionType::WKS,
+            RecordData::PTR(_) => QuestionType::PTR,
+            RecordData::HINFO { .. } => QuestionType::HINFO,
+            RecordData::MINFO { .. } => QuestionType::MINFO,
+            RecordData::MX { .. } => QuestionType::MX,
+            RecordData::TXT(_) => QuestionType::TXT,
+            RecordData::AAAA(_) => QuestionType::AAAA,
+            RecordData::OPT(_) => QuestionType::OPT,
+            RecordData::DS(_) => QuestionType::DS,
+            RecordData::RRSIG(_) => QuestionType::RRSIG,
+            RecordData::NSEC(_) => QuestionType::NSEC,
+            RecordData::DNSKEY(_) => QuestionType::DNSKEY,
+            RecordData::NSEC3(_) => QuestionType::NSEC3,
+            RecordData::NSEC3PARAM(_) => QuestionType::NSEC3PARAM,
+            RecordData::TSIG(_) => QuestionType::TSIG,
+            RecordData::Unknown(code, _) => QuestionType::from(*code),
+        }
+    }
+
+    // the single domain name held by NS, CNAME, PTR and friends
+    pub fn target(&self) -> Option<&Domain> {
+        match self {
+            RecordData::NS(d)
+            | RecordData::MD(d)
+            | RecordData::MF(d)
+            | RecordData::CNAME(d)
+            | RecordData::MB(d)
+            | RecordData::MG(d)
+            | RecordData::MR(d)
+            | RecordData::PTR(d) => Some(d),
+            RecordData::MX { exchange, .. } => Some(exchange),
+            _ => None,
+        }
+    }
+
+    // decode `len` bytes of RDATA starting at `pos`
+    pub fn decode(
+        buf: &Bytes,
+        pos: usize,
+        len: usize,
+        qtype: &QuestionType,
+        label_map: LabelMap,
+    ) -> Result<Self> {
+        let end = pos + len;
+        let (current, data) = match qtype {
+            QuestionType::A => {
+                let (c, ip) = parse_u32(buf, pos)?;
+                (c, RecordData::A(Ipv4Addr::from(ip)))
+            }
+            QuestionType::NS => decode_name(buf, pos, label_map, RecordData::NS)?,
+            QuestionType::MD => decode_name(buf, pos, label_map, RecordData::MD)?,
+            QuestionType::MF => decode_name(buf, pos, label_map, RecordData::MF)?,
+            QuestionType::CNAME => decode_name(buf, pos, label_map, RecordData::CNAME)?,
+            QuestionType::MB => decode_name(buf, pos, label_map, RecordData::MB)?,
+            QuestionType::MG => decode_name(buf, pos, label_map, RecordData::MG)?,
+            QuestionType::MR => decode_name(buf, pos, label_map, RecordData::MR)?,
+            QuestionType::PTR => decode_name(buf, pos, label_map, RecordData::PTR)?,
+            QuestionType::SOA => {
+                let (c, mname) = Domain::decode(buf, pos, label_map)?;
+                let (c, rname) = Domain::decode(buf, c, label_map)?;
+                let (c, serial) = parse_u32(buf, c)?;
+                let (c, refresh) = parse_u32(buf, c)?;
+                let (c, retry) = parse_u32(buf, c)?;
+                let (c, expire) = parse_u32(buf, c)?;
+                let (c, minimum) = parse_u32(buf, c)?;
+                (
+                    c,
+                    RecordData::SOA(Soa {
+                        mname,
+                        rname,
+                        serial,
+                        refresh,
+                        retry,
+                        expire,
+                        minimum,
+                    }),
+                )
+            }
+            QuestionType::NULL => {
+                let (c, data) = parse_data(buf, pos, len)?;
+                (c, RecordData::NULL(data))
+            }
+            QuestionType::WKS => {
+                ensure!(len >= 5, "WKS record is too short");
+                let (c, address) = parse_u32(buf, pos)?;
+                let (c, protocol) = parse_u8(buf, c)?;
+                let (c, bitmap) = parse_data(buf, c, end - c)?;
+                (c, RecordData::WKS {
+                    address: Ipv4Addr::from(address),
+                    protocol,
+                    bitmap,
+                })
+            }
+            QuestionType::HINFO => {
+                let (c, cpu) = parse_string(buf, pos)?;
+                let (c, os) = parse_string(buf, c)?;
+                (c, RecordData::HINFO { cpu, os })
+            }
+            QuestionType::MINFO => {
+                let (c, rmailbx) = Domain::decode(buf, pos, label_map)?;
+                let (c, emailbx) = Domain::decode(buf, c, label_map)?;
+                (c, RecordData::MINFO { rmailbx, emailbx })
+            }
+            QuestionType::MX => {
+                let (c, preference) = parse_u16(buf, pos)?;
+                let (c, exchange) = Domain::decode(buf, c, label_map)?;
+                (c, RecordData::MX {
+                    preference,
+                    exchange,
+                })
+            }
+            QuestionType::TXT => {
+                let mut strings = Vec::new();
+                let mut c = pos;
+                while c < end {
+                    let (next, len) = parse_u8(buf, c)?;
+                    let (next, s) = parse_data(buf, next, len as usize)?;
+                    strings.push(s);
+                    c = next;
+                }
+                let text: Result<Vec<String>, _> = strings
+                    .into_iter()
+                    .map(|s| String::from_utf8(s.to_vec()))
+                    .collect();
+                match text {
+                    Ok(text) => (c, RecordData::TXT(text)),
+                    Err(_) => (c, RecordData::Unknown(qtype.code(), buf.slice(pos..c))),
+                }
+            }
+            QuestionType::AAAA => {
+                ensure!(len == 16, "AAAA record is {len} bytes, not 16");
+                let (c, data) = parse_data(buf, pos, len)?;
+                let octets: [u8; 16] = data[..].try_into()?;
+                (c, RecordData::AAAA(Ipv6Addr::from(octets)))
+            }
+            QuestionType::OPT => {
+                let (c, options) = decode_options(buf, pos, len)?;
+                (c, RecordData::OPT(options))
+            }
+            QuestionType::DS => {
+                ensure!(len >= 4, "DS record is too short");
+                let (c, key_tag) = parse_u16(buf, pos)?;
+                let (c, algorithm) = parse_u8(buf, c)?;
+                let (c, digest_type) = parse_u8(buf, c)?;
+                let (c, digest) = parse_data(buf, c, end - c)?;
+                (
+                    c,
+                    RecordData::DS(Ds {
+                        key_tag,
+                        algorithm,
+                        digest_type,
+                        digest,
+                    }),
+                )
+            }
+            QuestionType::RRSIG => {
+                let (c, type_covered) = parse_u16(buf, pos)?;
+                let (c, algorithm) = parse_u8(buf, c)?;
+                let (c, labels) = parse_u8(buf, c)?;
+                let (c, original_ttl) = parse_u32(buf, c)?;
+                let (c, expiration) = parse_u32(buf, c)?;
+                let (c, inception) = parse_u32(buf, c)?;
+                let (c, key_tag) = parse_u16(buf, c)?;
+                let (c, signer) = Domain::decode(buf, c, label_map)?;
+                ensure!(c <= end, "RRSIG signer runs past the end of the record");
+                let (c, signature) = parse_data(buf, c, end - c)?;
+                (
+                    c,
+                    RecordData::RRSIG(Rrsig {
+                        type_covered,
+                        algorithm,
+                        labels,
+                        original_ttl,
+                        expiration,
+                        inception,
+                        key_tag,
+                        signer,
+                        signature,
+                    }),
+                )
+            }
+            QuestionType::NSEC => {
+                let (c, next) = Domain::decode(buf, pos, label_map)?;
+                ensure!(c <= end, "NSEC next name runs past the end of the record");
+                let (c, types) = decode_type_bitmap(buf, c, end)?;
+                (c, RecordData::NSEC(Nsec { next, types }))
+            }
+            QuestionType::DNSKEY => {
+                ensure!(len >= 4, "DNSKEY record is too short");
+                let (c, flags) = parse_u16(buf, pos)?;
+                let (c, protocol) = parse_u8(buf, c)?;
+                let (c, algorithm) = parse_u8(buf, c)?;
+                let (c, public_key) = parse_data(buf, c, end - c)?;
+                (
+                    c,
+                    RecordData::DNSKEY(DnsKey {
+                        flags,
+                        protocol,
+                        algorithm,
+                        public_key,
+                    }),
+                )
+            }
+            QuestionType::NSEC3 => {
+                let (c, hash_algorithm) = parse_u8(buf, pos)?;
+                let (c, flags) = parse_u8(buf, c)?;
+                let (c, iterations) = parse_u16(buf, c)?;
+                let (c, salt_len) = parse_u8(buf, c)?;
+                let (c, salt) = parse_data(buf, c, salt_len as usize)?;
+                let (c, hash_len) = parse_u8(buf, c)?;
+                let (c, next_hashed) = parse_data(buf, c, hash_len as usize)?;
+                ensure!(c <= end, "NSEC3 hash runs past the end of the record");
+                let (c, types) = decode_type_bitmap(buf, c, end)?;
+                (
+                    c,
+                    RecordData::NSEC3(Nsec3 {
+                        hash_algorithm,
+                        flags,
+                        iterations,
+                        salt,
+                        next_hashed,
+                        types,
+                    }),
+                )
+            }
+            QuestionType::NSEC3PARAM => {
+                let (c, hash_algorithm) = parse_u8(buf, pos)?;
+                let (c, flags) = parse_u8(buf, c)?;
+                let (c, iterations) = parse_u16(buf, c)?;
+                let (c, salt_len) = parse_u8(buf, c)?;
+                let (c, salt) = parse_data(buf, c, salt_len as usize)?;
+                (
+                    c,
+                    RecordData::NSEC3PARAM(Nsec3Param {
+                        hash_algorithm,
+                        flags,
+                        iterations,
+                        salt,
+                    }),
+                )
+            }
+            QuestionType::TSIG => {
+                let (c, algorithm) = Domain::decode(buf, pos, label_map)?;
+                let (c, time_high) = parse_u16(buf, c)?;
+                let (c, time_low) = parse_u32(buf, c)?;
+                let (c, fudge) = parse_u16(buf, c)?;
+                let (c, mac_size) = parse_u16(buf, c)?;
+                let (c, mac) = parse_data(buf, c, mac_size as usize)?;
+                let (c, original_id) = parse_u16(buf, c)?;
+                let (c, error) = parse_u16(buf, c)?;
+                let (c, other_len) = parse_u16(buf, c)?;
+                let (c, other) = parse_data(buf, c, other_len as usize)?;
+                (
+                    c,
+                    RecordData::TSIG(Tsig {
+                        algorithm,
+                        time_signed: (time_high as u64) << 32 | time_low as u64,
+                        fudge,
+                        mac,
+                        original_id,
+                        error,
+                        other,
+                    }),
+                )
+            }
+            QuestionType::IXFR | QuestionType::AXFR | QuestionType::ANY => {
+                bail!("{qtype:?} can only be asked for, there are no {qtype:?} records")
+            }
+            QuestionType::Unknown(code) => {
+                let (c, data) = parse_data(buf, pos, len)?;
+                (c, RecordData::Unknown(*code, data))
+            }
+        };
+
+        ensure!(
+            current == end,
+            "RDATA length mismatch for {qtype:?}: expected {len} bytes, used {}",
+            current - pos
+        );
+
+        Ok(data)
+    }
+
+    // decode RDATA that has already been uncompressed, i.e. DnsAnswer::data
+    pub fn from_bytes(data: &Bytes, qtype: &QuestionType) -> Result<Self> {
+        Self::decode(data, 0, data.len(), qtype, &mut HashMap::new())
+    }
+
+    pub fn encode(&self) -> Result<Bytes> {
+        let mut buf = BytesMut::new();
+
+        match self {
+            RecordData::A(ip) => buf.put_u32((*ip).into()),
+            RecordData::AAAA(ip) => buf.extend_from_slice(&ip.octets()),
+            RecordData::NS(d)
+            | RecordData::MD(d)
+            | RecordData::MF(d)
+            | RecordData::CNAME(d)
+            | RecordData::MB(d)
+            | RecordData::MG(d)
+            | RecordData::MR(d)
+            | RecordData::PTR(d) => buf.extend_from_slice(&encode_name(d)?),
+            RecordData::SOA(soa) => {
+                buf.extend_from_slice(&encode_name(&soa.mname)?);
+                buf.extend_from_slice(&encode_name(&soa.rname)?);
+                buf.put_u32(soa.serial);
+                buf.put_u32(soa.refresh);
+                buf.put_u32(soa.retry);
+                buf.put_u32(soa.expire);
+                buf.put_u32(soa.minimum);
+            }
+            RecordData::NULL(data) => buf.extend_from_slice(data),
+            RecordData::WKS {
+                address,
+                protocol,
+                bitmap,
+            } => {
+                buf.put_u32((*address).into());
+                buf.put_u8(*protocol);
+                buf.extend_from_slice(bitmap);
+            }
+            RecordData::HINFO { cpu, os } => {
+                encode_string(cpu, &mut buf)?;
+                encode_string(os, &mut buf)?;
+            }
+            RecordData::MINFO { rmailbx, emailbx } => {
+                buf.extend_from_slice(&encode_name(rmailbx)?);
+                buf.extend_from_slice(&encode_name(emailbx)?);
+            }
+            RecordData::MX {
+                preference,
+                exchange,
+            } => {
+                buf.put_u16(*preference);
+                buf.extend_from_slice(&encode_name(exchange)?);
+            }
+            RecordData::TXT(strings) => {
+                for s in strings {
+                    encode_string(s, &mut buf)?;
+                }
+            }
+            RecordData::OPT(options) => encode_options(options, &mut buf)?,
+            RecordData::DS(ds) => {
+                buf.put_u16(ds.key_tag);
+                buf.put_u8(ds.algorithm);
+                buf.put_u8(ds.digest_type);
+                buf.extend_from_slice(&ds.digest);
+            }
+            RecordData::RRSIG(rrsig) => {
+                buf.extend_from_slice(&rrsig.encode_without_signature()?);
+                buf.extend_from_slice(&rrsig.signature);
+            }
+            RecordData::NSEC(nsec) => {
+                buf.extend_from_slice(&encode_name(&nsec.next)?);
+                encode_type_bitmap(&nsec.types, &mut buf);
+            }
+            RecordData::DNSKEY(key) => {
+                buf.put_u16(key.flags);
+                buf.put_u8(key.protocol);
+                buf.put_u8(key.algorithm);
+                buf.extend_from_slice(&key.public_key);
+            }
+            RecordData::NSEC3(nsec3) => {
+                buf.put_u8(nsec3.hash_algorithm);
+                buf.put_u8(nsec3.flags);
+                buf.put_u16(nsec3.iterations);
+                buf.put_u8(u8::try_from(nsec3.salt.len())?);
+                buf.extend_from_slice(&nsec3.salt);
+                buf.put_u8(u8::try_from(nsec3.next_hashed.len())?);
+                buf.extend_from_slice(&nsec3.next_hashed);
+                encode_type_bitmap(&nsec3.types, &mut buf);
+            }
+            RecordData::NSEC3PARAM(param) => {
+                buf.put_u8(param.hash_algorithm);
+                buf.put_u8(param.flags);
+                buf.put_u16(param.iterations);
+                buf.put_u8(u8::try_from(param.salt.len())?);
+                buf.extend_from_slice(&param.salt);
+            }
+            RecordData::TSIG(tsig) => {
+                ensure!(
+                    tsig.time_signed < 1 << 48,
+                    "TSIG time doesn't fit in 48 bits"
+                );
+                buf.extend_from_slice(&encode_name(&tsig.algorithm)?);
+                buf.put_u16((tsig.time_signed >> 32) as u16);
+                buf.put_u32(tsig.time_signed as u32);
+                buf.put_u16(tsig.fudge);
+                buf.put_u16(u16::try_from(tsig.mac.len())?);
+                buf.extend_from_slice(&tsig.mac);
+                buf.put_u16(tsig.original_id);
+                buf.put_u16(tsig.error);
+                buf.put_u16(u16::try_from(tsig.other.len())?);
+                buf.extend_from_slice(&tsig.other);
+            }
+            RecordData::Unknown(_, data) => buf.extend_from_slice(data),
+        }
+
+        ensure!(buf.len() <= u16::MAX as usize, "RDATA is too large");
+        Ok(buf.into())
+    }
+}
+
+impl Rrsig {
+    // the RDATA up to the signature, with the signer in canonical form this is the start of what
+    // gets signed (RFC 4034 S3.1.8.1)
+    pub fn encode_without_signature(&self) -> Result<Bytes> {
+        let mut buf = BytesMut::new();
+        buf.put_u16(self.type_covered);
+        buf.put_u8(self.algorithm);
+        buf.put_u8(self.labels);
+        buf.put_u32(self.original_ttl);
+        buf.put_u32(self.expiration);
+        buf.put_u32(self.inception);
+        buf.put_u16(self.key_tag);
+        buf.extend_from_slice(&encode_name(&self.signer)?);
+        Ok(buf.into())
+    }
+}
+
+impl DnsKey {
+    // the Zone Key flag, only keys with it set can sign a zone's data
+    pub const ZONE: u16 = 0x0100;
+
+    // the Secure Entry Point flag, by convention the key signing key
+    pub const SEP: u16 = 0x0001;
+
+    // the key tag, a checksum used to pick a key out of a set (RFC 4034 Appendix B)
+    pub fn key_tag(&self) -> u16 {
+        let mut rdata = BytesMut::new();
+        rdata.put_u16(self.flags);
+        rdata.put_u8(self.protocol);
+        rdata.put_u8(self.algorithm);
+        rdata.extend_from_slice(&self.public_key);
+
+        let mut acc: u32 = 0;
+        for (i, b) in rdata.iter().enumerate() {
+            acc += if i & 1 == 1 {
+                *b as u32
+            } else {
+                (*b as u32) << 8
+            };
+        }
+        acc += (acc >> 16) & 0xffff;
+        (acc & 0xffff) as u16
+    }
+}
+
+// the type bitmap of NSEC and NSEC3 (RFC 4034 S4.1.2): blocks of up to 32 bytes per window of
+// 256 types, each bit standing for one type
+fn decode_type_bitmap(buf: &Bytes, pos: usize, end: usize) -> Result<(usize, Vec<u16>)> {
+    let mut types = Vec::new();
+    let mut c = pos;
+
+    while c < end {
+        let (next, window) = parse_u8(buf, c)?;
+        let (next, length) = parse_u8(buf, next)?;
+        ensure!(
+            (1..=32).contains(&length),
+            "invalid type bitmap length {length}"
+        );
+        let (next, bitmap) = parse_data(buf, next, length as usize)?;
+        for (i, byte) in bitmap.iter().enumerate() {
+            for bit in 0..8 {
+                if byte & (0x80 >> bit) != 0 {
+                    types.push((window as u16) << 8 | (i * 8 + bit) as u16);
+                }
+            }
+        }
+        c = next;
+    }
+
+    Ok((c, types))
+}
+
+fn encode_type_bitmap(types: &[u16], buf: &mut BytesMut) {
+    let mut types = types.to_vec();
+    types.sort_unstable();
+    types.dedup();
+
+    for window in types.chunk_by(|a, b| a >> 8 == b >> 8) {
+        let last = (window[window.len() - 1] & 0xff) as usize;
+        let mut bitmap = vec![0u8; last / 8 + 1];
+        for t in window {
+            let low = (t & 0xff) as usize;
+            bitmap[low / 8] |= 0x80 >> (low % 8);
+        }
+        buf.put_u8((window[0] >> 8) as u8);
+        buf.put_u8(bitmap.len() as u8);
+        buf.extend_from_slice(&bitmap);
+    }
+}
+
+// Presentation format (RFC 1035 S5.1), what goes after the type in a zone file. Binary fields
+// are base64 or hex as their RFCs say, and times are left as plain seconds (RFC 4034 S3.2).
+impl fmt::Display for RecordData {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RecordData::A(address) => write!(f, "{address}"),
+            RecordData::AAAA(address) => write!(f, "{address}"),
+            RecordData::NS(name)
+            | RecordData::MD(name)
+            | RecordData::MF(name)
+            | RecordData::CNAME(name)
+            | RecordData::MB(name)
+            | RecordData::MG(name)
+            | RecordData::MR(name)
+            | RecordData::PTR(name) => write!(f, "{name}"),
+            RecordData::SOA(soa) => write!(
+                f,
+                "{} {} {} {} {} {} {}",
+                soa.mname, soa.rname, soa.serial, soa.refresh, soa.retry, soa.expire, soa.minimum
+            ),
+            // RFC 3597 S5, for data we have no other way of showing
+            RecordData::NULL(data) => write!(f, "\\# {} {}", data.len(), hex::encode(data)),
+            RecordData::WKS {
+                address,
+                protocol,
+                bitmap,
+            } => write!(f, "{address} {protocol} {}", hex::encode(bitmap)),
+            RecordData::HINFO { cpu, os } => {
+                write!(f, "{} {}", quoted(cpu), quoted(os))
+            }
+            RecordData::MINFO { rmailbx, emailbx } => write!(f, "{rmailbx} {emailbx}"),
+            RecordData::MX {
+                preference,
+                exchange,
+            } => write!(f, "{preference} {exchange}"),
+            RecordData::TXT(strings) => {
+                let strings: Vec<_> = strings.iter().map(|s| quoted(s)).collect();
+                write!(f, "{}", strings.join(" "))
+            }
+            RecordData::OPT(options) => {
+                let options: Vec<_> = options
+                    .iter()
+                    .map(|o| format!("{}:{}", o.code, hex::encode(&o.data)))
+                    .collect();
+                write!(f, "{}", options.join(" "))
+            }
+            RecordData::DS(ds) => write!(
+                f,
+                "{} {} {} {}",
+                ds.key_tag,
+                ds.algorithm,
+                ds.digest_type,
+                hex::encode_upper(&ds.digest)
+            ),
+            RecordData::RRSIG(rrsig) => write!(
+                f,
+                "{} {} {} {} {} {} {} {} {}",
+                type_name(rrsig.type_covered),
+                rrsig.algorithm,
+                rrsig.labels,
+                rrsig.original_ttl,
+                rrsig.expiration,
+                rrsig.inception,
+                rrsig.key_tag,
+                rrsig.signer,
+                STANDARD.encode(&rrsig.signature)
+            ),
+            RecordData::NSEC(nsec) => write!(f, "{} {}", nsec.next, type_names(&nsec.types)),
+            RecordData::DNSKEY(key) => write!(
+                f,
+                "{} {} {} {}",
+                key.flags,
+                key.protocol,
+                key.algorithm,
+                STANDARD.encode(&key.public_key)
+            ),
+            RecordData::NSEC3(nsec3) => write!(
+                f,
+                "{} {} {} {} {} {}",
+                nsec3.hash_algorithm,
+                nsec3.flags,
+                nsec3.iterations,
+                salt(&nsec3.salt),
+                base32hex_encode(&nsec3.next_hashed),
+                type_names(&nsec3.types)
+            ),
+            RecordData::NSEC3PARAM(param) => write!(
+                f,
+                "{} {} {} {}",
+                param.hash_algorithm,
+                param.flags,
+                param.iterations,
+                salt(&param.salt)
+            ),
+            RecordData::TSIG(tsig) => write!(
+                f,
+                "{} {} {} {} {} {}",
+                tsig.algorithm,
+                tsig.time_signed,
+                tsig.fudge,
+                STANDARD.encode(&tsig.mac),
+                tsig.original_id,
+                tsig.error
+            ),
+            RecordData::Unknown(_, data) if data.is_empty() => write!(f, "\\# 0"),
+            RecordData::Unknown(_, data) => {
+                write!(f, "\\# {} {}", data.len(), hex::encode_upper(data))
+            }
+        }
+    }
+}
+
+fn type_name(code: u16) -> String {
+    QuestionType::from(code).name()
+}
+
+fn type_names(types: &[u16]) -> String {
+    let names: Vec<_> = types.iter().map(|t| type_name(*t)).collect();
+    names.join(" ")
+}
+
+// NSEC3 salts are hex, with - for no salt at all (RFC 5155 S3.3)
+fn salt(salt: &[u8]) -> String {
+    match salt.is_empty() {
+        true => "-".to_string(),
+        false => hex::encode_upper(salt),
+    }
+}
+
+// a <character-string> in quotes, escaping what would otherwise end it early
+fn quoted(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' | '\\' => {
+                out.push('\\');
+                out.push(c);
+            }
+            c if c.is_ascii_control() => out.push_str(&format!("\\{:03}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn decode_name(
+    buf: &Bytes,
+    pos: usize,
+    label_map: LabelMap,
+    f: fn(Domain) -> RecordData,
+) -> Result<(usize, RecordData)> {
+    let (c, d) = Domain::decode(buf, pos, label_map)?;
+    Ok((c, f(d)))
+}
+
+// names in RDATA are written out in full, using a fresh label map means nothing gets compressed
+fn encode_name(d: &Domain) -> Result<Bytes> {
+    d.encode(0, &mut HashMap::new())
+}
+
+pub fn encode_string(s: &str, buf: &mut BytesMut) -> Result<()> {
+    ensure!(s.len() <= 255, "character-string is longer than 255 bytes");
+    buf.put_u8(s.len() as u8);
+    buf.extend_from_slice(s.as_bytes());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quickcheck::Arbitrary;
+    use quickcheck::TestResult;
+    use quickcheck::quickcheck;
+
+    impl Arbitrary for Soa {
+        fn arbitrary(g: &mut quickcheck::Gen) -> Self {
+            Self {
+                mname: Domain::arbitrary(g),
+                rname: Domain::arbitrary(g),
+                serial: u32::arbitrary(g),
+                refresh: u32::arbitrary(g),
+                retry: u32::arbitrary(g),
+                expire: u32::arbitrary(g),
+                minimum: u32::arbitrary(g),
+            }
+        }
+    }
+
+    impl Arbitrary for RecordData {
+        fn arbitrary(g: &mut quickcheck::Gen) -> Self {
+            match u8::arbitrary(g) % 14 {
+                0 => RecordData::A(Ipv4Addr::from(u32::arbitrary(g))),
+                1 => RecordData::NS(Domain::arbitrary(g)),
+                2 => RecordData::CNAME(Domain::arbitrary(g)),
+                3 => RecordData::SOA(Soa::arbitrary(g)),
+                4 => RecordData::PTR(Domain::arbitrary(g)),
+                5 => RecordData::MX {
+                    preference: u16::arbitrary(g),
+                    exchange: Domain::arbitrary(g),
+                },
+                6 => RecordData::HINFO {
+                    cpu: "x86".to_string(),
+                    os: "linux".to_string(),
+                },
+                7 => RecordData::NSEC(Nsec {
+                    next: Domain::arbitrary(g),
+                    types: vec![1, 2, 6, 46, 47, 48, 257, 32769],
+                }),
+                8 => RecordData::DS(Ds {
+                    key_tag: u16::arbitrary(g),
+                    algorithm: 13,
+                    digest_type: 2,
+                    digest: Bytes::from(vec![u8::arbitrary(g); 32]),
+                }),
+                9 => RecordData::RRSIG(Rrsig {
+                    type_covered: 1,
+                    algorithm: 15,
+                    labels: 2,
+                    original_ttl: u32::arbitrary(g),
+                    expiration: u32::arbitrary(g),
+                    inception: u32::arbitrary(g),
+                    key_tag: u16::arbitrary(g),
+                    signer: Domain::arbitrary(g),
+                    signature: Bytes::from(vec![u8::arbitrary(g); 64]),
+                }),
+                10 => RecordData::NSEC3(Nsec3 {
+                    hash_algorithm: 1,
+                    flags: 1,
+                    iterations: u16::arbitrary(g),
+                    salt: Bytes::from_static(b"\xab\xcd"),
+                    next_hashed: Bytes::from(vec![u8::arbitrary(g); 20]),
+                    types: vec![],
+                }),
+                11 => RecordData::AAAA(Ipv6Addr::from(u128::arbitrary(g))),
+                12 => RecordData::Unknown(65, Bytes::from(Vec::<u8>::arbitrary(g))),
+                _ => RecordData::TXT(vec!["v=spf1 -all".to_string(), String::new()]),
+            }
+        }
+    }
+
+    #[test]
+    fn presentation_format_parses_back() {
+        let rdata = vec![
+            RecordData::A(Ipv4Addr::new(192, 0, 2, 1)),
+            RecordData::AAAA("2001:db8::1".parse().unwrap()),
+            RecordData::MX {
+                preference: 10,
+                exchange: "mail.example.com".parse().unwrap(),
+            },
+            RecordData::TXT(vec!["say \"hi\"".to_string(), "back\\slash".to_string()]),
+            RecordData::DS(Ds {
+                key_tag: 12345,
+                algorithm: 13,
+                digest_type: 2,
+                digest: Bytes::from_static(&[0xab; 32]),
+            }),
+            RecordData::RRSIG(Rrsig {
+                type_covered: QuestionType::A.code(),
+                algorithm: 13,
+                labels: 2,
+                original_ttl: 300,
+                expiration: 1700000000,
+                inception: 1690000000,
+                key_tag: 12345,
+                signer: "example.com".parse().unwrap(),
+                signature: Bytes::from_static(&[7; 64]),
+            }),
+            RecordData::NSEC(Nsec {
+                next: "b.example.com".parse().unwrap(),
+                types: vec![1, 46, 47],
+            }),
+            RecordData::NSEC3(Nsec3 {
+                hash_algorithm: 1,
+                flags: 0,
+                iterations: 0,
+                salt: Bytes::new(),
+                next_hashed: Bytes::from_static(&[0x5a; 20]),
+                types: vec![1, 28],
+            }),
+            RecordData::Unknown(65, Bytes::from_static(&[0, 1, 0, 0])),
+        ];
+
+        for r in rdata {
+            let text = format!("x.example.com. 300 IN {} {r}", r.qtype().name());
+            let origin: Domain = "example.com".parse().unwrap();
+            let records = crate::zone::ZoneParser::parse_str(origin, &text, "test").unwrap();
+            assert_eq!(records[0].rdata().unwrap(), r, "{text}");
+        }
+    }
+
+    #[test]
+    fn unknown_rdata_is_kept_as_it_came() {
+        // an SVCB record, which we have no type of our own for
+        let data = Bytes::from_static(&[0, 1, 3, b'f', b'o', b'o', 0]);
+        let rdata = RecordData::from_bytes(&data, &QuestionType::from(64)).unwrap();
+        assert_eq!(rdata, RecordData::Unknown(64, data.clone()));
+        assert_eq!(rdata.qtype(), QuestionType::Unknown(64));
+        assert_eq!(rdata.encode().unwrap(), data);
+        assert_eq!(rdata.to_string(), "\\# 7 000103666F6F00");
+
+        // TXT that isn't UTF-8 is still a valid TXT record
+        let txt = Bytes::from_static(&[2, 0xff, 0xfe, 1, b'a']);
+        let rdata = RecordData::from_bytes(&txt, &QuestionType::TXT).unwrap();
+        assert_eq!(rdata, RecordData::Unknown(16, txt.clone()));
+        assert_eq!(rdata.qtype(), QuestionType::TXT);
+        assert_eq!(rdata.encode().unwrap(), txt);
+
+        // but one whose strings run off the end isn't
+        let short = Bytes::from_static(&[5, b'a']);
+        assert!(RecordData::from_bytes(&short, &QuestionType::TXT).is_err());
+    }
+
+    quickcheck! {
+        fn encode_decode_rdata(r: RecordData) -> TestResult {
+            let buf = r.encode().unwrap();
+            let decoded = RecordData::from_bytes(&buf, &r.qtype()).unwrap();
+            assert_eq!(decoded, r);
+            TestResult::passed()
+        }
+    }
+}
@@ -0,0 +1,95 @@
+use crate::dns::{Domain, Label};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+// The name PTR lookups for an address go to: the octets backwards under in-addr.arpa for IPv4
+// (RFC 1035 S3.5), the nibbles backwards under ip6.arpa for IPv6 (RFC 3596 S2.5).
+pub fn reverse_name(address: &IpAddr) -> Domain {
+    let mut labels: Vec<Label> = match address {
+        IpAddr::V4(a) => a
+            .octets()
+            .iter()
+            .rev()
+            .map(|o| Label(o.to_string()))
+            .collect(),
+        IpAddr::V6(a) => a
+            .octets()
+            .iter()
+            .rev()
+            .flat_map(|o| [o & 0x0f, o >> 4])
+            .map(|n| Label(format!("{n:x}")))
+            .collect(),
+    };
+    let suffix: &[&str] = match address {
+        IpAddr::V4(_) => &["in-addr", "arpa"],
+        IpAddr::V6(_) => &["ip6", "arpa"],
+    };
+    labels.extend(suffix.iter().map(|l| Label(l.to_string())));
+    Domain { labels }
+}
+
+// the address a complete reverse name is for, None for anything else
+pub fn reverse_address(name: &Domain) -> Option<IpAddr> {
+    let labels: Vec<String> = name
+        .labels
+        .iter()
+        .map(|l| l.0.to_ascii_lowercase())
+        .collect();
+    match labels.as_slice() {
+        [digits @ .., a, b] if a == "in-addr" && b == "arpa" && digits.len() == 4 => {
+            let mut octets = [0u8; 4];
+            for (octet, digit) in octets.iter_mut().rev().zip(digits) {
+                // no leading zeros, 01 isn't a name reverse_name would make
+                if digit.len() > 1 && digit.starts_with('0') {
+                    return None;
+                }
+                *octet = digit.parse().ok()?;
+            }
+            Some(IpAddr::V4(Ipv4Addr::from(octets)))
+        }
+        [nibbles @ .., a, b] if a == "ip6" && b == "arpa" && nibbles.len() == 32 => {
+            let mut bits: u128 = 0;
+            for nibble in nibbles.iter().rev() {
+                if nibble.len() != 1 {
+                    return None;
+                }
+                bits = bits << 4 | u128::from_str_radix(nibble, 16).ok()?;
+            }
+            Some(IpAddr::V6(Ipv6Addr::from(bits)))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reverse_names_round_trip() {
+        let v4: IpAddr = "192.0.2.10".parse().unwrap();
+        assert_eq!(reverse_name(&v4).to_string(), "10.2.0.192.in-addr.arpa.");
+        assert_eq!(reverse_address(&reverse_name(&v4)), Some(v4));
+
+        let v6: IpAddr = "2001:db8::567:89ab".parse().unwrap();
+        assert_eq!(
+            reverse_name(&v6).to_string(),
+            "b.a.9.8.7.6.5.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.ip6.arpa."
+        );
+        assert_eq!(reverse_address(&reverse_name(&v6)), Some(v6));
+        assert_eq!(
+            reverse_address(&"10.2.0.192.IN-ADDR.ARPA".parse().unwrap()),
+            Some(v4)
+        );
+
+        // partial names are for whole networks, not an address
+        assert_eq!(
+            reverse_address(&"2.0.192.in-addr.arpa".parse().unwrap()),
+            None
+        );
+        assert_eq!(
+            reverse_address(&"300.2.0.192.in-addr.arpa".parse().unwrap()),
+            None
+        );
+        assert_eq!(reverse_address(&"www.example.com".parse().unwrap()), None);
+    }
+}
@@ -0,0 +1,279 @@
+use crate::dns::{DnsAnswer, Domain, QuestionType};
+use anyhow::{Result, bail, ensure};
+use bytes::{Bytes, BytesMut};
+use std::collections::HashMap;
+use std::ops::Deref;
+
+// A resource record set (RFC 2181 S5): the records sharing an owner, type and class. There's
+// always at least one record and they all have the same TTL, a record joining with a different
+// TTL brings the whole set down to the lower of the two (RFC 2181 S5.2). It reads like a slice of
+// its records but can only be changed through methods that keep it a set.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct RRset {
+    records: Vec<DnsAnswer>,
+}
+
+impl RRset {
+    pub fn new(record: DnsAnswer) -> Self {
+        Self {
+            records: vec![record],
+        }
+    }
+
+    pub fn from_records(records: impl IntoIterator<Item = DnsAnswer>) -> Result<Self> {
+        let mut records = records.into_iter();
+        let Some(first) = records.next() else {
+            bail!("an RRset needs at least one record");
+        };
+
+        let mut rrset = Self::new(first);
+        for record in records {
+            rrset.push(record)?;
+        }
+        Ok(rrset)
+    }
+
+    // split records up into their RRsets, in the order each set first appears
+    pub fn group<'a>(records: impl IntoIterator<Item = &'a DnsAnswer>) -> Vec<Self> {
+        let mut rrsets: Vec<Self> = Vec::new();
+        let mut index: HashMap<(Domain, u16, u16), usize> = HashMap::new();
+        for record in records {
+            let key = (
+                record.name.to_lowercase(),
+                record.qtype.code(),
+                record.class,
+            );
+            match index.get(&key) {
+                Some(&i) => rrsets[i].add(record.clone()),
+                None => {
+                    index.insert(key, rrsets.len());
+                    rrsets.push(Self::new(record.clone()));
+                }
+            }
+        }
+        rrsets
+    }
+
+    // true if the record belongs in this set, owner names are compared ignoring case
+    pub fn accepts(&self, record: &DnsAnswer) -> bool {
+        self.name().eq_ignore_case(&record.name)
+            && *self.qtype() == record.qtype
+            && self.class() == record.class
+    }
+
+    // add a record to the set, a copy of one that's already there is dropped
+    pub fn push(&mut self, record: DnsAnswer) -> Result<()> {
+        ensure!(
+            self.accepts(&record),
+            "{} {:?} doesn't belong in the RRset for {} {:?}",
+            record.name,
+            record.qtype,
+            self.name(),
+            self.qtype()
+        );
+        self.add(record);
+        Ok(())
+    }
+
+    fn add(&mut self, mut record: DnsAnswer) {
+        let ttl = self.ttl().min(record.ttl);
+        self.set_ttl(ttl);
+        if self.records.iter().any(|r| r.data == record.data) {
+            return;
+        }
+
+        record.ttl = ttl;
+        record.name = self.name().clone();
+        self.records.push(record);
+    }
+
+    pub fn name(&self) -> &Domain {
+        &self.records[0].name
+    }
+
+    pub fn qtype(&self) -> &QuestionType {
+        &self.records[0].qtype
+    }
+
+    pub fn class(&self) -> u16 {
+        self.records[0].class
+    }
+
+    pub fn ttl(&self) -> u32 {
+        self.records[0].ttl
+    }
+
+    pub fn set_ttl(&mut self, ttl: u32) {
+        for record in &mut self.records {
+            record.ttl = ttl;
+        }
+    }
+
+    // the same records under another owner, like a wildcard's records given out for a name
+    pub fn with_name(mut self, name: Domain) -> Self {
+        for record in &mut self.records {
+            record.name = name.clone();
+        }
+        self
+    }
+
+    // Put the records in canonical order (RFC 4034 S6.3), by their RDATA in canonical form as
+    // unsigned octet strings. Records that only differ in the case of the names inside them are
+    // the same record in canonical form, and only the first is kept.
+    pub fn sort(&mut self) -> Result<()> {
+        let mut keyed = Vec::with_capacity(self.records.len());
+        for record in self.records.drain(..) {
+            keyed.push((record.canonical_data()?, record));
+        }
+        keyed.sort_by(|a, b| a.0.cmp(&b.0));
+        keyed.dedup_by(|a, b| a.0 == b.0);
+        self.records = keyed.into_iter().map(|(_, record)| record).collect();
+        Ok(())
+    }
+
+    // The set in canonical wire form (RFC 4034 S6.2): each record with a lowercase owner, no
+    // compression and canonical RDATA, in canonical order. This is what gets signed.
+    pub fn canonical(&self) -> Result<Bytes> {
+        let mut sorted = self.clone();
+        sorted.sort()?;
+
+        let mut buf = BytesMut::new();
+        for record in &sorted.records {
+            buf.extend_from_slice(&record.canonical()?);
+        }
+        Ok(buf.into())
+    }
+
+    // true if both sets hold the same data, regardless of order, TTL or case
+    pub fn same_data(&self, other: &Self) -> Result<bool> {
+        if !self.accepts(&other.records[0]) {
+            return Ok(false);
+        }
+
+        let (mut a, mut b) = (self.clone(), other.clone());
+        a.sort()?;
+        b.sort()?;
+        let data = |rrset: &Self| -> Result<Vec<Bytes>> {
+            rrset.records.iter().map(|r| r.canonical_data()).collect()
+        };
+        Ok(data(&a)? == data(&b)?)
+    }
+
+    pub fn into_records(self) -> Vec<DnsAnswer> {
+        self.records
+    }
+}
+
+impl Deref for RRset {
+    type Target = [DnsAnswer];
+
+    fn deref(&self) -> &[DnsAnswer] {
+        &self.records
+    }
+}
+
+impl From<RRset> for Vec<DnsAnswer> {
+    fn from(rrset: RRset) -> Self {
+        rrset.records
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::RecordData;
+    use std::net::Ipv4Addr;
+
+    fn a(name: &str, ttl: u32, ip: [u8; 4]) -> DnsAnswer {
+        DnsAnswer::new(
+            name.parse().unwrap(),
+            ttl,
+            RecordData::A(Ipv4Addr::from(ip)),
+        )
+        .unwrap()
+    }
+
+    fn ns(name: &str, target: &str) -> DnsAnswer {
+        DnsAnswer::new(
+            name.parse().unwrap(),
+            300,
+            RecordData::NS(target.parse().unwrap()),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn keeps_one_ttl_and_no_duplicates() {
+        let mut rrset = RRset::new(a("www.example.com", 300, [192, 0, 2, 2]));
+        rrset
+            .push(a("WWW.example.com", 60, [192, 0, 2, 1]))
+            .unwrap();
+        rrset
+            .push(a("www.example.com", 600, [192, 0, 2, 2]))
+            .unwrap();
+        assert_eq!(rrset.len(), 2);
+        assert_eq!(rrset.ttl(), 60);
+        assert!(rrset.iter().all(|r| r.ttl == 60 && r.name == *rrset.name()));
+
+        assert!(
+            rrset
+                .push(a("mail.example.com", 60, [192, 0, 2, 3]))
+                .is_err()
+        );
+        assert!(
+            rrset
+                .push(ns("www.example.com", "ns1.example.com"))
+                .is_err()
+        );
+        assert!(RRset::from_records(Vec::new()).is_err());
+    }
+
+    #[test]
+    fn groups_records_in_order() {
+        let records = [
+            a("www.example.com", 300, [192, 0, 2, 1]),
+            ns("example.com", "ns1.example.com"),
+            a("WWW.example.com", 300, [192, 0, 2, 2]),
+        ];
+        let rrsets = RRset::group(&records);
+        assert_eq!(rrsets.len(), 2);
+        assert_eq!(rrsets[0].len(), 2);
+        assert_eq!(*rrsets[1].qtype(), QuestionType::NS);
+    }
+
+    #[test]
+    fn sorts_and_encodes_canonically() {
+        let mut rrset = RRset::from_records([
+            ns("Example.COM", "NS2.example.com"),
+            ns("example.com", "ns1.example.com"),
+            ns("example.com", "ns2.EXAMPLE.com"),
+        ])
+        .unwrap();
+        rrset.sort().unwrap();
+        let targets: Vec<String> = rrset
+            .iter()
+            .map(|r| match r.rdata().unwrap() {
+                RecordData::NS(target) => target.to_string(),
+                _ => unreachable!(),
+            })
+            .collect();
+        assert_eq!(targets, vec!["ns1.example.com.", "NS2.example.com."]);
+
+        // lowercase owner and RDATA names, uncompressed, one record after the other
+        let mut expected = BytesMut::new();
+        for target in ["ns1", "ns2"] {
+            expected.extend_from_slice(b"\x07example\x03com\x00\x00\x02\x00\x01\x00\x00\x01\x2c");
+            expected.extend_from_slice(b"\x00\x11\x03");
+            expected.extend_from_slice(target.as_bytes());
+            expected.extend_from_slice(b"\x07example\x03com\x00");
+        }
+        assert_eq!(rrset.canonical().unwrap(), expected);
+
+        let other = RRset::from_records([
+            ns("example.com", "ns2.example.com"),
+            ns("example.com", "NS1.example.com"),
+        ])
+        .unwrap();
+        assert!(rrset.same_data(&other).unwrap());
+    }
+}
@@ -0,0 +1,99 @@
+use crate::dns::{DnsMessage, format_error, server_failure};
+use crate::handler::{DnsHandler, DnsRequest, Transport};
+use crate::parse::DnsData;
+use anyhow::{Context, Result, ensure};
+use bytes::{BufMut, Bytes, BytesMut};
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::timeout;
+use tracing::{debug, warn};
+
+// how long we keep a connection open without hearing from the client (RFC 7766 S6.2.3)
+pub const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Over stream transports every message is preceded by its length as a 16 bit integer (RFC 1035
+// S4.2.2). Returns None when the other side closes the connection between messages.
+pub async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Option<Bytes>> {
+    let mut len = [0; 2];
+    match reader.read_exact(&mut len).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+
+    let mut buf = vec![0; u16::from_be_bytes(len) as usize];
+    reader
+        .read_exact(&mut buf)
+        .await
+        .context("connection closed part way through a message")?;
+    Ok(Some(buf.into()))
+}
+
+pub async fn write_frame<W: AsyncWrite + Unpin>(writer: &mut W, message: &[u8]) -> Result<()> {
+    ensure!(
+        message.len() <= u16::MAX as usize,
+        "message is too big for a stream transport"
+    );
+
+    // one write for the length and message, so they don't go out as separate segments
+    let mut buf = BytesMut::with_capacity(message.len() + 2);
+    buf.put_u16(message.len() as u16);
+    buf.extend_from_slice(message);
+    writer.write_all(&buf).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+// Answer requests on a stream connection until the client closes it or goes quiet for too long.
+// A request can get several messages back, e.g. a zone transfer.
+pub async fn serve_connection<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    client: SocketAddr,
+    transport: Transport,
+    handler: &dyn DnsHandler,
+    idle: Duration,
+) -> Result<()> {
+    loop {
+        let buf = match timeout(idle, read_frame(&mut stream)).await {
+            Ok(Ok(Some(buf))) => buf,
+            Ok(Ok(None)) => return Ok(()),
+            Ok(Err(e)) => return Err(e),
+            Err(_) => {
+                debug!("closing idle connection from {client}");
+                return Ok(());
+            }
+        };
+
+        let message = match DnsMessage::decode(&buf, 0, &mut HashMap::new()) {
+            Ok((_, message)) => message,
+            Err(e) => {
+                // the rest of the connection can still be fine, as long as we know who to tell
+                let reply = format_error(&buf).ok_or(e)?;
+                write_frame(&mut stream, &reply.encode(0, &mut HashMap::new())?).await?;
+                continue;
+            }
+        };
+        let request = DnsRequest {
+            message,
+            client,
+            transport,
+            raw: buf,
+            key: None,
+        };
+
+        // one query going wrong is no reason to give up on the ones after it
+        let replies = match handler.handle_stream(&request).await {
+            Ok(replies) => replies,
+            Err(e) => {
+                warn!("failed to handle request from {client}: {e:#}");
+                vec![server_failure(&request.message)]
+            }
+        };
+        for reply in replies {
+            write_frame(&mut stream, &reply.encode(0, &mut HashMap::new())?).await?;
+        }
+    }
+}
@@ -0,0 +1,78 @@
+use anyhow::{Context, Result, ensure};
+use rustls::crypto::ring::{Ticketer, default_provider};
+use rustls::server::ServerSessionMemoryCache;
+use rustls::{ClientConfig, RootCertStore, ServerConfig};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+// where DNS over TLS is served unless we're told otherwise (RFC 7858 S3.1)
+pub const DOT_PORT: u16 = 853;
+
+// The ALPN protocol ID for DNS over TLS. Clients don't have to ask for it, but if they ask for
+// something else the handshake fails rather than talking the wrong protocol.
+pub const DOT_ALPN: &[u8] = b"dot";
+
+// Setting up TLS costs a lot more than a TCP handshake, so DoT connections are kept around for
+// longer before we give up on them (RFC 7858 S3.4)
+pub const TLS_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+// how many sessions we remember for clients resuming by session ID
+const SESSION_CACHE_SIZE: usize = 1024;
+
+// A TLS server config from a PEM certificate chain and private key. Clients that reconnect can
+// resume their session, by ID or with a TLS 1.3 ticket, and skip the full handshake.
+pub fn tls_server_config(
+    cert: impl AsRef<Path>,
+    key: impl AsRef<Path>,
+) -> Result<Arc<ServerConfig>> {
+    let (cert, key) = (cert.as_ref(), key.as_ref());
+    let chain = rustls_pemfile::certs(&mut BufReader::new(
+        File::open(cert).with_context(|| format!("can't open {}", cert.display()))?,
+    ))
+    .collect::<Result<Vec<_>, _>>()
+    .with_context(|| format!("bad certificate in {}", cert.display()))?;
+    ensure!(!chain.is_empty(), "no certificates in {}", cert.display());
+    let key = rustls_pemfile::private_key(&mut BufReader::new(
+        File::open(key).with_context(|| format!("can't open {}", key.display()))?,
+    ))
+    .with_context(|| format!("bad private key in {}", key.display()))?
+    .with_context(|| format!("no private key in {}", key.display()))?;
+
+    let mut config = ServerConfig::builder_with_provider(Arc::new(default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(chain, key)?;
+    config.session_storage = ServerSessionMemoryCache::new(SESSION_CACHE_SIZE);
+    config.ticketer = Ticketer::new()?;
+    Ok(Arc::new(config))
+}
+
+// A TLS client config for talking to upstream servers, trusting the CA certificates in a PEM file
+// or, without one, the public CAs in Mozilla's root program.
+pub fn tls_client_config(roots: Option<&Path>) -> Result<Arc<ClientConfig>> {
+    let roots = match roots {
+        Some(path) => {
+            let mut store = RootCertStore::empty();
+            for cert in rustls_pemfile::certs(&mut BufReader::new(
+                File::open(path).with_context(|| format!("can't open {}", path.display()))?,
+            )) {
+                store
+                    .add(cert.with_context(|| format!("bad certificate in {}", path.display()))?)?;
+            }
+            ensure!(!store.is_empty(), "no certificates in {}", path.display());
+            store
+        }
+        None => RootCertStore {
+            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+        },
+    };
+
+    let config = ClientConfig::builder_with_provider(Arc::new(default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(Arc::new(config))
+}
@@ -0,0 +1,94 @@
+use crate::dns::{DnsAnswer, DnsKey, Domain, Ds, RecordData};
+use crate::dnssec::ds_matches;
+use crate::zone::ZoneParser;
+use anyhow::{Context, Result, bail, ensure};
+use bytes::Bytes;
+use std::path::Path;
+
+// The root zone's key signing keys as published by IANA (https://data.iana.org/root-anchors/),
+// KSK-2017 and KSK-2024.
+const ROOT_ANCHORS: [(u16, &str); 2] = [
+    (
+        20326,
+        "e06d44b80b8f1d39a95c0b0d7c65d08458e880409bbc683457104237c7f8ec8d",
+    ),
+    (
+        38696,
+        "683d2d0acb8c9b712a1948b27f741219298d0a450d612c483af444a4c0fb2b16",
+    ),
+];
+
+// Where validation starts: the DS or DNSKEY records of one zone that we take on faith, every
+// other key has to chain back up to these.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrustAnchor {
+    pub zone: Domain,
+    pub ds: Vec<Ds>,
+    pub keys: Vec<DnsKey>,
+}
+
+impl TrustAnchor {
+    pub fn root() -> Self {
+        Self {
+            zone: Domain::root(),
+            ds: ROOT_ANCHORS
+                .iter()
+                .map(|(key_tag, digest)| Ds {
+                    key_tag: *key_tag,
+                    algorithm: 8,
+                    digest_type: 2,
+                    digest: Bytes::from(hex::decode(digest).unwrap()),
+                })
+                .collect(),
+            keys: vec![],
+        }
+    }
+
+    // DS and DNSKEY records for a single zone, anything else is an error
+    pub fn from_records(records: &[DnsAnswer]) -> Result<Self> {
+        let zone = records
+            .first()
+            .context("no trust anchor records")?
+            .name
+            .clone();
+        let mut anchor = Self {
+            zone: zone.to_lowercase(),
+            ds: vec![],
+            keys: vec![],
+        };
+
+        for record in records {
+            ensure!(
+                record.name.eq_ignore_case(&zone),
+                "trust anchors for both {zone} and {}",
+                record.name
+            );
+            match record.rdata()? {
+                RecordData::DS(ds) => anchor.ds.push(ds),
+                RecordData::DNSKEY(key) => anchor.keys.push(key),
+                _ => bail!("{:?} record in the trust anchors", record.qtype),
+            }
+        }
+
+        Ok(anchor)
+    }
+
+    // A file of DS and/or DNSKEY records in master file format, as unbound and BIND write them.
+    // The TTLs don't mean anything for an anchor, so they can be left out.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let records = ZoneParser::new(Domain::root())
+            .with_default_ttl(0)
+            .load(path)?;
+        Self::from_records(&records).with_context(|| format!("{}", path.display()))
+    }
+
+    // the keys of our zone's DNSKEY RRset that we trust directly
+    pub fn trusted<'a>(&self, keys: &'a [DnsKey]) -> Vec<&'a DnsKey> {
+        keys.iter()
+            .filter(|k| {
+                self.keys.contains(k) || self.ds.iter().any(|ds| ds_matches(ds, &self.zone, k))
+            })
+            .collect()
+    }
+}
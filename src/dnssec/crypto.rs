@@ -0,0 +1,335 @@
+use crate::dns::{DnsAnswer, DnsKey, Domain, Ds, RRset, RecordData, Rrsig};
+use anyhow::Result;
+use bytes::BytesMut;
+use ring::digest;
+use ring::signature::{self, RsaPublicKeyComponents, UnparsedPublicKey};
+
+// DNSKEY algorithms we can check signatures for (RFC 8624 S3.1), the rest are treated as unsigned
+pub const ALGORITHM_RSASHA256: u8 = 8;
+pub const ALGORITHM_ECDSAP256SHA256: u8 = 13;
+pub const ALGORITHM_ECDSAP384SHA384: u8 = 14;
+pub const ALGORITHM_ED25519: u8 = 15;
+
+// DS digest types (RFC 8624 S3.3)
+pub const DIGEST_SHA1: u8 = 1;
+pub const DIGEST_SHA256: u8 = 2;
+pub const DIGEST_SHA384: u8 = 4;
+
+// the only protocol a DNSKEY may have (RFC 4034 S2.1.2)
+pub const DNSKEY_PROTOCOL: u8 = 3;
+
+pub fn supported_algorithm(algorithm: u8) -> bool {
+    matches!(
+        algorithm,
+        ALGORITHM_RSASHA256
+            | ALGORITHM_ECDSAP256SHA256
+            | ALGORITHM_ECDSAP384SHA384
+            | ALGORITHM_ED25519
+    )
+}
+
+pub fn supported_digest(digest_type: u8) -> bool {
+    matches!(digest_type, DIGEST_SHA1 | DIGEST_SHA256 | DIGEST_SHA384)
+}
+
+// check `signature` over `data` with the public key in a DNSKEY
+pub fn verify_signature(key: &DnsKey, data: &[u8], signature: &[u8]) -> bool {
+    let public_key = key.public_key.as_ref();
+
+    match key.algorithm {
+        // the exponent length is one byte, or a zero and then two more (RFC 3110 S2)
+        ALGORITHM_RSASHA256 => {
+            let (e_len, rest) = match public_key {
+                [0, hi, lo, rest @ ..] => ((*hi as usize) << 8 | *lo as usize, rest),
+                [len, rest @ ..] => (*len as usize, rest),
+                [] => return false,
+            };
+            if e_len == 0 || rest.len() <= e_len {
+                return false;
+            }
+            let (e, n) = rest.split_at(e_len);
+            let n = &n[n.iter().take_while(|b| **b == 0).count()..];
+
+            RsaPublicKeyComponents { n, e }
+                .verify(
+                    &signature::RSA_PKCS1_1024_8192_SHA256_FOR_LEGACY_USE_ONLY,
+                    data,
+                    signature,
+                )
+                .is_ok()
+        }
+
+        // the key is the bare point and the signature r | s (RFC 6605 S4), ring wants the point
+        // in its uncompressed SEC1 form
+        ALGORITHM_ECDSAP256SHA256 | ALGORITHM_ECDSAP384SHA384 => {
+            let (algorithm, len): (&'static signature::EcdsaVerificationAlgorithm, usize) =
+                match key.algorithm {
+                    ALGORITHM_ECDSAP256SHA256 => (&signature::ECDSA_P256_SHA256_FIXED, 64),
+                    _ => (&signature::ECDSA_P384_SHA384_FIXED, 96),
+                };
+            if public_key.len() != len {
+                return false;
+            }
+
+            let mut point = Vec::with_capacity(len + 1);
+            point.push(0x04);
+            point.extend_from_slice(public_key);
+            UnparsedPublicKey::new(algorithm, point)
+                .verify(data, signature)
+                .is_ok()
+        }
+        ALGORITHM_ED25519 => UnparsedPublicKey::new(&signature::ED25519, public_key)
+            .verify(data, signature)
+            .is_ok(),
+        _ => false,
+    }
+}
+
+// the digest a DS record holds for `key`, over the owner name and the DNSKEY RDATA (RFC 4034
+// S5.1.4)
+pub fn ds_digest(owner: &Domain, key: &DnsKey, digest_type: u8) -> Result<Option<Vec<u8>>> {
+    let algorithm = match digest_type {
+        DIGEST_SHA1 => &digest::SHA1_FOR_LEGACY_USE_ONLY,
+        DIGEST_SHA256 => &digest::SHA256,
+        DIGEST_SHA384 => &digest::SHA384,
+        _ => return Ok(None),
+    };
+
+    let mut ctx = digest::Context::new(algorithm);
+    ctx.update(&owner.canonical());
+    ctx.update(&RecordData::DNSKEY(key.clone()).encode()?);
+    Ok(Some(ctx.finish().as_ref().to_vec()))
+}
+
+// true if the DS record was made from `key`
+pub fn ds_matches(ds: &Ds, owner: &Domain, key: &DnsKey) -> bool {
+    ds.algorithm == key.algorithm
+        && ds.key_tag == key.key_tag()
+        && ds_digest(owner, key, ds.digest_type)
+            .ok()
+            .flatten()
+            .is_some_and(|d| d == ds.digest.as_ref())
+}
+
+// A DS record for `key`, what goes in the parent zone.
+pub fn ds_for(owner: &Domain, key: &DnsKey, digest_type: u8) -> Result<Option<Ds>> {
+    Ok(ds_digest(owner, key, digest_type)?.map(|digest| Ds {
+        key_tag: key.key_tag(),
+        algorithm: key.algorithm,
+        digest_type,
+        digest: digest.into(),
+    }))
+}
+
+// The data an RRSIG signs (RFC 4034 S3.1.8.1): the RRSIG RDATA without the signature, then every
+// record of the RRset in canonical form and order, with the original TTL. A record that came from
+// a wildcard is signed as the wildcard, which the labels field lets us reconstruct.
+pub fn signed_data(rrsig: &Rrsig, rrset: &[DnsAnswer]) -> Result<Vec<u8>> {
+    let mut canonical_rrsig = rrsig.clone();
+    canonical_rrsig.signer = rrsig.signer.to_lowercase();
+
+    let mut buf = BytesMut::new();
+    buf.extend_from_slice(&canonical_rrsig.encode_without_signature()?);
+
+    let mut rrset = RRset::from_records(rrset.iter().cloned())?;
+    let labels = rrset.name().labels.len() - rrset.name().is_wildcard() as usize;
+    if (rrsig.labels as usize) < labels {
+        let wildcard = rrset.name().suffix(rrsig.labels as usize).prepend("*");
+        rrset = rrset.with_name(wildcard);
+    }
+    rrset.set_ttl(rrsig.original_ttl);
+    buf.extend_from_slice(&rrset.canonical()?);
+
+    Ok(buf.to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::zone::ZoneParser;
+    use base64::Engine;
+    use base64::engine::general_purpose::STANDARD;
+    use ring::rand::SystemRandom;
+    use ring::signature::{EcdsaKeyPair, Ed25519KeyPair, KeyPair, RsaKeyPair};
+
+    // a throwaway 2048 bit key made with openssl genpkey, ring can't make RSA keys itself
+    const RSA_PKCS8: &str = "
+    MIIEvgIBADANBgkqhkiG9w0BAQEFAASCBKgwggSkAgEAAoIBAQCn6gqW/yVUi5hvdwfrIxtF1gVI
+    eqrJE8ztpwUIQ9uMA3Ws5ax+QP5t8XiK7Xvd9ryR53EL80rREiAqbA+nVFF2Ss+onqgl97KLc22g
+    X9x+gRGwU/NIu8hyv7eGnho2N2XbmVCP20BxQrB/7hrCpSJm2J1/o1VQ+Jd7TqwCRM+Fs2v2mul+
+    z5qg/9oKSQ9f/eeAhYL56m4wBoSvCAD1g6Y/SMmhl/qkfvEgqsUecVwTk7pvM2ag9VkKrUApiJUX
+    Uslf/4Gld71ddGaTqq3ddjQTKgfAId50yQUfJ7iofkfccBIz0tB8JylX3haVsqhAtSWI/77A8vyg
+    0o8Xvt4O3/nLAgMBAAECggEABtOon4/7DA9q4KlFvKUeQD4d098M34T7EXR3a2ka3Xvshl2GIFHz
+    JjQpkvhpz1HvQ71Vpsc69PoYLbFcB5ERiM4s2C2vJ3AFJc9JFzqeg9QqjHE1VHEyCpxJ0WLPLQIe
+    lYmLsXWYDqLH+0OKefxtxCGtcZmJ5/NbcUt3CdaTW1upvZ24W61hKJnZdn7fF6CmAeVCP0iwxUWl
+    C1NmAhWPJ9EIxBsaHS8x2voL6+QFwd1jOfuNprqzx+keRgQJSF2X/QlgvSus3GRPHB5SU0QSSpUd
+    RD377Iw91ztm4XvRT7qIGisjW7B5JR/Gzuz56QbIEILnFysqnCfhIUd7chir9QKBgQDQQ9+Vl7f/
+    +7HZYhvo3BAoKuUFBQ/uq/L4+TKuKdBiAnxBcf0jm5jDkOYB6EaiF1HBypg3491b2U/8lHJ/jrN6
+    4qVpJcOjdOZDwFMptN6pjVo1qMyR1wejkOLP4/CIKV3OcYXnjCAX+iu5hJFO0Hxmi6oJuB3BFvCi
+    HfNp9JqcfQKBgQDOZous7ct6XYiaow1XFTlAdYwcGMrtn/7DMr2DE5u0UxIh8ggPQs32ZXSkqi/B
+    omkpjydlSKlCEeiPOyCfwFwEl9WT28EqL+zF1DaRHuXjI9I8tBvH1VIV2UhnvvZNK4Uwt19ateql
+    B1SAc8ZtX9JqB0gyyzjjBQ48/lkAwNvp5wKBgQCQCpGF9iQloOAV2HwzJpjqHxi8yw6E5VosBrad
+    rH/aeZuU7BDHYuNaR0o9gXfwva4nGUcESM4dq4RdzhIO1RSfZkg/HAZedAiaTVd3XgguPnxo5nFu
+    YMmAwEGOxTjkA7BEi1GwnsLHspgxPFX/q8SObARTqoqxtHsDtTFYU/vnRQKBgQDNs49yyg9npX42
+    N1hdOK61WjFH80GTL0h0+8W5s7oTLlM8930m0Hp6HUTxsxcjURyjqaMmoxLk4Po4fUIXVFugzD20
+    PWg3BkpgIw469WNBS3sEuq+RKj3DvqgVlVDdOglksuxaeEEhNbv2xOu2J7CJsTY4QScxweEd0pOb
+    V5oUcwKBgG9yjqjndpf0gu4arn0XasyQJigfwg4OAto13LAy8hXiYbH6FQlZFED1ieYreGhr3WML
+    HIi33TZdGguYM1kQY7Jb+rBIw8IvfKbwE80z/nMj4I4AFRcQldzd6Ubb5C+GUsSUPpG1Y1uO0eG6
+    y3cO2xZwzD1H8ZRgQ2ODa0Iu6hUW    ";
+
+    // the DNSKEY form of its public half, exponent length, exponent and modulus
+    const RSA_PUBLIC_KEY: &str = "
+    AwEAAafqCpb/JVSLmG93B+sjG0XWBUh6qskTzO2nBQhD24wDdazlrH5A/m3xeIrte932vJHncQvz
+    StESICpsD6dUUXZKz6ieqCX3sotzbaBf3H6BEbBT80i7yHK/t4aeGjY3ZduZUI/bQHFCsH/uGsKl
+    ImbYnX+jVVD4l3tOrAJEz4Wza/aa6X7PmqD/2gpJD1/954CFgvnqbjAGhK8IAPWDpj9IyaGX+qR+
+    8SCqxR5xXBOTum8zZqD1WQqtQCmIlRdSyV//gaV3vV10ZpOqrd12NBMqB8Ah3nTJBR8nuKh+R9xw
+    EjPS0HwnKVfeFpWyqEC1JYj/vsDy/KDSjxe+3g7f+cs=    ";
+
+    fn decode(text: &str) -> Vec<u8> {
+        let text: String = text.split_whitespace().collect();
+        STANDARD.decode(text).unwrap()
+    }
+
+    type Signer = Box<dyn Fn(&[u8]) -> Vec<u8>>;
+
+    // a key of every algorithm we support, as a DNSKEY and something that can sign with it
+    fn keys() -> Vec<(DnsKey, Signer)> {
+        let rng = SystemRandom::new();
+        let dnskey = |algorithm, public_key: &[u8]| DnsKey {
+            flags: DnsKey::ZONE,
+            protocol: DNSKEY_PROTOCOL,
+            algorithm,
+            public_key: public_key.to_vec().into(),
+        };
+        let mut keys: Vec<(DnsKey, Signer)> = Vec::new();
+
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
+        let pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+        keys.push((
+            dnskey(ALGORITHM_ED25519, pair.public_key().as_ref()),
+            Box::new(move |data| pair.sign(data).as_ref().to_vec()),
+        ));
+
+        for (algorithm, signing) in [
+            (
+                ALGORITHM_ECDSAP256SHA256,
+                &signature::ECDSA_P256_SHA256_FIXED_SIGNING,
+            ),
+            (
+                ALGORITHM_ECDSAP384SHA384,
+                &signature::ECDSA_P384_SHA384_FIXED_SIGNING,
+            ),
+        ] {
+            let pkcs8 = EcdsaKeyPair::generate_pkcs8(signing, &rng).unwrap();
+            let pair = EcdsaKeyPair::from_pkcs8(signing, pkcs8.as_ref(), &rng).unwrap();
+            let rng = rng.clone();
+            keys.push((
+                dnskey(algorithm, &pair.public_key().as_ref()[1..]),
+                Box::new(move |data| pair.sign(&rng, data).unwrap().as_ref().to_vec()),
+            ));
+        }
+
+        let pair = RsaKeyPair::from_pkcs8(&decode(RSA_PKCS8)).unwrap();
+        keys.push((
+            dnskey(ALGORITHM_RSASHA256, &decode(RSA_PUBLIC_KEY)),
+            Box::new(move |data| {
+                let mut signature = vec![0; pair.public().modulus_len()];
+                pair.sign(&signature::RSA_PKCS1_SHA256, &rng, data, &mut signature)
+                    .unwrap();
+                signature
+            }),
+        ));
+
+        keys
+    }
+
+    fn records(text: &str) -> Vec<DnsAnswer> {
+        ZoneParser::parse_str("example.com".parse().unwrap(), text, "test").unwrap()
+    }
+
+    #[test]
+    fn matches_the_rfc_4034_ds_example() {
+        let records = records(
+            "dskey 86400 IN DNSKEY 256 3 5 ( AQOeiiR0GOMYkDshWoSKz9Xz
+                fwJr1AYtsmx3TGkJaNXVbfi/ 2pHm822aJ5iI9BMzNXxeYCmZ
+                DRD99WYwYqUSdjMmmAphXdvx egXd/M5+X7OrzKBaMbCVdFLU
+                Uh6DhweJBjEVv5f2wwjM9Xzc nOf+EPbtG9DMBmADjFDc2w/r
+                ljwvFw== )\n\
+             dskey 86400 IN DS 60485 5 1 ( 2BB183AF5F22588179A53B0A
+                98631FAD1A292118 )",
+        );
+        let RecordData::DNSKEY(key) = records[0].rdata().unwrap() else {
+            panic!("not a DNSKEY");
+        };
+        let RecordData::DS(ds) = records[1].rdata().unwrap() else {
+            panic!("not a DS");
+        };
+
+        assert_eq!(key.key_tag(), 60485);
+        assert!(ds_matches(&ds, &records[0].name, &key));
+        assert!(!ds_matches(
+            &ds,
+            &"other.example.com".parse().unwrap(),
+            &key
+        ));
+    }
+
+    #[test]
+    fn verifies_signatures_for_every_algorithm() {
+        let rrset = records(
+            "www 300 A 192.0.2.1\n\
+             WWW 300 A 192.0.2.2\n\
+             www 300 A 192.0.2.1",
+        );
+        let wildcard = records("*.wild 300 TXT \"hello\"");
+
+        for (key, sign) in keys() {
+            let rrsig = Rrsig {
+                type_covered: 1,
+                algorithm: key.algorithm,
+                labels: 3,
+                original_ttl: 300,
+                expiration: 2_000_000_000,
+                inception: 1_000_000_000,
+                key_tag: key.key_tag(),
+                signer: "Example.COM".parse().unwrap(),
+                signature: Default::default(),
+            };
+            let signature = sign(&signed_data(&rrsig, &rrset).unwrap());
+            assert!(
+                verify_signature(&key, &signed_data(&rrsig, &rrset).unwrap(), &signature),
+                "algorithm {}",
+                key.algorithm
+            );
+
+            // order, case, duplicates and TTLs don't matter, the data does
+            let mut shuffled = rrset.clone();
+            shuffled.reverse();
+            shuffled.iter_mut().for_each(|r| r.ttl = 5);
+            assert!(verify_signature(
+                &key,
+                &signed_data(&rrsig, &shuffled[..2]).unwrap(),
+                &signature
+            ));
+            assert!(!verify_signature(
+                &key,
+                &signed_data(&rrsig, &rrset[..1]).unwrap(),
+                &signature
+            ));
+
+            // an answer synthesised from *.wild is checked against the wildcard's signature
+            let rrsig = Rrsig {
+                type_covered: 16,
+                ..rrsig
+            };
+            let signature = sign(&signed_data(&rrsig, &wildcard).unwrap());
+            let mut expanded = wildcard.clone();
+            expanded[0].name = "anything.wild.example.com".parse().unwrap();
+            assert!(verify_signature(
+                &key,
+                &signed_data(&rrsig, &expanded).unwrap(),
+                &signature
+            ));
+        }
+    }
+}
@@ -0,0 +1,454 @@
+use crate::dns::{DnsAnswer, Domain, Nsec, Nsec3, QuestionType, RecordData};
+use crate::dnssec::{
+    MAX_NSEC3_ITERATIONS, NSEC3_FLAG_OPT_OUT, NSEC3_HASH_SHA1, base32hex_decode, nsec3_covers,
+    nsec3_hash,
+};
+use anyhow::{Result, bail, ensure};
+use std::cmp::Ordering;
+
+// What the NSEC or NSEC3 records in a response prove about a name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Denial {
+    NxDomain,
+
+    // the name exists but not with the type, `delegation` if it is a zone cut seen from the
+    // parent side, i.e. it has NS records and no SOA
+    NoData { delegation: bool },
+
+    // Nothing can be proven either way: the name falls in an opt-out span that can hide unsigned
+    // delegations, or the zone uses more NSEC3 iterations than we are willing to compute. Either
+    // way the answer is treated as unsigned.
+    Insecure,
+}
+
+// Prove that `name` doesn't exist, or doesn't have `qtype`, from the NSEC or NSEC3 records of
+// `zone`. The records must already have had their signatures checked.
+pub fn prove_denial(
+    name: &Domain,
+    qtype: &QuestionType,
+    zone: &Domain,
+    records: &[DnsAnswer],
+    nxdomain: bool,
+) -> Result<Denial> {
+    let nsecs = nsec_records(zone, records);
+    if !nsecs.is_empty() {
+        return match nxdomain {
+            true => nsec_nxdomain(name, &nsecs),
+            false => nsec_nodata(name, qtype, &nsecs),
+        };
+    }
+
+    let Some(chain) = Nsec3Chain::new(zone, records)? else {
+        bail!("no NSEC or NSEC3 records to prove {name} doesn't exist");
+    };
+    if chain.iterations > MAX_NSEC3_ITERATIONS {
+        return Ok(Denial::Insecure);
+    }
+    match nxdomain {
+        true => chain.nxdomain(name),
+        false => chain.nodata(name, qtype),
+    }
+}
+
+// An answer synthesised from a wildcard is only valid if the name it was asked for doesn't exist
+// in its own right (RFC 4035 S5.3.4). `labels` is from the RRSIG, the number of labels in the
+// wildcard's owner not counting the *.
+pub fn prove_wildcard(
+    name: &Domain,
+    labels: u8,
+    zone: &Domain,
+    records: &[DnsAnswer],
+) -> Result<()> {
+    let nsecs = nsec_records(zone, records);
+    if !nsecs.is_empty() {
+        ensure!(
+            nsecs
+                .iter()
+                .any(|(owner, nsec)| nsec_covers(owner, nsec, name)),
+            "no NSEC shows {name} doesn't exist for the wildcard to apply"
+        );
+        return Ok(());
+    }
+
+    let Some(chain) = Nsec3Chain::new(zone, records)? else {
+        bail!("no NSEC or NSEC3 records show {name} doesn't exist for the wildcard to apply");
+    };
+    if chain.iterations > MAX_NSEC3_ITERATIONS {
+        return Ok(());
+    }
+    let next_closer = name.suffix(labels as usize + 1);
+    ensure!(
+        chain.covering(&next_closer).is_some(),
+        "no NSEC3 covers {next_closer} for the wildcard to apply"
+    );
+    Ok(())
+}
+
+fn nsec_records(zone: &Domain, records: &[DnsAnswer]) -> Vec<(Domain, Nsec)> {
+    records
+        .iter()
+        .filter(|r| r.qtype == QuestionType::NSEC && r.name.is_subdomain_of(zone))
+        .filter_map(|r| match r.rdata() {
+            Ok(RecordData::NSEC(nsec)) => Some((r.name.clone(), nsec)),
+            _ => None,
+        })
+        .collect()
+}
+
+fn has_type(types: &[u16], qtype: QuestionType) -> bool {
+    types.contains(&qtype.code())
+}
+
+// the types that would have answered the question, CNAME always would have
+fn answers(types: &[u16], qtype: &QuestionType) -> bool {
+    has_type(types, qtype.clone()) || has_type(types, QuestionType::CNAME)
+}
+
+fn is_delegation(types: &[u16]) -> bool {
+    has_type(types, QuestionType::NS) && !has_type(types, QuestionType::SOA)
+}
+
+// Only the DS of a delegation lives in the parent, so the parent's NSEC there can't be used to
+// deny anything else (RFC 6840 S4.1).
+fn check_nodata(name: &Domain, qtype: &QuestionType, types: &[u16]) -> Result<Denial> {
+    ensure!(
+        !answers(types, qtype),
+        "{name} has {qtype:?} records after all"
+    );
+
+    let delegation = is_delegation(types);
+    ensure!(
+        !delegation || *qtype == QuestionType::DS,
+        "NSEC from above the zone cut at {name} used to deny {qtype:?}"
+    );
+    Ok(Denial::NoData { delegation })
+}
+
+// true if `name` falls strictly between the NSEC's owner and next name, the last NSEC in a zone
+// points back at the apex
+fn nsec_covers(owner: &Domain, nsec: &Nsec, name: &Domain) -> bool {
+    owner.canonical_cmp(name) == Ordering::Less
+        && (name.canonical_cmp(&nsec.next) == Ordering::Less
+            || nsec.next.canonical_cmp(owner) != Ordering::Greater)
+}
+
+// the longest name both are under
+fn common_ancestor(a: &Domain, b: &Domain) -> Domain {
+    let shared = a
+        .labels
+        .iter()
+        .rev()
+        .zip(b.labels.iter().rev())
+        .take_while(|(x, y)| x.0.eq_ignore_ascii_case(&y.0))
+        .count();
+    a.suffix(shared)
+}
+
+// the closest encloser of a name an NSEC covers, the deeper of its ancestors shared with either
+// end of the NSEC
+fn closest_encloser(name: &Domain, owner: &Domain, nsec: &Nsec) -> Domain {
+    let a = common_ancestor(name, owner);
+    let b = common_ancestor(name, &nsec.next);
+    if a.labels.len() >= b.labels.len() {
+        a
+    } else {
+        b
+    }
+}
+
+// RFC 4035 S5.4: an NSEC covers the name, and another shows there is no wildcard at the closest
+// encloser that could have answered instead
+fn nsec_nxdomain(name: &Domain, nsecs: &[(Domain, Nsec)]) -> Result<Denial> {
+    ensure!(
+        !nsecs.iter().any(|(owner, _)| owner.eq_ignore_case(name)),
+        "NSEC shows {name} exists"
+    );
+    let Some((owner, nsec)) = nsecs.iter().find(|(o, n)| nsec_covers(o, n, name)) else {
+        bail!("no NSEC covers {name}");
+    };
+
+    let wildcard = closest_encloser(name, owner, nsec).prepend("*");
+    ensure!(
+        !nsecs.iter().any(|(o, _)| o.eq_ignore_case(&wildcard)),
+        "{wildcard} exists and should have answered for {name}"
+    );
+    ensure!(
+        nsecs.iter().any(|(o, n)| nsec_covers(o, n, &wildcard)),
+        "no NSEC covers {wildcard}"
+    );
+    Ok(Denial::NxDomain)
+}
+
+fn nsec_nodata(name: &Domain, qtype: &QuestionType, nsecs: &[(Domain, Nsec)]) -> Result<Denial> {
+    if let Some((_, nsec)) = nsecs.iter().find(|(o, _)| o.eq_ignore_case(name)) {
+        return check_nodata(name, qtype, &nsec.types);
+    }
+
+    let Some((owner, nsec)) = nsecs.iter().find(|(o, n)| nsec_covers(o, n, name)) else {
+        bail!("no NSEC matches or covers {name}");
+    };
+
+    // an empty non-terminal: there's nothing at the name, but there are names underneath it
+    if nsec.next.is_subdomain_of(name) && !nsec.next.eq_ignore_case(name) {
+        return Ok(Denial::NoData { delegation: false });
+    }
+
+    // otherwise the answer would have come from a wildcard that doesn't have the type
+    let wildcard = closest_encloser(name, owner, nsec).prepend("*");
+    let Some((_, wild)) = nsecs.iter().find(|(o, _)| o.eq_ignore_case(&wildcard)) else {
+        bail!("no NSEC proves {name} has no {qtype:?}");
+    };
+    ensure!(
+        !answers(&wild.types, qtype),
+        "{wildcard} has {qtype:?} records"
+    );
+    Ok(Denial::NoData { delegation: false })
+}
+
+// the NSEC3 records of one zone, with their owner hashes decoded
+struct Nsec3Chain<'a> {
+    zone: &'a Domain,
+    salt: Vec<u8>,
+    iterations: u16,
+    records: Vec<(Vec<u8>, Nsec3)>,
+}
+
+impl<'a> Nsec3Chain<'a> {
+    fn new(zone: &'a Domain, records: &[DnsAnswer]) -> Result<Option<Self>> {
+        let mut chain: Option<Self> = None;
+
+        for record in records.iter().filter(|r| r.qtype == QuestionType::NSEC3) {
+            // NSEC3 owners are always a single hash label under the apex
+            if record.name.parent().is_none_or(|p| !p.eq_ignore_case(zone)) {
+                continue;
+            }
+            let RecordData::NSEC3(nsec3) = record.rdata()? else {
+                continue;
+            };
+            ensure!(
+                nsec3.hash_algorithm == NSEC3_HASH_SHA1,
+                "unknown NSEC3 hash algorithm {}",
+                nsec3.hash_algorithm
+            );
+            let hash = base32hex_decode(&record.name.labels[0].0)?;
+
+            let chain = chain.get_or_insert_with(|| Self {
+                zone,
+                salt: nsec3.salt.to_vec(),
+                iterations: nsec3.iterations,
+                records: vec![],
+            });
+            ensure!(
+                chain.salt == nsec3.salt.as_ref() && chain.iterations == nsec3.iterations,
+                "NSEC3 records with different parameters in {zone}"
+            );
+            chain.records.push((hash, nsec3));
+        }
+
+        Ok(chain)
+    }
+
+    fn hash(&self, name: &Domain) -> Vec<u8> {
+        nsec3_hash(name, &self.salt, self.iterations)
+    }
+
+    fn matching(&self, name: &Domain) -> Option<&Nsec3> {
+        let hash = self.hash(name);
+        self.records
+            .iter()
+            .find(|(owner, _)| *owner == hash)
+            .map(|(_, n)| n)
+    }
+
+    fn covering(&self, name: &Domain) -> Option<&Nsec3> {
+        let hash = self.hash(name);
+        self.records
+            .iter()
+            .find(|(owner, n)| nsec3_covers(owner, n, &hash))
+            .map(|(_, n)| n)
+    }
+
+    // The closest encloser proof (RFC 5155 S8.3): the longest existing ancestor of the name,
+    // and the NSEC3 covering the name one label below it, the next closer name.
+    fn closest_encloser(&self, name: &Domain) -> Result<(Domain, &Nsec3)> {
+        for len in (self.zone.labels.len()..name.labels.len()).rev() {
+            let encloser = name.suffix(len);
+            if self.matching(&encloser).is_none() {
+                continue;
+            }
+
+            let next_closer = name.suffix(len + 1);
+            let Some(cover) = self.covering(&next_closer) else {
+                bail!("no NSEC3 covers {next_closer}");
+            };
+            return Ok((encloser, cover));
+        }
+
+        bail!("no closest encloser for {name}")
+    }
+
+    fn nxdomain(&self, name: &Domain) -> Result<Denial> {
+        ensure!(self.matching(name).is_none(), "NSEC3 shows {name} exists");
+
+        let (encloser, cover) = self.closest_encloser(name)?;
+        let wildcard = encloser.prepend("*");
+        if self.covering(&wildcard).is_none() {
+            // with opt-out the name may be an unsigned delegation (RFC 5155 S9.2)
+            if cover.flags & NSEC3_FLAG_OPT_OUT != 0 {
+                return Ok(Denial::Insecure);
+            }
+            bail!("no NSEC3 covers {wildcard}");
+        }
+        Ok(Denial::NxDomain)
+    }
+
+    fn nodata(&self, name: &Domain, qtype: &QuestionType) -> Result<Denial> {
+        if let Some(nsec3) = self.matching(name) {
+            return check_nodata(name, qtype, &nsec3.types);
+        }
+
+        let (encloser, cover) = self.closest_encloser(name)?;
+
+        // no DS for a name in an opt-out span, it's an unsigned delegation (RFC 5155 S8.6)
+        if *qtype == QuestionType::DS && cover.flags & NSEC3_FLAG_OPT_OUT != 0 {
+            return Ok(Denial::Insecure);
+        }
+
+        let wildcard = encloser.prepend("*");
+        let Some(wild) = self.matching(&wildcard) else {
+            bail!("no NSEC3 proves {name} has no {qtype:?}");
+        };
+        ensure!(
+            !answers(&wild.types, qtype),
+            "{wildcard} has {qtype:?} records"
+        );
+        Ok(Denial::NoData { delegation: false })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dnssec::{base32hex_encode, nsec3_owner};
+    use crate::zone::ZoneParser;
+
+    fn zone() -> Domain {
+        "example".parse().unwrap()
+    }
+
+    fn name(s: &str) -> Domain {
+        s.parse().unwrap()
+    }
+
+    // the NSEC chain of a zone holding example, a, the delegation sub, *.w and x.y
+    fn nsec_chain() -> Vec<DnsAnswer> {
+        ZoneParser::parse_str(
+            zone(),
+            "@ 300 NSEC a NS SOA RRSIG NSEC DNSKEY\n\
+             a 300 NSEC sub A RRSIG NSEC\n\
+             sub 300 NSEC *.w NS RRSIG NSEC\n\
+             *.w 300 NSEC x.y TXT RRSIG NSEC\n\
+             x.y 300 NSEC @ A RRSIG NSEC",
+            "test",
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn proves_denial_with_nsec() {
+        let records = nsec_chain();
+        let deny = |n: &str, qtype: QuestionType, nxdomain| {
+            prove_denial(&name(n), &qtype, &zone(), &records, nxdomain)
+        };
+
+        assert_eq!(
+            deny("b.example", QuestionType::A, true).unwrap(),
+            Denial::NxDomain
+        );
+        assert_eq!(
+            deny("a.example", QuestionType::MX, false).unwrap(),
+            Denial::NoData { delegation: false }
+        );
+
+        // y.example only exists because x.y.example does
+        assert_eq!(
+            deny("y.example", QuestionType::A, false).unwrap(),
+            Denial::NoData { delegation: false }
+        );
+
+        // a.example has an A record, and b.w.example would match the wildcard
+        assert!(deny("a.example", QuestionType::A, false).is_err());
+        assert!(deny("b.w.example", QuestionType::A, true).is_err());
+
+        // the parent's NSEC at a delegation only denies the DS
+        assert_eq!(
+            deny("sub.example", QuestionType::DS, false).unwrap(),
+            Denial::NoData { delegation: true }
+        );
+        assert!(deny("sub.example", QuestionType::A, false).is_err());
+
+        // the wildcard has TXT but not A
+        assert_eq!(
+            deny("b.w.example", QuestionType::A, false).unwrap(),
+            Denial::NoData { delegation: false }
+        );
+        assert!(prove_wildcard(&name("b.w.example"), 2, &zone(), &records).is_ok());
+        assert!(prove_wildcard(&name("a.example"), 1, &zone(), &records).is_err());
+    }
+
+    #[test]
+    fn proves_denial_with_nsec3() {
+        let salt = [0xaa, 0xbb];
+        let hash = |n: &str| nsec3_hash(&name(n), &salt, 5);
+
+        // the chain for example, a and x.y, plus y as the empty non-terminal
+        let mut hashes: Vec<(Vec<u8>, &str)> = vec![
+            (hash("example"), "NS SOA"),
+            (hash("a.example"), "A"),
+            (hash("y.example"), ""),
+            (hash("x.y.example"), "A"),
+        ];
+        hashes.sort();
+
+        let text: String = (0..hashes.len())
+            .map(|i| {
+                let (owner, types) = &hashes[i];
+                let next = &hashes[(i + 1) % hashes.len()].0;
+                format!(
+                    "{} 300 NSEC3 1 0 5 aabb {} {types}\n",
+                    base32hex_encode(owner),
+                    base32hex_encode(next)
+                )
+            })
+            .collect();
+        let records = ZoneParser::parse_str(zone(), &text, "test").unwrap();
+        assert!(
+            records
+                .iter()
+                .any(|r| r.name == nsec3_owner(&name("a.example"), &zone(), &salt, 5))
+        );
+
+        let deny = |n: &str, qtype: QuestionType, nxdomain| {
+            prove_denial(&name(n), &qtype, &zone(), &records, nxdomain)
+        };
+        assert_eq!(
+            deny("b.example", QuestionType::A, true).unwrap(),
+            Denial::NxDomain
+        );
+        assert_eq!(
+            deny("q.x.y.example", QuestionType::A, true).unwrap(),
+            Denial::NxDomain
+        );
+        assert_eq!(
+            deny("a.example", QuestionType::MX, false).unwrap(),
+            Denial::NoData { delegation: false }
+        );
+        assert_eq!(
+            deny("y.example", QuestionType::A, false).unwrap(),
+            Denial::NoData { delegation: false }
+        );
+        assert!(deny("a.example", QuestionType::A, true).is_err());
+        assert!(deny("x.y.example", QuestionType::A, false).is_err());
+        assert!(deny("b.example", QuestionType::A, false).is_err());
+    }
+}
@@ -0,0 +1,289 @@
+use crate::dns::{DnsKey, Domain, RecordData};
+use crate::dnssec::{
+    ALGORITHM_ECDSAP256SHA256, ALGORITHM_ECDSAP384SHA384, ALGORITHM_ED25519, ALGORITHM_RSASHA256,
+    DNSKEY_PROTOCOL,
+};
+use crate::zone::ZoneParser;
+use anyhow::{Context, Result, anyhow, bail, ensure};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use bytes::Bytes;
+use ring::rand::SystemRandom;
+use ring::rsa::{KeyPairComponents, PublicKeyComponents};
+use ring::signature::{self, EcdsaKeyPair, Ed25519KeyPair, KeyPair as _, RsaKeyPair};
+use std::collections::HashMap;
+use std::path::Path;
+
+#[derive(Debug)]
+enum KeyPair {
+    Rsa(RsaKeyPair),
+    Ecdsa(EcdsaKeyPair),
+    Ed25519(Ed25519KeyPair),
+}
+
+// A private key we sign a zone with, along with the DNSKEY it's published as. Keys with the SEP
+// flag are key signing keys and only sign the DNSKEY RRset, the rest sign everything else.
+#[derive(Debug)]
+pub struct SigningKey {
+    pub zone: Domain,
+    pub dnskey: DnsKey,
+    pair: KeyPair,
+}
+
+impl SigningKey {
+    // a fresh key, ring can only make elliptic curve ones
+    pub fn generate(zone: Domain, flags: u16, algorithm: u8) -> Result<Self> {
+        let rng = SystemRandom::new();
+        let (pair, public_key) = match algorithm {
+            ALGORITHM_ED25519 => {
+                let pkcs8 = Ed25519KeyPair::generate_pkcs8(&rng)
+                    .map_err(|_| anyhow!("couldn't generate an Ed25519 key"))?;
+                let pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref())
+                    .map_err(|e| anyhow!("generated a bad Ed25519 key: {e}"))?;
+                let public_key = pair.public_key().as_ref().to_vec();
+                (KeyPair::Ed25519(pair), public_key)
+            }
+            ALGORITHM_ECDSAP256SHA256 | ALGORITHM_ECDSAP384SHA384 => {
+                let alg = ecdsa_algorithm(algorithm);
+                let pkcs8 = EcdsaKeyPair::generate_pkcs8(alg, &rng)
+                    .map_err(|_| anyhow!("couldn't generate an ECDSA key"))?;
+                let pair = EcdsaKeyPair::from_pkcs8(alg, pkcs8.as_ref(), &rng)
+                    .map_err(|e| anyhow!("generated a bad ECDSA key: {e}"))?;
+
+                // DNSKEYs leave off the uncompressed point marker (RFC 6605 S4)
+                let public_key = pair.public_key().as_ref()[1..].to_vec();
+                (KeyPair::Ecdsa(pair), public_key)
+            }
+            _ => bail!("can't generate keys for algorithm {algorithm}"),
+        };
+
+        Ok(Self {
+            zone,
+            dnskey: DnsKey {
+                flags,
+                protocol: DNSKEY_PROTOCOL,
+                algorithm,
+                public_key: Bytes::from(public_key),
+            },
+            pair,
+        })
+    }
+
+    // A key in the pair of files dnssec-keygen writes: Kzone.+alg+tag.key holding the DNSKEY
+    // record and Kzone.+alg+tag.private with the private key. Either file, or the name without
+    // an extension, will do.
+    pub fn from_files(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_string_lossy();
+        let base = path
+            .strip_suffix(".key")
+            .or_else(|| path.strip_suffix(".private"))
+            .unwrap_or(&path);
+        let key_path = format!("{base}.key");
+        let private_path = format!("{base}.private");
+
+        let records = ZoneParser::new(Domain::root())
+            .with_default_ttl(0)
+            .load(&key_path)?;
+        let [record] = records.as_slice() else {
+            bail!("{key_path} should hold exactly one DNSKEY");
+        };
+        let RecordData::DNSKEY(dnskey) = record.rdata()? else {
+            bail!("{key_path} holds a {:?} record, not a DNSKEY", record.qtype);
+        };
+
+        let private = std::fs::read_to_string(&private_path)
+            .with_context(|| format!("reading {private_path}"))?;
+        let pair = private_key(&dnskey, &private).with_context(|| private_path.clone())?;
+
+        Ok(Self {
+            zone: record.name.to_lowercase(),
+            dnskey,
+            pair,
+        })
+    }
+
+    pub fn is_ksk(&self) -> bool {
+        self.dnskey.flags & DnsKey::SEP != 0
+    }
+
+    pub fn key_tag(&self) -> u16 {
+        self.dnskey.key_tag()
+    }
+
+    pub fn algorithm(&self) -> u8 {
+        self.dnskey.algorithm
+    }
+
+    pub fn sign(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let rng = SystemRandom::new();
+        match &self.pair {
+            KeyPair::Rsa(pair) => {
+                let mut signature = vec![0; pair.public().modulus_len()];
+                pair.sign(&signature::RSA_PKCS1_SHA256, &rng, data, &mut signature)
+                    .map_err(|_| anyhow!("RSA signing failed"))?;
+                Ok(signature)
+            }
+            KeyPair::Ecdsa(pair) => Ok(pair
+                .sign(&rng, data)
+                .map_err(|_| anyhow!("ECDSA signing failed"))?
+                .as_ref()
+                .to_vec()),
+            KeyPair::Ed25519(pair) => Ok(pair.sign(data).as_ref().to_vec()),
+        }
+    }
+}
+
+fn ecdsa_algorithm(algorithm: u8) -> &'static signature::EcdsaSigningAlgorithm {
+    match algorithm {
+        ALGORITHM_ECDSAP384SHA384 => &signature::ECDSA_P384_SHA384_FIXED_SIGNING,
+        _ => &signature::ECDSA_P256_SHA256_FIXED_SIGNING,
+    }
+}
+
+// The private key file is a list of "Field: value" lines, binary values in base64. Which fields
+// there are depends on the algorithm.
+fn private_key(dnskey: &DnsKey, text: &str) -> Result<KeyPair> {
+    let fields: HashMap<&str, &str> = text
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim(), value.trim()))
+        .collect();
+    let field = |name: &str| -> Result<Vec<u8>> {
+        let value = fields
+            .get(name)
+            .with_context(|| format!("no {name} field"))?;
+        STANDARD
+            .decode(value)
+            .with_context(|| format!("{name} isn't valid base64"))
+    };
+
+    // "Algorithm: 13 (ECDSAP256SHA256)"
+    let algorithm: u8 = fields
+        .get("Algorithm")
+        .and_then(|a| a.split_whitespace().next())
+        .context("no Algorithm field")?
+        .parse()
+        .context("Algorithm isn't a number")?;
+    ensure!(
+        algorithm == dnskey.algorithm,
+        "private key is for algorithm {algorithm} but the DNSKEY is for {}",
+        dnskey.algorithm
+    );
+
+    let public_key = dnskey.public_key.as_ref();
+    match algorithm {
+        ALGORITHM_RSASHA256 => {
+            let components = KeyPairComponents {
+                public_key: PublicKeyComponents {
+                    n: field("Modulus")?,
+                    e: field("PublicExponent")?,
+                },
+                d: field("PrivateExponent")?,
+                p: field("Prime1")?,
+                q: field("Prime2")?,
+                dP: field("Exponent1")?,
+                dQ: field("Exponent2")?,
+                qInv: field("Coefficient")?,
+            };
+            let pair = RsaKeyPair::from_components(&components)
+                .map_err(|e| anyhow!("bad RSA key: {e}"))?;
+            Ok(KeyPair::Rsa(pair))
+        }
+        ALGORITHM_ECDSAP256SHA256 | ALGORITHM_ECDSAP384SHA384 => {
+            let point = [&[0x04], public_key].concat();
+            let pair = EcdsaKeyPair::from_private_key_and_public_key(
+                ecdsa_algorithm(algorithm),
+                &field("PrivateKey")?,
+                &point,
+                &SystemRandom::new(),
+            )
+            .map_err(|e| anyhow!("bad ECDSA key: {e}"))?;
+            Ok(KeyPair::Ecdsa(pair))
+        }
+        ALGORITHM_ED25519 => {
+            let pair = Ed25519KeyPair::from_seed_and_public_key(&field("PrivateKey")?, public_key)
+                .map_err(|e| anyhow!("bad Ed25519 key: {e}"))?;
+            Ok(KeyPair::Ed25519(pair))
+        }
+        _ => bail!("can't sign with algorithm {algorithm}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dnssec::verify_signature;
+
+    const RSA_KEY: &str = "example.com. IN DNSKEY 257 3 8 \
+        AwEAAa6Cg0z00l4IigqM7hkjCRDZabB4Uf0XMXAjFoR0sm8UyBg6rGxMLQrjAi08o9bCAYdjn4g11aSQ5vG8USQr\
+        ZH+oex7sETarkjwvSoFfDZflLW/Ioppoq5ySMEGjKFSsrvHFw7LX3LPlGqAoswSuOcQWgvBBgVnPmBa9uQcNePoO\
+        eLAXD9Zxa0Pi/ms3wFIAWIZATJzfh8D1u0EnaTIDen/wl9bOe2/vjyMTioGncfi+TQfY0S4jR46KLHnO/TNX6dvy\
+        ftdtfPhYdYlj1xGHwHYAQWuqZq2uYiwRa+GnbVEm95/4g5Z6fqfaiaIl156AODphd9Y2EMMGZWyeA5Zd35s=\n";
+
+    const RSA_PRIVATE: &str = "Private-key-format: v1.3
+Algorithm: 8 (RSASHA256)
+Modulus: roKDTPTSXgiKCozuGSMJENlpsHhR/RcxcCMWhHSybxTIGDqsbEwtCuMCLTyj1sIBh2OfiDXVpJDm8bxRJCtkf6h7HuwRNquSPC9KgV8Nl+Utb8iimmirnJIwQaMoVKyu8cXDstfcs+UaoCizBK45xBaC8EGBWc+YFr25Bw14+g54sBcP1nFrQ+L+azfAUgBYhkBMnN+HwPW7QSdpMgN6f/CX1s57b++PIxOKgadx+L5NB9jRLiNHjoosec79M1fp2/J+1218+Fh1iWPXEYfAdgBBa6pmra5iLBFr4adtUSb3n/iDlnp+p9qJoiXXnoA4OmF31jYQwwZlbJ4Dll3fmw==
+PublicExponent: AQAB
+PrivateExponent: NQYQ8SweU1YS43vIyLh9g7Iby/PzLYyDiAgRSnPO17OfzMTfKRTIRtuNCMO5IILYRhP+IDwDSzqAAYlvuia5JZTCvDWMUWk2SLUx1TdVgQawlJuI1JPjOPKuWPNDSN8jVPD7MncmZz7ApVNYeZ6ajLsuU+QDco+CLb693qe4T3nfpe6CPp1r2sWk6nSh6lgTRDIQSF6yt4kD1GSc2Eed/+/fzI1MUVvuNOir6LPNJJfZDEQGRSe0FYb7MppQLupMPyb1JODPA3/MZyRRK1PMuw73dRbU5nAbwQBx7Uzax7jmbPfulSG2S/l3wXJXp0tae895qyUj0TdeTiOqK/7IKQ==
+Prime1: 25n5FSzcBIDQmftVIc8MXgZPhOjDmwOQyrOvwmeDKdPSr1Qltuyc6dCT86pe/hgpcg1cYDod6vCMNHMj38p5znMY0t2TDiJ85o7ZWMBNEFoM3UoEqPPN/Y+uYUhBg7z22igoKhkfRE14QcCRQBHjLv1r1i4562CSMbb8K7vCNlc=
+Prime2: y287ezHCAWP7p3q6hExxrQbINqhOvjyolTS+M6ed/vCqjjEqD7LmQA7nTOBCmu2x0wt+WZDjpoXcVBRuOBh5LFJ6dP5KBhTUEJR7PgeG0ejDKXTPBYkqUzCPyzW95bb0WJTMo9WsOkRgqhazKX6P0rOBJpDLmkDSB9n2owiHrl0=
+Exponent1: wZ6NXh0yVAobYliWI/wA5Hnd7MqphGhtZjttsRNBM0TfUtT3d8RBSWosuL8ziAdXq4aaNwGm1TmH9Mq8C5njMaKLdYgFG4ZO5511hunuSIeNJiMX3rsvmOvxDTb/BJ88avbTCuMhjUBc0mRt7LaYvxqT1MDnMl/wJkM3LXwbbXs=
+Exponent2: HZN2WlrJkdovZBH7u/BBNp6dzRR7Hj1DZS3d2TStS7tBkJqlOtMPlpCUICEajduw1rX9EJh4AGXFupOd0pn8Q+1Z0FiH+opcqmxr3oDJoQruFMDeuDdQ5/dPcIPHr/YBYD+B4O/XskDurMvQEGBOGJvwUuMKNsrlvi56JQnAcCE=
+Coefficient: HHpGagHeTbv37GwiRZ3L9ij9WeVsuPJrat1Uymrl06sqIUHmf8ODN3+/I4QPUJKYsT0+mH4e/DDZKg6Xzw4DDCMy9GlcnD4xQfu3S14HAuByRoK0G5fo1KttKpMeRgROr4+th5RSOoC5wSSBhE2l+snSGT6UjmSFjegoM0c4K0Q=
+";
+
+    // RFC 8080 S6.1, an Ed25519 key and its public half
+    const ED25519_KEY: &str =
+        "example.com. 3600 IN DNSKEY 256 3 15 l02Woi0iS8Aa25FQkUd9RMzZHJpBoRQwAQEX1SxZJA4=\n";
+    const ED25519_PRIVATE: &str = "Private-key-format: v1.2
+Algorithm: 15 (ED25519)
+PrivateKey: ODIyNjAzODQ2MjgwODAxMjI2NDUxOTAyMDQxNDIyNjI=
+";
+
+    fn load(name: &str, key: &str, private: &str) -> Result<SigningKey> {
+        let dir = std::env::temp_dir().join(format!("dnssec-keys-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let base = dir.join(name);
+        std::fs::write(format!("{}.key", base.display()), key).unwrap();
+        std::fs::write(format!("{}.private", base.display()), private).unwrap();
+        SigningKey::from_files(format!("{}.private", base.display()))
+    }
+
+    #[test]
+    fn loads_bind_key_files() {
+        for (name, key, private) in [
+            ("Kexample.com.+008+00001", RSA_KEY, RSA_PRIVATE),
+            ("Kexample.com.+015+00002", ED25519_KEY, ED25519_PRIVATE),
+        ] {
+            let key = load(name, key, private).unwrap();
+            assert_eq!(key.zone.to_string(), "example.com.");
+
+            let signature = key.sign(b"data").unwrap();
+            assert!(verify_signature(&key.dnskey, b"data", &signature), "{name}");
+            assert!(!verify_signature(&key.dnskey, b"other", &signature));
+        }
+
+        let rsa = load("Kexample.com.+008+00001", RSA_KEY, RSA_PRIVATE).unwrap();
+        assert!(rsa.is_ksk());
+
+        // the private key has to be for the DNSKEY's algorithm
+        let wrong = ED25519_PRIVATE.replace("15 (ED25519)", "13 (ECDSAP256SHA256)");
+        assert!(load("Kexample.com.+015+00003", ED25519_KEY, &wrong).is_err());
+    }
+
+    #[test]
+    fn generates_elliptic_curve_keys() {
+        for algorithm in [
+            ALGORITHM_ECDSAP256SHA256,
+            ALGORITHM_ECDSAP384SHA384,
+            ALGORITHM_ED25519,
+        ] {
+            let key = SigningKey::generate("example.com".parse().unwrap(), 256, algorithm).unwrap();
+            assert!(!key.is_ksk());
+
+            let signature = key.sign(b"data").unwrap();
+            assert!(verify_signature(&key.dnskey, b"data", &signature));
+        }
+        assert!(SigningKey::generate(Domain::root(), 256, ALGORITHM_RSASHA256).is_err());
+    }
+}
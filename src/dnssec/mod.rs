@@ -0,0 +1,15 @@
+mod anchor;
+mod crypto;
+mod denial;
+mod key;
+mod nsec3;
+mod signer;
+mod validator;
+
+pub use anchor::*;
+pub use crypto::*;
+pub use denial::*;
+pub use key::*;
+pub use nsec3::*;
+pub use signer::*;
+pub use validator::*;
@@ -0,0 +1,549 @@
+use crate::dns::{
+    DnsAnswer, DnsKey, Domain, Nsec, Nsec3, Nsec3Param, QuestionType, RRset, RecordData, Rrsig,
+};
+use crate::dnssec::{SigningKey, nsec3_hash, nsec3_owner, signed_data};
+use crate::zone::Zone;
+use anyhow::{Context, Result, ensure};
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::debug;
+
+#[derive(Debug, Clone)]
+pub struct SignerConfig {
+    // how long each signature is good for
+    pub validity: Duration,
+
+    // signatures start this long ago, for validators whose clocks are behind ours
+    pub inception_offset: Duration,
+
+    // signatures with less than this left are replaced when the zone is signed again
+    pub refresh: Duration,
+
+    // hash the chain that denies names with NSEC3, or use plain NSEC when there's nothing here
+    pub nsec3: Option<Nsec3Param>,
+}
+
+impl Default for SignerConfig {
+    fn default() -> Self {
+        Self {
+            validity: Duration::from_secs(14 * 86400),
+            inception_offset: Duration::from_secs(3600),
+            refresh: Duration::from_secs(7 * 86400),
+            nsec3: None,
+        }
+    }
+}
+
+// Signs a zone with its keys: publishes the DNSKEYs, builds the NSEC or NSEC3 chain and signs
+// every RRset the zone is authoritative for (RFC 4035 S2). Signatures are kept and reused for
+// RRsets that haven't changed until they're due for a refresh, so signing a zone again after an
+// update only costs as many signatures as there were changes.
+#[derive(Debug)]
+pub struct ZoneSigner {
+    keys: Vec<SigningKey>,
+    config: SignerConfig,
+
+    // signatures we've made, keyed on the key that made them and the data they cover
+    signatures: Mutex<HashMap<(usize, Vec<u8>), Rrsig>>,
+}
+
+impl ZoneSigner {
+    pub fn new(keys: Vec<SigningKey>) -> Result<Self> {
+        ensure!(!keys.is_empty(), "can't sign a zone without any keys");
+        Ok(Self {
+            keys,
+            config: SignerConfig::default(),
+            signatures: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn with_config(mut self, config: SignerConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn keys(&self) -> &[SigningKey] {
+        &self.keys
+    }
+
+    // Any DNSSEC records the zone already has are replaced, so signing a signed zone again just
+    // refreshes it. The result has no journal: the changes in it are to the unsigned data, and a
+    // secondary applying them over IXFR would end up with signatures that don't match.
+    pub fn sign(&self, zone: &Zone) -> Result<Zone> {
+        let origin = zone.origin.to_lowercase();
+        let soa = zone.soa_record().context("zone has no SOA")?.clone();
+        let minimum = zone.soa()?.minimum.min(soa.ttl);
+
+        let ours: Vec<&DnsKey> = self.keys.iter().map(|k| &k.dnskey).collect();
+        let mut records: Vec<DnsAnswer> = zone
+            .records()
+            .into_iter()
+            .filter(|r| {
+                !matches!(
+                    r.qtype,
+                    QuestionType::RRSIG
+                        | QuestionType::NSEC
+                        | QuestionType::NSEC3
+                        | QuestionType::NSEC3PARAM
+                )
+            })
+            .filter(|r| match r.rdata() {
+                Ok(RecordData::DNSKEY(key)) => !ours.contains(&&key),
+                _ => true,
+            })
+            .cloned()
+            .collect();
+        for key in &self.keys {
+            records.push(DnsAnswer::new(
+                origin.clone(),
+                soa.ttl,
+                RecordData::DNSKEY(key.dnskey.clone()),
+            )?);
+        }
+        if let Some(param) = &self.config.nsec3 {
+            records.push(DnsAnswer::new(
+                origin.clone(),
+                0,
+                RecordData::NSEC3PARAM(param.clone()),
+            )?);
+        }
+
+        let authoritative = Authoritative::new(&origin, &records);
+        let chain = match &self.config.nsec3 {
+            Some(param) => nsec3_chain(&origin, &authoritative, param, minimum)?,
+            None => nsec_chain(&authoritative, minimum)?,
+        };
+        records.extend(chain);
+
+        let signatures = self.sign_rrsets(&origin, &authoritative.rrsets(&records))?;
+        records.extend(signatures);
+
+        debug!("signed {origin} with {} keys", self.keys.len());
+        Zone::new(zone.origin.clone(), records)
+    }
+
+    // true if the zone isn't signed, or some of its signatures are due for a refresh
+    pub fn needs_refresh(&self, zone: &Zone) -> bool {
+        if !zone.is_signed() {
+            return true;
+        }
+        let now = now();
+        zone.records().into_iter().any(|r| match r.rdata() {
+            Ok(RecordData::RRSIG(rrsig)) => remaining(&rrsig, now) < self.refresh_secs(),
+            _ => false,
+        })
+    }
+
+    fn refresh_secs(&self) -> i64 {
+        self.config.refresh.as_secs() as i64
+    }
+
+    // Sign each RRset with one key of every algorithm we have, as validators expect (RFC 6840
+    // S5.11). Key signing keys sign the DNSKEY RRset and the others sign the rest, unless there
+    // are only keys of one kind.
+    fn sign_rrsets(&self, origin: &Domain, rrsets: &[RRset]) -> Result<Vec<DnsAnswer>> {
+        let now = now();
+        let mut cache = self.signatures.lock().unwrap();
+        let mut used = HashMap::new();
+        let mut signatures = Vec::new();
+
+        for rrset in rrsets {
+            let dnskey = *rrset.qtype() == QuestionType::DNSKEY;
+            for (index, key) in self.keys.iter().enumerate() {
+                let kind_exists = self
+                    .keys
+                    .iter()
+                    .any(|k| k.algorithm() == key.algorithm() && k.is_ksk() == dnskey);
+                if kind_exists && key.is_ksk() != dnskey {
+                    continue;
+                }
+
+                let owner = rrset.name();
+                let mut rrsig = Rrsig {
+                    type_covered: rrset.qtype().code(),
+                    algorithm: key.algorithm(),
+                    labels: (owner.labels.len() - owner.is_wildcard() as usize) as u8,
+                    original_ttl: rrset.ttl(),
+                    expiration: 0,
+                    inception: 0,
+                    key_tag: key.key_tag(),
+                    signer: origin.clone(),
+                    signature: Bytes::new(),
+                };
+
+                // the signed data without the times in it identifies the RRset
+                let id = (index, signed_data(&rrsig, rrset)?);
+                rrsig = match cache.get(&id) {
+                    Some(cached) if remaining(cached, now) >= self.refresh_secs() => cached.clone(),
+                    _ => {
+                        rrsig.inception =
+                            now.wrapping_sub(self.config.inception_offset.as_secs() as u32);
+                        rrsig.expiration = now.wrapping_add(self.config.validity.as_secs() as u32);
+                        rrsig.signature = Bytes::from(key.sign(&signed_data(&rrsig, rrset)?)?);
+                        rrsig
+                    }
+                };
+
+                signatures.push(DnsAnswer::new(
+                    owner.clone(),
+                    rrset.ttl(),
+                    RecordData::RRSIG(rrsig.clone()),
+                )?);
+                used.insert(id, rrsig);
+            }
+        }
+
+        // forget signatures over data that's gone
+        *cache = used;
+        Ok(signatures)
+    }
+}
+
+// The names a zone is authoritative for: everything but what's below a zone cut. The cuts
+// themselves count, they own the NSEC records and DS RRsets for the delegations.
+struct Authoritative {
+    cuts: Vec<Domain>,
+
+    // every authoritative owner name, in canonical order, with the types it has
+    names: Vec<(Domain, Vec<u16>)>,
+}
+
+impl Authoritative {
+    fn new(origin: &Domain, records: &[DnsAnswer]) -> Self {
+        let cuts: Vec<Domain> = records
+            .iter()
+            .filter(|r| r.qtype == QuestionType::NS && !r.name.eq_ignore_case(origin))
+            .map(|r| r.name.to_lowercase())
+            .collect();
+        let mut authoritative = Self {
+            cuts,
+            names: Vec::new(),
+        };
+
+        let mut names: HashMap<Domain, Vec<u16>> = HashMap::new();
+        for record in records {
+            if !authoritative.is_occluded(&record.name) {
+                names
+                    .entry(record.name.to_lowercase())
+                    .or_default()
+                    .push(record.qtype.code());
+            }
+        }
+        authoritative.names = names
+            .into_iter()
+            .map(|(name, mut types)| {
+                types.sort_unstable();
+                types.dedup();
+                (name, types)
+            })
+            .collect();
+        authoritative
+            .names
+            .sort_by(|(a, _), (b, _)| a.canonical_cmp(b));
+        authoritative
+    }
+
+    // below a zone cut, i.e. glue
+    fn is_occluded(&self, name: &Domain) -> bool {
+        self.cuts
+            .iter()
+            .any(|cut| name.is_subdomain_of(cut) && !name.eq_ignore_case(cut))
+    }
+
+    fn is_cut(&self, name: &Domain) -> bool {
+        self.cuts.iter().any(|cut| cut.eq_ignore_case(name))
+    }
+
+    // The RRsets to sign. The NS records at a cut and any glue belong to the child zone.
+    fn rrsets(&self, records: &[DnsAnswer]) -> Vec<RRset> {
+        RRset::group(records.iter().filter(|r| {
+            let delegation = r.qtype == QuestionType::NS && self.is_cut(&r.name);
+            !delegation && !self.is_occluded(&r.name)
+        }))
+    }
+}
+
+// one NSEC per name, each pointing at the next in canonical order and the last back at the apex
+fn nsec_chain(authoritative: &Authoritative, ttl: u32) -> Result<Vec<DnsAnswer>> {
+    let names = &authoritative.names;
+    let mut chain = Vec::with_capacity(names.len());
+    for (i, (name, types)) in names.iter().enumerate() {
+        let mut types = types.clone();
+        types.extend([QuestionType::RRSIG.code(), QuestionType::NSEC.code()]);
+        types.sort_unstable();
+        types.dedup();
+
+        let next = names[(i + 1) % names.len()].0.clone();
+        chain.push(DnsAnswer::new(
+            name.clone(),
+            ttl,
+            RecordData::NSEC(Nsec { next, types }),
+        )?);
+    }
+    Ok(chain)
+}
+
+// The NSEC3 chain (RFC 5155 S7.1). Empty non-terminals get a record too, with no types, so that
+// a name above data doesn't look like it doesn't exist.
+fn nsec3_chain(
+    origin: &Domain,
+    authoritative: &Authoritative,
+    param: &Nsec3Param,
+    ttl: u32,
+) -> Result<Vec<DnsAnswer>> {
+    let mut names: HashMap<Domain, Vec<u16>> = authoritative.names.iter().cloned().collect();
+    for (name, _) in &authoritative.names {
+        let mut ancestor = name.parent();
+        while let Some(name) = ancestor {
+            if name.labels.len() <= origin.labels.len() {
+                break;
+            }
+            ancestor = name.parent();
+            names.entry(name).or_default();
+        }
+    }
+
+    let mut hashed: Vec<(Vec<u8>, Domain, Vec<u16>)> = names
+        .into_iter()
+        .map(|(name, mut types)| {
+            // an unsigned delegation has nothing signed at it
+            let unsigned = types.is_empty()
+                || (authoritative.is_cut(&name) && !types.contains(&QuestionType::DS.code()));
+            if !unsigned {
+                types.push(QuestionType::RRSIG.code());
+                types.sort_unstable();
+            }
+            let hash = nsec3_hash(&name, &param.salt, param.iterations);
+            (hash, name, types)
+        })
+        .collect();
+    hashed.sort_by(|a, b| a.0.cmp(&b.0));
+    hashed.dedup_by(|a, b| a.0 == b.0);
+
+    let mut chain = Vec::with_capacity(hashed.len());
+    for (i, (_, name, types)) in hashed.iter().enumerate() {
+        let next = &hashed[(i + 1) % hashed.len()].0;
+        chain.push(DnsAnswer::new(
+            nsec3_owner(name, origin, &param.salt, param.iterations),
+            ttl,
+            RecordData::NSEC3(Nsec3 {
+                hash_algorithm: param.hash_algorithm,
+                flags: 0,
+                iterations: param.iterations,
+                salt: param.salt.clone(),
+                next_hashed: Bytes::from(next.clone()),
+                types: types.clone(),
+            }),
+        )?);
+    }
+    Ok(chain)
+}
+
+fn now() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as u32
+}
+
+// seconds until a signature expires, using serial number arithmetic (RFC 4034 S3.1.5)
+fn remaining(rrsig: &Rrsig, now: u32) -> i64 {
+    rrsig.expiration.wrapping_sub(now) as i32 as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dnssec::verify_signature;
+    use crate::zone::ZoneParser;
+
+    const ZONE: &str = r#"
+$TTL 300
+@           SOA ns1 hostmaster 1 3600 600 86400 60
+            NS  ns1
+ns1         A   192.0.2.1
+www         A   192.0.2.2
+            TXT "hello"
+a.b.c       A   192.0.2.3
+child       NS  ns.child
+            DS  12345 15 2 0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef
+ns.child    A   192.0.2.4
+"#;
+
+    fn zone() -> Zone {
+        let origin: Domain = "example.com".parse().unwrap();
+        Zone::new(
+            origin.clone(),
+            ZoneParser::parse_str(origin, ZONE, "zone").unwrap(),
+        )
+        .unwrap()
+    }
+
+    fn signer(config: SignerConfig) -> ZoneSigner {
+        let origin: Domain = "example.com".parse().unwrap();
+        ZoneSigner::new(vec![
+            SigningKey::generate(origin.clone(), 257, 15).unwrap(),
+            SigningKey::generate(origin, 256, 15).unwrap(),
+        ])
+        .unwrap()
+        .with_config(config)
+    }
+
+    fn of_type(zone: &Zone, qtype: QuestionType) -> Vec<DnsAnswer> {
+        zone.records()
+            .into_iter()
+            .filter(|r| r.qtype == qtype)
+            .cloned()
+            .collect()
+    }
+
+    fn rrsigs(zone: &Zone) -> Vec<(Domain, Rrsig)> {
+        zone.records()
+            .into_iter()
+            .filter_map(|r| match r.rdata() {
+                Ok(RecordData::RRSIG(rrsig)) => Some((r.name.clone(), rrsig)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn signs_every_authoritative_rrset() {
+        let signer = signer(SignerConfig::default());
+        let signed = signer.sign(&zone()).unwrap();
+        assert!(signed.is_signed());
+        assert_eq!(of_type(&signed, QuestionType::DNSKEY).len(), 2);
+
+        for (owner, rrsig) in rrsigs(&signed) {
+            let rrset: Vec<DnsAnswer> = signed
+                .records()
+                .into_iter()
+                .filter(|r| r.name.eq_ignore_case(&owner) && r.qtype.code() == rrsig.type_covered)
+                .cloned()
+                .collect();
+            let key = signer
+                .keys()
+                .iter()
+                .find(|k| k.key_tag() == rrsig.key_tag)
+                .unwrap();
+            let data = signed_data(&rrsig, &rrset).unwrap();
+            assert!(
+                verify_signature(&key.dnskey, &data, &rrsig.signature),
+                "{owner}"
+            );
+
+            // the KSK signs the keys and the ZSK everything else
+            let dnskey = rrsig.type_covered == QuestionType::DNSKEY.code();
+            assert_eq!(key.is_ksk(), dnskey);
+        }
+
+        // glue and the NS records at the cut belong to the child, its DS is ours
+        let signed_at = |name: &str, qtype: QuestionType| {
+            let name: Domain = name.parse().unwrap();
+            rrsigs(&signed)
+                .iter()
+                .any(|(owner, r)| owner.eq_ignore_case(&name) && r.type_covered == qtype.code())
+        };
+        assert!(signed_at("www.example.com", QuestionType::TXT));
+        assert!(signed_at("child.example.com", QuestionType::DS));
+        assert!(!signed_at("child.example.com", QuestionType::NS));
+        assert!(!signed_at("ns.child.example.com", QuestionType::A));
+    }
+
+    #[test]
+    fn chains_names_with_nsec() {
+        let signed = signer(SignerConfig::default()).sign(&zone()).unwrap();
+        let mut chain: Vec<(String, String)> = of_type(&signed, QuestionType::NSEC)
+            .into_iter()
+            .map(|r| match r.rdata() {
+                Ok(RecordData::NSEC(nsec)) => (r.name.to_string(), nsec.next.to_string()),
+                _ => unreachable!(),
+            })
+            .collect();
+        chain.sort();
+        assert_eq!(chain, vec![
+            (
+                "a.b.c.example.com.".to_string(),
+                "child.example.com.".to_string()
+            ),
+            (
+                "child.example.com.".to_string(),
+                "ns1.example.com.".to_string()
+            ),
+            ("example.com.".to_string(), "a.b.c.example.com.".to_string()),
+            (
+                "ns1.example.com.".to_string(),
+                "www.example.com.".to_string()
+            ),
+            ("www.example.com.".to_string(), "example.com.".to_string()),
+        ]);
+
+        let name: Domain = "nope.example.com".parse().unwrap();
+        assert_eq!(
+            signed.nsec_owner(&name).unwrap().to_string(),
+            "child.example.com."
+        );
+    }
+
+    #[test]
+    fn chains_hashes_with_nsec3() {
+        let param = Nsec3Param {
+            hash_algorithm: 1,
+            flags: 0,
+            iterations: 0,
+            salt: Bytes::from_static(&[0xab, 0xcd]),
+        };
+        let signed = signer(SignerConfig {
+            nsec3: Some(param.clone()),
+            ..SignerConfig::default()
+        })
+        .sign(&zone())
+        .unwrap();
+        assert!(of_type(&signed, QuestionType::NSEC).is_empty());
+        assert_eq!(signed.nsec3_param(), Some(param.clone()));
+
+        // five names with data and the empty non-terminals b.c and c
+        assert_eq!(of_type(&signed, QuestionType::NSEC3).len(), 7);
+
+        let hash = |name: &str| nsec3_hash(&name.parse().unwrap(), &param.salt, 0);
+        let types = |name: &str| match signed.nsec3_matching(&hash(name))[0].rdata() {
+            Ok(RecordData::NSEC3(nsec3)) => nsec3.types,
+            _ => unreachable!(),
+        };
+        assert!(types("c.example.com").is_empty());
+        assert_eq!(types("child.example.com"), vec![
+            QuestionType::NS.code(),
+            QuestionType::DS.code(),
+            QuestionType::RRSIG.code()
+        ]);
+
+        // each NSEC3 is signed, and the records come with their signatures
+        assert_eq!(signed.nsec3_matching(&hash("www.example.com")).len(), 2);
+        assert_eq!(signed.nsec3_covering(&hash("nope.example.com")).len(), 2);
+    }
+
+    #[test]
+    fn reuses_signatures_until_they_need_refreshing() {
+        let signer = signer(SignerConfig::default());
+        let signed = signer.sign(&zone()).unwrap();
+        assert!(!signer.needs_refresh(&signed));
+        assert!(signer.needs_refresh(&zone()));
+
+        let again = signer.sign(&signed).unwrap();
+        let mut before = rrsigs(&signed);
+        let mut after = rrsigs(&again);
+        before.sort_by_key(|(owner, r)| (owner.to_string(), r.type_covered, r.key_tag));
+        after.sort_by_key(|(owner, r)| (owner.to_string(), r.type_covered, r.key_tag));
+        assert_eq!(before, after);
+
+        // with a refresh longer than the validity every signature is always due
+        let eager = self::signer(SignerConfig {
+            refresh: Duration::from_secs(30 * 86400),
+            ..SignerConfig::default()
+        });
+        let signed = eager.sign(&zone()).unwrap();
+        assert!(eager.needs_refresh(&signed));
+    }
+}
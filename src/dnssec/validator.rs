@@ -0,0 +1,613 @@
+use crate::dns::{
+    DnsAnswer, DnsKey, DnsMessage, DnsQuestion, Domain, EDE_DNSKEY_MISSING, EDE_DNSSEC_BOGUS,
+    EDE_NSEC_MISSING, EDE_RRSIGS_MISSING, EDE_SIGNATURE_EXPIRED, EDE_SIGNATURE_NOT_YET_VALID,
+    QuestionType, RRset, RecordData, ResponseCode, Rrsig,
+};
+use crate::dnssec::{
+    DNSKEY_PROTOCOL, Denial, TrustAnchor, ds_matches, prove_denial, prove_wildcard, signed_data,
+    supported_algorithm, supported_digest, verify_signature,
+};
+use crate::resolver::Resolver;
+use crate::zone::Serial;
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::debug;
+
+// validated keys are trusted for their TTL, but no longer than this
+const MAX_KEY_TTL: u32 = 3600;
+
+// The security status of a response (RFC 4035 S4.3).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Security {
+    // every RRset chains back to the trust anchor
+    Secure,
+
+    // at least part of the response comes from a zone that is provably unsigned
+    Insecure,
+
+    // the response should have been signed, but the signatures or proofs don't check out
+    Bogus(Bogus),
+}
+
+// why a response is bogus, `code` is the Extended DNS Error to report it with
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bogus {
+    pub code: u16,
+    pub reason: String,
+}
+
+impl Bogus {
+    fn new(code: u16, reason: impl Into<String>) -> Self {
+        Self {
+            code,
+            reason: reason.into(),
+        }
+    }
+}
+
+type Verdict<T> = std::result::Result<T, Bogus>;
+
+type VerdictFuture<'a, T> = Pin<Box<dyn Future<Output = Verdict<T>> + Send + 'a>>;
+
+// the keys of a zone, once we know whether to trust them
+#[derive(Debug, Clone)]
+enum ZoneKeys {
+    Secure(Vec<DnsKey>),
+    Insecure,
+}
+
+// what checking the signatures on one RRset told us
+enum Checked {
+    // signed by `zone`, and if it came from a wildcard, how many labels the wildcard had
+    Secure { zone: Domain, wildcard: Option<u8> },
+    Insecure,
+}
+
+// what the parent has to say about the DS records for a name
+enum DsLookup {
+    Signed(Vec<crate::dns::Ds>),
+
+    // the delegation is provably unsigned, or signed with nothing we understand
+    Unsigned,
+
+    // the name exists, but there is no zone cut there
+    NotACut,
+
+    // the name doesn't exist
+    Missing,
+}
+
+// the signed NSEC, NSEC3 and SOA records of an authority section
+struct Proof {
+    zone: Option<Domain>,
+    records: Vec<DnsAnswer>,
+    insecure: bool,
+}
+
+// Validates responses for the resolver (RFC 4035 S5). Keys are fetched as needed, chaining DS to
+// DNSKEY down from the trust anchor, and kept around once validated.
+#[derive(Debug)]
+pub struct Validator {
+    anchor: TrustAnchor,
+    keys: Mutex<HashMap<Domain, (Instant, ZoneKeys)>>,
+}
+
+impl Validator {
+    pub fn new(anchor: TrustAnchor) -> Self {
+        Self {
+            anchor,
+            keys: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn anchor(&self) -> &TrustAnchor {
+        &self.anchor
+    }
+
+    // check a response the resolver put together for `question`
+    pub async fn validate(
+        &self,
+        resolver: &Resolver,
+        question: &DnsQuestion,
+        response: &DnsMessage,
+    ) -> Security {
+        match self.check_response(resolver, question, response).await {
+            Ok(true) => Security::Secure,
+            Ok(false) => Security::Insecure,
+            Err(bogus) => {
+                debug!("{} is bogus: {}", question.name, bogus.reason);
+                Security::Bogus(bogus)
+            }
+        }
+    }
+
+    async fn check_response(
+        &self,
+        resolver: &Resolver,
+        question: &DnsQuestion,
+        response: &DnsMessage,
+    ) -> Verdict<bool> {
+        let rcode = response.response_code();
+        if rcode != ResponseCode::NoError as u8 && rcode != ResponseCode::NxDomain as u8 {
+            return Ok(false);
+        }
+
+        let mut secure = true;
+        let (rrsets, signatures) = split_rrsets(&response.answers.answers);
+        let mut wildcards = Vec::new();
+        for rrset in &rrsets {
+            match self.check_rrset(resolver, rrset, &signatures).await? {
+                Checked::Secure {
+                    zone,
+                    wildcard: Some(labels),
+                } => wildcards.push((rrset.name().clone(), labels, zone)),
+                Checked::Secure { .. } => {}
+                Checked::Insecure => secure = false,
+            }
+        }
+
+        // an answer from a wildcard needs proof that there was nothing closer
+        if !wildcards.is_empty() {
+            let proof = self.signed_proof(resolver, response).await?;
+            for (name, labels, zone) in wildcards {
+                prove_wildcard(&name, labels, &zone, &proof.records)
+                    .map_err(|e| Bogus::new(EDE_NSEC_MISSING, format!("{e:#}")))?;
+            }
+        }
+
+        // whatever is missing has to be missing where the CNAME chain ended up
+        let name = chain_end(question, &response.answers.answers);
+        let answered = question.qtype == QuestionType::CNAME
+            || rrsets
+                .iter()
+                .any(|r| r.name().eq_ignore_case(&name) && *r.qtype() == question.qtype);
+        let nxdomain = rcode == ResponseCode::NxDomain as u8;
+        if nxdomain || !answered {
+            match self
+                .check_denial(resolver, &name, &question.qtype, response, nxdomain)
+                .await?
+            {
+                Some(Denial::NxDomain | Denial::NoData { .. }) => {}
+                Some(Denial::Insecure) | None => secure = false,
+            }
+        }
+
+        Ok(secure)
+    }
+
+    // check the signatures on one RRset against the keys of the zone that signed it
+    async fn check_rrset(
+        &self,
+        resolver: &Resolver,
+        rrset: &RRset,
+        signatures: &[(Domain, Rrsig)],
+    ) -> Verdict<Checked> {
+        let owner = rrset.name();
+        let qtype = rrset.qtype();
+        let covering: Vec<&Rrsig> = signatures
+            .iter()
+            .filter(|(name, sig)| name.eq_ignore_case(owner) && sig.type_covered == qtype.code())
+            .map(|(_, sig)| sig)
+            .collect();
+
+        let Some(first) = covering.first() else {
+            return match self.is_insecure(resolver, &zone_side(owner, qtype)).await? {
+                true => Ok(Checked::Insecure),
+                false => Err(Bogus::new(
+                    EDE_RRSIGS_MISSING,
+                    format!("no RRSIG over {owner} {qtype:?}"),
+                )),
+            };
+        };
+
+        // The signer has to be above the data it signs. DS records belong to the parent, so the
+        // child signing its own would send us round in circles looking for its keys.
+        let signer = first.signer.to_lowercase();
+        let above = match qtype {
+            QuestionType::DS => owner.is_subdomain_of(&signer) && !owner.eq_ignore_case(&signer),
+            _ => owner.is_subdomain_of(&signer),
+        };
+        if !above {
+            return Err(Bogus::new(
+                EDE_DNSSEC_BOGUS,
+                format!("{owner} {qtype:?} is signed by {signer}"),
+            ));
+        }
+
+        let ZoneKeys::Secure(keys) = self.zone_keys(resolver, &signer).await? else {
+            return Ok(Checked::Insecure);
+        };
+        let covering: Vec<&Rrsig> = covering
+            .into_iter()
+            .filter(|s| s.signer.eq_ignore_case(&signer))
+            .collect();
+        let rrsig = verify_rrset(rrset, &covering, &keys)?;
+
+        let labels = owner.labels.len() - owner.is_wildcard() as usize;
+        let wildcard = ((rrsig.labels as usize) < labels).then_some(rrsig.labels);
+        Ok(Checked::Secure {
+            zone: signer,
+            wildcard,
+        })
+    }
+
+    // the validated DNSKEYs of `zone`, or the fact that it isn't signed
+    fn zone_keys<'a>(
+        &'a self,
+        resolver: &'a Resolver,
+        zone: &'a Domain,
+    ) -> VerdictFuture<'a, ZoneKeys> {
+        Box::pin(async move {
+            let zone = zone.to_lowercase();
+            if let Some(keys) = self.cached_keys(&zone) {
+                return Ok(keys);
+            }
+
+            // nothing outside the anchor's tree can chain back to it
+            if !zone.is_subdomain_of(&self.anchor.zone) {
+                return Ok(ZoneKeys::Insecure);
+            }
+
+            let (keys, ttl) = if zone == self.anchor.zone {
+                self.anchor_keys(resolver).await?
+            } else {
+                match self.ds_lookup(resolver, &zone).await? {
+                    DsLookup::Signed(ds) => self.delegated_keys(resolver, &zone, &ds).await?,
+                    DsLookup::Unsigned => (ZoneKeys::Insecure, MAX_KEY_TTL),
+                    DsLookup::NotACut | DsLookup::Missing => {
+                        return Err(Bogus::new(
+                            EDE_DNSKEY_MISSING,
+                            format!("{zone} signs records but isn't a signed zone"),
+                        ));
+                    }
+                }
+            };
+
+            let expires = Instant::now() + Duration::from_secs(ttl.min(MAX_KEY_TTL).into());
+            self.keys
+                .lock()
+                .unwrap()
+                .insert(zone, (expires, keys.clone()));
+            Ok(keys)
+        })
+    }
+
+    fn cached_keys(&self, zone: &Domain) -> Option<ZoneKeys> {
+        let mut keys = self.keys.lock().unwrap();
+        match keys.get(zone) {
+            Some((expires, k)) if *expires > Instant::now() => Some(k.clone()),
+            Some(_) => {
+                keys.remove(zone);
+                None
+            }
+            None => None,
+        }
+    }
+
+    // the anchor zone's DNSKEY RRset has to be signed by one of the keys we were given
+    async fn anchor_keys(&self, resolver: &Resolver) -> Verdict<(ZoneKeys, u32)> {
+        let zone = &self.anchor.zone;
+        let (rrset, signatures, keys) = self.fetch_dnskeys(resolver, zone).await?;
+
+        let trusted: Vec<DnsKey> = self.anchor.trusted(&keys).into_iter().cloned().collect();
+        if trusted.is_empty() {
+            return Err(Bogus::new(
+                EDE_DNSKEY_MISSING,
+                format!("no DNSKEY for {zone} matches the trust anchor"),
+            ));
+        }
+        verify_rrset(&rrset, &signatures.iter().collect::<Vec<_>>(), &trusted)?;
+
+        Ok((ZoneKeys::Secure(keys), rrset.ttl()))
+    }
+
+    // a delegated zone's DNSKEY RRset has to be signed by a key the parent has a DS for
+    async fn delegated_keys(
+        &self,
+        resolver: &Resolver,
+        zone: &Domain,
+        ds: &[crate::dns::Ds],
+    ) -> Verdict<(ZoneKeys, u32)> {
+        let (rrset, signatures, keys) = self.fetch_dnskeys(resolver, zone).await?;
+
+        let entry: Vec<DnsKey> = keys
+            .iter()
+            .filter(|k| ds.iter().any(|d| ds_matches(d, zone, k)))
+            .cloned()
+            .collect();
+        if entry.is_empty() {
+            return Err(Bogus::new(
+                EDE_DNSKEY_MISSING,
+                format!("no DNSKEY for {zone} matches its DS records"),
+            ));
+        }
+        verify_rrset(&rrset, &signatures.iter().collect::<Vec<_>>(), &entry)?;
+
+        Ok((ZoneKeys::Secure(keys), rrset.ttl()))
+    }
+
+    async fn fetch_dnskeys(
+        &self,
+        resolver: &Resolver,
+        zone: &Domain,
+    ) -> Verdict<(RRset, Vec<Rrsig>, Vec<DnsKey>)> {
+        let question = DnsQuestion::new(zone.clone(), QuestionType::DNSKEY);
+        let response = resolver.resolve(&question).await.map_err(|e| {
+            Bogus::new(
+                EDE_DNSKEY_MISSING,
+                format!("looking up the DNSKEYs for {zone}: {e:#}"),
+            )
+        })?;
+
+        let (rrsets, signatures) = split_rrsets(&response.answers.answers);
+        let rrset = rrsets
+            .into_iter()
+            .find(|r| *r.qtype() == QuestionType::DNSKEY && r.name().eq_ignore_case(zone))
+            .ok_or_else(|| Bogus::new(EDE_DNSKEY_MISSING, format!("{zone} has no DNSKEYs")))?;
+        let signatures = signatures
+            .into_iter()
+            .filter(|(name, sig)| {
+                name.eq_ignore_case(zone) && sig.type_covered == QuestionType::DNSKEY.code()
+            })
+            .map(|(_, sig)| sig)
+            .collect();
+        let keys = rrset
+            .iter()
+            .filter_map(|r| match r.rdata() {
+                Ok(RecordData::DNSKEY(key)) => Some(key),
+                _ => None,
+            })
+            .collect();
+
+        Ok((rrset, signatures, keys))
+    }
+
+    // ask the parent for the DS records at `name`, and check what it says
+    async fn ds_lookup(&self, resolver: &Resolver, name: &Domain) -> Verdict<DsLookup> {
+        let question = DnsQuestion::new(name.clone(), QuestionType::DS);
+        let response = resolver.resolve(&question).await.map_err(|e| {
+            Bogus::new(
+                EDE_DNSSEC_BOGUS,
+                format!("looking up the DS for {name}: {e:#}"),
+            )
+        })?;
+
+        let (rrsets, signatures) = split_rrsets(&response.answers.answers);
+        if let Some(rrset) = rrsets
+            .iter()
+            .find(|r| *r.qtype() == QuestionType::DS && r.name().eq_ignore_case(name))
+        {
+            if let Checked::Insecure = self.check_rrset(resolver, rrset, &signatures).await? {
+                return Ok(DsLookup::Unsigned);
+            }
+
+            // a DS we can't use is as good as none (RFC 4035 S5.2)
+            let usable: Vec<crate::dns::Ds> = rrset
+                .iter()
+                .filter_map(|r| match r.rdata() {
+                    Ok(RecordData::DS(ds)) => Some(ds),
+                    _ => None,
+                })
+                .filter(|ds| supported_algorithm(ds.algorithm) && supported_digest(ds.digest_type))
+                .collect();
+            return Ok(match usable.is_empty() {
+                true => DsLookup::Unsigned,
+                false => DsLookup::Signed(usable),
+            });
+        }
+
+        let nxdomain = response.response_code() == ResponseCode::NxDomain as u8;
+        Ok(
+            match self
+                .check_denial(resolver, name, &QuestionType::DS, &response, nxdomain)
+                .await?
+            {
+                None | Some(Denial::Insecure) => DsLookup::Unsigned,
+                Some(Denial::NoData { delegation: true }) => DsLookup::Unsigned,
+                Some(Denial::NoData { delegation: false }) => DsLookup::NotACut,
+                Some(Denial::NxDomain) => DsLookup::Missing,
+            },
+        )
+    }
+
+    // Unsigned data is only acceptable below a delegation that is provably unsigned. We find out
+    // by walking down from the anchor towards the name, asking for the DS at each level.
+    fn is_insecure<'a>(
+        &'a self,
+        resolver: &'a Resolver,
+        name: &'a Domain,
+    ) -> VerdictFuture<'a, bool> {
+        Box::pin(async move {
+            if !name.is_subdomain_of(&self.anchor.zone) {
+                return Ok(true);
+            }
+            if let ZoneKeys::Insecure = self.zone_keys(resolver, &self.anchor.zone).await? {
+                return Ok(true);
+            }
+
+            for len in self.anchor.zone.labels.len() + 1..=name.labels.len() {
+                let candidate = name.suffix(len);
+                match self.ds_lookup(resolver, &candidate).await? {
+                    DsLookup::Signed(_) => {
+                        if let ZoneKeys::Insecure = self.zone_keys(resolver, &candidate).await? {
+                            return Ok(true);
+                        }
+                    }
+                    DsLookup::Unsigned => return Ok(true),
+                    DsLookup::NotACut => {}
+                    DsLookup::Missing => return Ok(false),
+                }
+            }
+
+            Ok(false)
+        })
+    }
+
+    // What the authority section proves about `name` not having `qtype`. None means the proof
+    // itself is unsigned, which is fine as long as the name is in an unsigned zone.
+    async fn check_denial(
+        &self,
+        resolver: &Resolver,
+        name: &Domain,
+        qtype: &QuestionType,
+        response: &DnsMessage,
+        nxdomain: bool,
+    ) -> Verdict<Option<Denial>> {
+        let proof = self.signed_proof(resolver, response).await?;
+        let Some(zone) = proof.zone else {
+            if proof.insecure || self.is_insecure(resolver, &zone_side(name, qtype)).await? {
+                return Ok(None);
+            }
+            return Err(Bogus::new(
+                EDE_NSEC_MISSING,
+                format!("nothing proves {name} {qtype:?} doesn't exist"),
+            ));
+        };
+
+        if *qtype == QuestionType::DS && zone.eq_ignore_case(name) {
+            return Err(Bogus::new(
+                EDE_DNSSEC_BOGUS,
+                format!("{name} denied its own DS"),
+            ));
+        }
+
+        prove_denial(name, qtype, &zone, &proof.records, nxdomain)
+            .map(Some)
+            .map_err(|e| Bogus::new(EDE_NSEC_MISSING, format!("{e:#}")))
+    }
+
+    // check the signatures on the records in the authority section that prove things
+    async fn signed_proof(&self, resolver: &Resolver, response: &DnsMessage) -> Verdict<Proof> {
+        let (rrsets, signatures) = split_rrsets(&response.authority.answers);
+        let mut proof = Proof {
+            zone: None,
+            records: vec![],
+            insecure: false,
+        };
+
+        for rrset in rrsets.iter().filter(|r| {
+            matches!(
+                r.qtype(),
+                QuestionType::SOA | QuestionType::NSEC | QuestionType::NSEC3
+            )
+        }) {
+            match self.check_rrset(resolver, rrset, &signatures).await? {
+                Checked::Secure { zone, .. } => {
+                    // the zone the NSECs come from is the one that matters
+                    if *rrset.qtype() != QuestionType::SOA || proof.zone.is_none() {
+                        proof.zone = Some(zone);
+                    }
+                    proof.records.extend(rrset.iter().cloned());
+                }
+                Checked::Insecure => proof.insecure = true,
+            }
+        }
+
+        Ok(proof)
+    }
+}
+
+// find a signature over the RRset that one of the keys checks out
+fn verify_rrset(rrset: &RRset, signatures: &[&Rrsig], keys: &[DnsKey]) -> Verdict<Rrsig> {
+    let owner = rrset.name();
+    let qtype = rrset.qtype();
+    let now = Serial(
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs() as u32,
+    );
+    let mut failure = Bogus::new(
+        EDE_DNSSEC_BOGUS,
+        format!("no valid signature over {owner} {qtype:?}"),
+    );
+
+    for rrsig in signatures {
+        if !supported_algorithm(rrsig.algorithm) || rrsig.labels as usize > owner.labels.len() {
+            continue;
+        }
+
+        // RRSIG times use serial number arithmetic so they keep working past 2106 (RFC 4034
+        // S3.1.5)
+        if now < Serial(rrsig.inception) {
+            failure = Bogus::new(
+                EDE_SIGNATURE_NOT_YET_VALID,
+                format!("signature over {owner} {qtype:?} isn't valid yet"),
+            );
+            continue;
+        }
+        if now > Serial(rrsig.expiration) {
+            failure = Bogus::new(
+                EDE_SIGNATURE_EXPIRED,
+                format!("signature over {owner} {qtype:?} has expired"),
+            );
+            continue;
+        }
+
+        let Ok(data) = signed_data(rrsig, rrset) else {
+            continue;
+        };
+        let verified = keys
+            .iter()
+            .filter(|k| {
+                k.algorithm == rrsig.algorithm
+                    && k.protocol == DNSKEY_PROTOCOL
+                    && k.flags & DnsKey::ZONE != 0
+                    && k.key_tag() == rrsig.key_tag
+            })
+            .any(|k| verify_signature(k, &data, &rrsig.signature));
+        if verified {
+            return Ok((*rrsig).clone());
+        }
+    }
+
+    Err(failure)
+}
+
+// Split records into RRsets, keeping the RRSIGs apart along with their owner names.
+fn split_rrsets(records: &[DnsAnswer]) -> (Vec<RRset>, Vec<(Domain, Rrsig)>) {
+    let mut signatures = Vec::new();
+    for record in records.iter().filter(|r| r.qtype == QuestionType::RRSIG) {
+        if let Ok(RecordData::RRSIG(rrsig)) = record.rdata() {
+            signatures.push((record.name.clone(), rrsig));
+        }
+    }
+
+    let rrsets = RRset::group(records.iter().filter(|r| {
+        !matches!(
+            r.qtype,
+            QuestionType::RRSIG | QuestionType::OPT | QuestionType::TSIG
+        )
+    }));
+    (rrsets, signatures)
+}
+
+// the name a CNAME chain starting at the question ends at
+fn chain_end(question: &DnsQuestion, answers: &[DnsAnswer]) -> Domain {
+    let mut name = question.name.clone();
+    for _ in 0..answers.len() {
+        let target = answers
+            .iter()
+            .filter(|a| a.qtype == QuestionType::CNAME && a.name.eq_ignore_case(&name))
+            .find_map(|a| match a.rdata() {
+                Ok(RecordData::CNAME(target)) => Some(target),
+                _ => None,
+            });
+        match target {
+            Some(target) => name = target,
+            None => break,
+        }
+    }
+    name
+}
+
+// the name whose zone a record belongs to, which for a DS is the parent's
+fn zone_side(name: &Domain, qtype: &QuestionType) -> Domain {
+    match qtype {
+        QuestionType::DS => name.parent().unwrap_or_else(|| name.clone()),
+        _ => name.clone(),
+    }
+}
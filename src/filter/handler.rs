@@ -0,0 +1,179 @@
+use crate::dns::{
+    DnsAnswer, DnsAnswerSet, DnsMessage, DnsQuestion, EDE_BLOCKED, QuestionType, RecordData,
+    ResponseCode,
+};
+use crate::filter::{Blocklists, Verdict};
+use crate::handler::{DnsHandler, DnsRequest};
+use crate::stats::Stats;
+use anyhow::{Result, ensure};
+use async_trait::async_trait;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+use std::sync::Arc;
+use tracing::debug;
+
+pub const STAT_FILTER_BLOCKED: &str = "filter.blocked";
+pub const STAT_FILTER_ALLOWED: &str = "filter.allowed";
+
+// how long clients can cache the answers we make up for blocked names
+const BLOCKED_TTL: u32 = 60;
+
+// what a client gets back for a blocked name
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlockResponse {
+    NxDomain,
+
+    // 0.0.0.0 for A and :: for AAAA, nothing for other types
+    NullAddress,
+
+    Refused,
+
+    // our own addresses, for a page explaining why something was blocked. Types we have no
+    // address for get an empty answer.
+    Sinkhole(Vec<IpAddr>),
+}
+
+impl FromStr for BlockResponse {
+    type Err = anyhow::Error;
+
+    // nxdomain, null, refused, or the sinkhole addresses separated by semicolons
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s.trim().to_ascii_lowercase().as_str() {
+            "nxdomain" => Self::NxDomain,
+            "null" => Self::NullAddress,
+            "refused" => Self::Refused,
+            addresses => {
+                let addresses = addresses
+                    .split(';')
+                    .map(|a| a.trim().parse())
+                    .collect::<Result<Vec<IpAddr>, _>>()?;
+                ensure!(!addresses.is_empty(), "no sinkhole addresses");
+                Self::Sinkhole(addresses)
+            }
+        })
+    }
+}
+
+impl BlockResponse {
+    fn addresses(&self) -> Vec<IpAddr> {
+        match self {
+            Self::NullAddress => vec![
+                IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+            ],
+            Self::Sinkhole(addresses) => addresses.clone(),
+            Self::NxDomain | Self::Refused => Vec::new(),
+        }
+    }
+}
+
+// Answers for names on our blocklists itself, and passes everything else on to the handler it
+// wraps.
+#[derive(Debug)]
+pub struct FilterHandler {
+    lists: Arc<Blocklists>,
+    inner: Arc<dyn DnsHandler>,
+    response: BlockResponse,
+    stats: Arc<Stats>,
+}
+
+impl FilterHandler {
+    pub fn new(lists: Arc<Blocklists>, inner: Arc<dyn DnsHandler>) -> Self {
+        Self {
+            lists,
+            inner,
+            response: BlockResponse::NullAddress,
+            stats: Arc::new(Stats::default()),
+        }
+    }
+
+    pub fn with_response(mut self, response: BlockResponse) -> Self {
+        self.response = response;
+        self
+    }
+
+    pub fn with_stats(mut self, stats: Arc<Stats>) -> Self {
+        self.stats = stats;
+        self
+    }
+
+    pub fn stats(&self) -> Arc<Stats> {
+        self.stats.clone()
+    }
+
+    fn blocked(&self, request: &DnsRequest, question: &DnsQuestion) -> Result<DnsMessage> {
+        let mut reply = request.message.clone().as_reply();
+        reply.header.recursion_available = true;
+
+        let reply = match &self.response {
+            BlockResponse::NxDomain => reply.with_response_code(ResponseCode::NxDomain),
+            BlockResponse::Refused => reply.with_response_code(ResponseCode::Refused),
+            response => {
+                let mut answers = Vec::new();
+                for address in response.addresses() {
+                    let rdata = match (address, &question.qtype) {
+                        (IpAddr::V4(a), QuestionType::A) => RecordData::A(a),
+                        (IpAddr::V6(a), QuestionType::AAAA) => RecordData::AAAA(a),
+                        _ => continue,
+                    };
+                    answers.push(DnsAnswer::new(question.name.clone(), BLOCKED_TTL, rdata)?);
+                }
+                reply
+                    .with_response_code(ResponseCode::NoError)
+                    .with_answers(DnsAnswerSet { answers })?
+            }
+        };
+
+        // only clients that speak EDNS get told why (RFC 8914 S4.16)
+        match request.message.edns() {
+            Some(_) => reply.with_extended_error(EDE_BLOCKED, ""),
+            None => Ok(reply),
+        }
+    }
+}
+
+#[async_trait]
+impl DnsHandler for FilterHandler {
+    async fn handle(&self, request: &DnsRequest) -> Result<Option<DnsMessage>> {
+        let Some(question) = request.message.question() else {
+            return self.inner.handle(request).await;
+        };
+
+        match self.lists.check(&question.name) {
+            Verdict::Blocked => {
+                debug!("blocked {} {:?}", question.name, question.qtype);
+                self.stats.increment(STAT_FILTER_BLOCKED);
+                return Ok(Some(self.blocked(request, question)?));
+            }
+            Verdict::Allowed => self.stats.increment(STAT_FILTER_ALLOWED),
+            Verdict::Pass => {}
+        }
+
+        self.inner.handle(request).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_block_responses() {
+        assert_eq!(
+            "NXDOMAIN".parse::<BlockResponse>().unwrap(),
+            BlockResponse::NxDomain
+        );
+        assert_eq!(
+            "null".parse::<BlockResponse>().unwrap(),
+            BlockResponse::NullAddress
+        );
+        assert_eq!(
+            "192.0.2.1; 2001:db8::1".parse::<BlockResponse>().unwrap(),
+            BlockResponse::Sinkhole(vec![
+                "192.0.2.1".parse().unwrap(),
+                "2001:db8::1".parse().unwrap()
+            ])
+        );
+        assert!("sometimes".parse::<BlockResponse>().is_err());
+    }
+}
@@ -0,0 +1,368 @@
+use crate::dns::Domain;
+use anyhow::{Context, Result};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+use tracing::{debug, info, warn};
+
+// names hosts files map to themselves, which aren't something to block
+const HOSTS_LOCAL_NAMES: &[&str] = &[
+    "localhost",
+    "localhost.localdomain",
+    "local",
+    "broadcasthost",
+    "ip6-localhost",
+    "ip6-loopback",
+    "ip6-localnet",
+    "ip6-mcastprefix",
+    "ip6-allnodes",
+    "ip6-allrouters",
+    "ip6-allhosts",
+];
+
+// A set of names, each either matching just itself or everything underneath it as well.
+#[derive(Debug, Default, Clone)]
+pub struct DomainList {
+    exact: HashSet<Domain>,
+
+    // names whose subdomains match, the name itself only matches if it's in `exact` too
+    below: HashSet<Domain>,
+}
+
+impl DomainList {
+    pub fn insert_exact(&mut self, name: &Domain) {
+        self.exact.insert(name.to_lowercase());
+    }
+
+    // the name and everything below it
+    pub fn insert_suffix(&mut self, name: &Domain) {
+        self.exact.insert(name.to_lowercase());
+        self.below.insert(name.to_lowercase());
+    }
+
+    // everything below the name but not the name itself
+    pub fn insert_below(&mut self, name: &Domain) {
+        self.below.insert(name.to_lowercase());
+    }
+
+    pub fn matches(&self, name: &Domain) -> bool {
+        let name = name.to_lowercase();
+        if self.exact.contains(&name) {
+            return true;
+        }
+        (1..name.labels.len()).any(|n| self.below.contains(&name.suffix(n)))
+    }
+
+    pub fn len(&self) -> usize {
+        self.exact.len() + self.below.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+// What one line of a list says about a name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Rule {
+    Exact(Domain),
+    Suffix(Domain),
+    Below(Domain),
+}
+
+// Parse a line in any of the formats we understand, so lists can be mixed freely:
+//
+//   0.0.0.0 ads.example.com       hosts file, every name on the line, exactly
+//   ads.example.com               plain domain, exactly
+//   *.ads.example.com             plain domain, everything below it
+//   ||ads.example.com^            adblock, the name and everything below it
+//   @@||ads.example.com^          adblock exception, an allowlist entry wherever it appears
+//
+// Comments, cosmetic adblock rules and rules with options or paths don't say anything about DNS
+// and are skipped. The bool is true for exceptions.
+fn parse_line(line: &str) -> Option<(Vec<Rule>, bool)> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('!') || line.starts_with('[') {
+        return None;
+    }
+    if line.contains("##") || line.contains("#@#") || line.contains("#?#") {
+        return None;
+    }
+    let line = line.split('#').next()?.trim();
+
+    if let Some(rule) = line.strip_prefix("||") {
+        return Some((vec![Rule::Suffix(adblock_name(rule)?)], false));
+    }
+    if let Some(rule) = line.strip_prefix("@@||") {
+        return Some((vec![Rule::Suffix(adblock_name(rule)?)], true));
+    }
+
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    match fields.as_slice() {
+        [] => None,
+        [name] => match name.strip_prefix("*.") {
+            Some(parent) => Some((vec![Rule::Below(hostname(parent)?)], false)),
+            None => Some((vec![Rule::Exact(hostname(name)?)], false)),
+        },
+        [address, names @ ..] => {
+            address.parse::<std::net::IpAddr>().ok()?;
+            let rules: Vec<Rule> = names
+                .iter()
+                .filter(|n| !HOSTS_LOCAL_NAMES.contains(&n.to_ascii_lowercase().as_str()))
+                .filter_map(|n| hostname(n).map(Rule::Exact))
+                .collect();
+            (!rules.is_empty()).then_some((rules, false))
+        }
+    }
+}
+
+// the name in an adblock rule, as long as the rule is nothing more than a name
+fn adblock_name(rule: &str) -> Option<Domain> {
+    let name = rule.strip_suffix('^').unwrap_or(rule);
+    hostname(name)
+}
+
+// only names made of the characters hostnames are, anything else is a pattern we don't handle
+fn hostname(name: &str) -> Option<Domain> {
+    let valid = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    if !valid {
+        return None;
+    }
+    name.parse().ok()
+}
+
+// The names to block, and the ones to let through even if a blocklist has them.
+#[derive(Debug, Default, Clone)]
+pub struct Blocklist {
+    pub block: DomainList,
+    pub allow: DomainList,
+}
+
+// what the lists say about a name
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Blocked,
+
+    // a blocklist has it but an allowlist overrides it
+    Allowed,
+
+    // no list mentions it
+    Pass,
+}
+
+impl Blocklist {
+    // Add the rules in `text`. Everything in an allowlist is an exception, in a blocklist only
+    // the @@ rules are.
+    pub fn add_rules(&mut self, text: &str, allowlist: bool) -> usize {
+        let mut count = 0;
+        for line in text.lines() {
+            let Some((rules, exception)) = parse_line(line) else {
+                continue;
+            };
+            let list = match allowlist || exception {
+                true => &mut self.allow,
+                false => &mut self.block,
+            };
+            for rule in rules {
+                match rule {
+                    Rule::Exact(name) => list.insert_exact(&name),
+                    Rule::Suffix(name) => list.insert_suffix(&name),
+                    Rule::Below(name) => list.insert_below(&name),
+                }
+                count += 1;
+            }
+        }
+        count
+    }
+
+    pub fn check(&self, name: &Domain) -> Verdict {
+        match (self.block.matches(name), self.allow.matches(name)) {
+            (false, _) => Verdict::Pass,
+            (true, true) => Verdict::Allowed,
+            (true, false) => Verdict::Blocked,
+        }
+    }
+}
+
+// A blocklist built from files, which is rebuilt whenever one of them changes on disk.
+#[derive(Debug)]
+pub struct Blocklists {
+    // each file and whether it's an allowlist
+    files: Vec<(PathBuf, bool)>,
+
+    list: RwLock<Arc<Blocklist>>,
+
+    // when each file was last modified, as of the last time we read them
+    modified: Mutex<Vec<Option<SystemTime>>>,
+}
+
+impl Blocklists {
+    pub fn new(blocklists: Vec<PathBuf>, allowlists: Vec<PathBuf>) -> Result<Self> {
+        let files: Vec<(PathBuf, bool)> = blocklists
+            .into_iter()
+            .map(|f| (f, false))
+            .chain(allowlists.into_iter().map(|f| (f, true)))
+            .collect();
+        let lists = Self {
+            modified: Mutex::new(vec![None; files.len()]),
+            files,
+            list: RwLock::new(Arc::default()),
+        };
+        lists.load()?;
+        Ok(lists)
+    }
+
+    pub fn check(&self, name: &Domain) -> Verdict {
+        self.list.read().unwrap().check(name)
+    }
+
+    pub fn list(&self) -> Arc<Blocklist> {
+        self.list.read().unwrap().clone()
+    }
+
+    fn load(&self) -> Result<()> {
+        let mut list = Blocklist::default();
+        let mut modified = Vec::with_capacity(self.files.len());
+        for (path, allowlist) in &self.files {
+            modified.push(modified_time(path));
+            let text = std::fs::read_to_string(path)
+                .with_context(|| format!("reading {}", path.display()))?;
+            let count = list.add_rules(&text, *allowlist);
+            debug!("{count} rules from {}", path.display());
+        }
+
+        info!(
+            "loaded {} blocked and {} allowed names from {} lists",
+            list.block.len(),
+            list.allow.len(),
+            self.files.len()
+        );
+        *self.list.write().unwrap() = Arc::new(list);
+        *self.modified.lock().unwrap() = modified;
+        Ok(())
+    }
+
+    // Read the files again if any of them has changed since we last did, returning whether we
+    // did. A list that fails to load leaves the old one in place.
+    pub fn reload(&self) -> Result<bool> {
+        let current: Vec<Option<SystemTime>> = self
+            .files
+            .iter()
+            .map(|(path, _)| modified_time(path))
+            .collect();
+        if current == *self.modified.lock().unwrap() {
+            return Ok(false);
+        }
+
+        self.load()?;
+        Ok(true)
+    }
+
+    pub fn start_reloading(self: &Arc<Self>, interval: Duration) {
+        let lists = self.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                if let Err(e) = lists.reload() {
+                    warn!("reloading blocklists: {e:#}");
+                }
+            }
+        });
+    }
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn name(s: &str) -> Domain {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_mixed_list_formats() {
+        let mut list = Blocklist::default();
+        let text = "
+# a hosts file
+127.0.0.1 localhost
+0.0.0.0 ads.example.com tracker.example.com # trailing comment
+::      ipv6.example.net
+
+! adblock
+||doubleclick.net^
+@@||ok.doubleclick.net^
+||example.org/path^
+||example.org^$third-party
+example.com##.banner
+
+plain.example.com
+*.wild.example.com
+";
+        assert_eq!(list.add_rules(text, false), 7);
+
+        assert_eq!(list.check(&name("ads.example.com")), Verdict::Blocked);
+        assert_eq!(list.check(&name("ADS.Example.com.")), Verdict::Blocked);
+        assert_eq!(list.check(&name("x.ads.example.com")), Verdict::Pass);
+        assert_eq!(list.check(&name("ipv6.example.net")), Verdict::Blocked);
+        assert_eq!(list.check(&name("localhost")), Verdict::Pass);
+
+        assert_eq!(list.check(&name("doubleclick.net")), Verdict::Blocked);
+        assert_eq!(list.check(&name("ad.doubleclick.net")), Verdict::Blocked);
+        assert_eq!(list.check(&name("ok.doubleclick.net")), Verdict::Allowed);
+        assert_eq!(list.check(&name("a.ok.doubleclick.net")), Verdict::Allowed);
+        assert_eq!(list.check(&name("example.org")), Verdict::Pass);
+
+        assert_eq!(list.check(&name("plain.example.com")), Verdict::Blocked);
+        assert_eq!(list.check(&name("a.b.wild.example.com")), Verdict::Blocked);
+        assert_eq!(list.check(&name("wild.example.com")), Verdict::Pass);
+    }
+
+    #[test]
+    fn allowlists_override_blocklists() {
+        let mut list = Blocklist::default();
+        list.add_rules("||example.com^", false);
+        list.add_rules("www.example.com\n||cdn.example.com^", true);
+
+        assert_eq!(list.check(&name("ads.example.com")), Verdict::Blocked);
+        assert_eq!(list.check(&name("www.example.com")), Verdict::Allowed);
+        assert_eq!(list.check(&name("img.cdn.example.com")), Verdict::Allowed);
+    }
+
+    #[test]
+    fn reloads_when_files_change() {
+        let dir = std::env::temp_dir().join(format!("blocklist-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("block.txt");
+        std::fs::write(&path, "ads.example.com\n").unwrap();
+
+        let lists = Blocklists::new(vec![path.clone()], vec![]).unwrap();
+        assert_eq!(lists.check(&name("ads.example.com")), Verdict::Blocked);
+        assert!(!lists.reload().unwrap());
+
+        std::fs::write(&path, "tracker.example.com\n").unwrap();
+        let later = SystemTime::now() + Duration::from_secs(5);
+        std::fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(later)
+            .unwrap();
+        assert!(lists.reload().unwrap());
+        assert_eq!(lists.check(&name("ads.example.com")), Verdict::Pass);
+        assert_eq!(lists.check(&name("tracker.example.com")), Verdict::Blocked);
+
+        // a list that's gone keeps the old rules
+        std::fs::remove_file(&path).unwrap();
+        assert!(lists.reload().is_err());
+        assert_eq!(lists.check(&name("tracker.example.com")), Verdict::Blocked);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
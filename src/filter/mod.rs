@@ -0,0 +1,9 @@
+mod handler;
+mod list;
+mod policy;
+mod rpz;
+
+pub use handler::*;
+pub use list::*;
+pub use policy::*;
+pub use rpz::*;
@@ -0,0 +1,328 @@
+use crate::dns::{
+    DnsAnswer, DnsAnswerSet, DnsMessage, DnsQuestion, Domain, EDE_FILTERED, QuestionType,
+    RecordData, ResponseCode,
+};
+use crate::filter::{PolicyAction, PolicyZone};
+use crate::handler::{DnsHandler, DnsRequest, Transport};
+use crate::stats::Stats;
+use anyhow::Result;
+use async_trait::async_trait;
+use bytes::Bytes;
+use std::net::IpAddr;
+use std::sync::Arc;
+use tracing::debug;
+
+pub const STAT_RPZ_HITS: &str = "rpz.hits";
+
+// Applies response policy zones around the handler it wraps: QNAME triggers before the query is
+// passed on, and response IP, NSDNAME and NSIP triggers once there's an answer. Zones are checked
+// in order and the first one with a matching trigger decides.
+#[derive(Debug)]
+pub struct PolicyHandler {
+    zones: Vec<Arc<PolicyZone>>,
+    inner: Arc<dyn DnsHandler>,
+    stats: Arc<Stats>,
+}
+
+// where a policy matched, for the zone's SOA and for logging
+struct Hit<'a> {
+    zone: &'a PolicyZone,
+    action: &'a PolicyAction,
+    trigger: &'static str,
+}
+
+impl PolicyHandler {
+    pub fn new(zones: Vec<Arc<PolicyZone>>, inner: Arc<dyn DnsHandler>) -> Self {
+        Self {
+            zones,
+            inner,
+            stats: Arc::new(Stats::default()),
+        }
+    }
+
+    pub fn with_stats(mut self, stats: Arc<Stats>) -> Self {
+        self.stats = stats;
+        self
+    }
+
+    pub fn stats(&self) -> Arc<Stats> {
+        self.stats.clone()
+    }
+
+    fn qname_hit(&self, name: &Domain) -> Option<Hit<'_>> {
+        self.zones.iter().find_map(|zone| {
+            Some(Hit {
+                zone,
+                action: zone.qname_policy(name)?,
+                trigger: "qname",
+            })
+        })
+    }
+
+    // the triggers that need the answer, including QNAME triggers on the names a CNAME chain led
+    // to
+    async fn response_hit(
+        &self,
+        request: &DnsRequest,
+        question: &DnsQuestion,
+        reply: &DnsMessage,
+    ) -> Result<Option<Hit<'_>>> {
+        let answers = &reply.answers.answers;
+        let chain: Vec<&Domain> = answers
+            .iter()
+            .map(|a| &a.name)
+            .filter(|n| !n.eq_ignore_case(&question.name))
+            .collect();
+        let addresses: Vec<IpAddr> = answers.iter().filter_map(address).collect();
+
+        // only go looking for name servers if some zone is going to want them
+        let (nameservers, ns_addresses) = match self.zones.iter().any(|z| z.has_ns_triggers()) {
+            true => self.nameservers(request, &question.name).await?,
+            false => (Vec::new(), Vec::new()),
+        };
+
+        for zone in &self.zones {
+            let hit = |action, trigger| Hit {
+                zone,
+                action,
+                trigger,
+            };
+            if let Some(action) = chain.iter().find_map(|n| zone.qname_policy(n)) {
+                return Ok(Some(hit(action, "qname")));
+            }
+            if let Some(action) = zone.ip_policy(&addresses) {
+                return Ok(Some(hit(action, "ip")));
+            }
+            if let Some(action) = zone.nsdname_policy(&nameservers) {
+                return Ok(Some(hit(action, "nsdname")));
+            }
+            if let Some(action) = zone.nsip_policy(&ns_addresses) {
+                return Ok(Some(hit(action, "nsip")));
+            }
+        }
+        Ok(None)
+    }
+
+    // Ask the handler we wrap, which is normally a cache, so repeated lookups are cheap.
+    // Policies don't apply to our own lookups.
+    async fn lookup(
+        &self,
+        request: &DnsRequest,
+        name: &Domain,
+        qtype: QuestionType,
+    ) -> Result<Option<DnsMessage>> {
+        let mut message = DnsMessage::query(
+            request.message.header.packet_id,
+            DnsQuestion::new(name.clone(), qtype),
+        );
+        message.header.recursion_desired = true;
+        let lookup = DnsRequest {
+            message,
+            raw: Bytes::new(),
+            key: None,
+            ..request.clone()
+        };
+        self.inner.handle(&lookup).await
+    }
+
+    // The name servers for the zone `name` is in, and their addresses. A name without NS records
+    // of its own gets the SOA of its zone in a negative answer, which tells us where to look.
+    async fn nameservers(
+        &self,
+        request: &DnsRequest,
+        name: &Domain,
+    ) -> Result<(Vec<Domain>, Vec<IpAddr>)> {
+        let mut nameservers = Vec::new();
+        let mut zone = Some(name.clone());
+        for _ in 0..2 {
+            let Some(name) = zone.take() else {
+                break;
+            };
+            let Some(reply) = self.lookup(request, &name, QuestionType::NS).await? else {
+                break;
+            };
+            nameservers = records(&reply.answers.answers, QuestionType::NS)
+                .filter_map(|r| match r.rdata() {
+                    Ok(RecordData::NS(ns)) => Some(ns),
+                    _ => None,
+                })
+                .collect();
+            if !nameservers.is_empty() {
+                break;
+            }
+            zone = records(&reply.authority.answers, QuestionType::SOA)
+                .map(|soa| soa.name.clone())
+                .find(|apex| !apex.eq_ignore_case(&name));
+        }
+
+        let mut addresses = Vec::new();
+        for ns in &nameservers {
+            for qtype in [QuestionType::A, QuestionType::AAAA] {
+                if let Some(reply) = self.lookup(request, ns, qtype).await? {
+                    addresses.extend(reply.answers.answers.iter().filter_map(address));
+                }
+            }
+        }
+        Ok((nameservers, addresses))
+    }
+
+    // What the client gets for a policy hit. `reply` is what we'd have answered without the
+    // policy, if we've got that far.
+    async fn apply(
+        &self,
+        request: &DnsRequest,
+        question: &DnsQuestion,
+        hit: Hit<'_>,
+        reply: Option<DnsMessage>,
+    ) -> Result<Option<DnsMessage>> {
+        debug!(
+            "{} policy in {} for {} {:?}: {:?}",
+            hit.trigger,
+            hit.zone.origin(),
+            question.name,
+            question.qtype,
+            hit.action
+        );
+        self.stats.increment(STAT_RPZ_HITS);
+
+        let mut response = request.message.clone().as_reply();
+        response.header.recursion_available = true;
+        let soa = DnsAnswerSet {
+            answers: hit.zone.soa().cloned().into_iter().collect(),
+        };
+
+        let response = match hit.action {
+            PolicyAction::Passthru => return self.passthru(request, reply).await,
+            PolicyAction::Drop => return Ok(None),
+            PolicyAction::TcpOnly if request.transport != Transport::Udp => {
+                return self.passthru(request, reply).await;
+            }
+            PolicyAction::TcpOnly => {
+                response.header.truncation = true;
+                return Ok(Some(response));
+            }
+            PolicyAction::NxDomain => response
+                .with_response_code(ResponseCode::NxDomain)
+                .with_authority(soa)?,
+            PolicyAction::NoData => response
+                .with_response_code(ResponseCode::NoError)
+                .with_authority(soa)?,
+            PolicyAction::LocalData(records) => {
+                self.local_data(request, question, records, response, soa)
+                    .await?
+            }
+        };
+
+        // only clients that speak EDNS get told why (RFC 8914 S4.18)
+        match request.message.edns() {
+            Some(_) => Ok(Some(response.with_extended_error(EDE_FILTERED, "")?)),
+            None => Ok(Some(response)),
+        }
+    }
+
+    async fn passthru(
+        &self,
+        request: &DnsRequest,
+        reply: Option<DnsMessage>,
+    ) -> Result<Option<DnsMessage>> {
+        match reply {
+            Some(reply) => Ok(Some(reply)),
+            None => self.inner.handle(request).await,
+        }
+    }
+
+    // Answer with the policy's own records, renamed to the query name. A CNAME is followed like
+    // any other, so the client gets the answer for where it points too.
+    async fn local_data(
+        &self,
+        request: &DnsRequest,
+        question: &DnsQuestion,
+        records: &[DnsAnswer],
+        response: DnsMessage,
+        soa: DnsAnswerSet,
+    ) -> Result<DnsMessage> {
+        let cname = records.iter().find_map(|r| match r.rdata() {
+            Ok(RecordData::CNAME(target)) => Some((r.ttl, target)),
+            _ => None,
+        });
+
+        if let Some((ttl, target)) = cname {
+            let target = match target.is_wildcard() {
+                true => Domain {
+                    labels: question
+                        .name
+                        .labels
+                        .iter()
+                        .chain(&target.labels[1..])
+                        .cloned()
+                        .collect(),
+                },
+                false => target,
+            };
+            let mut answers = vec![DnsAnswer::new(
+                question.name.clone(),
+                ttl,
+                RecordData::CNAME(target.clone()),
+            )?];
+            let mut response_code = ResponseCode::NoError as u8;
+            if question.qtype != QuestionType::CNAME
+                && let Some(reply) = self
+                    .lookup(request, &target, question.qtype.clone())
+                    .await?
+            {
+                answers.extend(reply.answers.answers);
+                response_code = reply.header.response_code;
+            }
+            let mut response = response.with_answers(DnsAnswerSet { answers })?;
+            response.header.response_code = response_code;
+            return Ok(response);
+        }
+
+        let answers: Vec<DnsAnswer> = records
+            .iter()
+            .filter(|r| r.qtype == question.qtype || question.qtype == QuestionType::ANY)
+            .map(|r| DnsAnswer {
+                name: question.name.clone(),
+                ..r.clone()
+            })
+            .collect();
+        let response = response.with_response_code(ResponseCode::NoError);
+        match answers.is_empty() {
+            true => response.with_authority(soa),
+            false => response.with_answers(DnsAnswerSet { answers }),
+        }
+    }
+}
+
+#[async_trait]
+impl DnsHandler for PolicyHandler {
+    async fn handle(&self, request: &DnsRequest) -> Result<Option<DnsMessage>> {
+        let Some(question) = request.message.question() else {
+            return self.inner.handle(request).await;
+        };
+
+        if let Some(hit) = self.qname_hit(&question.name) {
+            return self.apply(request, question, hit, None).await;
+        }
+
+        let Some(reply) = self.inner.handle(request).await? else {
+            return Ok(None);
+        };
+        match self.response_hit(request, question, &reply).await? {
+            Some(hit) => self.apply(request, question, hit, Some(reply)).await,
+            None => Ok(Some(reply)),
+        }
+    }
+}
+
+fn address(record: &DnsAnswer) -> Option<IpAddr> {
+    match record.rdata() {
+        Ok(RecordData::A(a)) => Some(IpAddr::V4(a)),
+        Ok(RecordData::AAAA(a)) => Some(IpAddr::V6(a)),
+        _ => None,
+    }
+}
+
+fn records(records: &[DnsAnswer], qtype: QuestionType) -> impl Iterator<Item = &DnsAnswer> {
+    records.iter().filter(move |r| r.qtype == qtype)
+}
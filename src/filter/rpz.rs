@@ -0,0 +1,432 @@
+use crate::dns::{DnsAnswer, Domain, IpPrefix, Label, QuestionType, RecordData};
+use crate::zone::ZoneParser;
+use anyhow::{Context, Result, bail, ensure};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::Path;
+
+// the labels that mark the kinds of trigger other than QNAME (draft-vixie-dnsop-dns-rpz S4)
+const RPZ_IP: &str = "rpz-ip";
+const RPZ_NSDNAME: &str = "rpz-nsdname";
+const RPZ_NSIP: &str = "rpz-nsip";
+const RPZ_CLIENT_IP: &str = "rpz-client-ip";
+
+// What to do about a query or response a policy matches. The actions are spelled as CNAMEs to
+// special names in the zone, anything else at a trigger is local data to answer with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PolicyAction {
+    // CNAME .
+    NxDomain,
+
+    // CNAME *.
+    NoData,
+
+    // CNAME rpz-passthru., answer as if no policy had matched
+    Passthru,
+
+    // CNAME rpz-drop., don't answer at all
+    Drop,
+
+    // CNAME rpz-tcp-only., send UDP clients a truncated response so they retry over TCP
+    TcpOnly,
+
+    // The records to answer with instead, their owners are the trigger's. A CNAME whose target
+    // starts with *. has the query name put in place of the *.
+    LocalData(Vec<DnsAnswer>),
+}
+
+impl PolicyAction {
+    fn from_records(records: Vec<DnsAnswer>) -> Result<Self> {
+        let cname = records.iter().find_map(|r| match r.rdata() {
+            Ok(RecordData::CNAME(target)) => Some(target),
+            _ => None,
+        });
+        let Some(target) = cname else {
+            return Ok(Self::LocalData(records));
+        };
+        ensure!(
+            records.len() == 1,
+            "{} has a CNAME and other records",
+            records[0].name
+        );
+
+        let special = match target.labels.as_slice() {
+            [] => Some(Self::NxDomain),
+            [Label(l)] if l == "*" => Some(Self::NoData),
+            [Label(l)] if l.eq_ignore_ascii_case("rpz-passthru") => Some(Self::Passthru),
+            [Label(l)] if l.eq_ignore_ascii_case("rpz-drop") => Some(Self::Drop),
+            [Label(l)] if l.eq_ignore_ascii_case("rpz-tcp-only") => Some(Self::TcpOnly),
+            _ => None,
+        };
+        Ok(special.unwrap_or(Self::LocalData(records)))
+    }
+}
+
+// Triggers on a name, either exactly or (with a *. in front) on anything below it.
+#[derive(Debug, Default, Clone)]
+struct NameTriggers {
+    exact: HashMap<Domain, PolicyAction>,
+    below: HashMap<Domain, PolicyAction>,
+}
+
+impl NameTriggers {
+    fn insert(&mut self, name: Domain, action: PolicyAction) {
+        match name.is_wildcard() {
+            true => self.below.insert(name.parent().unwrap_or_default(), action),
+            false => self.exact.insert(name, action),
+        };
+    }
+
+    // an exact match beats a wildcard, and closer wildcards beat ones further up
+    fn find(&self, name: &Domain) -> Option<&PolicyAction> {
+        let name = name.to_lowercase();
+        if let Some(action) = self.exact.get(&name) {
+            return Some(action);
+        }
+        (0..name.labels.len())
+            .rev()
+            .find_map(|n| self.below.get(&name.suffix(n)))
+    }
+
+    fn is_empty(&self) -> bool {
+        self.exact.is_empty() && self.below.is_empty()
+    }
+}
+
+// Triggers on addresses, where the longest matching prefix wins.
+#[derive(Debug, Default, Clone)]
+struct IpTriggers(Vec<(IpPrefix, PolicyAction)>);
+
+impl IpTriggers {
+    fn find(&self, address: &IpAddr) -> Option<(u8, &PolicyAction)> {
+        self.0
+            .iter()
+            .filter(|(prefix, _)| prefix.contains(address))
+            .max_by_key(|(prefix, _)| prefix.prefix_len())
+            .map(|(prefix, action)| (prefix.prefix_len(), action))
+    }
+
+    // the best match for any of the addresses
+    fn find_any<'a>(
+        &self,
+        addresses: impl IntoIterator<Item = &'a IpAddr>,
+    ) -> Option<&PolicyAction> {
+        addresses
+            .into_iter()
+            .filter_map(|a| self.find(a))
+            .max_by_key(|(len, _)| *len)
+            .map(|(_, action)| action)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+// A response policy zone (RPZ), an ordinary zone whose owner names say what to look out for and
+// whose records say what to do about it:
+//
+//   bad.example.com          CNAME .              QNAME, the query name
+//   *.bad.example.com        CNAME *.             ...or anything below it
+//   24.0.2.0.192.rpz-ip      CNAME rpz-drop.      response IP, an address in the answer
+//   ns.bad.rpz-nsdname       CNAME .              NSDNAME, a name server for the query name
+//   32.1.2.0.192.rpz-nsip    CNAME .              NSIP, a name server's address
+//
+// Owners are relative to the zone's origin.
+#[derive(Debug, Clone)]
+pub struct PolicyZone {
+    origin: Domain,
+    soa: Option<DnsAnswer>,
+    qname: NameTriggers,
+    ip: IpTriggers,
+    nsdname: NameTriggers,
+    nsip: IpTriggers,
+}
+
+impl PolicyZone {
+    pub fn new(origin: Domain, records: Vec<DnsAnswer>) -> Result<Self> {
+        let origin = origin.to_lowercase();
+        let mut zone = Self {
+            origin: origin.clone(),
+            soa: None,
+            qname: NameTriggers::default(),
+            ip: IpTriggers::default(),
+            nsdname: NameTriggers::default(),
+            nsip: IpTriggers::default(),
+        };
+
+        // a trigger's action is all the records at its owner name, in the order they came
+        let mut owners: Vec<Domain> = Vec::new();
+        let mut by_owner: HashMap<Domain, Vec<DnsAnswer>> = HashMap::new();
+        for record in records {
+            let owner = record.name.to_lowercase();
+            ensure!(
+                owner.is_subdomain_of(&origin),
+                "{} is outside the policy zone {origin}",
+                record.name
+            );
+            if owner == origin {
+                if record.qtype == QuestionType::SOA {
+                    zone.soa = Some(record);
+                }
+                continue;
+            }
+            if !by_owner.contains_key(&owner) {
+                owners.push(owner.clone());
+            }
+            by_owner.entry(owner).or_default().push(record);
+        }
+
+        for owner in owners {
+            let records = by_owner.remove(&owner).unwrap_or_default();
+            let trigger = owner.labels[..owner.labels.len() - origin.labels.len()].to_vec();
+            zone.add_trigger(trigger, records)
+                .with_context(|| format!("bad policy at {owner}"))?;
+        }
+        Ok(zone)
+    }
+
+    pub fn from_file(origin: Domain, path: impl AsRef<Path>) -> Result<Self> {
+        let records = ZoneParser::parse_file(origin.clone(), path)?;
+        Self::new(origin, records)
+    }
+
+    fn add_trigger(&mut self, mut trigger: Vec<Label>, records: Vec<DnsAnswer>) -> Result<()> {
+        let action = PolicyAction::from_records(records)?;
+        let kind = trigger.last().map(|l| l.0.clone()).unwrap_or_default();
+        match kind.as_str() {
+            RPZ_IP | RPZ_NSIP => {
+                trigger.pop();
+                let prefix = parse_ip_trigger(&trigger)?;
+                match kind.as_str() {
+                    RPZ_IP => self.ip.0.push((prefix, action)),
+                    _ => self.nsip.0.push((prefix, action)),
+                }
+            }
+            RPZ_NSDNAME => {
+                trigger.pop();
+                self.nsdname.insert(Domain { labels: trigger }, action);
+            }
+            // we don't look at who's asking, that's for access control
+            RPZ_CLIENT_IP => {}
+            _ => self.qname.insert(Domain { labels: trigger }, action),
+        }
+        Ok(())
+    }
+
+    pub fn origin(&self) -> &Domain {
+        &self.origin
+    }
+
+    // the zone's SOA, which goes in the authority section of the answers we make up
+    pub fn soa(&self) -> Option<&DnsAnswer> {
+        self.soa.as_ref()
+    }
+
+    pub fn qname_policy(&self, name: &Domain) -> Option<&PolicyAction> {
+        self.qname.find(name)
+    }
+
+    pub fn ip_policy<'a>(
+        &self,
+        addresses: impl IntoIterator<Item = &'a IpAddr>,
+    ) -> Option<&PolicyAction> {
+        self.ip.find_any(addresses)
+    }
+
+    pub fn nsdname_policy<'a>(
+        &self,
+        names: impl IntoIterator<Item = &'a Domain>,
+    ) -> Option<&PolicyAction> {
+        names.into_iter().find_map(|n| self.nsdname.find(n))
+    }
+
+    pub fn nsip_policy<'a>(
+        &self,
+        addresses: impl IntoIterator<Item = &'a IpAddr>,
+    ) -> Option<&PolicyAction> {
+        self.nsip.find_any(addresses)
+    }
+
+    pub fn has_ip_triggers(&self) -> bool {
+        !self.ip.is_empty()
+    }
+
+    // NSDNAME and NSIP triggers need the query name's name servers looked up
+    pub fn has_ns_triggers(&self) -> bool {
+        !self.nsdname.is_empty() || !self.nsip.is_empty()
+    }
+}
+
+// The labels of an rpz-ip or rpz-nsip trigger, the prefix length and then the address backwards:
+// 24.0.2.0.192 is 192.0.2.0/24, and 48.zz.db8.2001 is 2001:db8::/48 with zz standing for the ::.
+fn parse_ip_trigger(labels: &[Label]) -> Result<IpPrefix> {
+    let Some((len, address)) = labels.split_first() else {
+        bail!("empty address trigger");
+    };
+    let len: u8 = len.0.parse().context("bad prefix length")?;
+    let parts: Vec<&str> = address.iter().rev().map(|l| l.0.as_str()).collect();
+
+    let ipv4 = parts.len() == 4 && parts.iter().all(|p| p.parse::<u8>().is_ok());
+    let address = match ipv4 {
+        true => IpAddr::V4(parts.join(".").parse::<Ipv4Addr>()?),
+        false => {
+            let mut text = parts
+                .iter()
+                .map(|p| match p.eq_ignore_ascii_case("zz") {
+                    true => "",
+                    false => p,
+                })
+                .collect::<Vec<_>>()
+                .join(":");
+            if text.starts_with(':') {
+                text.insert(0, ':');
+            }
+            if text.ends_with(':') {
+                text.push(':');
+            }
+            IpAddr::V6(
+                text.parse()
+                    .with_context(|| format!("bad address {text}"))?,
+            )
+        }
+    };
+    IpPrefix::new(address, len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn name(s: &str) -> Domain {
+        s.parse().unwrap()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    const POLICY: &str = "
+$TTL 300
+@                           SOA  ns.rpz.test. admin.rpz.test. 1 3600 600 86400 60
+                            NS   ns.rpz.test.
+bad.example.com             CNAME .
+*.bad.example.com           CNAME *.
+ok.bad.example.com          CNAME rpz-passthru.
+*.drop.example.com          CNAME rpz-drop.
+tcp.example.com             CNAME rpz-tcp-only.
+garden.example.com          CNAME walled.garden.test.
+*.wild.example.com          CNAME *.walled.garden.test.
+local.example.com           A    192.0.2.1
+                            TXT  \"local data\"
+24.0.2.0.192.rpz-ip         CNAME .
+32.9.2.0.192.rpz-ip         CNAME rpz-passthru.
+48.zz.db8.2001.rpz-ip       CNAME *.
+ns.evil.test.rpz-nsdname    CNAME .
+*.evil.test.rpz-nsdname     CNAME *.
+16.0.0.0.203.rpz-nsip       CNAME rpz-drop.
+32.1.2.3.4.rpz-client-ip    CNAME .
+";
+
+    fn zone() -> PolicyZone {
+        let origin = name("rpz.test");
+        let records = ZoneParser::parse_str(origin.clone(), POLICY, "rpz.test").unwrap();
+        PolicyZone::new(origin, records).unwrap()
+    }
+
+    #[test]
+    fn finds_qname_triggers() {
+        let zone = zone();
+        assert!(zone.soa().is_some());
+        assert_eq!(
+            zone.qname_policy(&name("BAD.example.com")),
+            Some(&PolicyAction::NxDomain)
+        );
+        assert_eq!(
+            zone.qname_policy(&name("a.b.bad.example.com")),
+            Some(&PolicyAction::NoData)
+        );
+        assert_eq!(
+            zone.qname_policy(&name("ok.bad.example.com")),
+            Some(&PolicyAction::Passthru)
+        );
+        assert_eq!(
+            zone.qname_policy(&name("x.drop.example.com")),
+            Some(&PolicyAction::Drop)
+        );
+        assert_eq!(zone.qname_policy(&name("drop.example.com")), None);
+        assert_eq!(
+            zone.qname_policy(&name("tcp.example.com")),
+            Some(&PolicyAction::TcpOnly)
+        );
+        assert_eq!(zone.qname_policy(&name("example.com")), None);
+
+        let Some(PolicyAction::LocalData(records)) = zone.qname_policy(&name("local.example.com"))
+        else {
+            panic!("expected local data");
+        };
+        assert_eq!(records.len(), 2);
+        assert!(matches!(
+            zone.qname_policy(&name("garden.example.com")),
+            Some(PolicyAction::LocalData(_))
+        ));
+    }
+
+    #[test]
+    fn finds_address_and_name_server_triggers() {
+        let zone = zone();
+        assert!(zone.has_ip_triggers() && zone.has_ns_triggers());
+
+        assert_eq!(
+            zone.ip_policy(&[ip("192.0.2.10")]),
+            Some(&PolicyAction::NxDomain)
+        );
+        // the longest prefix wins, whichever address it's for
+        assert_eq!(
+            zone.ip_policy(&[ip("192.0.2.10"), ip("192.0.2.9")]),
+            Some(&PolicyAction::Passthru)
+        );
+        assert_eq!(
+            zone.ip_policy(&[ip("2001:db8::1")]),
+            Some(&PolicyAction::NoData)
+        );
+        assert_eq!(zone.ip_policy(&[ip("198.51.100.1")]), None);
+
+        assert_eq!(
+            zone.nsdname_policy(&[name("ns.evil.test")]),
+            Some(&PolicyAction::NxDomain)
+        );
+        assert_eq!(
+            zone.nsdname_policy(&[name("ns.good.test"), name("ns2.evil.test")]),
+            Some(&PolicyAction::NoData)
+        );
+        assert_eq!(
+            zone.nsip_policy(&[ip("203.0.200.1")]),
+            Some(&PolicyAction::Drop)
+        );
+        assert_eq!(zone.nsip_policy(&[ip("203.1.0.1")]), None);
+    }
+
+    #[test]
+    fn parses_address_triggers() {
+        let labels = |s: &str| name(s).labels;
+        assert_eq!(
+            parse_ip_trigger(&labels("32.1.0.0.127")).unwrap(),
+            "127.0.0.1/32".parse().unwrap()
+        );
+        assert_eq!(
+            parse_ip_trigger(&labels("128.1.zz.db8.2001")).unwrap(),
+            "2001:db8::1/128".parse().unwrap()
+        );
+        assert_eq!(
+            parse_ip_trigger(&labels("64.zz.1.db8.2001")).unwrap(),
+            "2001:db8:1::/64".parse().unwrap()
+        );
+        assert_eq!(
+            parse_ip_trigger(&labels("128.1.zz")).unwrap(),
+            "::1/128".parse().unwrap()
+        );
+        assert!(parse_ip_trigger(&labels("33.1.0.0.127")).is_err());
+        assert!(parse_ip_trigger(&labels("24.bogus")).is_err());
+    }
+}
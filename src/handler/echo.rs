@@ -0,0 +1,15 @@
+use crate::dns::DnsMessage;
+use crate::handler::{DnsHandler, DnsRequest};
+use anyhow::Result;
+use async_trait::async_trait;
+
+// sends the request straight back, which is handy for testing the encoding and decoding
+#[derive(Debug, Default)]
+pub struct EchoHandler;
+
+#[async_trait]
+impl DnsHandler for EchoHandler {
+    async fn handle(&self, request: &DnsRequest) -> Result<Option<DnsMessage>> {
+        Ok(Some(request.message.clone()))
+    }
+}
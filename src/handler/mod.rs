@@ -0,0 +1,5 @@
+mod echo;
+mod traits;
+
+pub use echo::*;
+pub use traits::*;
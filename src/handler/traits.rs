@@ -0,0 +1,53 @@
+use crate::dns::{DnsMessage, Domain};
+use anyhow::Result;
+use async_trait::async_trait;
+use bytes::Bytes;
+use std::fmt::Debug;
+use std::net::SocketAddr;
+
+// how a request reached us
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Transport {
+    #[default]
+    Udp,
+    Tcp,
+
+    // DNS over TLS, which is TCP as far as handlers should care, apart from being private
+    Tls,
+
+    // DNS over HTTPS, or plain HTTP behind a proxy that does the TLS. One message per request.
+    Https,
+
+    // DNS over QUIC, a stream per query
+    Quic,
+}
+
+// everything a handler gets to know about an incoming request
+#[derive(Debug, Clone)]
+pub struct DnsRequest {
+    pub message: DnsMessage,
+    pub client: SocketAddr,
+    pub transport: Transport,
+
+    // the message as it came off the wire, for checks that need the exact bytes (like TSIG)
+    pub raw: Bytes,
+
+    // the TSIG key the request was signed with, once a TsigHandler has checked the signature
+    pub key: Option<Domain>,
+}
+
+// A handler turns a request into a response. Handlers are meant to be stacked, e.g. a cache
+// wrapping a resolver, so each one decides for itself whether to answer or pass the request on to
+// the handler it wraps.
+//
+// Returning None means no response should be sent at all.
+#[async_trait]
+pub trait DnsHandler: Debug + Send + Sync {
+    async fn handle(&self, request: &DnsRequest) -> Result<Option<DnsMessage>>;
+
+    // Over a stream transport a single request can get several messages back, which is how zone
+    // transfers work. Handlers that never do that can leave this alone.
+    async fn handle_stream(&self, request: &DnsRequest) -> Result<Vec<DnsMessage>> {
+        Ok(self.handle(request).await?.into_iter().collect())
+    }
+}
@@ -0,0 +1,230 @@
+use crate::dns::Domain;
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+use tracing::{debug, info, warn};
+
+// The entries of an /etc/hosts style file, an address followed by the names it has, the first
+// of which is the canonical one.
+#[derive(Debug, Default, Clone)]
+pub struct HostsFile {
+    // each name's addresses in the order the file has them
+    addresses: HashMap<Domain, Vec<IpAddr>>,
+
+    // each address's canonical names
+    names: HashMap<IpAddr, Vec<Domain>>,
+}
+
+impl HostsFile {
+    pub fn parse(text: &str) -> Self {
+        let mut hosts = Self::default();
+        for (num, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default();
+            let mut fields = line.split_whitespace();
+            let Some(address) = fields.next() else {
+                continue;
+            };
+
+            // link local IPv6 addresses can carry an interface, which means nothing to DNS
+            let address = address.split('%').next().unwrap_or_default();
+            let Ok(address) = address.parse::<IpAddr>() else {
+                debug!("line {}: {address} isn't an address", num + 1);
+                continue;
+            };
+
+            let names: Vec<Domain> = fields
+                .filter_map(|n| match n.parse::<Domain>() {
+                    Ok(name) => Some(name),
+                    Err(e) => {
+                        debug!("line {}: {e}", num + 1);
+                        None
+                    }
+                })
+                .collect();
+            // reverse lookups are by the canonical form, an IPv4-mapped address is the IPv4 one
+            if let Some(canonical) = names.first() {
+                let canonicals = hosts.names.entry(address.to_canonical()).or_default();
+                if !canonicals.iter().any(|c| c.eq_ignore_case(canonical)) {
+                    canonicals.push(canonical.clone());
+                }
+            }
+            for name in names {
+                let addresses = hosts.addresses.entry(name.to_lowercase()).or_default();
+                if !addresses.contains(&address) {
+                    addresses.push(address);
+                }
+            }
+        }
+        hosts
+    }
+
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let text =
+            std::fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+        Ok(Self::parse(&text))
+    }
+
+    // None if the file doesn't have the name at all, which isn't the same as it having no
+    // addresses of the type we want
+    pub fn addresses(&self, name: &Domain) -> Option<&[IpAddr]> {
+        self.addresses.get(&name.to_lowercase()).map(Vec::as_slice)
+    }
+
+    pub fn names(&self, address: &IpAddr) -> Option<&[Domain]> {
+        self.names.get(&address.to_canonical()).map(Vec::as_slice)
+    }
+
+    pub fn len(&self) -> usize {
+        self.addresses.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.addresses.is_empty()
+    }
+}
+
+// A hosts file that's read again whenever it changes on disk.
+#[derive(Debug)]
+pub struct Hosts {
+    path: PathBuf,
+    hosts: RwLock<Arc<HostsFile>>,
+
+    // when the file was last modified, as of the last time we read it
+    modified: Mutex<Option<SystemTime>>,
+}
+
+impl Hosts {
+    pub fn new(path: impl Into<PathBuf>) -> Result<Self> {
+        let hosts = Self {
+            path: path.into(),
+            hosts: RwLock::new(Arc::default()),
+            modified: Mutex::new(None),
+        };
+        hosts.load()?;
+        Ok(hosts)
+    }
+
+    pub fn get(&self) -> Arc<HostsFile> {
+        self.hosts.read().unwrap().clone()
+    }
+
+    fn load(&self) -> Result<()> {
+        let modified = modified_time(&self.path);
+        let hosts = HostsFile::from_file(&self.path)?;
+        info!("loaded {} names from {}", hosts.len(), self.path.display());
+        *self.hosts.write().unwrap() = Arc::new(hosts);
+        *self.modified.lock().unwrap() = modified;
+        Ok(())
+    }
+
+    // Read the file again if it's changed since we last did, returning whether we did. A file
+    // that fails to load leaves the old entries in place.
+    pub fn reload(&self) -> Result<bool> {
+        if modified_time(&self.path) == *self.modified.lock().unwrap() {
+            return Ok(false);
+        }
+
+        self.load()?;
+        Ok(true)
+    }
+
+    pub fn start_reloading(self: &Arc<Self>, interval: Duration) {
+        let hosts = self.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                if let Err(e) = hosts.reload() {
+                    warn!("reloading hosts file: {e:#}");
+                }
+            }
+        });
+    }
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn name(s: &str) -> Domain {
+        s.parse().unwrap()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_hosts_files() {
+        let hosts = HostsFile::parse(
+            "
+# comments and blank lines are skipped
+
+127.0.0.1       localhost
+192.0.2.10      nas.home.arpa nas   # the canonical name comes first
+192.0.2.11      printer.home.arpa
+2001:db8::10    nas.home.arpa
+fe80::1%eth0    router.home.arpa
+::ffff:192.0.2.12 mapped.home.arpa
+not-an-address  bogus.home.arpa
+",
+        );
+
+        assert_eq!(
+            hosts.addresses(&name("NAS.home.arpa")),
+            Some(&[ip("192.0.2.10"), ip("2001:db8::10")][..])
+        );
+        assert_eq!(hosts.addresses(&name("nas")), Some(&[ip("192.0.2.10")][..]));
+        assert_eq!(
+            hosts.addresses(&name("router.home.arpa")),
+            Some(&[ip("fe80::1")][..])
+        );
+        assert_eq!(hosts.addresses(&name("bogus.home.arpa")), None);
+
+        assert_eq!(
+            hosts.names(&ip("192.0.2.10")),
+            Some(&[name("nas.home.arpa")][..])
+        );
+        assert_eq!(
+            hosts.names(&ip("2001:db8::10")),
+            Some(&[name("nas.home.arpa")][..])
+        );
+        assert_eq!(
+            hosts.names(&ip("192.0.2.12")),
+            Some(&[name("mapped.home.arpa")][..])
+        );
+        assert_eq!(hosts.names(&ip("192.0.2.99")), None);
+    }
+
+    #[test]
+    fn reloads_when_the_file_changes() {
+        let dir = std::env::temp_dir().join(format!("hosts-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("hosts");
+        std::fs::write(&path, "192.0.2.1 one.home.arpa\n").unwrap();
+
+        let hosts = Hosts::new(&path).unwrap();
+        assert!(hosts.get().addresses(&name("one.home.arpa")).is_some());
+        assert!(!hosts.reload().unwrap());
+
+        std::fs::write(&path, "192.0.2.2 two.home.arpa\n").unwrap();
+        let later = SystemTime::now() + Duration::from_secs(5);
+        std::fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(later)
+            .unwrap();
+        assert!(hosts.reload().unwrap());
+        assert!(hosts.get().addresses(&name("one.home.arpa")).is_none());
+        assert!(hosts.get().addresses(&name("two.home.arpa")).is_some());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,90 @@
+use crate::dns::{
+    DnsAnswer, DnsAnswerSet, DnsMessage, DnsQuestion, QuestionType, RecordData, ResponseCode,
+    reverse_address,
+};
+use crate::handler::{DnsHandler, DnsRequest};
+use crate::hosts::Hosts;
+use anyhow::Result;
+use async_trait::async_trait;
+use std::net::IpAddr;
+use std::sync::Arc;
+use tracing::debug;
+
+// hosts file entries can change at any time, so clients shouldn't hang on to them for long
+const HOSTS_TTL: u32 = 60;
+
+// Answers A, AAAA and PTR queries from a hosts file, and passes names the file doesn't have on to
+// the handler it wraps. Names in the file are ours, so asking for any other type gets an empty
+// answer rather than whatever upstream thinks.
+#[derive(Debug)]
+pub struct HostsHandler {
+    hosts: Arc<Hosts>,
+    inner: Arc<dyn DnsHandler>,
+}
+
+impl HostsHandler {
+    pub fn new(hosts: Arc<Hosts>, inner: Arc<dyn DnsHandler>) -> Self {
+        Self { hosts, inner }
+    }
+
+    // the records for the question, None if the file has nothing to say about it
+    fn answers(&self, question: &DnsQuestion) -> Result<Option<Vec<DnsAnswer>>> {
+        let hosts = self.hosts.get();
+        let name = question.name.clone();
+
+        if question.qtype == QuestionType::PTR
+            && let Some(address) = reverse_address(&question.name)
+        {
+            let Some(names) = hosts.names(&address) else {
+                return Ok(None);
+            };
+            return Ok(Some(
+                names
+                    .iter()
+                    .map(|n| DnsAnswer::new(name.clone(), HOSTS_TTL, RecordData::PTR(n.clone())))
+                    .collect::<Result<_>>()?,
+            ));
+        }
+
+        let Some(addresses) = hosts.addresses(&question.name) else {
+            return Ok(None);
+        };
+        let mut answers = Vec::new();
+        for address in addresses {
+            let rdata = match (address, &question.qtype) {
+                (IpAddr::V4(a), QuestionType::A | QuestionType::ANY) => RecordData::A(*a),
+                (IpAddr::V6(a), QuestionType::AAAA | QuestionType::ANY) => RecordData::AAAA(*a),
+                _ => continue,
+            };
+            answers.push(DnsAnswer::new(name.clone(), HOSTS_TTL, rdata)?);
+        }
+        Ok(Some(answers))
+    }
+}
+
+#[async_trait]
+impl DnsHandler for HostsHandler {
+    async fn handle(&self, request: &DnsRequest) -> Result<Option<DnsMessage>> {
+        let Some(question) = request.message.question() else {
+            return self.inner.handle(request).await;
+        };
+        let Some(answers) = self.answers(question)? else {
+            return self.inner.handle(request).await;
+        };
+
+        debug!(
+            "{} {:?} from the hosts file: {} records",
+            question.name,
+            question.qtype,
+            answers.len()
+        );
+        let mut reply = request.message.clone().as_reply();
+        reply.header.auth_answer = true;
+        reply.header.recursion_available = true;
+        Ok(Some(
+            reply
+                .with_response_code(ResponseCode::NoError)
+                .with_answers(DnsAnswerSet { answers })?,
+        ))
+    }
+}
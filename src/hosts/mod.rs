@@ -0,0 +1,5 @@
+mod file;
+mod handler;
+
+pub use file::*;
+pub use handler::*;
@@ -1,17 +1,538 @@
-use crate::dns::DnsServer;
-use anyhow::Result;
+use crate::acl::{Access, AclHandler};
+use crate::cache::{CacheConfig, CachingHandler, DnsCache};
+use crate::dns::{
+    DOQ_PORT, DOT_PORT, DnsServer, Domain, Nsec3Param, tls_client_config, tls_server_config,
+};
+use crate::dnssec::{SignerConfig, SigningKey, TrustAnchor, ZoneSigner};
+use crate::filter::{Blocklists, FilterHandler, PolicyHandler, PolicyZone};
+use crate::handler::DnsHandler;
+use crate::hosts::{Hosts, HostsHandler};
+use crate::resolver::{
+    ForwardHandler, RecursiveHandler, Resolver, ResolverConfig, RootHints, Upstream,
+};
+use crate::rrl::{ResponseLimiter, RrlConfig, RrlHandler};
+use crate::stats::Stats;
+use crate::throttle::{ClientLimiter, ClientLimits, ThrottleHandler};
+use crate::tsig::{Keyring, TsigHandler};
+use crate::view::{View, ViewHandler};
+use crate::zone::{
+    AuthoritativeHandler, Notifier, NotifyHandler, Secondary, SecondaryZone, UpdateHandler,
+    ZoneStore,
+};
+use anyhow::{Context, Result, ensure};
+use bytes::Bytes;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
 use tracing::info;
 
+// how often the hosts and blocklist files are checked for changes
+const RELOAD_INTERVAL: Duration = Duration::from_secs(30);
+
+// how often the stats are logged, unless DNS_STATS_INTERVAL says otherwise
+const STATS_INTERVAL: Duration = Duration::from_secs(300);
+
 pub async fn run() -> Result<()> {
     // initialize tracing
     tracing_subscriber::fmt::init();
 
-    // build our server
-    let server = DnsServer::build("127.0.0.1:2053").await?;
+    // zones we're primary for, as a comma separated list of origin=path, and zones we're a
+    // secondary for, as origin=primary;primary
+    let primary_zones = std::env::var("DNS_ZONES").ok();
+    let secondary_zones = std::env::var("DNS_SECONDARY_ZONES").ok();
+    if primary_zones.is_some() || secondary_zones.is_some() {
+        let handler = authoritative(primary_zones, secondary_zones)?;
+        let stats = stats()?;
+        let handler = access_control(handler, stats.clone())?;
+        let handler = throttling(handler, stats.clone())?;
+        let handler = rate_limiting(handler, stats)?;
+        return serve(handler).await;
+    }
+
+    // Either one resolver for everybody, or split horizon views, a comma separated list of view
+    // names checked in order. See `views` for how each one is set up.
+    let stats = stats()?;
+    let view_names: Vec<String> = env_list("DNS_VIEWS")?;
+    let mut handler = match view_names.is_empty() {
+        true => resolving(env_list("DNS_UPSTREAMS")?, stats.clone())?,
+        false => views(&view_names, stats.clone())?,
+    };
+
+    // names from an /etc/hosts style file, which are answered before anything gets cached
+    if let Ok(path) = std::env::var("DNS_HOSTS_FILE") {
+        let hosts = Arc::new(Hosts::new(path)?);
+        hosts.start_reloading(RELOAD_INTERVAL);
+        handler = Arc::new(HostsHandler::new(hosts, handler));
+    }
+
+    // Response policy zones, as a comma separated list of origin=path in the order they're
+    // checked. The first zone with a matching trigger decides what happens.
+    let mut policy_zones = Vec::new();
+    for zone in env_list::<String>("DNS_RPZ")? {
+        let (origin, path) = zone
+            .split_once('=')
+            .with_context(|| format!("expected origin=path, got {zone}"))?;
+        let zone = PolicyZone::from_file(origin.trim().parse()?, path.trim())?;
+        policy_zones.push(Arc::new(zone));
+    }
+    if !policy_zones.is_empty() {
+        handler = Arc::new(PolicyHandler::new(policy_zones, handler).with_stats(stats.clone()));
+    }
+
+    // Blocklists and the allowlists that override them, as comma separated paths to files in
+    // hosts, plain domain or adblock format. Blocked names get DNS_BLOCK_RESPONSE: nxdomain,
+    // null (the default), refused, or sinkhole addresses separated by semicolons.
+    let blocklists: Vec<PathBuf> = env_list("DNS_BLOCKLISTS")?;
+    if !blocklists.is_empty() {
+        let lists = Arc::new(Blocklists::new(blocklists, env_list("DNS_ALLOWLISTS")?)?);
+        lists.start_reloading(RELOAD_INTERVAL);
+
+        let mut filter = FilterHandler::new(lists, handler).with_stats(stats.clone());
+        if let Ok(response) = std::env::var("DNS_BLOCK_RESPONSE") {
+            filter = filter.with_response(response.parse()?);
+        }
+        handler = Arc::new(filter);
+    }
+
+    let handler = access_control(handler, stats.clone())?;
+    let handler = throttling(handler, stats.clone())?;
+    let handler = rate_limiting(handler, stats)?;
+    serve(handler).await
+}
+
+// The counters every part of the server reports to, logged every DNS_STATS_INTERVAL seconds or
+// never if that's 0.
+fn stats() -> Result<Arc<Stats>> {
+    let stats = Arc::new(Stats::default());
+    let interval = match env_var("DNS_STATS_INTERVAL")? {
+        Some(seconds) => Duration::from_secs(seconds),
+        None => STATS_INTERVAL,
+    };
+    if !interval.is_zero() {
+        stats.start_reporting(interval);
+    }
+    Ok(stats)
+}
+
+// Build our server and run it. DNS over TLS is on when there's a PEM certificate chain in
+// DNS_TLS_CERT and its key in DNS_TLS_KEY, listening on DNS_TLS_ADDRESS or port 853, and so is
+// DNS over QUIC on DNS_QUIC_ADDRESS or UDP port 853. DNS over HTTPS is on when DNS_HTTPS_ADDRESS
+// is set, with the same certificate, or over plain HTTP with DNS_HTTPS_PLAINTEXT=1 for running
+// behind a reverse proxy. They all drop connections that are idle for DNS_TLS_IDLE_TIMEOUT
+// seconds.
+async fn serve(handler: Arc<dyn DnsHandler>) -> Result<()> {
+    let mut server = DnsServer::build("127.0.0.1:2053")
+        .await?
+        .with_handler(handler);
+    if let Some(idle) = env_var("DNS_TLS_IDLE_TIMEOUT")? {
+        server = server.with_tls_idle_timeout(Duration::from_secs(idle));
+    }
+
+    let tls = match (std::env::var("DNS_TLS_CERT"), std::env::var("DNS_TLS_KEY")) {
+        (Ok(cert), Ok(key)) => Some(tls_server_config(cert, key)?),
+        _ => None,
+    };
+    if let Some(tls) = &tls {
+        let address =
+            std::env::var("DNS_TLS_ADDRESS").unwrap_or_else(|_| format!("127.0.0.1:{DOT_PORT}"));
+        server = server.with_tls(&address, tls.clone()).await?;
+
+        let address =
+            std::env::var("DNS_QUIC_ADDRESS").unwrap_or_else(|_| format!("127.0.0.1:{DOQ_PORT}"));
+        server = server.with_quic(&address, tls.clone())?;
+    }
+    if let Ok(address) = std::env::var("DNS_HTTPS_ADDRESS") {
+        let plaintext = std::env::var("DNS_HTTPS_PLAINTEXT").is_ok_and(|v| v == "1");
+        let tls = match plaintext {
+            true => None,
+            false => Some(tls.context("DoH needs DNS_TLS_CERT and DNS_TLS_KEY")?),
+        };
+        server = server.with_https(&address, tls).await?;
+    }
     info!("server: {:?}", server);
 
-    // run
-    server.run_until_stopped().await?;
+    server.run_until_stopped().await
+}
+
+// A cache in front of either the upstream servers, if we're given any, or our own recursive
+// resolver. Upstreams are plain addresses for UDP, tls://, https:// and quic:// URLs, or sdns://
+// stamps, and the encrypted ones' certificates are checked against the public CAs or those in the
+// PEM file in DNS_UPSTREAM_CA.
+fn resolving(upstreams: Vec<Upstream>, stats: Arc<Stats>) -> Result<Arc<dyn DnsHandler>> {
+    let resolver: Arc<dyn DnsHandler> = match upstreams.is_empty() {
+        false => {
+            let mut forwarder = ForwardHandler::new(upstreams);
+            if let Ok(path) = std::env::var("DNS_UPSTREAM_CA") {
+                forwarder = forwarder.with_tls_config(tls_client_config(Some(path.as_ref()))?);
+            }
+            Arc::new(forwarder)
+        }
+        true => {
+            // the root hints default to the real root servers, but can be loaded from a
+            // named.root file
+            let root_hints = match std::env::var("DNS_ROOT_HINTS") {
+                Ok(path) => RootHints::from_file(path)?,
+                Err(_) => RootHints::default(),
+            };
+
+            // DNSSEC validation is on with a trust anchor file, or with DNS_DNSSEC_VALIDATION=1
+            // to use the root's published keys
+            let trust_anchor = match std::env::var("DNS_TRUST_ANCHOR") {
+                Ok(path) => Some(TrustAnchor::from_file(path)?),
+                Err(_) => std::env::var("DNS_DNSSEC_VALIDATION")
+                    .is_ok_and(|v| v == "1")
+                    .then(TrustAnchor::root),
+            };
+            let resolver = Resolver::new(ResolverConfig {
+                root_hints,
+                trust_anchor,
+                ..ResolverConfig::default()
+            });
+            Arc::new(RecursiveHandler::new(Arc::new(resolver)))
+        }
+    };
+
+    let cache = Arc::new(DnsCache::new(CacheConfig::default()));
+    let handler = Arc::new(CachingHandler::new(cache, resolver).with_stats(stats.clone()));
+
+    // the clients allowed to use it, see `access_control`
+    match std::env::var("DNS_ALLOW_RECURSION") {
+        Ok(acl) => Ok(Arc::new(
+            AclHandler::new(handler)
+                .with_acl(Access::Recursion, acl.parse()?)
+                .with_stats(stats),
+        )),
+        Err(_) => Ok(handler),
+    }
+}
+
+// Access lists for each kind of request, as CIDR blocks separated by commas where the first
+// block a client is in decides, a ! in front denies it, and "any" and "none" mean what they say.
+// Requests a list doesn't allow are refused, and kinds of request without one are open to all.
+// DNS_ALLOW_RECURSION is applied in front of the resolver rather than here.
+fn access_control(handler: Arc<dyn DnsHandler>, stats: Arc<Stats>) -> Result<Arc<dyn DnsHandler>> {
+    let mut acls = AclHandler::new(handler.clone()).with_stats(stats);
+    for (access, name) in [
+        (Access::Query, "DNS_ALLOW_QUERY"),
+        (Access::Transfer, "DNS_ALLOW_TRANSFER"),
+        (Access::Update, "DNS_ALLOW_UPDATE"),
+        (Access::Notify, "DNS_ALLOW_NOTIFY"),
+    ] {
+        if let Ok(acl) = std::env::var(name) {
+            acls = acls.with_acl(access, acl.parse()?);
+        }
+    }
+    match acls.is_empty() {
+        true => Ok(handler),
+        false => Ok(Arc::new(acls)),
+    }
+}
+
+// Limits on each client's queries, on when either is set: DNS_CLIENT_QPS queries a second and
+// DNS_CLIENT_MAX_IN_FLIGHT of them being worked on at once. Clients over a limit are refused, or
+// ignored with DNS_CLIENT_OVER_LIMIT=drop. DNS_CLIENT_IPV4_PREFIX and DNS_CLIENT_IPV6_PREFIX
+// limit whole netblocks together rather than each address, and clients in the DNS_CLIENT_EXEMPT
+// access list have no limits.
+fn throttling(handler: Arc<dyn DnsHandler>, stats: Arc<Stats>) -> Result<Arc<dyn DnsHandler>> {
+    let qps = env_var("DNS_CLIENT_QPS")?;
+    let max_in_flight = env_var("DNS_CLIENT_MAX_IN_FLIGHT")?;
+    if qps.is_none() && max_in_flight.is_none() {
+        return Ok(handler);
+    }
+
+    let defaults = ClientLimits::default();
+    let limits = ClientLimits {
+        queries_per_second: qps.unwrap_or(0),
+        max_in_flight: max_in_flight.unwrap_or(0),
+        ipv4_prefix_len: env_var("DNS_CLIENT_IPV4_PREFIX")?.unwrap_or(defaults.ipv4_prefix_len),
+        ipv6_prefix_len: env_var("DNS_CLIENT_IPV6_PREFIX")?.unwrap_or(defaults.ipv6_prefix_len),
+        over_limit: env_var("DNS_CLIENT_OVER_LIMIT")?.unwrap_or(defaults.over_limit),
+    };
+    ensure!(
+        limits.ipv4_prefix_len <= 32 && limits.ipv6_prefix_len <= 128,
+        "bad netblock size for client limits"
+    );
+    info!("limiting clients: {limits:?}");
+
+    let limiter = Arc::new(ClientLimiter::new(limits));
+    let mut throttle = ThrottleHandler::new(limiter, handler).with_stats(stats);
+    if let Ok(exempt) = std::env::var("DNS_CLIENT_EXEMPT") {
+        throttle = throttle.with_exempt(exempt.parse()?);
+    }
+    Ok(Arc::new(throttle))
+}
+
+// Response rate limiting for UDP clients, on when any of the rates are set. DNS_RRL_RESPONSES,
+// DNS_RRL_NXDOMAINS and DNS_RRL_ERRORS are identical responses per second to a client netblock,
+// with NXDOMAINs and errors limited at the rate for responses unless they're given their own.
+// Every DNS_RRL_SLIP'th response over the limit is sent truncated rather than dropped.
+// DNS_RRL_IPV4_PREFIX and DNS_RRL_IPV6_PREFIX size the netblocks, and clients in the
+// DNS_RRL_EXEMPT access list are never limited.
+fn rate_limiting(handler: Arc<dyn DnsHandler>, stats: Arc<Stats>) -> Result<Arc<dyn DnsHandler>> {
+    let responses = env_var("DNS_RRL_RESPONSES")?;
+    let nxdomains = env_var("DNS_RRL_NXDOMAINS")?;
+    let errors = env_var("DNS_RRL_ERRORS")?;
+    if responses.is_none() && nxdomains.is_none() && errors.is_none() {
+        return Ok(handler);
+    }
+
+    let defaults = RrlConfig::default();
+    let responses = responses.unwrap_or(defaults.responses_per_second);
+    let config = RrlConfig {
+        responses_per_second: responses,
+        nxdomains_per_second: nxdomains.unwrap_or(responses),
+        errors_per_second: errors.unwrap_or(responses),
+        slip: env_var("DNS_RRL_SLIP")?.unwrap_or(defaults.slip),
+        ipv4_prefix_len: env_var("DNS_RRL_IPV4_PREFIX")?.unwrap_or(defaults.ipv4_prefix_len),
+        ipv6_prefix_len: env_var("DNS_RRL_IPV6_PREFIX")?.unwrap_or(defaults.ipv6_prefix_len),
+    };
+    ensure!(
+        config.ipv4_prefix_len <= 32 && config.ipv6_prefix_len <= 128,
+        "bad netblock size for rate limiting"
+    );
+    info!("rate limiting responses: {config:?}");
+
+    let limiter = Arc::new(ResponseLimiter::new(config));
+    let mut rrl = RrlHandler::new(limiter, handler).with_stats(stats);
+    if let Ok(exempt) = std::env::var("DNS_RRL_EXEMPT") {
+        rrl = rrl.with_exempt(exempt.parse()?);
+    }
+    Ok(Arc::new(rrl))
+}
+
+// Each view is configured with variables named after it, e.g. for a view called internal:
+//
+//   DNS_VIEW_INTERNAL_CLIENTS    CIDR blocks of the clients it's for
+//   DNS_VIEW_INTERNAL_KEYS       TSIG keys that select it whatever the client's address
+//   DNS_VIEW_INTERNAL_ZONES      its own zones, as origin=path
+//   DNS_VIEW_INTERNAL_RECURSION  0 to refuse names outside its zones rather than resolve them
+//   DNS_VIEW_INTERNAL_UPSTREAMS  where to forward to instead of DNS_UPSTREAMS
+//
+// Every view has a cache of its own, so answers never leak from one to another.
+fn views(names: &[String], stats: Arc<Stats>) -> Result<Arc<dyn DnsHandler>> {
+    let mut views = Vec::new();
+    for name in names {
+        let var = |setting: &str| format!("DNS_VIEW_{}_{setting}", name.to_uppercase());
+
+        let recursion = std::env::var(var("RECURSION")).map_or(true, |v| v != "0");
+        let mut handler: Option<Arc<dyn DnsHandler>> = None;
+        if recursion {
+            let mut upstreams: Vec<Upstream> = env_list(&var("UPSTREAMS"))?;
+            if upstreams.is_empty() {
+                upstreams = env_list("DNS_UPSTREAMS")?;
+            }
+            handler = Some(resolving(upstreams, stats.clone())?);
+        }
+
+        let zones: Vec<String> = env_list(&var("ZONES"))?;
+        if !zones.is_empty() || handler.is_none() {
+            let store = Arc::new(ZoneStore::default());
+            for zone in zones {
+                let (origin, path) = zone
+                    .split_once('=')
+                    .with_context(|| format!("expected origin=path, got {zone}"))?;
+                store.load_file(origin.trim().parse()?, path.trim())?;
+            }
+            let mut authoritative = AuthoritativeHandler::new(store);
+            if let Some(resolving) = handler {
+                authoritative = authoritative.with_fallback(resolving);
+            }
+            handler = Some(Arc::new(authoritative));
+        }
+
+        let view = View::new(name.as_str(), handler.context("view without a handler")?)
+            .with_clients(env_list(&var("CLIENTS"))?)
+            .with_keys(env_list(&var("KEYS"))?);
+        info!("view: {view:?}");
+        views.push(view);
+    }
+
+    // keys only select a view once their signature has been checked
+    let mut handler: Arc<dyn DnsHandler> = Arc::new(ViewHandler::new(views));
+    if let Ok(path) = std::env::var("DNS_TSIG_KEYS") {
+        handler = Arc::new(TsigHandler::new(
+            Arc::new(Keyring::from_file(path)?),
+            handler,
+        ));
+    }
+    Ok(handler)
+}
+
+// a single value from the environment, None if it isn't set
+fn env_var<T: FromStr>(name: &str) -> Result<Option<T>>
+where
+    T::Err: Into<anyhow::Error>,
+{
+    match std::env::var(name) {
+        Ok(value) => Ok(Some(
+            value
+                .trim()
+                .parse()
+                .map_err(Into::into)
+                .with_context(|| format!("bad value for {name}"))?,
+        )),
+        Err(_) => Ok(None),
+    }
+}
+
+// a comma separated list from the environment, empty if it isn't set
+fn env_list<T: FromStr>(name: &str) -> Result<Vec<T>>
+where
+    T::Err: Into<anyhow::Error>,
+{
+    match std::env::var(name) {
+        Ok(list) => list
+            .split(',')
+            .map(|item| item.trim().parse().map_err(Into::into))
+            .collect(),
+        Err(_) => Ok(Vec::new()),
+    }
+}
+
+// an access list of addresses and TSIG key names, separated by `separator`
+fn access_list(list: &str, separator: char) -> Result<(Vec<IpAddr>, Vec<Domain>)> {
+    let mut addresses = Vec::new();
+    let mut keys = Vec::new();
+    for item in list
+        .split(separator)
+        .map(str::trim)
+        .filter(|i| !i.is_empty())
+    {
+        match item.parse() {
+            Ok(address) => addresses.push(address),
+            Err(_) => keys.push(item.parse()?),
+        }
+    }
+    Ok((addresses, keys))
+}
+
+fn authoritative(
+    primary_zones: Option<String>,
+    secondary_zones: Option<String>,
+) -> Result<Arc<dyn DnsHandler>> {
+    // TSIG keys from a file of BIND key statements, and the one we sign our own transfers and
+    // NOTIFYs with
+    let keyring = match std::env::var("DNS_TSIG_KEYS") {
+        Ok(path) => Keyring::from_file(path)?,
+        Err(_) => Keyring::default(),
+    };
+    let key = match std::env::var("DNS_TSIG_KEY") {
+        Ok(name) => Some(
+            keyring
+                .get(&name.parse()?)
+                .with_context(|| format!("no TSIG key called {name}"))?,
+        ),
+        Err(_) => None,
+    };
+
+    let zones = Arc::new(ZoneStore::default());
+
+    // Zones to sign, as origin=keyfile;keyfile with BIND's K*.key/K*.private pairs. They're
+    // signed with NSEC unless DNS_NSEC3=1, and signatures are refreshed before they expire.
+    let nsec3 = std::env::var("DNS_NSEC3")
+        .is_ok_and(|v| v == "1")
+        .then(|| Nsec3Param {
+            hash_algorithm: 1,
+            flags: 0,
+            iterations: 0,
+            salt: Bytes::new(),
+        });
+    let zone_keys = std::env::var("DNS_ZONE_KEYS").unwrap_or_default();
+    for zone in zone_keys.split(',').filter(|z| !z.trim().is_empty()) {
+        let (origin, files) = zone
+            .split_once('=')
+            .with_context(|| format!("expected origin=keyfile, got {zone}"))?;
+        let keys = files
+            .split(';')
+            .map(|f| SigningKey::from_files(f.trim()))
+            .collect::<Result<_>>()?;
+        let signer = ZoneSigner::new(keys)?.with_config(SignerConfig {
+            nsec3: nsec3.clone(),
+            ..SignerConfig::default()
+        });
+        zones.set_signer(&origin.trim().parse()?, Arc::new(signer));
+    }
+    if !zone_keys.is_empty() {
+        zones.start_resigning(Duration::from_secs(3600));
+    }
+
+    let mut origins = Vec::new();
+    for zone in primary_zones.iter().flat_map(|z| z.split(',')) {
+        let (origin, path) = zone
+            .split_once('=')
+            .with_context(|| format!("expected origin=path, got {zone}"))?;
+        let origin: Domain = origin.trim().parse()?;
+        zones.load_file(origin.clone(), path.trim())?;
+        origins.push(origin);
+    }
+
+    // secondaries that get told about changes to any of our zones
+    let notify: Vec<SocketAddr> = env_list("DNS_NOTIFY")?;
+    if !notify.is_empty() {
+        let mut notifier = origins
+            .into_iter()
+            .fold(Notifier::new(zones.clone()), |n, origin| {
+                n.with_secondaries(origin, notify.clone())
+            });
+        if let Some(key) = &key {
+            notifier = notifier.with_key(key.clone());
+        }
+        Arc::new(notifier).start();
+    }
+
+    let mut secondaries = Vec::new();
+    for zone in secondary_zones.iter().flat_map(|z| z.split(',')) {
+        let (origin, primaries) = zone
+            .split_once('=')
+            .with_context(|| format!("expected origin=primary, got {zone}"))?;
+        secondaries.push(SecondaryZone {
+            origin: origin.trim().parse()?,
+            primaries: primaries
+                .split(';')
+                .map(|p| p.trim().parse())
+                .collect::<Result<_, _>>()?,
+            key: key.clone(),
+        });
+    }
+
+    // the addresses and keys allowed to transfer our zones
+    let (addresses, keys) = access_list(
+        &std::env::var("DNS_TRANSFER_ALLOWED").unwrap_or_default(),
+        ',',
+    )?;
+    let mut handler: Arc<dyn DnsHandler> = Arc::new(
+        AuthoritativeHandler::new(zones.clone())
+            .with_transfer_allowed(addresses)
+            .with_transfer_keys(keys),
+    );
+
+    // the addresses and keys allowed to update each zone, as origin=address;key
+    if let Ok(access) = std::env::var("DNS_UPDATE_ALLOWED") {
+        let mut update = UpdateHandler::new(zones.clone(), handler);
+        for zone in access.split(',') {
+            let (origin, allowed) = zone
+                .split_once('=')
+                .with_context(|| format!("expected origin=address, got {zone}"))?;
+            let origin: Domain = origin.trim().parse()?;
+            let (addresses, keys) = access_list(allowed, ';')?;
+            update = update
+                .with_zone_access(origin.clone(), addresses)
+                .with_zone_keys(origin, keys);
+        }
+        handler = Arc::new(update);
+    }
+
+    if !secondaries.is_empty() {
+        let secondary = Arc::new(Secondary::new(zones, secondaries));
+        secondary.start();
+        handler = Arc::new(NotifyHandler::new(secondary, handler));
+    }
 
-    Ok(())
+    // signatures are checked before anything else sees the request
+    if !keyring.is_empty() {
+        handler = Arc::new(TsigHandler::new(Arc::new(keyring), handler));
+    }
+    Ok(handler)
 }
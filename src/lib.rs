@@ -1,3 +1,16 @@
+pub mod acl;
+pub mod cache;
 pub mod dns;
+pub mod dnssec;
+pub mod filter;
+pub mod handler;
+pub mod hosts;
 pub mod initialization;
 pub mod parse;
+pub mod resolver;
+pub mod rrl;
+pub mod stats;
+pub mod throttle;
+pub mod tsig;
+pub mod view;
+pub mod zone;
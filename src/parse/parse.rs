@@ -17,7 +17,19 @@ pub trait DnsData: Sized {
     fn decode(buf: &Bytes, pos: usize, label_map: LabelMap) -> Result<(usize, Self)>;
 }
 
+// everything we decode comes off the network, so make sure we never slice past the end of the
+// buffer (Bytes::slice panics rather than returning an error)
+fn ensure_available(buf: &Bytes, pos: usize, len: usize) -> Result<()> {
+    ensure!(
+        pos.checked_add(len).is_some_and(|end| end <= buf.len()),
+        "unexpected end of buffer: wanted {len} bytes at offset {pos}, buffer is {} bytes",
+        buf.len()
+    );
+    Ok(())
+}
+
 pub fn parse_u8(buf: &Bytes, pos: usize) -> Result<(usize, u8)> {
+    ensure_available(buf, pos, 1)?;
     Ok((
         pos + 1,
         u8::from_be_bytes(buf.slice(pos..pos + 1).as_ref().try_into()?),
@@ -26,6 +38,7 @@ pub fn parse_u8(buf: &Bytes, pos: usize) -> Result<(usize, u8)> {
 
 #[instrument(skip_all, ret)]
 pub fn parse_u16(buf: &Bytes, pos: usize) -> Result<(usize, u16)> {
+    ensure_available(buf, pos, 2)?;
     Ok((
         pos + 2,
         u16::from_be_bytes(buf.slice(pos..pos + 2).as_ref().try_into()?),
@@ -33,18 +46,21 @@ pub fn parse_u16(buf: &Bytes, pos: usize) -> Result<(usize, u16)> {
 }
 
 pub fn parse_u32(buf: &Bytes, pos: usize) -> Result<(usize, u32)> {
+    ensure_available(buf, pos, 4)?;
     Ok((
         pos + 4,
         u32::from_be_bytes(buf.slice(pos..pos + 4).as_ref().try_into()?),
     ))
 }
 
+// strings in DNS (labels and <character-string>s) are a single length octet followed by that
+// many bytes
 #[instrument(skip_all, ret)]
 pub fn parse_string(buf: &Bytes, pos: usize) -> Result<(usize, String)> {
     // first we'll read the length of the string
-    let (current, length) = parse_u16(buf, pos)?;
+    let (current, length) = parse_u8(buf, pos)?;
     debug!("Parsing string of length {length}");
-    ensure!(length <= 0x3fff, "String too large");
+    ensure_available(buf, current, length as usize)?;
 
     Ok((
         current + length as usize,
@@ -53,5 +69,6 @@ pub fn parse_string(buf: &Bytes, pos: usize) -> Result<(usize, String)> {
 }
 
 pub fn parse_data(buf: &Bytes, pos: usize, len: usize) -> Result<(usize, Bytes)> {
+    ensure_available(buf, pos, len)?;
     Ok((pos + len, buf.slice(pos..pos + len).clone()))
 }
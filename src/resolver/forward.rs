@@ -0,0 +1,79 @@
+use crate::dns::{DnsMessage, ResponseCode};
+use crate::handler::{DnsHandler, DnsRequest};
+use crate::resolver::{Upstream, UpstreamClient, random_id};
+use anyhow::Result;
+use async_trait::async_trait;
+use rustls::ClientConfig;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{info, warn};
+
+// hands every request to an upstream resolver and relays whatever it says
+#[derive(Debug)]
+pub struct ForwardHandler {
+    upstreams: Vec<UpstreamClient>,
+    timeout: Duration,
+}
+
+impl ForwardHandler {
+    pub fn new(upstreams: Vec<Upstream>) -> Self {
+        Self {
+            upstreams: upstreams.into_iter().map(UpstreamClient::new).collect(),
+            timeout: Duration::from_secs(2),
+        }
+    }
+
+    // the CAs to trust for encrypted upstreams, rather than the public ones
+    pub fn with_tls_config(mut self, config: Arc<ClientConfig>) -> Self {
+        self.upstreams = self
+            .upstreams
+            .into_iter()
+            .map(|client| client.with_tls_config(config.clone()))
+            .collect();
+        self
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    // try each upstream in order, the first one to answer wins
+    pub async fn forward(&self, request: &DnsMessage) -> Result<DnsMessage> {
+        // use our own ID upstream, the client's ID is only meaningful between us and the client
+        let mut upstream_request = request.clone();
+        upstream_request.header.packet_id = random_id();
+
+        for upstream in &self.upstreams {
+            info!("forwarding DNS request to {}", upstream.upstream());
+            match upstream.exchange(&upstream_request, self.timeout).await {
+                Ok(mut reply) => {
+                    reply.header.packet_id = request.header.packet_id;
+                    return Ok(reply);
+                }
+                Err(e) => warn!("failed to forward to {}: {e:#}", upstream.upstream()),
+            }
+        }
+
+        Err(anyhow::Error::msg("no upstream responded"))
+    }
+}
+
+#[async_trait]
+impl DnsHandler for ForwardHandler {
+    async fn handle(&self, request: &DnsRequest) -> Result<Option<DnsMessage>> {
+        match self.forward(&request.message).await {
+            Ok(reply) => Ok(Some(reply)),
+            Err(e) => {
+                warn!("forwarding failed: {e:#}");
+                Ok(Some(
+                    request
+                        .message
+                        .clone()
+                        .as_reply()
+                        .with_response_code(ResponseCode::ServFail),
+                ))
+            }
+        }
+    }
+}
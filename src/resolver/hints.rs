@@ -0,0 +1,141 @@
+use crate::dns::Domain;
+use anyhow::{Context, Result, ensure};
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::Path;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NameServer {
+    pub name: Domain,
+    pub address: IpAddr,
+}
+
+// The servers we start every resolution from. Usually this is the root zone, but nothing stops
+// you from pointing it at some other hierarchy (the tests do exactly that).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RootHints {
+    pub servers: Vec<NameServer>,
+}
+
+// a.root-servers.net through m.root-servers.net
+const ROOT_SERVERS: [(&str, Ipv4Addr); 13] = [
+    ("a.root-servers.net.", Ipv4Addr::new(198, 41, 0, 4)),
+    ("b.root-servers.net.", Ipv4Addr::new(170, 247, 170, 2)),
+    ("c.root-servers.net.", Ipv4Addr::new(192, 33, 4, 12)),
+    ("d.root-servers.net.", Ipv4Addr::new(199, 7, 91, 13)),
+    ("e.root-servers.net.", Ipv4Addr::new(192, 203, 230, 10)),
+    ("f.root-servers.net.", Ipv4Addr::new(192, 5, 5, 241)),
+    ("g.root-servers.net.", Ipv4Addr::new(192, 112, 36, 4)),
+    ("h.root-servers.net.", Ipv4Addr::new(198, 97, 190, 53)),
+    ("i.root-servers.net.", Ipv4Addr::new(192, 36, 148, 17)),
+    ("j.root-servers.net.", Ipv4Addr::new(192, 58, 128, 30)),
+    ("k.root-servers.net.", Ipv4Addr::new(193, 0, 14, 129)),
+    ("l.root-servers.net.", Ipv4Addr::new(199, 7, 83, 42)),
+    ("m.root-servers.net.", Ipv4Addr::new(202, 12, 27, 33)),
+];
+
+impl Default for RootHints {
+    fn default() -> Self {
+        Self {
+            servers: ROOT_SERVERS
+                .iter()
+                .map(|(name, ip)| NameServer {
+                    // these are all valid names
+                    name: name.parse().unwrap(),
+                    address: IpAddr::V4(*ip),
+                })
+                .collect(),
+        }
+    }
+}
+
+impl RootHints {
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read root hints from {}", path.display()))?
+            .parse()
+    }
+}
+
+// Parses the named.root format that IANA publishes:
+//
+//  .                        3600000      NS    A.ROOT-SERVERS.NET.
+//  A.ROOT-SERVERS.NET.      3600000      A     198.41.0.4
+//
+// Only the address records matter to us, the NS records just tell us which names are servers.
+// Anything we can't use (AAAA records, for now) is skipped.
+impl FromStr for RootHints {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut servers = Vec::new();
+
+        for (num, line) in s.lines().enumerate() {
+            // strip comments
+            let line = line.split(';').next().unwrap_or_default();
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.is_empty() {
+                continue;
+            }
+
+            // the TTL and class are both optional
+            let rtype = fields
+                .iter()
+                .skip(1)
+                .find(|f| f.parse::<u32>().is_err() && !f.eq_ignore_ascii_case("IN"))
+                .with_context(|| format!("line {}: missing record type", num + 1))?;
+
+            if !rtype.eq_ignore_ascii_case("A") {
+                continue;
+            }
+
+            let address = fields
+                .last()
+                .and_then(|a| a.parse::<Ipv4Addr>().ok())
+                .with_context(|| format!("line {}: invalid address", num + 1))?;
+
+            servers.push(NameServer {
+                name: fields[0]
+                    .parse()
+                    .with_context(|| format!("line {}: invalid name", num + 1))?,
+                address: IpAddr::V4(address),
+            });
+        }
+
+        ensure!(!servers.is_empty(), "root hints don't contain any servers");
+        Ok(Self { servers })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_named_root() {
+        let hints: RootHints = r#"
+; This file holds the information on root name servers needed to
+; initialize cache of Internet domain name servers
+.                        3600000      NS    A.ROOT-SERVERS.NET.
+A.ROOT-SERVERS.NET.      3600000      A     198.41.0.4
+A.ROOT-SERVERS.NET.      3600000      AAAA  2001:503:ba3e::2:30
+.                        3600000      NS    B.ROOT-SERVERS.NET.
+B.ROOT-SERVERS.NET.      IN A     170.247.170.2
+"#
+        .parse()
+        .unwrap();
+
+        assert_eq!(hints.servers.len(), 2);
+        assert_eq!(hints.servers[0].name.to_string(), "A.ROOT-SERVERS.NET.");
+        assert_eq!(
+            hints.servers[1].address,
+            IpAddr::V4(Ipv4Addr::new(170, 247, 170, 2))
+        );
+    }
+
+    #[test]
+    fn empty_hints_are_rejected() {
+        assert!("; nothing here\n".parse::<RootHints>().is_err());
+    }
+}
@@ -0,0 +1,15 @@
+mod forward;
+mod hints;
+mod pipeline;
+mod query;
+mod recursive;
+mod stamp;
+mod upstream;
+
+pub use forward::*;
+pub use hints::*;
+pub use pipeline::*;
+pub use query::*;
+pub use recursive::*;
+pub use stamp::*;
+pub use upstream::*;
@@ -0,0 +1,155 @@
+use crate::dns::{DnsMessage, read_frame, write_frame};
+use crate::parse::DnsData;
+use anyhow::{Context, Result, ensure};
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicU16, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tracing::debug;
+
+type Waiting = Arc<Mutex<HashMap<u16, oneshot::Sender<DnsMessage>>>>;
+
+// Queries pipelined over a stream connection to an upstream server. Each one goes out as soon as
+// it's asked, without waiting for the ones before it to be answered, and the responses are
+// matched back up by ID in whatever order they come (RFC 7766 S6.2.1.1).
+pub struct Pipeline {
+    queries: mpsc::UnboundedSender<Bytes>,
+    waiting: Waiting,
+    closed: Arc<AtomicBool>,
+    next_id: AtomicU16,
+    reader: JoinHandle<()>,
+}
+
+impl Pipeline {
+    pub fn new<S>(stream: S) -> Self
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let (mut reader, mut writer) = tokio::io::split(stream);
+        let waiting: Waiting = Arc::new(Mutex::new(HashMap::new()));
+        let closed = Arc::new(AtomicBool::new(false));
+
+        // Queries are written by a task of their own, so one given up on part way through being
+        // written can't leave half a message on the connection
+        let (queries, mut outgoing) = mpsc::unbounded_channel::<Bytes>();
+        tokio::spawn({
+            let closed = closed.clone();
+            async move {
+                while let Some(query) = outgoing.recv().await {
+                    if let Err(e) = write_frame(&mut writer, &query).await {
+                        debug!("pipelined connection failed: {e:#}");
+                        break;
+                    }
+                }
+                closed.store(true, Ordering::Relaxed);
+            }
+        });
+
+        // responses go to whoever's waiting for their ID, until the connection goes and
+        // everyone still waiting is let down
+        let reader = tokio::spawn({
+            let (waiting, closed) = (waiting.clone(), closed.clone());
+            async move {
+                while let Ok(Some(raw)) = read_frame(&mut reader).await {
+                    let Ok((_, response)) = DnsMessage::decode(&raw, 0, &mut HashMap::new()) else {
+                        debug!("undecodable response on a pipelined connection");
+                        break;
+                    };
+                    let waiter = waiting.lock().unwrap().remove(&response.header.packet_id);
+                    if let Some(waiter) = waiter {
+                        let _ = waiter.send(response);
+                    }
+                }
+                let mut waiting = waiting.lock().unwrap();
+                closed.store(true, Ordering::Relaxed);
+                waiting.clear();
+            }
+        });
+
+        Self {
+            queries,
+            waiting,
+            closed,
+            next_id: AtomicU16::new(0),
+            reader,
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Relaxed)
+    }
+
+    // how many queries are waiting for their responses
+    pub fn in_flight(&self) -> usize {
+        self.waiting.lock().unwrap().len()
+    }
+
+    // Send a query and wait for its response. The query goes out with an ID that's unique on
+    // the connection, and the response comes back with the one it was asked with.
+    pub async fn exchange(&self, request: &DnsMessage) -> Result<DnsMessage> {
+        let (id, response) = {
+            let mut waiting = self.waiting.lock().unwrap();
+            ensure!(!self.is_closed(), "connection closed");
+            ensure!(
+                waiting.len() <= u16::MAX as usize,
+                "no IDs left on the connection"
+            );
+            let mut id = self.next_id.fetch_add(1, Ordering::Relaxed);
+            while waiting.contains_key(&id) {
+                id = self.next_id.fetch_add(1, Ordering::Relaxed);
+            }
+            let (sender, receiver) = oneshot::channel();
+            waiting.insert(id, sender);
+            (id, receiver)
+        };
+        let _given_up = GivenUp {
+            waiting: &self.waiting,
+            id,
+        };
+
+        let mut query = request.clone();
+        query.header.packet_id = id;
+        self.queries
+            .send(query.encode(0, &mut HashMap::new())?)
+            .ok()
+            .context("connection closed")?;
+
+        let mut response = response
+            .await
+            .context("connection closed before the response came")?;
+        response.header.packet_id = request.header.packet_id;
+        Ok(response)
+    }
+}
+
+impl fmt::Debug for Pipeline {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Pipeline")
+            .field("in_flight", &self.in_flight())
+            .field("closed", &self.is_closed())
+            .finish()
+    }
+}
+
+impl Drop for Pipeline {
+    fn drop(&mut self) {
+        // the writer stops by itself once there's nobody left to send it queries
+        self.reader.abort();
+    }
+}
+
+// stops waiting for a response, if it hasn't already come, when the query is given up on
+struct GivenUp<'a> {
+    waiting: &'a Waiting,
+    id: u16,
+}
+
+impl Drop for GivenUp<'_> {
+    fn drop(&mut self) {
+        self.waiting.lock().unwrap().remove(&self.id);
+    }
+}
@@ -0,0 +1,141 @@
+use crate::dns::{
+    DnsMessage, DnsPacketType, DnsQuestion, MAX_UDP_MESSAGE_SIZE, read_frame, write_frame,
+};
+use crate::parse::DnsData;
+use anyhow::{Context, Result, ensure};
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::BuildHasher;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::time::{Instant, timeout_at};
+use tracing::debug;
+
+// Query IDs are one of the few things standing between us and a spoofed response, so they
+// shouldn't be guessable. RandomState is seeded randomly per instance, which is plenty here.
+pub fn random_id() -> u16 {
+    RandomState::new().hash_one(std::time::SystemTime::now()) as u16
+}
+
+// send a single non-recursive question to a name server over UDP and wait for its reply
+pub async fn query_nameserver(
+    server: SocketAddr,
+    question: &DnsQuestion,
+    wait: Duration,
+) -> Result<DnsMessage> {
+    exchange(
+        server,
+        &DnsMessage::query(random_id(), question.clone()),
+        wait,
+    )
+    .await
+}
+
+// Send a request to a server over UDP and wait for the matching response. A truncated one means
+// the answer didn't fit, so we ask again over TCP.
+pub async fn exchange(
+    server: SocketAddr,
+    request: &DnsMessage,
+    wait: Duration,
+) -> Result<DnsMessage> {
+    Ok(exchange_raw(server, request, wait).await?.1)
+}
+
+// exchange, also giving back the response as it came off the wire, e.g. to check its TSIG
+pub async fn exchange_raw(
+    server: SocketAddr,
+    request: &DnsMessage,
+    wait: Duration,
+) -> Result<(Bytes, DnsMessage)> {
+    let question = request
+        .question()
+        .ok_or(anyhow::Error::msg("request has no question"))?;
+    let query = request.encode(0, &mut HashMap::new())?;
+    let deadline = Instant::now() + wait;
+
+    debug!("asking {server} for {} {:?}", question.name, question.qtype);
+    let (raw, response) = timeout_at(deadline, exchange_udp(server, request, &query))
+        .await
+        .with_context(|| format!("timed out waiting for {server}"))??;
+    if !response.header.truncation {
+        return Ok((raw, response));
+    }
+
+    // the whole answer only fits over a stream (RFC 7766 S5)
+    debug!("{server} truncated its response, asking again over TCP");
+    timeout_at(deadline, exchange_tcp(server, request, &query))
+        .await
+        .with_context(|| format!("timed out waiting for {server} over TCP"))?
+}
+
+async fn exchange_udp(
+    server: SocketAddr,
+    request: &DnsMessage,
+    query: &[u8],
+) -> Result<(Bytes, DnsMessage)> {
+    let bind_addr = match server {
+        SocketAddr::V4(_) => "0.0.0.0:0",
+        SocketAddr::V6(_) => "[::]:0",
+    };
+    let sock = UdpSocket::bind(bind_addr).await?;
+    sock.connect(server).await?;
+    sock.send(query).await?;
+
+    // Anything that isn't the answer to our query, like a spoofed response with a guessed ID,
+    // is thrown away and we keep waiting for the real one until the timeout
+    let mut buf = [0; MAX_UDP_MESSAGE_SIZE];
+    loop {
+        let len = sock.recv(&mut buf).await?;
+        let raw = Bytes::copy_from_slice(&buf[..len]);
+        let response = DnsMessage::decode(&raw, 0, &mut HashMap::new())
+            .and_then(|(_, response)| check_response(server, request, &response).map(|_| response));
+        match response {
+            Ok(response) => return Ok((raw, response)),
+            Err(e) => debug!("ignoring a response from {server}: {e:#}"),
+        }
+    }
+}
+
+async fn exchange_tcp(
+    server: SocketAddr,
+    request: &DnsMessage,
+    query: &[u8],
+) -> Result<(Bytes, DnsMessage)> {
+    let mut stream = TcpStream::connect(server).await?;
+    write_frame(&mut stream, query).await?;
+    let raw = read_frame(&mut stream)
+        .await?
+        .with_context(|| format!("{server} closed the connection without answering"))?;
+    let (_, response) = DnsMessage::decode(&raw, 0, &mut HashMap::new())?;
+    check_response(server, request, &response)?;
+    Ok((raw, response))
+}
+
+// make sure a response from a server is actually the answer to what we asked it
+pub fn check_response(
+    server: impl fmt::Display,
+    request: &DnsMessage,
+    response: &DnsMessage,
+) -> Result<()> {
+    let question = request
+        .question()
+        .ok_or(anyhow::Error::msg("request has no question"))?;
+    ensure!(
+        response.header.packet_id == request.header.packet_id,
+        "response from {server} has the wrong ID"
+    );
+    ensure!(
+        response.header.query_type == DnsPacketType::Response,
+        "{server} sent us a query instead of a response"
+    );
+    ensure!(
+        response
+            .question()
+            .is_some_and(|q| q.name.eq_ignore_case(&question.name) && q.qtype == question.qtype),
+        "response from {server} is for a different question"
+    );
+    Ok(())
+}
@@ -0,0 +1,534 @@
+use crate::dns::{
+    DnsAnswer, DnsAnswerSet, DnsMessage, DnsQuestion, Domain, Edns, MAX_UDP_MESSAGE_SIZE,
+    QuestionType, RecordData, ResponseCode,
+};
+use crate::dnssec::{Security, TrustAnchor, Validator};
+use crate::handler::{DnsHandler, DnsRequest};
+use crate::resolver::{RootHints, exchange, query_nameserver, random_id};
+use anyhow::{Result, bail, ensure};
+use async_trait::async_trait;
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{debug, info, instrument, warn};
+
+#[derive(Debug, Clone)]
+pub struct ResolverConfig {
+    pub root_hints: RootHints,
+
+    // the port every name server is contacted on, only ever not 53 in tests
+    pub port: u16,
+
+    // how many times one resolution can start another, i.e. following a CNAME or looking up the
+    // address of a name server that came without glue
+    pub max_depth: usize,
+
+    // the total number of queries we're willing to send upstream for a single client query
+    pub max_queries: usize,
+
+    // how long to wait for any one name server
+    pub timeout: Duration,
+
+    // where DNSSEC validation starts, no anchor means no validation
+    pub trust_anchor: Option<TrustAnchor>,
+}
+
+impl Default for ResolverConfig {
+    fn default() -> Self {
+        Self {
+            root_hints: RootHints::default(),
+            port: 53,
+            max_depth: 8,
+            max_queries: 64,
+            timeout: Duration::from_secs(2),
+            trust_anchor: None,
+        }
+    }
+}
+
+type ResolveFuture<'a> = Pin<Box<dyn Future<Output = Result<DnsMessage>> + Send + 'a>>;
+
+// An iterative resolver. It starts at the root hints and walks down the tree, following the
+// referrals in each response until it reaches a server that can answer.
+#[derive(Debug)]
+pub struct Resolver {
+    config: ResolverConfig,
+    validator: Option<Validator>,
+}
+
+impl Resolver {
+    pub fn new(config: ResolverConfig) -> Self {
+        let validator = config.trust_anchor.clone().map(Validator::new);
+        Self { config, validator }
+    }
+
+    pub fn validating(&self) -> bool {
+        self.validator.is_some()
+    }
+
+    // Check the DNSSEC signatures on a response from `resolve`. Without a trust anchor there is
+    // nothing to check against, so everything is insecure.
+    pub async fn validate(&self, question: &DnsQuestion, response: &DnsMessage) -> Security {
+        match &self.validator {
+            Some(validator) => validator.validate(self, question, response).await,
+            None => Security::Insecure,
+        }
+    }
+
+    // Resolve a question. The returned message is the final upstream response, except that the
+    // answer section also contains any CNAMEs we followed along the way.
+    #[instrument(name = "Resolving", skip_all, fields(name = %question.name, qtype = ?question.qtype))]
+    pub async fn resolve(&self, question: &DnsQuestion) -> Result<DnsMessage> {
+        let mut budget = self.config.max_queries;
+        self.resolve_at_depth(question.clone(), 0, &mut budget)
+            .await
+    }
+
+    fn resolve_at_depth<'a>(
+        &'a self,
+        question: DnsQuestion,
+        depth: usize,
+        budget: &'a mut usize,
+    ) -> ResolveFuture<'a> {
+        Box::pin(async move {
+            ensure!(
+                depth <= self.config.max_depth,
+                "gave up resolving {}: too many levels of indirection",
+                question.name
+            );
+
+            // start at the top
+            let mut zone = Domain::root();
+            let mut servers: Vec<IpAddr> = self
+                .config
+                .root_hints
+                .servers
+                .iter()
+                .map(|s| s.address)
+                .collect();
+
+            loop {
+                let response = self.query_any(&servers, &question, budget).await?;
+                let response = scrub(&question, &zone, response)?;
+
+                // the name doesn't exist, nothing more to do
+                if response.response_code() == ResponseCode::NxDomain as u8 {
+                    return Ok(response);
+                }
+
+                // we got an answer, or at least part of one
+                if let Some(chain) = self.follow_answers(&question, &response)? {
+                    return match chain {
+                        Answer::Complete => Ok(response),
+                        Answer::Alias { records, target } => {
+                            debug!("following CNAME {} -> {target}", question.name);
+                            let target_question = DnsQuestion {
+                                name: target,
+                                qtype: question.qtype.clone(),
+                                class: question.class,
+                            };
+                            let mut rest = self
+                                .resolve_at_depth(target_question, depth + 1, budget)
+                                .await?;
+
+                            let mut answers = records;
+                            answers.append(&mut rest.answers.answers);
+                            rest.with_answers(DnsAnswerSet { answers })
+                        }
+                    };
+                }
+
+                // no answer, hopefully a referral to servers closer to the name
+                let Some((child, nameservers)) = referral(&question.name, &zone, &response) else {
+                    // no answer and no referral means the name exists but has no records of the
+                    // type we asked for
+                    return Ok(response);
+                };
+
+                // The DS records for a zone live in its parent, so a referral to the zone itself
+                // is the parent's answer. It carries the proof there are none, if there aren't.
+                if question.qtype == QuestionType::DS && child.eq_ignore_case(&question.name) {
+                    return Ok(response);
+                }
+
+                debug!(
+                    "referred to {child} with {} name servers",
+                    nameservers.len()
+                );
+                servers = glue(&zone, &nameservers, &response);
+
+                // nobody gave us addresses for the name servers, so we have to go find them
+                // ourselves
+                if servers.is_empty() {
+                    servers = self
+                        .resolve_nameservers(&nameservers, depth, budget)
+                        .await?;
+                }
+
+                zone = child;
+            }
+        })
+    }
+
+    // Look up the address of each name server in turn, stopping once we have some. A name server
+    // with no IPv4 address may still have an IPv6 one.
+    async fn resolve_nameservers(
+        &self,
+        nameservers: &[Domain],
+        depth: usize,
+        budget: &mut usize,
+    ) -> Result<Vec<IpAddr>> {
+        for ns in nameservers {
+            for qtype in [QuestionType::A, QuestionType::AAAA] {
+                let question = DnsQuestion::new(ns.clone(), qtype);
+                let response = match self.resolve_at_depth(question, depth + 1, budget).await {
+                    Ok(response) => response,
+                    Err(e) => {
+                        warn!("failed to resolve name server {ns}: {e:#}");
+                        continue;
+                    }
+                };
+
+                let addresses = addresses(&response.answers.answers);
+                if !addresses.is_empty() {
+                    return Ok(addresses);
+                }
+            }
+        }
+
+        bail!("couldn't find an address for any of {nameservers:?}")
+    }
+
+    // ask each server in turn until one of them gives us a usable response
+    async fn query_any(
+        &self,
+        servers: &[IpAddr],
+        question: &DnsQuestion,
+        budget: &mut usize,
+    ) -> Result<DnsMessage> {
+        for ip in servers {
+            ensure!(
+                *budget > 0,
+                "query budget exhausted resolving {}",
+                question.name
+            );
+            *budget -= 1;
+
+            let server = SocketAddr::new(*ip, self.config.port);
+            match self.query_server(server, question).await {
+                Ok(response)
+                    if response.response_code() == ResponseCode::NoError as u8
+                        || response.response_code() == ResponseCode::NxDomain as u8 =>
+                {
+                    return Ok(response);
+                }
+                Ok(response) => {
+                    warn!("{server} responded with rcode {}", response.response_code())
+                }
+                Err(e) => warn!("failed to query {server}: {e:#}"),
+            }
+        }
+
+        bail!("no name server responded for {}", question.name)
+    }
+
+    // when validating we need the signatures, which only come if we set DO (RFC 4035 S3.2.1)
+    async fn query_server(&self, server: SocketAddr, question: &DnsQuestion) -> Result<DnsMessage> {
+        if self.validator.is_none() {
+            return query_nameserver(server, question, self.config.timeout).await;
+        }
+
+        let request = DnsMessage::query(random_id(), question.clone()).with_edns(Edns {
+            udp_payload_size: MAX_UDP_MESSAGE_SIZE as u16,
+            dnssec_ok: true,
+            ..Edns::default()
+        })?;
+        exchange(server, &request, self.config.timeout).await
+    }
+
+    // Work out whether the answer section answers our question. A server will often include the
+    // records for the CNAME target if it has them, so we follow the chain as far as the response
+    // takes us before deciding whether we need to go elsewhere.
+    fn follow_answers(
+        &self,
+        question: &DnsQuestion,
+        response: &DnsMessage,
+    ) -> Result<Option<Answer>> {
+        let answers = &response.answers.answers;
+        let mut name = question.name.clone();
+        let mut records = Vec::new();
+
+        for _ in 0..=answers.len() {
+            let owned: Vec<&DnsAnswer> = answers
+                .iter()
+                .filter(|a| a.name.eq_ignore_case(&name))
+                .collect();
+
+            if owned.iter().any(|a| a.qtype == question.qtype) {
+                return Ok(Some(Answer::Complete));
+            }
+
+            let Some(cname) = owned.iter().find(|a| a.qtype == QuestionType::CNAME) else {
+                break;
+            };
+
+            // keep the signatures over the CNAME, the validator needs them
+            records.push((*cname).clone());
+            records.extend(
+                owned
+                    .iter()
+                    .filter(|a| covers(a, &QuestionType::CNAME))
+                    .map(|a| (*a).clone()),
+            );
+            name = match cname.rdata()? {
+                RecordData::CNAME(target) => target,
+                _ => bail!("CNAME record without a target"),
+            };
+        }
+
+        if records.is_empty() {
+            return Ok(None);
+        }
+
+        ensure!(
+            records.len() <= answers.len(),
+            "CNAME loop while resolving {}",
+            question.name
+        );
+
+        Ok(Some(Answer::Alias {
+            records,
+            target: name,
+        }))
+    }
+}
+
+enum Answer {
+    // the response answers the question (possibly via CNAMEs it also contains)
+    Complete,
+
+    // the response only got us part of the way, we still need to resolve `target`
+    Alias {
+        records: Vec<DnsAnswer>,
+        target: Domain,
+    },
+}
+
+// Throw away whatever a server had no business telling us, before it can get anywhere near a
+// client or a cache. Answers have to be on the chain of CNAMEs from the name we asked about and
+// inside the zone we asked the server about. The authority section can only have the zone's
+// NS and SOA records at or above a name on the chain, and records inside the zone that sign or
+// prove things (RFC 2181 S5.4.1, and what resolvers call the bailiwick rule).
+fn scrub(question: &DnsQuestion, zone: &Domain, response: DnsMessage) -> Result<DnsMessage> {
+    let answers = &response.answers.answers;
+    let in_zone = |a: &&DnsAnswer| a.name.is_subdomain_of(zone);
+
+    let mut chain = vec![question.name.clone()];
+    for _ in 0..answers.len() {
+        let last = &chain[chain.len() - 1];
+        let Some(target) = answers
+            .iter()
+            .filter(in_zone)
+            .filter(|a| a.qtype == QuestionType::CNAME && a.name.eq_ignore_case(last))
+            .find_map(|a| match a.rdata() {
+                Ok(RecordData::CNAME(target)) => Some(target),
+                _ => None,
+            })
+        else {
+            break;
+        };
+        if chain.iter().any(|name| name.eq_ignore_case(&target)) {
+            break;
+        }
+        chain.push(target);
+    }
+    let on_chain = |name: &Domain| chain.iter().any(|c| c.eq_ignore_case(name));
+    let above_chain = |name: &Domain| chain.iter().any(|c| c.is_subdomain_of(name));
+
+    let answers: Vec<DnsAnswer> = answers
+        .iter()
+        .filter(in_zone)
+        .filter(|a| on_chain(&a.name))
+        .filter(|a| {
+            a.qtype == question.qtype
+                || question.qtype == QuestionType::ANY
+                || matches!(a.qtype, QuestionType::CNAME | QuestionType::RRSIG)
+        })
+        .cloned()
+        .collect();
+    let authority: Vec<DnsAnswer> = response
+        .authority
+        .answers
+        .iter()
+        .filter(in_zone)
+        .filter(|a| match a.qtype {
+            QuestionType::NS | QuestionType::SOA => above_chain(&a.name),
+            QuestionType::DS | QuestionType::NSEC | QuestionType::NSEC3 | QuestionType::RRSIG => {
+                true
+            }
+            _ => false,
+        })
+        .cloned()
+        .collect();
+
+    let before = response.answers.answers.len() + response.authority.answers.len();
+    let dropped = before - answers.len() - authority.len();
+    if dropped > 0 {
+        debug!(
+            "dropped {dropped} out of bailiwick records answering {}",
+            question.name
+        );
+    }
+    response
+        .with_answers(DnsAnswerSet { answers })?
+        .with_authority(DnsAnswerSet { answers: authority })
+}
+
+// If the response delegates to a zone that is closer to `name` than the one we asked, return
+// that zone and its name servers. Anything pointing sideways or back up the tree is ignored, that
+// way a broken server can't send us around in circles.
+fn referral(name: &Domain, zone: &Domain, response: &DnsMessage) -> Option<(Domain, Vec<Domain>)> {
+    let child = response
+        .authority
+        .answers
+        .iter()
+        .find(|a| a.qtype == QuestionType::NS)?
+        .name
+        .clone();
+
+    if !name.is_subdomain_of(&child)
+        || !child.is_subdomain_of(zone)
+        || child.labels.len() <= zone.labels.len()
+    {
+        return None;
+    }
+
+    let nameservers: Vec<Domain> = response
+        .authority
+        .answers
+        .iter()
+        .filter(|a| a.qtype == QuestionType::NS && a.name.eq_ignore_case(&child))
+        .filter_map(|a| match a.rdata() {
+            Ok(RecordData::NS(ns)) => Some(ns),
+            _ => None,
+        })
+        .collect();
+
+    (!nameservers.is_empty()).then_some((child, nameservers))
+}
+
+// true for an RRSIG over records of `qtype`
+fn covers(record: &DnsAnswer, qtype: &QuestionType) -> bool {
+    match record.rdata() {
+        Ok(RecordData::RRSIG(rrsig)) => rrsig.type_covered == qtype.code(),
+        _ => false,
+    }
+}
+
+// the IPv4 and IPv6 addresses among some records
+fn addresses<'a>(records: impl IntoIterator<Item = &'a DnsAnswer>) -> Vec<IpAddr> {
+    records
+        .into_iter()
+        .filter_map(|a| match a.rdata() {
+            Ok(RecordData::A(ip)) => Some(IpAddr::V4(ip)),
+            Ok(RecordData::AAAA(ip)) => Some(IpAddr::V6(ip)),
+            _ => None,
+        })
+        .collect()
+}
+
+// Addresses from the additional section for the name servers we were referred to. We only trust
+// glue for names inside the zone that sent it, anything else could be an attempt to poison us.
+fn glue(zone: &Domain, nameservers: &[Domain], response: &DnsMessage) -> Vec<IpAddr> {
+    addresses(
+        response
+            .additional
+            .answers
+            .iter()
+            .filter(|a| a.name.is_subdomain_of(zone))
+            .filter(|a| nameservers.iter().any(|ns| ns.eq_ignore_case(&a.name))),
+    )
+}
+
+// answers client queries by doing the full resolution ourselves
+#[derive(Debug)]
+pub struct RecursiveHandler {
+    resolver: Arc<Resolver>,
+}
+
+impl RecursiveHandler {
+    pub fn new(resolver: Arc<Resolver>) -> Self {
+        Self { resolver }
+    }
+}
+
+#[async_trait]
+impl DnsHandler for RecursiveHandler {
+    async fn handle(&self, request: &DnsRequest) -> Result<Option<DnsMessage>> {
+        let mut reply = request.message.clone().as_reply();
+        reply.header.recursion_available = true;
+
+        let Some(question) = request.message.question() else {
+            return Ok(Some(reply.with_response_code(ResponseCode::FormErr)));
+        };
+
+        match self.resolver.resolve(question).await {
+            Ok(response) => {
+                info!(
+                    "resolved {} with rcode {}",
+                    question.name,
+                    response.response_code()
+                );
+
+                // AD is ours to set, not something to echo back (RFC 6840 S5.8)
+                let edns = request.message.edns();
+                let dnssec_ok = edns.as_ref().is_some_and(|e| e.dnssec_ok);
+                let wants_ad = dnssec_ok || request.message.header.authentic_data();
+                reply.header.set_authentic_data(false);
+
+                // with CD set the client does its own validation (RFC 4035 S3.2.2)
+                if !request.message.header.checking_disabled() {
+                    match self.resolver.validate(question, &response).await {
+                        Security::Secure => reply.header.set_authentic_data(wants_ad),
+                        Security::Insecure => {}
+                        Security::Bogus(bogus) => {
+                            warn!("{} failed validation: {}", question.name, bogus.reason);
+                            let reply = reply.with_response_code(ResponseCode::ServFail);
+                            return Ok(Some(match edns {
+                                Some(_) => reply.with_extended_error(bogus.code, &bogus.reason)?,
+                                None => reply,
+                            }));
+                        }
+                    }
+                }
+
+                reply.header.response_code = response.header.response_code;
+                let (mut answers, mut authority) = (response.answers, response.authority);
+                if !dnssec_ok {
+                    strip_dnssec(&mut answers, &question.qtype);
+                    strip_dnssec(&mut authority, &question.qtype);
+                }
+                Ok(Some(
+                    reply.with_answers(answers)?.with_authority(authority)?,
+                ))
+            }
+            Err(e) => {
+                warn!("failed to resolve {}: {e:#}", question.name);
+                Ok(Some(reply.with_response_code(ResponseCode::ServFail)))
+            }
+        }
+    }
+}
+
+// Clients that didn't set DO don't get DNSSEC records they didn't ask for (RFC 4035 S3.2.1).
+fn strip_dnssec(records: &mut DnsAnswerSet, qtype: &QuestionType) {
+    records.answers.retain(|a| {
+        a.qtype == *qtype
+            || !matches!(
+                a.qtype,
+                QuestionType::RRSIG | QuestionType::NSEC | QuestionType::NSEC3
+            )
+    });
+}
@@ -0,0 +1,325 @@
+use anyhow::{Context, Result, bail, ensure};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use std::str::FromStr;
+
+// what a server says about itself in its stamp's properties
+pub const STAMP_DNSSEC: u64 = 1 << 0;
+pub const STAMP_NO_LOGS: u64 = 1 << 1;
+pub const STAMP_NO_FILTER: u64 = 1 << 2;
+
+// the protocol identifier a stamp starts with
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[repr(u8)]
+pub enum StampProtocol {
+    #[default]
+    Plain = 0x00,
+    DnsCrypt = 0x01,
+    DoH = 0x02,
+    DoT = 0x03,
+    DoQ = 0x04,
+    ODoHTarget = 0x05,
+    DnsCryptRelay = 0x81,
+    ODoHRelay = 0x85,
+}
+
+impl TryFrom<u8> for StampProtocol {
+    type Error = anyhow::Error;
+
+    fn try_from(value: u8) -> Result<Self> {
+        Ok(match value {
+            0x00 => Self::Plain,
+            0x01 => Self::DnsCrypt,
+            0x02 => Self::DoH,
+            0x03 => Self::DoT,
+            0x04 => Self::DoQ,
+            0x05 => Self::ODoHTarget,
+            0x81 => Self::DnsCryptRelay,
+            0x85 => Self::ODoHRelay,
+            other => bail!("unknown stamp protocol {other:#04x}"),
+        })
+    }
+}
+
+// A DNS stamp, everything needed to reach a server packed into an sdns:// URL, as the public
+// resolver lists publish them (https://dnscrypt.info/stamps-specifications). Which fields are
+// used depends on the protocol. The strings are kept as they were, so a stamp encodes back to
+// exactly what it was parsed from.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Stamp {
+    pub protocol: StampProtocol,
+    pub props: u64,
+
+    // the server's IP address, with or without a port, or just a port as in ":443", or nothing
+    pub address: String,
+
+    // DNSCrypt's provider public key and name
+    pub public_key: Vec<u8>,
+    pub provider_name: String,
+
+    // SHA256 hashes of certificates in the server's chain
+    pub hashes: Vec<Vec<u8>>,
+
+    // the host name, with an optional port, and path for DoH
+    pub hostname: String,
+    pub path: String,
+
+    // resolvers to look up the host name with
+    pub bootstrap: Vec<String>,
+}
+
+impl FromStr for Stamp {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let encoded = s
+            .strip_prefix("sdns://")
+            .with_context(|| format!("{s} isn't a DNS stamp"))?;
+        let bytes = URL_SAFE_NO_PAD.decode(encoded.trim_end_matches('='))?;
+        let mut reader = Reader { bytes: &bytes };
+
+        let mut stamp = Stamp {
+            protocol: reader.byte()?.try_into()?,
+            ..Stamp::default()
+        };
+        if stamp.protocol != StampProtocol::DnsCryptRelay {
+            stamp.props = u64::from_le_bytes(reader.take(8)?.try_into()?);
+        }
+        match stamp.protocol {
+            StampProtocol::Plain | StampProtocol::DnsCryptRelay => {
+                stamp.address = reader.string()?;
+            }
+            StampProtocol::DnsCrypt => {
+                stamp.address = reader.string()?;
+                stamp.public_key = reader.lp()?.to_vec();
+                stamp.provider_name = reader.string()?;
+            }
+            StampProtocol::DoH | StampProtocol::DoT | StampProtocol::DoQ => {
+                stamp.address = reader.string()?;
+                stamp.hashes = reader.vlp()?;
+                stamp.hostname = reader.string()?;
+                if stamp.protocol == StampProtocol::DoH {
+                    stamp.path = reader.string()?;
+                }
+                if !reader.bytes.is_empty() {
+                    stamp.bootstrap = strings(reader.vlp()?)?;
+                }
+            }
+            StampProtocol::ODoHTarget => {
+                stamp.hostname = reader.string()?;
+                stamp.path = reader.string()?;
+            }
+            StampProtocol::ODoHRelay => {
+                stamp.address = reader.string()?;
+                stamp.hashes = reader.vlp()?;
+                stamp.hostname = reader.string()?;
+                stamp.path = reader.string()?;
+                if !reader.bytes.is_empty() {
+                    stamp.bootstrap = strings(reader.vlp()?)?;
+                }
+            }
+        }
+        ensure!(reader.bytes.is_empty(), "trailing bytes in stamp");
+        Ok(stamp)
+    }
+}
+
+impl Stamp {
+    // The sdns:// URL for the stamp. Fields longer than their length prefix can say make it an
+    // error, rather than a stamp for some other server.
+    pub fn encode(&self) -> Result<String> {
+        let mut bytes = vec![self.protocol as u8];
+        if self.protocol != StampProtocol::DnsCryptRelay {
+            bytes.extend_from_slice(&self.props.to_le_bytes());
+        }
+        let bootstrap: Vec<&[u8]> = self.bootstrap.iter().map(|b| b.as_bytes()).collect();
+        let hashes: Vec<&[u8]> = self.hashes.iter().map(Vec::as_slice).collect();
+        match self.protocol {
+            StampProtocol::Plain | StampProtocol::DnsCryptRelay => {
+                lp(&mut bytes, self.address.as_bytes())?;
+            }
+            StampProtocol::DnsCrypt => {
+                lp(&mut bytes, self.address.as_bytes())?;
+                lp(&mut bytes, &self.public_key)?;
+                lp(&mut bytes, self.provider_name.as_bytes())?;
+            }
+            StampProtocol::DoH | StampProtocol::DoT | StampProtocol::DoQ => {
+                lp(&mut bytes, self.address.as_bytes())?;
+                vlp(&mut bytes, &hashes)?;
+                lp(&mut bytes, self.hostname.as_bytes())?;
+                if self.protocol == StampProtocol::DoH {
+                    lp(&mut bytes, self.path.as_bytes())?;
+                }
+                if !bootstrap.is_empty() {
+                    vlp(&mut bytes, &bootstrap)?;
+                }
+            }
+            StampProtocol::ODoHTarget => {
+                lp(&mut bytes, self.hostname.as_bytes())?;
+                lp(&mut bytes, self.path.as_bytes())?;
+            }
+            StampProtocol::ODoHRelay => {
+                lp(&mut bytes, self.address.as_bytes())?;
+                vlp(&mut bytes, &hashes)?;
+                lp(&mut bytes, self.hostname.as_bytes())?;
+                lp(&mut bytes, self.path.as_bytes())?;
+                if !bootstrap.is_empty() {
+                    vlp(&mut bytes, &bootstrap)?;
+                }
+            }
+        }
+        Ok(format!("sdns://{}", URL_SAFE_NO_PAD.encode(bytes)))
+    }
+}
+
+// a length prefixed field, which can't be longer than a byte can say
+fn lp(out: &mut Vec<u8>, field: &[u8]) -> Result<()> {
+    ensure!(field.len() <= 0xff, "stamp field is longer than 255 bytes");
+    out.push(field.len() as u8);
+    out.extend_from_slice(field);
+    Ok(())
+}
+
+// A set of fields, each prefixed with its length with the top bit set on all but the last. An
+// empty set is a single zero.
+fn vlp(out: &mut Vec<u8>, fields: &[&[u8]]) -> Result<()> {
+    if fields.is_empty() {
+        out.push(0);
+    }
+    for (i, field) in fields.iter().enumerate() {
+        ensure!(field.len() <= 0x7f, "stamp field is longer than 127 bytes");
+        let more = if i + 1 < fields.len() { 0x80 } else { 0 };
+        out.push(field.len() as u8 | more);
+        out.extend_from_slice(field);
+    }
+    Ok(())
+}
+
+fn strings(fields: Vec<Vec<u8>>) -> Result<Vec<String>> {
+    fields
+        .into_iter()
+        .map(|field| String::from_utf8(field).context("stamp field isn't UTF-8"))
+        .collect()
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        ensure!(self.bytes.len() >= len, "stamp is cut short");
+        let (field, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(field)
+    }
+
+    fn byte(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn lp(&mut self) -> Result<&'a [u8]> {
+        let len = self.byte()? as usize;
+        self.take(len)
+    }
+
+    fn string(&mut self) -> Result<String> {
+        String::from_utf8(self.lp()?.to_vec()).context("stamp field isn't UTF-8")
+    }
+
+    fn vlp(&mut self) -> Result<Vec<Vec<u8>>> {
+        let mut fields = Vec::new();
+        loop {
+            let len = self.byte()?;
+            let field = self.take((len & 0x7f) as usize)?;
+            if !field.is_empty() {
+                fields.push(field.to_vec());
+            }
+            if len & 0x80 == 0 {
+                return Ok(fields);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resolver::Upstream;
+
+    #[test]
+    fn stamps_round_trip() {
+        let stamps = [
+            // well known public resolvers over each protocol, and a relay
+            "sdns://AgcAAAAAAAAABzEuMC4wLjEAEmRucy5jbG91ZGZsYXJlLmNvbQovZG5zLXF1ZXJ5",
+            "sdns://AwcAAAAAAAAABzEuMS4xLjEAD29uZS5vbmUub25lLm9uZQ",
+            "sdns://AAcAAAAAAAAABzkuOS45Ljk",
+            "sdns://BAcAAAAAAAAAAAAXZG5zLmFkZ3VhcmQtZG5zLmNvbTo3ODQ",
+            "sdns://gQ0xOTIuMC4yLjE6NDQz",
+            // DNSCrypt, DoH with pinned hashes and bootstrap resolvers, and ODoH
+            "sdns://AQEAAAAAAAAAE1syMDAxOmRiODo6NTNdOjg0NDMgAAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8bMi5kbnNjcnlwdC1jZXJ0LmV4YW1wbGUuY29t",
+            "sdns://AgIAAAAAAAAAAKCqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqiC7u7u7u7u7u7u7u7u7u7u7u7u7u7u7u7u7u7u7u7u7uxRkb2guZXhhbXBsZS5jb206ODQ0MwovZG5zLXF1ZXJ5ijE5Mi4wLjIuNTMOWzIwMDE6ZGI4Ojo1M10",
+            "sdns://BQMAAAAAAAAAEG9kb2guZXhhbXBsZS5jb20KL2Rucy1xdWVyeQ",
+            "sdns://hQAAAAAAAAAACTE5Mi4wLjIuMQARcmVsYXkuZXhhbXBsZS5jb20GL3Byb3h5",
+        ];
+        for s in stamps {
+            let stamp: Stamp = s.parse().expect(s);
+            assert_eq!(stamp.encode().unwrap(), s);
+        }
+
+        let doh: Stamp = stamps[0].parse().unwrap();
+        assert_eq!(doh.protocol, StampProtocol::DoH);
+        assert_eq!(doh.props, STAMP_DNSSEC | STAMP_NO_LOGS | STAMP_NO_FILTER);
+        assert_eq!(doh.address, "1.0.0.1");
+        assert_eq!(doh.hostname, "dns.cloudflare.com");
+        assert_eq!(doh.path, "/dns-query");
+
+        let dnscrypt: Stamp = stamps[5].parse().unwrap();
+        assert_eq!(dnscrypt.public_key, (0..32).collect::<Vec<u8>>());
+        assert_eq!(dnscrypt.provider_name, "2.dnscrypt-cert.example.com");
+
+        let pinned: Stamp = stamps[6].parse().unwrap();
+        assert_eq!(pinned.hashes, vec![vec![0xaa; 32], vec![0xbb; 32]]);
+        assert_eq!(pinned.bootstrap, vec!["192.0.2.53", "[2001:db8::53]"]);
+
+        assert!("sdns://BwcAAAAAAAAA".parse::<Stamp>().is_err());
+        assert!("sdns://AgcAAAAAAAAABzEuMC4wLjE".parse::<Stamp>().is_err());
+
+        // fields too long for their length prefix can't be encoded
+        let long = Stamp {
+            hostname: "a".repeat(256),
+            ..doh.clone()
+        };
+        assert!(long.encode().is_err());
+        let long = Stamp {
+            hashes: vec![vec![0; 128]],
+            ..doh
+        };
+        assert!(long.encode().is_err());
+    }
+
+    #[test]
+    fn stamps_are_upstreams() {
+        let upstream: Upstream =
+            "sdns://AgcAAAAAAAAABzEuMC4wLjEAEmRucy5jbG91ZGZsYXJlLmNvbQovZG5zLXF1ZXJ5"
+                .parse()
+                .unwrap();
+        assert_eq!(
+            upstream.to_string(),
+            "https://dns.cloudflare.com:443/dns-query"
+        );
+        assert_eq!(upstream.address(), Some("1.0.0.1".parse().unwrap()));
+
+        let upstream: Upstream = "sdns://BAcAAAAAAAAAAAAXZG5zLmFkZ3VhcmQtZG5zLmNvbTo3ODQ"
+            .parse()
+            .unwrap();
+        assert_eq!(upstream.to_string(), "quic://dns.adguard-dns.com:784");
+        assert_eq!(upstream.address(), None);
+
+        let upstream: Upstream = "sdns://AAcAAAAAAAAABzkuOS45Ljk".parse().unwrap();
+        assert_eq!(upstream, Upstream::Udp("9.9.9.9:53".parse().unwrap()));
+
+        // we don't speak DNSCrypt
+        assert!("sdns://gQ0xOTIuMC4yLjE6NDQz".parse::<Upstream>().is_err());
+    }
+}
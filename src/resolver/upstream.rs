@@ -0,0 +1,514 @@
+use crate::dns::{
+    DNS_MESSAGE, DOH_PATH, DOQ_ALPN, DOQ_PORT, DOT_PORT, DnsMessage, read_frame, tls_client_config,
+    write_frame,
+};
+use crate::parse::DnsData;
+use crate::resolver::{Pipeline, Stamp, StampProtocol, check_response, exchange};
+use anyhow::{Context, Result, anyhow, bail, ensure};
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::client::conn::http2::{self, SendRequest};
+use hyper::header::{ACCEPT, CONTENT_TYPE};
+use hyper::{Request, StatusCode};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use quinn::crypto::rustls::QuicClientConfig;
+use quinn::{Connection, Endpoint};
+use rustls::ClientConfig;
+use rustls::pki_types::ServerName;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use tokio::net::{TcpStream, lookup_host};
+use tokio::sync::Mutex;
+use tokio::time::timeout;
+use tokio_rustls::TlsConnector;
+use tokio_rustls::client::TlsStream;
+use tracing::debug;
+
+const DNS_PORT: u16 = 53;
+const HTTPS_PORT: u16 = 443;
+
+// how many queries can be waiting on one DoT connection before we open another
+const MAX_PIPELINED: usize = 64;
+
+// how many DoT connections we keep open to one upstream
+const MAX_CONNECTIONS: usize = 4;
+
+// An upstream resolver and how to talk to it. They're written as URLs, e.g. tls://dns.example,
+// https://dns.example/dns-query or quic://dns.example:853, as plain addresses for UDP, or as DNS
+// stamps. The encrypted ones are looked up by name unless a stamp gave us their address.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Upstream {
+    // plain DNS over UDP
+    Udp(SocketAddr),
+
+    // DNS over TLS (RFC 7858)
+    Tls {
+        host: String,
+        port: u16,
+        address: Option<IpAddr>,
+    },
+
+    // DNS over HTTPS (RFC 8484), POSTed to a path on the host
+    Https {
+        host: String,
+        port: u16,
+        path: String,
+        address: Option<IpAddr>,
+    },
+
+    // DNS over QUIC (RFC 9250)
+    Quic {
+        host: String,
+        port: u16,
+        address: Option<IpAddr>,
+    },
+}
+
+impl Upstream {
+    // where to find the upstream without looking up its name
+    pub fn address(&self) -> Option<IpAddr> {
+        match self {
+            Self::Udp(address) => Some(address.ip()),
+            Self::Tls { address, .. }
+            | Self::Https { address, .. }
+            | Self::Quic { address, .. } => *address,
+        }
+    }
+}
+
+impl From<SocketAddr> for Upstream {
+    fn from(address: SocketAddr) -> Self {
+        Self::Udp(address)
+    }
+}
+
+impl FromStr for Upstream {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let Some((scheme, rest)) = s.split_once("://") else {
+            return Ok(Self::Udp(udp_address(s)?));
+        };
+        Ok(match scheme.to_ascii_lowercase().as_str() {
+            "udp" => Self::Udp(udp_address(rest.trim_end_matches('/'))?),
+            "tls" => {
+                let (host, port) = host_and_port(rest.trim_end_matches('/'), DOT_PORT)?;
+                Self::Tls {
+                    host,
+                    port,
+                    address: None,
+                }
+            }
+            "https" => {
+                let (authority, path) = match rest.find('/') {
+                    Some(slash) => rest.split_at(slash),
+                    None => (rest, DOH_PATH),
+                };
+                let (host, port) = host_and_port(authority, HTTPS_PORT)?;
+                Self::Https {
+                    host,
+                    port,
+                    path: path.to_string(),
+                    address: None,
+                }
+            }
+            "quic" => {
+                let (host, port) = host_and_port(rest.trim_end_matches('/'), DOQ_PORT)?;
+                Self::Quic {
+                    host,
+                    port,
+                    address: None,
+                }
+            }
+            "sdns" => s.parse::<Stamp>()?.try_into()?,
+            other => bail!("unknown upstream scheme {other} in {s}"),
+        })
+    }
+}
+
+impl fmt::Display for Upstream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Udp(address) => write!(f, "{address}"),
+            Self::Tls { host, port, .. } => write!(f, "tls://{}", authority(host, *port)),
+            Self::Https {
+                host, port, path, ..
+            } => write!(f, "https://{}{path}", authority(host, *port)),
+            Self::Quic { host, port, .. } => write!(f, "quic://{}", authority(host, *port)),
+        }
+    }
+}
+
+// Upstreams from stamps for the protocols we speak. The certificate hashes in them aren't
+// pinned, certificates are checked against our CAs the same as for any other upstream.
+impl TryFrom<Stamp> for Upstream {
+    type Error = anyhow::Error;
+
+    fn try_from(stamp: Stamp) -> Result<Self> {
+        if stamp.protocol == StampProtocol::Plain {
+            return Ok(Self::Udp(udp_address(&stamp.address)?));
+        }
+
+        // The address can be left out, or be just a port, and the host name can have a port
+        // too. A port on the host name wins.
+        let (address, address_port) = split_host_port(&stamp.address)?;
+        let address = match address {
+            "" => None,
+            ip => Some(ip.parse().with_context(|| format!("bad address {ip}"))?),
+        };
+        let (host, host_port) = split_host_port(&stamp.hostname)?;
+        ensure!(!host.is_empty(), "stamp has no host name");
+        let (host, port) = (host.to_string(), host_port.or(address_port));
+
+        Ok(match stamp.protocol {
+            StampProtocol::DoT => Self::Tls {
+                host,
+                port: port.unwrap_or(DOT_PORT),
+                address,
+            },
+            StampProtocol::DoH => Self::Https {
+                host,
+                port: port.unwrap_or(HTTPS_PORT),
+                path: stamp.path,
+                address,
+            },
+            StampProtocol::DoQ => Self::Quic {
+                host,
+                port: port.unwrap_or(DOQ_PORT),
+                address,
+            },
+            other => bail!("{other:?} stamps can't be used as upstreams"),
+        })
+    }
+}
+
+// an address for UDP, which needn't have a port
+fn udp_address(s: &str) -> Result<SocketAddr> {
+    if let Ok(address) = s.parse() {
+        return Ok(address);
+    }
+    let ip: IpAddr = s
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse()
+        .with_context(|| format!("bad upstream address {s}"))?;
+    Ok(SocketAddr::new(ip, DNS_PORT))
+}
+
+// the host and port in host:port, [ipv6]:port or just host
+fn host_and_port(s: &str, default_port: u16) -> Result<(String, u16)> {
+    let (host, port) = split_host_port(s)?;
+    ensure!(!host.is_empty(), "no host in {s}");
+    Ok((host.to_string(), port.unwrap_or(default_port)))
+}
+
+fn split_host_port(s: &str) -> Result<(&str, Option<u16>)> {
+    let (host, port) = match s.strip_prefix('[') {
+        Some(rest) => {
+            let (host, port) = rest
+                .split_once(']')
+                .with_context(|| format!("unclosed [ in {s}"))?;
+            (host, port.strip_prefix(':'))
+        }
+        None => match s.split_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (s, None),
+        },
+    };
+    let port = match port {
+        Some(port) => Some(port.parse().with_context(|| format!("bad port in {s}"))?),
+        None => None,
+    };
+    Ok((host, port))
+}
+
+fn authority(host: &str, port: u16) -> String {
+    match host.contains(':') {
+        true => format!("[{host}]:{port}"),
+        false => format!("{host}:{port}"),
+    }
+}
+
+// Sends queries to one upstream. Connections to encrypted upstreams are kept open between
+// queries and shared by all of them: DoT queries are pipelined, a few connections' worth at
+// most, and DoH and DoQ put each query on a stream of its own on the one connection.
+#[derive(Debug)]
+pub struct UpstreamClient {
+    upstream: Upstream,
+    tls: OnceLock<Arc<ClientConfig>>,
+    streams: Mutex<Vec<Arc<Pipeline>>>,
+    http: Mutex<Option<SendRequest<Full<Bytes>>>>,
+    quic: Mutex<Option<(Endpoint, Connection)>>,
+}
+
+impl UpstreamClient {
+    pub fn new(upstream: Upstream) -> Self {
+        Self {
+            upstream,
+            tls: OnceLock::new(),
+            streams: Mutex::new(Vec::new()),
+            http: Mutex::new(None),
+            quic: Mutex::new(None),
+        }
+    }
+
+    // the CAs to trust for encrypted upstreams, rather than the public ones
+    pub fn with_tls_config(mut self, config: Arc<ClientConfig>) -> Self {
+        self.tls = OnceLock::from(config);
+        self
+    }
+
+    pub fn upstream(&self) -> &Upstream {
+        &self.upstream
+    }
+
+    // send a request upstream and wait for the matching response
+    pub async fn exchange(&self, request: &DnsMessage, wait: Duration) -> Result<DnsMessage> {
+        let exchange = async {
+            match &self.upstream {
+                Upstream::Udp(server) => exchange(*server, request, wait).await,
+                Upstream::Tls { host, port, .. } => self.exchange_tls(host, *port, request).await,
+                Upstream::Https {
+                    host, port, path, ..
+                } => self.exchange_https(host, *port, path, request).await,
+                Upstream::Quic { host, port, .. } => self.exchange_quic(host, *port, request).await,
+            }
+        };
+        timeout(wait, exchange)
+            .await
+            .with_context(|| format!("timed out waiting for {}", self.upstream))?
+    }
+
+    async fn exchange_tls(
+        &self,
+        host: &str,
+        port: u16,
+        request: &DnsMessage,
+    ) -> Result<DnsMessage> {
+        let response = self.pipeline(host, port).await?.exchange(request).await?;
+        check_response(&self.upstream, request, &response)?;
+        Ok(response)
+    }
+
+    // the DoT connection with the fewest queries waiting, or a new one if they're all busy
+    async fn pipeline(&self, host: &str, port: u16) -> Result<Arc<Pipeline>> {
+        let mut streams = self.streams.lock().await;
+        streams.retain(|pipeline| !pipeline.is_closed());
+        let idlest = streams.iter().min_by_key(|pipeline| pipeline.in_flight());
+        if let Some(pipeline) = idlest
+            && (pipeline.in_flight() < MAX_PIPELINED || streams.len() >= MAX_CONNECTIONS)
+        {
+            return Ok(pipeline.clone());
+        }
+
+        // DoT has an ALPN ID, but plenty of servers would turn down a handshake asking for it
+        let stream = self.connect_tls(host, port, &[]).await?;
+        debug!("connected to {}", self.upstream);
+        let pipeline = Arc::new(Pipeline::new(stream));
+        streams.push(pipeline.clone());
+        Ok(pipeline)
+    }
+
+    async fn exchange_https(
+        &self,
+        host: &str,
+        port: u16,
+        path: &str,
+        request: &DnsMessage,
+    ) -> Result<DnsMessage> {
+        // the ID is zero so caches see the same query the same way (RFC 8484 S4.1)
+        let mut query = request.clone();
+        query.header.packet_id = 0;
+        let http = Request::post(format!("https://{}{path}", authority(host, port)))
+            .header(CONTENT_TYPE, DNS_MESSAGE)
+            .header(ACCEPT, DNS_MESSAGE)
+            .body(Full::new(query.encode(0, &mut HashMap::new())?))?;
+
+        let response = self
+            .http_sender(host, port)
+            .await?
+            .send_request(http)
+            .await?;
+        ensure!(
+            response.status() == StatusCode::OK,
+            "{} answered with HTTP {}",
+            self.upstream,
+            response.status()
+        );
+        let body = Limited::new(response.into_body(), u16::MAX as usize)
+            .collect()
+            .await
+            .map_err(|e| anyhow!(e))?
+            .to_bytes();
+
+        let (_, mut response) = DnsMessage::decode(&body, 0, &mut HashMap::new())?;
+        check_response(&self.upstream, &query, &response)?;
+        response.header.packet_id = request.header.packet_id;
+        Ok(response)
+    }
+
+    // the DoH connection, which HTTP/2 multiplexes all our queries over
+    async fn http_sender(&self, host: &str, port: u16) -> Result<SendRequest<Full<Bytes>>> {
+        let mut http = self.http.lock().await;
+        if let Some(sender) = http.as_ref().filter(|sender| !sender.is_closed()) {
+            return Ok(sender.clone());
+        }
+
+        let stream = self.connect_tls(host, port, &[b"h2"]).await?;
+        let (sender, connection) =
+            http2::handshake(TokioExecutor::new(), TokioIo::new(stream)).await?;
+        let upstream = self.upstream.clone();
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                debug!("connection to {upstream} failed: {e}");
+            }
+        });
+        debug!("connected to {}", self.upstream);
+        *http = Some(sender.clone());
+        Ok(sender)
+    }
+
+    async fn exchange_quic(
+        &self,
+        host: &str,
+        port: u16,
+        request: &DnsMessage,
+    ) -> Result<DnsMessage> {
+        // every query has a stream of its own, which makes IDs pointless (RFC 9250 S4.2.1)
+        let mut query = request.clone();
+        query.header.packet_id = 0;
+
+        let (mut send, mut recv) = self.quic_connection(host, port).await?.open_bi().await?;
+        write_frame(&mut send, &query.encode(0, &mut HashMap::new())?).await?;
+        send.finish()?;
+        let raw = read_frame(&mut recv)
+            .await?
+            .with_context(|| format!("{} closed the stream without answering", self.upstream))?;
+
+        let (_, mut response) = DnsMessage::decode(&raw, 0, &mut HashMap::new())?;
+        check_response(&self.upstream, &query, &response)?;
+        response.header.packet_id = request.header.packet_id;
+        Ok(response)
+    }
+
+    async fn quic_connection(&self, host: &str, port: u16) -> Result<Connection> {
+        let mut quic = self.quic.lock().await;
+        if let Some((_, connection)) = quic
+            .as_ref()
+            .filter(|(_, connection)| connection.close_reason().is_none())
+        {
+            return Ok(connection.clone());
+        }
+
+        let config = Arc::new(QuicClientConfig::try_from(self.tls_config(&[DOQ_ALPN])?)?);
+        let mut last_error = anyhow!("{host} has no addresses");
+        for address in self.addresses(host, port).await? {
+            let local: IpAddr = match address {
+                SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+                SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+            };
+            let mut endpoint = Endpoint::client(SocketAddr::new(local, 0))?;
+            endpoint.set_default_client_config(quinn::ClientConfig::new(config.clone()));
+            match endpoint.connect(address, host)?.await {
+                Ok(connection) => {
+                    debug!("connected to {}", self.upstream);
+                    *quic = Some((endpoint, connection.clone()));
+                    return Ok(connection);
+                }
+                Err(e) => last_error = e.into(),
+            }
+        }
+        Err(last_error)
+    }
+
+    // a TLS connection to the first of the host's addresses that takes it, with its certificate
+    // checked against the host name
+    async fn connect_tls(
+        &self,
+        host: &str,
+        port: u16,
+        alpn: &[&[u8]],
+    ) -> Result<TlsStream<TcpStream>> {
+        let name = ServerName::try_from(host.to_string())?;
+        let connector = TlsConnector::from(Arc::new(self.tls_config(alpn)?));
+        let mut last_error = anyhow!("{host} has no addresses");
+        for address in self.addresses(host, port).await? {
+            let stream = match TcpStream::connect(address).await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    last_error = e.into();
+                    continue;
+                }
+            };
+            match connector.connect(name.clone(), stream).await {
+                Ok(stream) => return Ok(stream),
+                Err(e) => last_error = e.into(),
+            }
+        }
+        Err(last_error)
+    }
+
+    // the addresses to try for an encrypted upstream
+    async fn addresses(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>> {
+        match self.upstream.address() {
+            Some(address) => Ok(vec![SocketAddr::new(address, port)]),
+            None => Ok(lookup_host((host, port)).await?.collect()),
+        }
+    }
+
+    // our TLS config asking for the given protocols. The clones share their session cache, so
+    // reconnecting can resume the last session.
+    fn tls_config(&self, alpn: &[&[u8]]) -> Result<ClientConfig> {
+        let tls = match self.tls.get() {
+            Some(tls) => tls,
+            None => {
+                let tls = tls_client_config(None)?;
+                self.tls.get_or_init(|| tls)
+            }
+        };
+        let mut config = ClientConfig::clone(tls);
+        config.alpn_protocols = alpn.iter().map(|protocol| protocol.to_vec()).collect();
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn upstreams_parse_and_display() {
+        let cases = [
+            ("192.0.2.1:5353", "192.0.2.1:5353"),
+            ("192.0.2.1", "192.0.2.1:53"),
+            ("udp://[2001:db8::1]", "[2001:db8::1]:53"),
+            ("tls://dns.example", "tls://dns.example:853"),
+            ("TLS://[2001:db8::1]:8853/", "tls://[2001:db8::1]:8853"),
+            ("https://dns.example", "https://dns.example:443/dns-query"),
+            (
+                "https://dns.example:8443/resolve",
+                "https://dns.example:8443/resolve",
+            ),
+            ("quic://dns.example", "quic://dns.example:853"),
+        ];
+        for (url, expected) in cases {
+            let upstream: Upstream = url.parse().unwrap();
+            assert_eq!(upstream.to_string(), expected);
+            assert_eq!(expected.parse::<Upstream>().unwrap(), upstream);
+        }
+
+        for bad in [
+            "dns.example",
+            "ftp://dns.example",
+            "tls://",
+            "tls://host:dot",
+            "quic://[::1",
+        ] {
+            assert!(bad.parse::<Upstream>().is_err(), "{bad}");
+        }
+    }
+}
@@ -0,0 +1,179 @@
+use crate::dns::IpPrefix;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+// Past this many buckets some have to go before another is added. Full buckets go first, they're
+// no different from having no bucket at all, and then the ones used longest ago. Between sweeps new
+// clients share one overflow bucket instead.
+pub const MAX_BUCKETS: usize = 100_000;
+
+// making room means looking at every bucket, which is plenty to do once a second
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+// the netblock of the given size that a client is limited as part of
+pub fn netblock(client: &IpAddr, ipv4_prefix_len: u8, ipv6_prefix_len: u8) -> IpPrefix {
+    let client = client.to_canonical();
+    let len = match client {
+        IpAddr::V4(_) => ipv4_prefix_len,
+        IpAddr::V6(_) => ipv6_prefix_len,
+    };
+    IpPrefix::new(client, len).unwrap_or_else(|_| IpPrefix::host(client))
+}
+
+// a bucket of up to a second's worth of tokens, refilling at a rate per second
+#[derive(Debug)]
+pub struct TokenBucket<T = ()> {
+    tokens: f64,
+    updated: Instant,
+
+    // anything else the limiter keeps track of alongside the tokens
+    pub state: T,
+}
+
+impl<T: Default> TokenBucket<T> {
+    fn full(now: Instant, rate: u32) -> Self {
+        Self {
+            tokens: rate as f64,
+            updated: now,
+            state: T::default(),
+        }
+    }
+}
+
+impl<T> TokenBucket<T> {
+    fn tokens_at(&self, now: Instant, rate: u32) -> f64 {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        (self.tokens + elapsed * rate as f64).min(rate as f64)
+    }
+
+    // takes a token if there's one left, after refilling for the time since the last one
+    pub fn take(&mut self, now: Instant, rate: u32) -> bool {
+        self.tokens = self.tokens_at(now, rate);
+        self.updated = now;
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+// A token bucket for each of whatever the limiter tells clients apart by, never more than `max`
+// of them however many different clients there are.
+#[derive(Debug)]
+pub struct BucketMap<K, T = ()> {
+    buckets: HashMap<K, TokenBucket<T>>,
+    max: usize,
+    swept: Option<Instant>,
+
+    // where clients go when there's no room for a bucket of their own yet
+    overflow: Option<TokenBucket<T>>,
+}
+
+impl<K: Eq + Hash, T: Default> Default for BucketMap<K, T> {
+    fn default() -> Self {
+        Self::with_max(MAX_BUCKETS)
+    }
+}
+
+impl<K: Eq + Hash, T: Default> BucketMap<K, T> {
+    pub fn with_max(max: usize) -> Self {
+        Self {
+            buckets: HashMap::new(),
+            max,
+            swept: None,
+            overflow: None,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buckets.is_empty()
+    }
+
+    // the bucket for `key`, a full one if it's new, `rate` gives the rate for any key
+    pub fn get(&mut self, key: K, now: Instant, rate: impl Fn(&K) -> u32) -> &mut TokenBucket<T> {
+        if self.buckets.len() >= self.max && !self.buckets.contains_key(&key) {
+            self.make_room(now, &rate);
+            if self.buckets.len() >= self.max {
+                return self
+                    .overflow
+                    .get_or_insert_with(|| TokenBucket::full(now, rate(&key)));
+            }
+        }
+        self.buckets
+            .entry(key)
+            .or_insert_with_key(|key| TokenBucket::full(now, rate(key)))
+    }
+
+    // Sweeps out the full buckets and then the oldest down to three quarters of the limit, so
+    // the next sweep isn't straight away. Filling up again within the second leaves things as they
+    // are, the clients being limited stay limited and the new ones wait for the next sweep.
+    fn make_room(&mut self, now: Instant, rate: impl Fn(&K) -> u32) {
+        let due = self
+            .swept
+            .is_none_or(|swept| now.saturating_duration_since(swept) >= SWEEP_INTERVAL);
+        if !due {
+            return;
+        }
+        self.swept = Some(now);
+
+        self.buckets.retain(|key, bucket| {
+            let rate = rate(key);
+            bucket.tokens_at(now, rate) < rate as f64
+        });
+
+        let target = self.max * 3 / 4;
+        if self.buckets.len() > target {
+            let excess = self.buckets.len() - target;
+            let mut updated: Vec<Instant> = self.buckets.values().map(|b| b.updated).collect();
+            let (_, cutoff, _) = updated.select_nth_unstable(excess - 1);
+            let cutoff = *cutoff;
+            self.buckets.retain(|_, bucket| bucket.updated > cutoff);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn never_holds_more_than_the_limit() {
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        let rate = |_: &u64| 1;
+        let mut buckets: BucketMap<u64> = BucketMap::with_max(4);
+        for key in 0..4 {
+            assert!(buckets.get(key, at(key), rate).take(at(key), 1));
+        }
+
+        // none of them are full, so the oldest goes
+        buckets.get(4, at(4), rate);
+        assert_eq!(buckets.len(), 4);
+        assert!(!buckets.buckets.contains_key(&0));
+
+        // a second later they've all filled back up
+        buckets.get(5, at(1500), rate);
+        assert_eq!(buckets.len(), 1);
+
+        // filling up again before the next sweep is due leaves the buckets alone, and the new
+        // clients share the one token between them
+        for key in 6..9 {
+            assert!(buckets.get(key, at(1600), rate).take(at(1600), 1));
+        }
+        assert!(buckets.get(9, at(1600), rate).take(at(1600), 1));
+        assert!(!buckets.get(10, at(1600), rate).take(at(1600), 1));
+        assert!(!buckets.get(11, at(1700), rate).take(at(1700), 1));
+        assert_eq!(buckets.len(), 4);
+        assert!(buckets.buckets.contains_key(&5));
+
+        // the client that used up its token is still limited
+        assert!(!buckets.get(6, at(1700), rate).take(at(1700), 1));
+    }
+}
@@ -0,0 +1,90 @@
+use crate::acl::Acl;
+use crate::dns::DnsMessage;
+use crate::handler::{DnsHandler, DnsRequest, Transport};
+use crate::rrl::{ResponseLimiter, Verdict};
+use crate::stats::Stats;
+use anyhow::Result;
+use async_trait::async_trait;
+use std::sync::Arc;
+use tracing::debug;
+
+pub const STAT_RRL_DROPPED: &str = "rrl.dropped";
+pub const STAT_RRL_SLIPPED: &str = "rrl.slipped";
+
+// Puts the responses the inner handler comes up with for UDP clients through the limiter. Over
+// the limit they're dropped, or slipped: sent back empty with TC set. TCP is left alone, since a
+// client that can complete a handshake isn't using a forged address.
+#[derive(Debug)]
+pub struct RrlHandler {
+    limiter: Arc<ResponseLimiter>,
+    exempt: Acl,
+    inner: Arc<dyn DnsHandler>,
+    stats: Arc<Stats>,
+}
+
+impl RrlHandler {
+    pub fn new(limiter: Arc<ResponseLimiter>, inner: Arc<dyn DnsHandler>) -> Self {
+        Self {
+            limiter,
+            exempt: Acl::none(),
+            inner,
+            stats: Arc::new(Stats::default()),
+        }
+    }
+
+    // clients that are never limited
+    pub fn with_exempt(mut self, exempt: Acl) -> Self {
+        self.exempt = exempt;
+        self
+    }
+
+    pub fn with_stats(mut self, stats: Arc<Stats>) -> Self {
+        self.stats = stats;
+        self
+    }
+
+    pub fn stats(&self) -> Arc<Stats> {
+        self.stats.clone()
+    }
+
+    fn slip(request: &DnsRequest, response: &DnsMessage) -> DnsMessage {
+        let mut slip = request.message.clone().as_reply();
+        slip.header.response_code = response.header.response_code;
+        slip.header.auth_answer = response.header.auth_answer;
+        slip.header.recursion_available = response.header.recursion_available;
+        slip.header.truncation = true;
+        slip
+    }
+}
+
+#[async_trait]
+impl DnsHandler for RrlHandler {
+    async fn handle(&self, request: &DnsRequest) -> Result<Option<DnsMessage>> {
+        let response = self.inner.handle(request).await?;
+        let Some(response) = response else {
+            return Ok(None);
+        };
+        let client = request.client.ip();
+        if request.transport != Transport::Udp || self.exempt.allows(&client) {
+            return Ok(Some(response));
+        }
+
+        match self.limiter.check(&client, &response) {
+            Verdict::Send => Ok(Some(response)),
+            Verdict::Slip => {
+                debug!("rate limited response to {}, slipping it", request.client);
+                self.stats.increment(STAT_RRL_SLIPPED);
+                Ok(Some(Self::slip(request, &response)))
+            }
+            Verdict::Drop => {
+                debug!("rate limited response to {}, dropping it", request.client);
+                self.stats.increment(STAT_RRL_DROPPED);
+                Ok(None)
+            }
+        }
+    }
+
+    async fn handle_stream(&self, request: &DnsRequest) -> Result<Vec<DnsMessage>> {
+        self.inner.handle_stream(request).await
+    }
+}
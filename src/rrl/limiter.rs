@@ -0,0 +1,297 @@
+use crate::cache::{Clock, SystemClock};
+use crate::dns::{DnsMessage, Domain, IpPrefix, QuestionType, ResponseCode};
+use crate::rrl::{BucketMap, netblock};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug, Clone)]
+pub struct RrlConfig {
+    // how many identical responses a client netblock gets each second, for each class of
+    // response. Zero means no limit for that class.
+    pub responses_per_second: u32,
+    pub nxdomains_per_second: u32,
+    pub errors_per_second: u32,
+
+    // Every slip'th response over the limit is sent truncated rather than dropped, so a real
+    // client whose address is being forged retries over TCP and still gets an answer. Zero drops
+    // them all and one truncates them all.
+    pub slip: u32,
+
+    // the size of the netblocks clients are lumped together in
+    pub ipv4_prefix_len: u8,
+    pub ipv6_prefix_len: u8,
+}
+
+impl Default for RrlConfig {
+    fn default() -> Self {
+        Self {
+            responses_per_second: 5,
+            nxdomains_per_second: 5,
+            errors_per_second: 5,
+            slip: 2,
+            ipv4_prefix_len: 24,
+            ipv6_prefix_len: 56,
+        }
+    }
+}
+
+// the classes of response that are limited separately
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ResponseClass {
+    // answers, NODATA and referrals
+    Answer,
+    NxDomain,
+    Error,
+}
+
+impl ResponseClass {
+    pub fn of(response: &DnsMessage) -> Self {
+        match response.response_code() {
+            code if code == ResponseCode::NoError as u8 => Self::Answer,
+            code if code == ResponseCode::NxDomain as u8 => Self::NxDomain,
+            _ => Self::Error,
+        }
+    }
+}
+
+// what to do with a response
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Send,
+    Slip,
+    Drop,
+}
+
+// What makes two responses identical. Answers are told apart by name and type, NXDOMAINs by the
+// zone they came from, so a flood of random names under one zone is one flood, and errors only
+// by the netblock they go to.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct BucketKey {
+    netblock: IpPrefix,
+    class: ResponseClass,
+    name: Option<Domain>,
+    qtype: Option<QuestionType>,
+}
+
+// Response rate limiting the way BIND does it: a token bucket for each kind of identical response
+// to each client netblock, holding a second's worth of responses and refilling at the class's
+// rate. Without it, forged queries can point a stream of large UDP responses at somebody.
+#[derive(Debug)]
+pub struct ResponseLimiter {
+    config: RrlConfig,
+    clock: Arc<dyn Clock>,
+    // each bucket's state is the responses it has refused, for working out when to slip
+    buckets: Mutex<BucketMap<BucketKey, u32>>,
+}
+
+impl ResponseLimiter {
+    pub fn new(config: RrlConfig) -> Self {
+        Self::with_clock(config, Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(config: RrlConfig, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            config,
+            clock,
+            buckets: Mutex::new(BucketMap::default()),
+        }
+    }
+
+    pub fn config(&self) -> &RrlConfig {
+        &self.config
+    }
+
+    fn rate(&self, class: ResponseClass) -> u32 {
+        match class {
+            ResponseClass::Answer => self.config.responses_per_second,
+            ResponseClass::NxDomain => self.config.nxdomains_per_second,
+            ResponseClass::Error => self.config.errors_per_second,
+        }
+    }
+
+    fn key(&self, client: &IpAddr, response: &DnsMessage) -> BucketKey {
+        let class = ResponseClass::of(response);
+        let question = response.question();
+        let (name, qtype) = match class {
+            ResponseClass::Answer => (
+                question.map(|q| q.name.to_lowercase()),
+                question.map(|q| q.qtype.clone()),
+            ),
+            ResponseClass::NxDomain => {
+                let zone = response
+                    .authority
+                    .answers
+                    .iter()
+                    .find(|a| a.qtype == QuestionType::SOA)
+                    .map(|soa| &soa.name)
+                    .or(question.map(|q| &q.name));
+                (zone.map(Domain::to_lowercase), None)
+            }
+            ResponseClass::Error => (None, None),
+        };
+        BucketKey {
+            netblock: netblock(
+                client,
+                self.config.ipv4_prefix_len,
+                self.config.ipv6_prefix_len,
+            ),
+            class,
+            name,
+            qtype,
+        }
+    }
+
+    // takes a token for the response if there's one left, and otherwise says whether to slip it
+    pub fn check(&self, client: &IpAddr, response: &DnsMessage) -> Verdict {
+        let key = self.key(client, response);
+        let rate = self.rate(key.class);
+        if rate == 0 {
+            return Verdict::Send;
+        }
+
+        let now = self.clock.now();
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.get(key, now, |key| self.rate(key.class));
+        if bucket.take(now, rate) {
+            return Verdict::Send;
+        }
+
+        bucket.state = bucket.state.wrapping_add(1);
+        // nothing but zero is a multiple of zero, so a slip of zero drops everything
+        match bucket.state.is_multiple_of(self.config.slip) {
+            true => Verdict::Slip,
+            false => Verdict::Drop,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::ManualClock;
+    use crate::dns::{DnsAnswer, DnsAnswerSet, DnsQuestion, RecordData, Soa};
+    use std::time::Duration;
+
+    fn response(name: &str, code: ResponseCode) -> DnsMessage {
+        DnsMessage::query(1, DnsQuestion::new(name.parse().unwrap(), QuestionType::A))
+            .as_reply()
+            .with_response_code(code)
+    }
+
+    fn nxdomain(name: &str, zone: &str) -> DnsMessage {
+        let soa = DnsAnswer::new(
+            zone.parse().unwrap(),
+            60,
+            RecordData::SOA(Soa {
+                mname: "ns1.example.com".parse().unwrap(),
+                rname: "hostmaster.example.com".parse().unwrap(),
+                minimum: 60,
+                ..Soa::default()
+            }),
+        )
+        .unwrap();
+        response(name, ResponseCode::NxDomain)
+            .with_authority(DnsAnswerSet { answers: vec![soa] })
+            .unwrap()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn identical_responses_are_limited_per_netblock() {
+        let clock = Arc::new(ManualClock::default());
+        let limiter = ResponseLimiter::with_clock(
+            RrlConfig {
+                responses_per_second: 2,
+                slip: 0,
+                ..Default::default()
+            },
+            clock.clone(),
+        );
+        let www = response("www.example.com", ResponseCode::NoError);
+        let check = |client, response| limiter.check(&ip(client), response);
+
+        assert_eq!(check("192.0.2.1", &www), Verdict::Send);
+        assert_eq!(check("192.0.2.200", &www), Verdict::Send);
+        assert_eq!(check("192.0.2.1", &www), Verdict::Drop);
+
+        // other names, and other netblocks, have buckets of their own
+        let mail = response("MAIL.example.com", ResponseCode::NoError);
+        assert_eq!(check("192.0.2.1", &mail), Verdict::Send);
+        assert_eq!(check("198.51.100.1", &www), Verdict::Send);
+        assert_eq!(check("2001:db8:0:ff::1", &www), Verdict::Send);
+        assert_eq!(check("2001:db8:0:ff::2", &www), Verdict::Send);
+        assert_eq!(check("2001:db8:0:ff::3", &www), Verdict::Drop);
+
+        // and they fill back up at the rate
+        clock.advance(Duration::from_millis(500));
+        assert_eq!(check("192.0.2.1", &www), Verdict::Send);
+        assert_eq!(check("192.0.2.1", &www), Verdict::Drop);
+    }
+
+    #[test]
+    fn nxdomains_are_limited_by_zone_and_errors_all_together() {
+        let limiter = ResponseLimiter::new(RrlConfig {
+            responses_per_second: 0,
+            nxdomains_per_second: 1,
+            errors_per_second: 1,
+            slip: 0,
+            ..Default::default()
+        });
+        let check = |response| limiter.check(&ip("192.0.2.1"), &response);
+
+        assert_eq!(
+            check(nxdomain("a.example.com", "example.com")),
+            Verdict::Send
+        );
+        assert_eq!(
+            check(nxdomain("b.example.com", "example.com")),
+            Verdict::Drop
+        );
+        assert_eq!(
+            check(nxdomain("a.example.net", "example.net")),
+            Verdict::Send
+        );
+
+        assert_eq!(
+            check(response("a.example.com", ResponseCode::Refused)),
+            Verdict::Send
+        );
+        assert_eq!(
+            check(response("b.example.net", ResponseCode::ServFail)),
+            Verdict::Drop
+        );
+
+        // a rate of zero is no limit
+        for _ in 0..10 {
+            assert_eq!(
+                check(response("www.example.com", ResponseCode::NoError)),
+                Verdict::Send
+            );
+        }
+    }
+
+    #[test]
+    fn every_slipth_response_over_the_limit_is_truncated() {
+        let limiter = ResponseLimiter::new(RrlConfig {
+            responses_per_second: 1,
+            slip: 3,
+            ..Default::default()
+        });
+        let www = response("www.example.com", ResponseCode::NoError);
+        let verdicts: Vec<_> = (0..7)
+            .map(|_| limiter.check(&ip("192.0.2.1"), &www))
+            .collect();
+        assert_eq!(verdicts, vec![
+            Verdict::Send,
+            Verdict::Drop,
+            Verdict::Drop,
+            Verdict::Slip,
+            Verdict::Drop,
+            Verdict::Drop,
+            Verdict::Slip,
+        ]);
+    }
+}
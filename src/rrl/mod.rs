@@ -0,0 +1,7 @@
+mod buckets;
+mod handler;
+mod limiter;
+
+pub use buckets::*;
+pub use handler::*;
+pub use limiter::*;
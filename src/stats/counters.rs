@@ -0,0 +1,105 @@
+use crate::stats::Tally;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::info;
+
+// how many keys each tally keeps track of
+const TALLY_CAPACITY: usize = 1000;
+
+// how many of the biggest keys in each tally are logged
+const REPORTED_KEYS: usize = 10;
+
+// Named counters shared between the parts of the server that want to report something. Each
+// module defines the names of the counters it owns next to the code that bumps them. Tallies are
+// counters broken down by key, for the things where who is more interesting than how many.
+#[derive(Debug, Default)]
+pub struct Stats {
+    counters: Mutex<BTreeMap<String, u64>>,
+    tallies: Mutex<BTreeMap<String, Tally>>,
+}
+
+impl Stats {
+    pub fn increment(&self, name: &str) {
+        self.add(name, 1);
+    }
+
+    pub fn add(&self, name: &str, n: u64) {
+        let mut counters = self.counters.lock().unwrap();
+        match counters.get_mut(name) {
+            Some(count) => *count += n,
+            None => {
+                counters.insert(name.to_string(), n);
+            }
+        }
+    }
+
+    pub fn get(&self, name: &str) -> u64 {
+        self.counters
+            .lock()
+            .unwrap()
+            .get(name)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    // every counter and its current value
+    pub fn snapshot(&self) -> BTreeMap<String, u64> {
+        self.counters.lock().unwrap().clone()
+    }
+
+    pub fn tally(&self, name: &str, key: &str) {
+        self.tallies
+            .lock()
+            .unwrap()
+            .entry(name.to_string())
+            .or_insert_with(|| Tally::new(TALLY_CAPACITY))
+            .add(key, 1);
+    }
+
+    // the n keys with the biggest counts in a tally, biggest first
+    pub fn top(&self, name: &str, n: usize) -> Vec<(String, u64)> {
+        self.tallies
+            .lock()
+            .unwrap()
+            .get(name)
+            .map(|tally| tally.top(n))
+            .unwrap_or_default()
+    }
+
+    // logs the counters and the top of each tally every interval, for as long as we're running
+    pub fn start_reporting(self: &Arc<Self>, interval: Duration) {
+        let stats = self.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                info!("stats: {:?}", stats.snapshot());
+                let tallies: Vec<String> = stats.tallies.lock().unwrap().keys().cloned().collect();
+                for name in tallies {
+                    info!("top {name}: {:?}", stats.top(&name, REPORTED_KEYS));
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counters_start_at_zero_and_add_up() {
+        let stats = Stats::default();
+        assert_eq!(stats.get("cache.hits"), 0);
+
+        stats.increment("cache.hits");
+        stats.add("cache.hits", 2);
+        stats.increment("cache.misses");
+
+        assert_eq!(stats.get("cache.hits"), 3);
+        assert_eq!(stats.snapshot().into_iter().collect::<Vec<_>>(), vec![
+            ("cache.hits".to_string(), 3),
+            ("cache.misses".to_string(), 1)
+        ]);
+    }
+}
@@ -0,0 +1,5 @@
+mod counters;
+mod tally;
+
+pub use counters::*;
+pub use tally::*;
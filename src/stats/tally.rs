@@ -0,0 +1,70 @@
+use std::collections::HashMap;
+
+// Counts per key, for finding the biggest ones, like the clients that hit a limit most often.
+// It keeps at most `capacity` keys. Once it's full, a new key takes over the smallest count and
+// adds to that (the Space-Saving algorithm), so the heavy hitters are never lost but a small
+// count can be an overestimate.
+#[derive(Debug)]
+pub struct Tally {
+    capacity: usize,
+    counts: HashMap<String, u64>,
+}
+
+impl Tally {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            counts: HashMap::new(),
+        }
+    }
+
+    pub fn add(&mut self, key: &str, n: u64) {
+        if let Some(count) = self.counts.get_mut(key) {
+            *count += n;
+            return;
+        }
+
+        let mut start = 0;
+        if self.counts.len() >= self.capacity {
+            let smallest = self
+                .counts
+                .iter()
+                .min_by_key(|(_, count)| **count)
+                .map(|(key, count)| (key.clone(), *count));
+            if let Some((smallest, count)) = smallest {
+                self.counts.remove(&smallest);
+                start = count;
+            }
+        }
+        self.counts.insert(key.to_string(), start + n);
+    }
+
+    // the n biggest counts, biggest first
+    pub fn top(&self, n: usize) -> Vec<(String, u64)> {
+        let mut top: Vec<_> = self.counts.iter().map(|(k, c)| (k.clone(), *c)).collect();
+        top.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        top.truncate(n);
+        top
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_the_heavy_hitters_when_full() {
+        let mut tally = Tally::new(2);
+        tally.add("192.0.2.1", 5);
+        tally.add("192.0.2.2", 1);
+        tally.add("192.0.2.3", 1);
+        tally.add("192.0.2.1", 1);
+
+        // .3 took over .2's count
+        assert_eq!(tally.top(5), vec![
+            ("192.0.2.1".to_string(), 6),
+            ("192.0.2.3".to_string(), 2)
+        ]);
+        assert_eq!(tally.top(1), vec![("192.0.2.1".to_string(), 6)]);
+    }
+}
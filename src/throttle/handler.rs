@@ -0,0 +1,101 @@
+use crate::acl::Acl;
+use crate::dns::{DnsMessage, ResponseCode};
+use crate::handler::{DnsHandler, DnsRequest};
+use crate::stats::Stats;
+use crate::throttle::{ClientLimiter, InFlight, OverLimit};
+use anyhow::Result;
+use async_trait::async_trait;
+use std::sync::Arc;
+use tracing::debug;
+
+pub const STAT_THROTTLE_RATE_LIMITED: &str = "throttle.rate_limited";
+pub const STAT_THROTTLE_IN_FLIGHT_LIMITED: &str = "throttle.in_flight_limited";
+
+// a tally of the netblocks that went over a limit, see Stats::top
+pub const STAT_THROTTLE_OFFENDERS: &str = "throttle.offenders";
+
+// Holds each client to a query rate and a number of queries we can be working on for it at once,
+// so one busy or hostile client can't use up the resolver. Unlike RRL this is about the queries,
+// whatever the answers are, and it applies over every transport.
+#[derive(Debug)]
+pub struct ThrottleHandler {
+    limiter: Arc<ClientLimiter>,
+    exempt: Acl,
+    inner: Arc<dyn DnsHandler>,
+    stats: Arc<Stats>,
+}
+
+impl ThrottleHandler {
+    pub fn new(limiter: Arc<ClientLimiter>, inner: Arc<dyn DnsHandler>) -> Self {
+        Self {
+            limiter,
+            exempt: Acl::none(),
+            inner,
+            stats: Arc::new(Stats::default()),
+        }
+    }
+
+    // clients that are never limited
+    pub fn with_exempt(mut self, exempt: Acl) -> Self {
+        self.exempt = exempt;
+        self
+    }
+
+    pub fn with_stats(mut self, stats: Arc<Stats>) -> Self {
+        self.stats = stats;
+        self
+    }
+
+    pub fn stats(&self) -> Arc<Stats> {
+        self.stats.clone()
+    }
+
+    // Err with what to send back if the client is over a limit, otherwise its in flight slot,
+    // which exempt clients don't need
+    fn admit(&self, request: &DnsRequest) -> Result<Option<InFlight>, Option<DnsMessage>> {
+        let client = request.client.ip();
+        if self.exempt.allows(&client) {
+            return Ok(None);
+        }
+
+        let over = match self.limiter.allow_query(&client) {
+            false => STAT_THROTTLE_RATE_LIMITED,
+            true => match self.limiter.enter(&client) {
+                Some(in_flight) => return Ok(Some(in_flight)),
+                None => STAT_THROTTLE_IN_FLIGHT_LIMITED,
+            },
+        };
+        debug!("{} is over its limit ({over})", request.client);
+        self.stats.increment(over);
+        let netblock = self.limiter.netblock(&client);
+        self.stats
+            .tally(STAT_THROTTLE_OFFENDERS, &netblock.to_string());
+
+        match self.limiter.limits().over_limit {
+            OverLimit::Refuse => {
+                let reply = request.message.clone().as_reply();
+                Err(Some(reply.with_response_code(ResponseCode::Refused)))
+            }
+            OverLimit::Drop => Err(None),
+        }
+    }
+}
+
+#[async_trait]
+impl DnsHandler for ThrottleHandler {
+    async fn handle(&self, request: &DnsRequest) -> Result<Option<DnsMessage>> {
+        let _in_flight = match self.admit(request) {
+            Ok(in_flight) => in_flight,
+            Err(response) => return Ok(response),
+        };
+        self.inner.handle(request).await
+    }
+
+    async fn handle_stream(&self, request: &DnsRequest) -> Result<Vec<DnsMessage>> {
+        let _in_flight = match self.admit(request) {
+            Ok(in_flight) => in_flight,
+            Err(response) => return Ok(response.into_iter().collect()),
+        };
+        self.inner.handle_stream(request).await
+    }
+}
@@ -0,0 +1,219 @@
+use crate::cache::{Clock, SystemClock};
+use crate::dns::IpPrefix;
+use crate::rrl::{BucketMap, netblock};
+use anyhow::{Result, bail};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+// what clients over their limits get
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OverLimit {
+    #[default]
+    Refuse,
+    Drop,
+}
+
+impl FromStr for OverLimit {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s.trim().to_ascii_lowercase().as_str() {
+            "refuse" | "refused" => Self::Refuse,
+            "drop" => Self::Drop,
+            other => bail!("expected refuse or drop, got {other}"),
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ClientLimits {
+    // queries a client can send each second, with up to a second's worth in a burst. Zero means
+    // no limit.
+    pub queries_per_second: u32,
+
+    // queries a client can have that we're still working on, zero means no limit
+    pub max_in_flight: usize,
+
+    // The size of the netblocks clients are lumped together in. The defaults limit each address
+    // on its own.
+    pub ipv4_prefix_len: u8,
+    pub ipv6_prefix_len: u8,
+
+    pub over_limit: OverLimit,
+}
+
+impl Default for ClientLimits {
+    fn default() -> Self {
+        Self {
+            queries_per_second: 100,
+            max_in_flight: 32,
+            ipv4_prefix_len: 32,
+            ipv6_prefix_len: 128,
+            over_limit: OverLimit::default(),
+        }
+    }
+}
+
+// Keeps track of how fast each client is sending us queries and how many of them are still in
+// flight. The rate is a token bucket per client; in flight queries are held by an InFlight guard
+// that gives its slot back when it's dropped, however the query ends.
+#[derive(Debug)]
+pub struct ClientLimiter {
+    limits: ClientLimits,
+    clock: Arc<dyn Clock>,
+    buckets: Mutex<BucketMap<IpPrefix>>,
+    in_flight: Arc<Mutex<HashMap<IpPrefix, usize>>>,
+}
+
+impl ClientLimiter {
+    pub fn new(limits: ClientLimits) -> Self {
+        Self::with_clock(limits, Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(limits: ClientLimits, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            limits,
+            clock,
+            buckets: Mutex::new(BucketMap::default()),
+            in_flight: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn limits(&self) -> &ClientLimits {
+        &self.limits
+    }
+
+    // the netblock a client is limited as part of
+    pub fn netblock(&self, client: &IpAddr) -> IpPrefix {
+        netblock(
+            client,
+            self.limits.ipv4_prefix_len,
+            self.limits.ipv6_prefix_len,
+        )
+    }
+
+    // takes a token for a query from the client, false if it's sending them too fast
+    pub fn allow_query(&self, client: &IpAddr) -> bool {
+        let rate = self.limits.queries_per_second;
+        if rate == 0 {
+            return true;
+        }
+
+        let now = self.clock.now();
+        let mut buckets = self.buckets.lock().unwrap();
+        buckets
+            .get(self.netblock(client), now, |_| rate)
+            .take(now, rate)
+    }
+
+    // a slot for one more query from the client, None if it already has as many in flight as
+    // it's allowed
+    pub fn enter(&self, client: &IpAddr) -> Option<InFlight> {
+        let netblock = self.netblock(client);
+        let mut in_flight = self.in_flight.lock().unwrap();
+        let count = in_flight.entry(netblock).or_default();
+        if self.limits.max_in_flight > 0 && *count >= self.limits.max_in_flight {
+            return None;
+        }
+        *count += 1;
+        Some(InFlight {
+            netblock,
+            counts: self.in_flight.clone(),
+        })
+    }
+
+    pub fn in_flight(&self, client: &IpAddr) -> usize {
+        let in_flight = self.in_flight.lock().unwrap();
+        in_flight
+            .get(&self.netblock(client))
+            .copied()
+            .unwrap_or_default()
+    }
+}
+
+// one query in flight, until this is dropped
+#[derive(Debug)]
+pub struct InFlight {
+    netblock: IpPrefix,
+    counts: Arc<Mutex<HashMap<IpPrefix, usize>>>,
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        let mut counts = self.counts.lock().unwrap();
+        if let Some(count) = counts.get_mut(&self.netblock) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&self.netblock);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::ManualClock;
+    use std::time::Duration;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn queries_are_limited_per_client() {
+        let clock = Arc::new(ManualClock::default());
+        let limiter = ClientLimiter::with_clock(
+            ClientLimits {
+                queries_per_second: 2,
+                ipv6_prefix_len: 64,
+                ..Default::default()
+            },
+            clock.clone(),
+        );
+
+        assert!(limiter.allow_query(&ip("192.0.2.1")));
+        assert!(limiter.allow_query(&ip("::ffff:192.0.2.1")));
+        assert!(!limiter.allow_query(&ip("192.0.2.1")));
+        assert!(limiter.allow_query(&ip("192.0.2.2")));
+
+        assert!(limiter.allow_query(&ip("2001:db8::1")));
+        assert!(limiter.allow_query(&ip("2001:db8::2")));
+        assert!(!limiter.allow_query(&ip("2001:db8::3")));
+
+        clock.advance(Duration::from_millis(500));
+        assert!(limiter.allow_query(&ip("192.0.2.1")));
+        assert!(!limiter.allow_query(&ip("192.0.2.1")));
+    }
+
+    #[test]
+    fn in_flight_slots_come_back_when_dropped() {
+        let limiter = ClientLimiter::new(ClientLimits {
+            max_in_flight: 2,
+            ..Default::default()
+        });
+        let client = ip("192.0.2.1");
+
+        let first = limiter.enter(&client);
+        let second = limiter.enter(&client);
+        assert!(first.is_some() && second.is_some());
+        assert!(limiter.enter(&client).is_none());
+        assert!(limiter.enter(&ip("192.0.2.2")).is_some());
+        assert_eq!(limiter.in_flight(&client), 2);
+
+        drop(first);
+        assert_eq!(limiter.in_flight(&client), 1);
+        assert!(limiter.enter(&client).is_some());
+        drop(second);
+        assert_eq!(limiter.in_flight(&client), 0);
+    }
+
+    #[test]
+    fn over_limit_parses() {
+        assert_eq!("Drop".parse::<OverLimit>().unwrap(), OverLimit::Drop);
+        assert_eq!("refused".parse::<OverLimit>().unwrap(), OverLimit::Refuse);
+        assert!("ignore".parse::<OverLimit>().is_err());
+    }
+}
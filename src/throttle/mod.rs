@@ -0,0 +1,5 @@
+mod handler;
+mod limiter;
+
+pub use handler::*;
+pub use limiter::*;
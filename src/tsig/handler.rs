@@ -0,0 +1,97 @@
+use crate::dns::{DnsAnswerSet, DnsMessage, QuestionType, ResponseCode};
+use crate::handler::{DnsHandler, DnsRequest};
+use crate::tsig::{Keyring, TsigCheck, TsigSession, verify_request};
+use anyhow::Result;
+use async_trait::async_trait;
+use std::sync::Arc;
+use tracing::{debug, warn};
+
+// what to do with a request once its TSIG has been looked at
+enum Checked {
+    Unsigned,
+
+    // the request with its TSIG taken off, and the session to sign the responses in
+    Signed(DnsRequest, TsigSession),
+
+    // the error response to send straight back
+    Rejected(DnsMessage),
+}
+
+// Checks the TSIG on requests and signs the responses to signed ones with the same key. A request
+// with a bad signature goes no further than here. Unsigned requests are passed on as they are,
+// it's up to the handlers behind this one whether they need a key (see DnsRequest::key).
+#[derive(Debug)]
+pub struct TsigHandler {
+    keyring: Arc<Keyring>,
+    inner: Arc<dyn DnsHandler>,
+}
+
+impl TsigHandler {
+    pub fn new(keyring: Arc<Keyring>, inner: Arc<dyn DnsHandler>) -> Self {
+        Self { keyring, inner }
+    }
+
+    fn check(&self, request: &DnsRequest) -> Result<Checked> {
+        // the reply to a bad request, without the TSIG or anything else it came with
+        let error_reply = || {
+            request
+                .message
+                .clone()
+                .as_reply()
+                .with_answers(DnsAnswerSet::default())?
+                .with_authority(DnsAnswerSet::default())?
+                .with_additional(DnsAnswerSet::default())
+        };
+
+        match verify_request(&self.keyring, &request.raw) {
+            Ok(TsigCheck::Unsigned) => Ok(Checked::Unsigned),
+            Ok(TsigCheck::Verified(session)) => {
+                let mut additional = request.message.additional.clone();
+                additional.answers.retain(|r| r.qtype != QuestionType::TSIG);
+
+                let mut request = request.clone();
+                request.message = request.message.with_additional(additional)?;
+                request.key = Some(session.key_name().clone());
+                Ok(Checked::Signed(request, session))
+            }
+            Ok(TsigCheck::Failed(failure)) => {
+                warn!("bad TSIG from {}: error {}", request.client, failure.error);
+                Ok(Checked::Rejected(failure.response(error_reply()?)?))
+            }
+            Err(e) => {
+                debug!("malformed TSIG from {}: {e:#}", request.client);
+                Ok(Checked::Rejected(
+                    error_reply()?.with_response_code(ResponseCode::FormErr),
+                ))
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl DnsHandler for TsigHandler {
+    async fn handle(&self, request: &DnsRequest) -> Result<Option<DnsMessage>> {
+        match self.check(request)? {
+            Checked::Unsigned => self.inner.handle(request).await,
+            Checked::Rejected(reply) => Ok(Some(reply)),
+            Checked::Signed(request, mut session) => match self.inner.handle(&request).await? {
+                Some(reply) => Ok(Some(session.sign(reply)?)),
+                None => Ok(None),
+            },
+        }
+    }
+
+    async fn handle_stream(&self, request: &DnsRequest) -> Result<Vec<DnsMessage>> {
+        match self.check(request)? {
+            Checked::Unsigned => self.inner.handle_stream(request).await,
+            Checked::Rejected(reply) => Ok(vec![reply]),
+            Checked::Signed(request, mut session) => self
+                .inner
+                .handle_stream(&request)
+                .await?
+                .into_iter()
+                .map(|reply| session.sign(reply))
+                .collect(),
+        }
+    }
+}
@@ -0,0 +1,247 @@
+use crate::dns::Domain;
+use anyhow::{Context, Result, bail, ensure};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use ring::hmac;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+
+// the HMACs TSIG can use that are still worth using (RFC 8945 S6)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TsigAlgorithm {
+    HmacSha256,
+    HmacSha384,
+    HmacSha512,
+}
+
+impl TsigAlgorithm {
+    // the algorithm's name as it goes in the TSIG record
+    pub fn name(self) -> Domain {
+        let name = match self {
+            TsigAlgorithm::HmacSha256 => "hmac-sha256",
+            TsigAlgorithm::HmacSha384 => "hmac-sha384",
+            TsigAlgorithm::HmacSha512 => "hmac-sha512",
+        };
+        name.parse().unwrap()
+    }
+
+    pub fn from_name(name: &Domain) -> Option<Self> {
+        [
+            TsigAlgorithm::HmacSha256,
+            TsigAlgorithm::HmacSha384,
+            TsigAlgorithm::HmacSha512,
+        ]
+        .into_iter()
+        .find(|a| a.name().eq_ignore_case(name))
+    }
+
+    fn hmac(self) -> hmac::Algorithm {
+        match self {
+            TsigAlgorithm::HmacSha256 => hmac::HMAC_SHA256,
+            TsigAlgorithm::HmacSha384 => hmac::HMAC_SHA384,
+            TsigAlgorithm::HmacSha512 => hmac::HMAC_SHA512,
+        }
+    }
+}
+
+impl FromStr for TsigAlgorithm {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Self::from_name(&s.parse()?).with_context(|| format!("unsupported TSIG algorithm {s}"))
+    }
+}
+
+// A shared secret, known by name to both ends of a transaction.
+#[derive(Clone)]
+pub struct TsigKey {
+    pub name: Domain,
+    pub algorithm: TsigAlgorithm,
+    key: hmac::Key,
+}
+
+impl TsigKey {
+    pub fn new(name: Domain, algorithm: TsigAlgorithm, secret: &[u8]) -> Self {
+        Self {
+            name: name.to_lowercase(),
+            algorithm,
+            key: hmac::Key::new(algorithm.hmac(), secret),
+        }
+    }
+
+    pub fn sign(&self, data: &[u8]) -> Vec<u8> {
+        hmac::sign(&self.key, data).as_ref().to_vec()
+    }
+
+    // checks in constant time, so a forger can't learn the MAC a byte at a time
+    pub fn verify(&self, data: &[u8], mac: &[u8]) -> bool {
+        hmac::verify(&self.key, data, mac).is_ok()
+    }
+}
+
+// the secret stays out of the logs
+impl fmt::Debug for TsigKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TsigKey")
+            .field("name", &self.name)
+            .field("algorithm", &self.algorithm)
+            .finish_non_exhaustive()
+    }
+}
+
+// Every key we know, looked up by name. The file format is BIND's key statements:
+//
+//   key "transfer.example.com" {
+//       algorithm hmac-sha256;
+//       secret "c2VjcmV0IHNoYXJlZCB3aXRoIHRoZSBzZWNvbmRhcmllcw==";
+//   };
+//
+// with # and // comments.
+#[derive(Debug, Default, Clone)]
+pub struct Keyring {
+    keys: HashMap<Domain, Arc<TsigKey>>,
+}
+
+impl Keyring {
+    pub fn insert(&mut self, key: TsigKey) {
+        self.keys.insert(key.name.clone(), Arc::new(key));
+    }
+
+    pub fn get(&self, name: &Domain) -> Option<Arc<TsigKey>> {
+        self.keys.get(&name.to_lowercase()).cloned()
+    }
+
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let text =
+            std::fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+        Self::parse_str(&text).with_context(|| format!("{}", path.display()))
+    }
+
+    pub fn parse_str(text: &str) -> Result<Self> {
+        let tokens = tokenize(text)?;
+        let mut tokens = tokens.iter().map(String::as_str);
+        let mut keyring = Self::default();
+
+        while let Some(token) = tokens.next() {
+            ensure!(token == "key", "expected a key statement, got {token}");
+            let name: Domain = tokens.next().context("key has no name")?.parse()?;
+            ensure!(tokens.next() == Some("{"), "expected {{ after key {name}");
+
+            let mut algorithm = None;
+            let mut secret = None;
+            loop {
+                match tokens.next() {
+                    Some("}") => break,
+                    Some("algorithm") => algorithm = Some(tokens.next().unwrap_or("").parse()?),
+                    Some("secret") => {
+                        let encoded = tokens.next().context("secret has no value")?;
+                        secret = Some(
+                            STANDARD
+                                .decode(encoded)
+                                .with_context(|| format!("secret for {name} isn't base64"))?,
+                        );
+                    }
+                    Some(other) => bail!("unexpected {other} in key {name}"),
+                    None => bail!("key {name} is missing its closing }}"),
+                }
+                ensure!(tokens.next() == Some(";"), "expected ; in key {name}");
+            }
+            ensure!(tokens.next() == Some(";"), "expected ; after key {name}");
+
+            let algorithm = algorithm.with_context(|| format!("key {name} has no algorithm"))?;
+            let secret = secret.with_context(|| format!("key {name} has no secret"))?;
+            keyring.insert(TsigKey::new(name, algorithm, &secret));
+        }
+
+        Ok(keyring)
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+}
+
+// split a key file into words, quoted strings and the punctuation between them
+fn tokenize(text: &str) -> Result<Vec<String>> {
+    let mut tokens = Vec::new();
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {}
+            '#' => while chars.next_if(|c| *c != '\n').is_some() {},
+            '/' if chars.peek() == Some(&'/') => while chars.next_if(|c| *c != '\n').is_some() {},
+            '{' | '}' | ';' => tokens.push(c.to_string()),
+            '"' => {
+                let mut token = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some(c) => token.push(c),
+                        None => bail!("unterminated string"),
+                    }
+                }
+                tokens.push(token);
+            }
+            c => {
+                let mut token = c.to_string();
+                while let Some(c) =
+                    chars.next_if(|c| !c.is_whitespace() && !matches!(c, '{' | '}' | ';' | '"'))
+                {
+                    token.push(c);
+                }
+                tokens.push(token);
+            }
+        }
+    }
+
+    Ok(tokens)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn loads_bind_key_statements() {
+        let keyring = Keyring::parse_str(
+            r#"
+            # the key our secondaries use
+            key "Transfer.Example.com." {
+                algorithm hmac-sha256;
+                secret "c2VjcmV0"; // "secret"
+            };
+            key update { algorithm HMAC-SHA512; secret "dXBkYXRl"; };
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(keyring.len(), 2);
+        let key = keyring
+            .get(&"transfer.example.com".parse().unwrap())
+            .unwrap();
+        assert_eq!(key.algorithm, TsigAlgorithm::HmacSha256);
+        assert_eq!(
+            key.sign(b"data"),
+            TsigKey::new(key.name.clone(), key.algorithm, b"secret").sign(b"data")
+        );
+        assert!(key.verify(b"data", &key.sign(b"data")));
+        assert!(!key.verify(b"other", &key.sign(b"data")));
+        assert_eq!(
+            keyring.get(&"update".parse().unwrap()).unwrap().algorithm,
+            TsigAlgorithm::HmacSha512
+        );
+
+        assert!(Keyring::parse_str(r#"key k { algorithm hmac-md5; secret "AA=="; };"#).is_err());
+        assert!(Keyring::parse_str(r#"key k { algorithm hmac-sha256; };"#).is_err());
+        assert!(Keyring::parse_str(r#"key k { secret "AA=="; "#).is_err());
+    }
+}
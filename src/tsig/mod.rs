@@ -0,0 +1,7 @@
+mod handler;
+mod key;
+mod sign;
+
+pub use handler::*;
+pub use key::*;
+pub use sign::*;
@@ -0,0 +1,471 @@
+use crate::dns::{
+    CLASS_ANY, DnsAnswer, DnsAnswerSet, DnsHeader, DnsMessage, DnsQuestionSet, Domain,
+    QuestionType, RecordData, ResponseCode, Tsig,
+};
+use crate::parse::DnsData;
+use crate::tsig::{Keyring, TsigKey};
+use anyhow::{Context, Result, bail, ensure};
+use bytes::{BufMut, Bytes, BytesMut};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// how far apart our clock and the other side's can be (RFC 8945 S10)
+pub const TSIG_FUDGE: u16 = 300;
+
+// TSIG errors, which go in the TSIG record rather than the header (RFC 8945 S3)
+pub const TSIG_BADSIG: u16 = 16;
+pub const TSIG_BADKEY: u16 = 17;
+pub const TSIG_BADTIME: u16 = 18;
+
+// how many messages in a row of a transfer can go unsigned (RFC 8945 S5.3.1)
+const MAX_UNSIGNED_MESSAGES: usize = 99;
+
+fn error_name(error: u16) -> String {
+    match error {
+        TSIG_BADSIG => "BADSIG".to_string(),
+        TSIG_BADKEY => "BADKEY".to_string(),
+        TSIG_BADTIME => "BADTIME".to_string(),
+        other => format!("error {other}"),
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+fn within_fudge(tsig: &Tsig) -> bool {
+    now().abs_diff(tsig.time_signed) <= tsig.fudge as u64
+}
+
+// The TSIG record of a message and where it starts. It has to be the last record of the message,
+// so there can only be one.
+pub fn find_tsig(raw: &Bytes) -> Result<Option<(usize, DnsAnswer)>> {
+    let label_map = &mut HashMap::new();
+    let (current, header) = DnsHeader::decode(raw, 0, label_map)?;
+    let (current, _) =
+        DnsQuestionSet::decode(raw, current, header.question_count as usize, label_map)?;
+    let records = header.answer_record_count as usize + header.authority_record_count as usize;
+    let (mut current, _) = DnsAnswerSet::decode(raw, current, records, label_map)?;
+
+    let additional = header.additional_record_count as usize;
+    let mut found = None;
+    for i in 0..additional {
+        let (next, record) = DnsAnswer::decode(raw, current, label_map)?;
+        if record.qtype == QuestionType::TSIG {
+            ensure!(
+                i + 1 == additional,
+                "TSIG isn't the last record in the message"
+            );
+            found = Some((current, record));
+        }
+        current = next;
+    }
+    Ok(found)
+}
+
+fn tsig_data(record: &DnsAnswer) -> Result<Tsig> {
+    ensure!(
+        record.class == CLASS_ANY && record.ttl == 0,
+        "malformed TSIG record"
+    );
+    match record.rdata()? {
+        RecordData::TSIG(tsig) => Ok(tsig),
+        _ => bail!("TSIG record without TSIG data"),
+    }
+}
+
+// the message as it was before it was signed: no TSIG, and the ID it was sent with
+fn unsigned(raw: &[u8], start: usize, original_id: u16) -> Vec<u8> {
+    let mut message = raw[..start].to_vec();
+    message[..2].copy_from_slice(&original_id.to_be_bytes());
+    let additional = u16::from_be_bytes([message[10], message[11]]) - 1;
+    message[10..12].copy_from_slice(&additional.to_be_bytes());
+    message
+}
+
+// names are covered by the MAC in their canonical form, lowercase and uncompressed
+fn canonical(name: &Domain) -> Result<Bytes> {
+    name.to_lowercase().encode(0, &mut HashMap::new())
+}
+
+// the fields of the TSIG record that the MAC covers (RFC 8945 S4.3.3)
+fn variables(key_name: &Domain, tsig: &Tsig, timers_only: bool) -> Result<Bytes> {
+    let mut buf = BytesMut::new();
+    if !timers_only {
+        buf.extend_from_slice(&canonical(key_name)?);
+        buf.put_u16(CLASS_ANY);
+        buf.put_u32(0);
+        buf.extend_from_slice(&canonical(&tsig.algorithm)?);
+    }
+    buf.put_u16((tsig.time_signed >> 32) as u16);
+    buf.put_u32(tsig.time_signed as u32);
+    buf.put_u16(tsig.fudge);
+    if !timers_only {
+        buf.put_u16(tsig.error);
+        buf.put_u16(u16::try_from(tsig.other.len())?);
+        buf.extend_from_slice(&tsig.other);
+    }
+    Ok(buf.into())
+}
+
+// The signatures of one transaction: a request and its responses, which for a zone transfer can
+// be many messages. Each MAC also covers the one before it, so messages can't be dropped or
+// reordered without it showing (RFC 8945 S5.3).
+#[derive(Debug)]
+pub struct TsigSession {
+    key: Arc<TsigKey>,
+
+    // the MAC of the last signed message, None until the request is signed
+    prior_mac: Option<Bytes>,
+
+    // after the first response only the timers are covered, not the rest of the TSIG variables
+    timers_only: bool,
+
+    // messages of a transfer that came without a TSIG, they're covered by the next one that has
+    unsigned: Vec<u8>,
+    unsigned_count: usize,
+}
+
+impl TsigSession {
+    pub fn new(key: Arc<TsigKey>) -> Self {
+        Self {
+            key,
+            prior_mac: None,
+            timers_only: false,
+            unsigned: Vec::new(),
+            unsigned_count: 0,
+        }
+    }
+
+    pub fn key_name(&self) -> &Domain {
+        &self.key.name
+    }
+
+    // everything the MAC of `message` covers
+    fn digest(&self, message: &[u8], tsig: &Tsig) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+        if let Some(prior) = &self.prior_mac {
+            data.put_u16(u16::try_from(prior.len())?);
+            data.extend_from_slice(prior);
+        }
+        data.extend_from_slice(&self.unsigned);
+        data.extend_from_slice(message);
+        data.extend_from_slice(&variables(&self.key.name, tsig, self.timers_only)?);
+        Ok(data)
+    }
+
+    fn advance(&mut self, mac: Bytes) {
+        if self.prior_mac.is_some() {
+            self.timers_only = true;
+        }
+        self.prior_mac = Some(mac);
+        self.unsigned.clear();
+        self.unsigned_count = 0;
+    }
+
+    fn check_mac(&self, raw: &[u8], start: usize, tsig: &Tsig) -> Result<bool> {
+        let message = unsigned(raw, start, tsig.original_id);
+        Ok(self.key.verify(&self.digest(&message, tsig)?, &tsig.mac))
+    }
+
+    fn sign_with(&mut self, message: DnsMessage, mut tsig: Tsig) -> Result<DnsMessage> {
+        let mut additional = message.additional.clone();
+        additional.answers.retain(|r| r.qtype != QuestionType::TSIG);
+        let message = message.with_additional(additional.clone())?;
+
+        let encoded = message.encode(0, &mut HashMap::new())?;
+        tsig.mac = self.key.sign(&self.digest(&encoded, &tsig)?).into();
+        self.advance(tsig.mac.clone());
+
+        additional.answers.push(DnsAnswer {
+            name: self.key.name.clone(),
+            qtype: QuestionType::TSIG,
+            class: CLASS_ANY,
+            ttl: 0,
+            data: RecordData::TSIG(tsig).encode()?,
+        });
+        message.with_additional(additional)
+    }
+
+    // sign the next message of the transaction, the request first if we're the client
+    pub fn sign(&mut self, message: DnsMessage) -> Result<DnsMessage> {
+        let tsig = Tsig {
+            algorithm: self.key.algorithm.name(),
+            time_signed: now(),
+            fudge: TSIG_FUDGE,
+            original_id: message.header.packet_id,
+            ..Tsig::default()
+        };
+        self.sign_with(message, tsig)
+    }
+
+    // Check the next response of the transaction. Part way through a transfer a response can
+    // come unsigned, it's then covered by the next signed one.
+    pub fn verify(&mut self, raw: &Bytes) -> Result<()> {
+        let Some((start, record)) = find_tsig(raw)? else {
+            ensure!(
+                self.timers_only && self.unsigned_count < MAX_UNSIGNED_MESSAGES,
+                "response isn't signed"
+            );
+            self.unsigned.extend_from_slice(raw);
+            self.unsigned_count += 1;
+            return Ok(());
+        };
+
+        let tsig = tsig_data(&record)?;
+        ensure!(
+            record.name.eq_ignore_case(&self.key.name),
+            "response is signed with {} rather than {}",
+            record.name,
+            self.key.name
+        );
+
+        // errors about the key or signature come back unsigned, anything else has to be signed
+        if tsig.error != 0 && tsig.mac.is_empty() {
+            bail!("our signature was rejected: {}", error_name(tsig.error));
+        }
+        ensure!(
+            self.check_mac(raw, start, &tsig)?,
+            "response has a bad signature"
+        );
+        self.advance(tsig.mac.clone());
+
+        ensure!(
+            tsig.error == 0,
+            "our signature was rejected: {}",
+            error_name(tsig.error)
+        );
+        ensure!(
+            within_fudge(&tsig),
+            "response was signed too far from our clock's time"
+        );
+        Ok(())
+    }
+
+    // a transfer has to end on a signed message
+    pub fn finish(&self) -> Result<()> {
+        ensure!(
+            self.unsigned_count == 0,
+            "the last message of the transfer wasn't signed"
+        );
+        Ok(())
+    }
+}
+
+// a request whose TSIG didn't check out
+#[derive(Debug)]
+pub struct TsigFailure {
+    pub error: u16,
+    key_name: Domain,
+    tsig: Tsig,
+
+    // a request with a good signature sent at the wrong time still gets a signed response
+    session: Option<TsigSession>,
+}
+
+impl TsigFailure {
+    // NOTAUTH with the TSIG error (RFC 8945 S5.3.2)
+    pub fn response(self, reply: DnsMessage) -> Result<DnsMessage> {
+        let reply = reply.with_response_code(ResponseCode::NotAuth);
+        let tsig = Tsig {
+            algorithm: self.tsig.algorithm.clone(),
+            fudge: TSIG_FUDGE,
+            original_id: reply.header.packet_id,
+            error: self.error,
+            ..Tsig::default()
+        };
+
+        if let Some(mut session) = self.session {
+            // BADTIME keeps the client's time, so the client can check it, and tells it ours
+            let tsig = Tsig {
+                time_signed: self.tsig.time_signed,
+                other: Bytes::copy_from_slice(&now().to_be_bytes()[2..]),
+                ..tsig
+            };
+            return session.sign_with(reply, tsig);
+        }
+
+        let tsig = Tsig {
+            time_signed: now(),
+            ..tsig
+        };
+        let mut additional = reply.additional.clone();
+        additional.answers.push(DnsAnswer {
+            name: self.key_name,
+            qtype: QuestionType::TSIG,
+            class: CLASS_ANY,
+            ttl: 0,
+            data: RecordData::TSIG(tsig).encode()?,
+        });
+        reply.with_additional(additional)
+    }
+}
+
+// what the TSIG of a request says about it
+#[derive(Debug)]
+pub enum TsigCheck {
+    Unsigned,
+
+    // signed with a key we know, the responses should be signed in the same session
+    Verified(TsigSession),
+
+    Failed(TsigFailure),
+}
+
+// Check the TSIG on a request: first the key, then the MAC, then the time (RFC 8945 S5.2).
+pub fn verify_request(keyring: &Keyring, raw: &Bytes) -> Result<TsigCheck> {
+    let Some((start, record)) = find_tsig(raw)? else {
+        return Ok(TsigCheck::Unsigned);
+    };
+    let tsig = tsig_data(&record).context("bad TSIG record")?;
+
+    let failed = |error, session| {
+        Ok(TsigCheck::Failed(TsigFailure {
+            error,
+            key_name: record.name.clone(),
+            tsig: tsig.clone(),
+            session,
+        }))
+    };
+
+    let key = match keyring.get(&record.name) {
+        Some(key) if key.algorithm.name().eq_ignore_case(&tsig.algorithm) => key,
+        _ => return failed(TSIG_BADKEY, None),
+    };
+
+    let mut session = TsigSession::new(key);
+    if !session.check_mac(raw, start, &tsig)? {
+        return failed(TSIG_BADSIG, None);
+    }
+    session.advance(tsig.mac.clone());
+
+    if !within_fudge(&tsig) {
+        return failed(TSIG_BADTIME, Some(session));
+    }
+    Ok(TsigCheck::Verified(session))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::DnsQuestion;
+    use crate::tsig::TsigAlgorithm;
+
+    fn keyring() -> Keyring {
+        let mut keyring = Keyring::default();
+        keyring.insert(TsigKey::new(
+            "key.example".parse().unwrap(),
+            TsigAlgorithm::HmacSha384,
+            b"a shared secret",
+        ));
+        keyring
+    }
+
+    fn wire(message: &DnsMessage) -> Bytes {
+        message.encode(0, &mut HashMap::new()).unwrap()
+    }
+
+    fn request() -> DnsMessage {
+        DnsMessage::query(
+            42,
+            DnsQuestion::new("example.com".parse().unwrap(), QuestionType::AXFR),
+        )
+    }
+
+    #[test]
+    fn signs_and_verifies_a_transfer() {
+        let keyring = keyring();
+        let key = keyring.get(&"key.example".parse().unwrap()).unwrap();
+
+        let mut client = TsigSession::new(key);
+        let signed = wire(&client.sign(request()).unwrap());
+        let TsigCheck::Verified(mut server) = verify_request(&keyring, &signed).unwrap() else {
+            panic!("request didn't verify");
+        };
+
+        // a signed response, an unsigned one, then another signed one
+        let reply = request().as_reply();
+        client
+            .verify(&wire(&server.sign(reply.clone()).unwrap()))
+            .unwrap();
+        client.verify(&wire(&reply)).unwrap();
+        assert!(client.finish().is_err());
+        server.unsigned.extend_from_slice(&wire(&reply));
+        client
+            .verify(&wire(&server.sign(reply.clone()).unwrap()))
+            .unwrap();
+        client.finish().unwrap();
+
+        // the chain breaks if a message goes missing
+        server.sign(reply.clone()).unwrap();
+        assert!(client.verify(&wire(&server.sign(reply).unwrap())).is_err());
+    }
+
+    #[test]
+    fn rejects_bad_requests() {
+        let keyring = keyring();
+        let key = keyring.get(&"key.example".parse().unwrap()).unwrap();
+
+        // a key with the right name but the wrong secret
+        let forged = Arc::new(TsigKey::new(
+            key.name.clone(),
+            key.algorithm,
+            b"a guessed secret",
+        ));
+        let signed = wire(&TsigSession::new(forged).sign(request()).unwrap());
+        let TsigCheck::Failed(failure) = verify_request(&keyring, &signed).unwrap() else {
+            panic!("forged request verified");
+        };
+        assert_eq!(failure.error, TSIG_BADSIG);
+
+        let reply = failure.response(request().as_reply()).unwrap();
+        assert_eq!(reply.response_code(), ResponseCode::NotAuth as u8);
+        let mut client = TsigSession::new(key.clone());
+        client.sign(request()).unwrap();
+        let error = client.verify(&wire(&reply)).unwrap_err();
+        assert!(error.to_string().contains("BADSIG"));
+
+        // an unknown key
+        let other = Arc::new(TsigKey::new(
+            "other".parse().unwrap(),
+            key.algorithm,
+            b"secret",
+        ));
+        let signed = wire(&TsigSession::new(other).sign(request()).unwrap());
+        assert!(matches!(
+            verify_request(&keyring, &signed).unwrap(),
+            TsigCheck::Failed(TsigFailure {
+                error: TSIG_BADKEY,
+                ..
+            })
+        ));
+
+        // a good signature from too long ago gets a signed BADTIME
+        let mut client = TsigSession::new(key);
+        let tsig = Tsig {
+            algorithm: TsigAlgorithm::HmacSha384.name(),
+            time_signed: now() - 3600,
+            fudge: TSIG_FUDGE,
+            original_id: 42,
+            ..Tsig::default()
+        };
+        let signed = wire(&client.sign_with(request(), tsig).unwrap());
+        let TsigCheck::Failed(failure) = verify_request(&keyring, &signed).unwrap() else {
+            panic!("stale request verified");
+        };
+        assert_eq!(failure.error, TSIG_BADTIME);
+        let reply = wire(&failure.response(request().as_reply()).unwrap());
+        let error = client.verify(&reply).unwrap_err();
+        assert!(error.to_string().contains("BADTIME"));
+
+        // and an unsigned one is fine, it's up to the handlers what to make of it
+        assert!(matches!(
+            verify_request(&keyring, &wire(&request())).unwrap(),
+            TsigCheck::Unsigned
+        ));
+    }
+}
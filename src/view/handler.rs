@@ -0,0 +1,129 @@
+use crate::dns::{DnsMessage, Domain, IpPrefix, ResponseCode};
+use crate::handler::{DnsHandler, DnsRequest};
+use anyhow::Result;
+use async_trait::async_trait;
+use std::sync::Arc;
+use tracing::debug;
+
+// A named set of zones and resolver settings, as its own handler stack, for the clients that
+// match it by source address or by the TSIG key they signed with.
+#[derive(Debug)]
+pub struct View {
+    name: String,
+    clients: Vec<IpPrefix>,
+    keys: Vec<Domain>,
+    handler: Arc<dyn DnsHandler>,
+}
+
+impl View {
+    // a view nobody matches until it's given clients or keys
+    pub fn new(name: impl Into<String>, handler: Arc<dyn DnsHandler>) -> Self {
+        Self {
+            name: name.into(),
+            clients: Vec::new(),
+            keys: Vec::new(),
+            handler,
+        }
+    }
+
+    pub fn with_clients(mut self, clients: Vec<IpPrefix>) -> Self {
+        self.clients = clients;
+        self
+    }
+
+    pub fn with_keys(mut self, keys: Vec<Domain>) -> Self {
+        self.keys = keys;
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn matches(&self, request: &DnsRequest) -> bool {
+        let signed = request
+            .key
+            .as_ref()
+            .is_some_and(|key| self.keys.iter().any(|k| k.eq_ignore_case(key)));
+        let client = request.client.ip();
+        signed || self.clients.iter().any(|c| c.contains(&client))
+    }
+}
+
+// Split horizon: each request goes to the first view that matches it, so the same name can get
+// different answers inside and outside. Requests no view matches are refused. The TSIG key is
+// only known once a TsigHandler has checked the signature, so that has to wrap this.
+#[derive(Debug)]
+pub struct ViewHandler {
+    views: Vec<View>,
+}
+
+impl ViewHandler {
+    pub fn new(views: Vec<View>) -> Self {
+        Self { views }
+    }
+
+    pub fn view(&self, request: &DnsRequest) -> Option<&View> {
+        self.views.iter().find(|v| v.matches(request))
+    }
+}
+
+#[async_trait]
+impl DnsHandler for ViewHandler {
+    async fn handle(&self, request: &DnsRequest) -> Result<Option<DnsMessage>> {
+        let Some(view) = self.view(request) else {
+            debug!("refusing {}, no view matches it", request.client);
+            let reply = request.message.clone().as_reply();
+            return Ok(Some(reply.with_response_code(ResponseCode::Refused)));
+        };
+        debug!("{} is in view {}", request.client, view.name);
+        view.handler.handle(request).await
+    }
+
+    // zone transfers differ between views too
+    async fn handle_stream(&self, request: &DnsRequest) -> Result<Vec<DnsMessage>> {
+        let Some(view) = self.view(request) else {
+            return Ok(self.handle(request).await?.into_iter().collect());
+        };
+        view.handler.handle_stream(request).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handler::{EchoHandler, Transport};
+    use bytes::Bytes;
+
+    fn request(client: &str, key: Option<&str>) -> DnsRequest {
+        DnsRequest {
+            message: DnsMessage::default(),
+            client: client.parse().unwrap(),
+            transport: Transport::Udp,
+            raw: Bytes::new(),
+            key: key.map(|k| k.parse().unwrap()),
+        }
+    }
+
+    #[test]
+    fn picks_the_first_matching_view() {
+        let echo = Arc::new(EchoHandler);
+        let views = ViewHandler::new(vec![
+            View::new("internal", echo.clone())
+                .with_clients(vec![
+                    "10.0.0.0/8".parse().unwrap(),
+                    "2001:db8::/32".parse().unwrap(),
+                ])
+                .with_keys(vec!["internal-key".parse().unwrap()]),
+            View::new("external", echo).with_clients(vec!["0.0.0.0/0".parse().unwrap()]),
+        ]);
+        let view = |client, key| views.view(&request(client, key)).map(View::name);
+
+        assert_eq!(view("10.1.2.3:53", None), Some("internal"));
+        assert_eq!(view("[2001:db8::1]:53", None), Some("internal"));
+        assert_eq!(view("[::ffff:10.1.2.3]:53", None), Some("internal"));
+        assert_eq!(view("192.0.2.1:53", None), Some("external"));
+        assert_eq!(view("192.0.2.1:53", Some("Internal-Key")), Some("internal"));
+        assert_eq!(view("[2001:db9::1]:53", None), None);
+    }
+}
@@ -0,0 +1,792 @@
+use crate::dns::{
+    DnsAnswer, DnsAnswerSet, DnsMessage, DnsQuestion, Domain, Edns, Opcode, QuestionType,
+    RecordData, ResponseCode,
+};
+use crate::dnssec::nsec3_hash;
+use crate::handler::{DnsHandler, DnsRequest, Transport};
+use crate::zone::{
+    NameNode, Zone, ZoneStore, axfr_records, ixfr_records, soa_serial, transfer_messages,
+};
+use anyhow::Result;
+use async_trait::async_trait;
+use std::net::IpAddr;
+use std::sync::Arc;
+use tracing::{debug, info, warn};
+
+// how many CNAMEs we'll follow inside a zone before deciding it's a loop
+const MAX_CNAME_CHAIN: usize = 16;
+
+// where a name lands in a zone
+enum Found<'a> {
+    // the name exists (possibly as an empty non-terminal)
+    Exact(&'a NameNode),
+
+    // the name doesn't exist but a wildcard at its closest encloser covers it (RFC 4592 S3.3)
+    Wildcard(&'a NameNode, Domain),
+
+    // the name is at or below a zone cut, the node holds the NS records for the child zone
+    Delegation(&'a NameNode),
+
+    // the name doesn't exist, and neither does a wildcard at its closest encloser
+    NxDomain(Domain),
+}
+
+// The sections of an authoritative response, before they're put into a message.
+#[derive(Debug, Default)]
+pub struct ZoneAnswer {
+    pub response_code: ResponseCode,
+
+    // false for referrals, the data there belongs to the child zone
+    pub authoritative: bool,
+    pub answers: Vec<DnsAnswer>,
+    pub authority: Vec<DnsAnswer>,
+    pub additional: Vec<DnsAnswer>,
+}
+
+impl ZoneAnswer {
+    fn add_authority(&mut self, records: Vec<DnsAnswer>) {
+        for record in records {
+            if !self.authority.contains(&record) {
+                self.authority.push(record);
+            }
+        }
+    }
+}
+
+impl Zone {
+    // Answer a question for a name in this zone, following the algorithm in RFC 1034 S4.3.2.
+    // With `dnssec` set, a signed zone also hands out the signatures over what it answers with
+    // and the NSEC or NSEC3 records proving what it doesn't have (RFC 4035 S3.1).
+    pub fn answer(&self, question: &DnsQuestion, dnssec: bool) -> ZoneAnswer {
+        let dnssec = dnssec && self.is_signed();
+        let mut answer = ZoneAnswer {
+            authoritative: true,
+            ..ZoneAnswer::default()
+        };
+        let mut name = question.name.clone();
+
+        for _ in 0..MAX_CNAME_CHAIN {
+            let (node, wildcard) = match self.find(&name, &question.qtype) {
+                Found::Exact(node) => (node, None),
+                Found::Wildcard(node, encloser) => (node, Some(encloser)),
+                Found::Delegation(cut) => {
+                    // a referral on its own isn't authoritative, but CNAMEs we found on the way
+                    // there are
+                    answer.authoritative = !answer.answers.is_empty();
+                    let ns: Vec<DnsAnswer> =
+                        cut.rrset(&QuestionType::NS).into_iter().cloned().collect();
+                    answer.additional = self.addresses(&ns);
+                    if dnssec {
+                        // the DS records, or proof that the child zone isn't signed
+                        let owner = ns[0].name.clone();
+                        let ds = self.signed_rrset(cut, &QuestionType::DS, &owner);
+                        answer.authority = ns;
+                        match ds.is_empty() {
+                            true => answer.add_authority(self.nsec_at(&owner)),
+                            false => answer.add_authority(ds),
+                        }
+                    } else {
+                        answer.authority = ns;
+                    }
+                    return answer;
+                }
+                Found::NxDomain(encloser) => {
+                    answer.response_code = ResponseCode::NxDomain;
+                    answer.authority = self.negative_soa(dnssec);
+                    if dnssec {
+                        answer.add_authority(self.encloser_proof(&name, &encloser));
+                        answer.add_authority(self.nsec_covering(&encloser.prepend("*")));
+                    }
+                    return answer;
+                }
+            };
+
+            // records at a wildcard are handed out as if they belonged to the name asked for
+            let synthesise = |r: &DnsAnswer| DnsAnswer {
+                name: name.clone(),
+                ..r.clone()
+            };
+
+            // an answer from a wildcard comes with proof the name itself doesn't exist
+            if dnssec && let Some(encloser) = &wildcard {
+                answer.add_authority(self.wildcard_proof(&name, encloser));
+            }
+
+            let rrset = node.rrset(&question.qtype);
+            if !rrset.is_empty() {
+                answer.answers.extend(rrset.into_iter().map(synthesise));
+                if dnssec {
+                    answer
+                        .answers
+                        .extend(self.signatures(node, &question.qtype, &name));
+                }
+                answer.additional = self.addresses(&answer.answers);
+                return answer;
+            }
+
+            let Some(cname) = node.rrset(&QuestionType::CNAME).into_iter().next() else {
+                // the name exists but has nothing of the type asked for
+                answer.add_authority(self.negative_soa(dnssec));
+                if dnssec {
+                    match &wildcard {
+                        Some(encloser) => {
+                            answer.add_authority(self.encloser_proof(&name, encloser));
+                            answer.add_authority(self.nsec_at(&encloser.prepend("*")));
+                        }
+                        None => answer.add_authority(self.nsec_at(&name)),
+                    }
+                }
+                return answer;
+            };
+            answer.answers.push(synthesise(cname));
+            if dnssec {
+                answer
+                    .answers
+                    .extend(self.signatures(node, &QuestionType::CNAME, &name));
+            }
+
+            // we can only carry on if the target is ours, otherwise the client has to go and ask
+            // somebody else
+            match cname.rdata() {
+                Ok(RecordData::CNAME(target)) if target.is_subdomain_of(&self.origin) => {
+                    debug!("following CNAME {name} -> {target}");
+                    name = target;
+                }
+                _ => return answer,
+            }
+        }
+
+        // a CNAME loop in our own data, the best we can do is say what we followed
+        debug!("CNAME chain for {} is too long", question.name);
+        answer.response_code = ResponseCode::ServFail;
+        answer
+    }
+
+    fn find(&self, name: &Domain, qtype: &QuestionType) -> Found<'_> {
+        if !name.is_subdomain_of(&self.origin) {
+            return Found::NxDomain(self.origin.clone());
+        }
+
+        let walk = self.tree().walk(name);
+
+        // Look for a zone cut between the apex and the name, the apex's own NS records don't
+        // count. The DS records at a cut are ours rather than the child's, so a DS query for
+        // the cut itself doesn't stop there.
+        let end = match (qtype, walk.exact) {
+            (QuestionType::DS, true) => walk.path.len() - 1,
+            _ => walk.path.len(),
+        };
+        for node in walk.path[..end].iter().skip(self.origin.labels.len() + 1) {
+            if node.has(&QuestionType::NS) {
+                return Found::Delegation(node);
+            }
+        }
+
+        if walk.exact {
+            return Found::Exact(walk.closest_encloser());
+        }
+
+        // the path has a node for the root and then one per label
+        let encloser = name.suffix(walk.path.len() - 1);
+        match walk.closest_encloser().child("*") {
+            Some(wildcard) => Found::Wildcard(wildcard, encloser),
+            None => Found::NxDomain(encloser),
+        }
+    }
+
+    // the SOA for negative responses, with the TTL negative answers should be cached for
+    // (RFC 2308 S3), and its signature when the client wants that too
+    fn negative_soa(&self, dnssec: bool) -> Vec<DnsAnswer> {
+        let Some(record) = self.soa_record() else {
+            return Vec::new();
+        };
+
+        let mut record = record.clone();
+        if let Ok(RecordData::SOA(soa)) = record.rdata() {
+            record.ttl = record.ttl.min(soa.minimum);
+        }
+        let mut records = vec![record];
+        if dnssec && let Some(apex) = self.node(&self.origin) {
+            records.extend(self.signatures(apex, &QuestionType::SOA, &self.origin));
+        }
+        records
+    }
+
+    // the RRSIGs at a node over records of `qtype`, named like the records they go out with
+    fn signatures(&self, node: &NameNode, qtype: &QuestionType, name: &Domain) -> Vec<DnsAnswer> {
+        node.rrset(&QuestionType::RRSIG)
+            .into_iter()
+            .filter(|r| matches!(r.rdata(), Ok(RecordData::RRSIG(rrsig)) if rrsig.type_covered == qtype.code()))
+            .map(|r| DnsAnswer {
+                name: name.clone(),
+                ..r.clone()
+            })
+            .collect()
+    }
+
+    // an RRset followed by its signatures
+    fn signed_rrset(&self, node: &NameNode, qtype: &QuestionType, name: &Domain) -> Vec<DnsAnswer> {
+        let mut records: Vec<DnsAnswer> = node.rrset(qtype).into_iter().cloned().collect();
+        if !records.is_empty() {
+            records.extend(self.signatures(node, qtype, name));
+        }
+        records
+    }
+
+    // The NSEC or NSEC3 record for a name that exists, saying which types it has. Empty
+    // non-terminals have no NSEC of their own, the one covering them does the job instead.
+    fn nsec_at(&self, name: &Domain) -> Vec<DnsAnswer> {
+        if let Some(param) = self.nsec3_param() {
+            return self.nsec3_matching(&nsec3_hash(name, &param.salt, param.iterations));
+        }
+
+        match self.node(name) {
+            Some(node) if node.has(&QuestionType::NSEC) => {
+                self.signed_rrset(node, &QuestionType::NSEC, name)
+            }
+            _ => self.nsec_covering(name),
+        }
+    }
+
+    // the NSEC or NSEC3 record proving a name doesn't exist
+    fn nsec_covering(&self, name: &Domain) -> Vec<DnsAnswer> {
+        if let Some(param) = self.nsec3_param() {
+            return self.nsec3_covering(&nsec3_hash(name, &param.salt, param.iterations));
+        }
+
+        let Some(owner) = self.nsec_owner(name) else {
+            return Vec::new();
+        };
+        self.node(owner)
+            .map(|node| self.signed_rrset(node, &QuestionType::NSEC, owner))
+            .unwrap_or_default()
+    }
+
+    // Proof that `encloser` is the closest thing to `name` the zone has. With NSEC that's the
+    // record covering the name, NSEC3 needs the encloser's own record and one covering the
+    // next closer name as well (RFC 5155 S7.2.1).
+    fn encloser_proof(&self, name: &Domain, encloser: &Domain) -> Vec<DnsAnswer> {
+        if self.nsec3_param().is_none() {
+            return self.nsec_covering(name);
+        }
+
+        let mut records = self.nsec_at(encloser);
+        records.extend(self.nsec_covering(&name.suffix(encloser.labels.len() + 1)));
+        records
+    }
+
+    // Proof that a wildcard answer was the right one, because the name asked for doesn't exist.
+    // The RRSIG labels count tells the client the encloser, so NSEC3 only needs the next
+    // closer name covered (RFC 5155 S7.2.6).
+    fn wildcard_proof(&self, name: &Domain, encloser: &Domain) -> Vec<DnsAnswer> {
+        match self.nsec3_param() {
+            Some(_) => self.nsec_covering(&name.suffix(encloser.labels.len() + 1)),
+            None => self.nsec_covering(name),
+        }
+    }
+
+    // Addresses we hold for the names the records point at, for the additional section. This
+    // is also how referrals get their glue, which lives below the zone cut.
+    fn addresses(&self, records: &[DnsAnswer]) -> Vec<DnsAnswer> {
+        let mut additional: Vec<DnsAnswer> = Vec::new();
+        for record in records {
+            let target = match record.rdata() {
+                Ok(RecordData::NS(target)) => target,
+                Ok(RecordData::MX { exchange, .. }) => exchange,
+                _ => continue,
+            };
+
+            let Some(node) = self.node(&target) else {
+                continue;
+            };
+            let addresses = node
+                .rrset(&QuestionType::A)
+                .into_iter()
+                .chain(node.rrset(&QuestionType::AAAA));
+            for address in addresses {
+                if !additional.contains(address) {
+                    additional.push(address.clone());
+                }
+            }
+        }
+        additional
+    }
+}
+
+// answers from the zones we're authoritative for, and refuses anything else unless there's a
+// fallback handler to pass it on to
+#[derive(Debug)]
+pub struct AuthoritativeHandler {
+    zones: Arc<ZoneStore>,
+    fallback: Option<Arc<dyn DnsHandler>>,
+
+    // the clients allowed to transfer our zones, nobody unless we're told otherwise
+    transfer_allowed: Vec<IpAddr>,
+
+    // TSIG keys that allow a transfer from anywhere
+    transfer_keys: Vec<Domain>,
+}
+
+impl AuthoritativeHandler {
+    pub fn new(zones: Arc<ZoneStore>) -> Self {
+        Self {
+            zones,
+            fallback: None,
+            transfer_allowed: Vec::new(),
+            transfer_keys: Vec::new(),
+        }
+    }
+
+    // where queries for names outside our zones go, e.g. a resolver
+    pub fn with_fallback(mut self, fallback: Arc<dyn DnsHandler>) -> Self {
+        self.fallback = Some(fallback);
+        self
+    }
+
+    pub fn with_transfer_allowed(mut self, clients: Vec<IpAddr>) -> Self {
+        self.transfer_allowed = clients;
+        self
+    }
+
+    pub fn with_transfer_keys(mut self, keys: Vec<Domain>) -> Self {
+        self.transfer_keys = keys;
+        self
+    }
+
+    // the zone to transfer, if it's one of ours exactly and the client is on the allow list or
+    // signed the request with one of the transfer keys
+    fn transferable(&self, request: &DnsRequest, question: &DnsQuestion) -> Option<Arc<Zone>> {
+        let client = request.client.ip().to_canonical();
+        let Some(zone) = self.zones.get(&question.name) else {
+            debug!(
+                "refusing transfer of {}, not one of our zones",
+                question.name
+            );
+            return None;
+        };
+
+        let signed = request
+            .key
+            .as_ref()
+            .is_some_and(|key| self.transfer_keys.iter().any(|k| k.eq_ignore_case(key)));
+        if !signed && !self.transfer_allowed.contains(&client) {
+            warn!("refusing transfer of {} to {client}", question.name);
+            return None;
+        }
+
+        Some(zone)
+    }
+
+    // answer an AXFR or IXFR with as many messages as it takes
+    fn transfer(&self, request: &DnsRequest, question: &DnsQuestion) -> Result<Vec<DnsMessage>> {
+        let reply = request.message.clone().as_reply();
+        let Some(zone) = self.transferable(request, question) else {
+            return Ok(vec![reply.with_response_code(ResponseCode::Refused)]);
+        };
+
+        let records = match question.qtype {
+            QuestionType::IXFR => {
+                // the client tells us what it has with an SOA in the authority section
+                let Some(serial) = request
+                    .message
+                    .authority
+                    .answers
+                    .iter()
+                    .find(|r| r.qtype == QuestionType::SOA)
+                    .and_then(|r| soa_serial(r).ok())
+                else {
+                    return Ok(vec![reply.with_response_code(ResponseCode::FormErr)]);
+                };
+
+                match ixfr_records(&zone, serial)? {
+                    Some(records) => records,
+                    None => {
+                        debug!("no journal for {} since {serial}", question.name);
+                        axfr_records(&zone)?
+                    }
+                }
+            }
+            _ => axfr_records(&zone)?,
+        };
+
+        info!(
+            "transferring {} ({:?}, {} records) to {}",
+            question.name,
+            question.qtype,
+            records.len(),
+            request.client
+        );
+        transfer_messages(&request.message, records)
+    }
+}
+
+fn is_transfer(qtype: &QuestionType) -> bool {
+    matches!(qtype, QuestionType::AXFR | QuestionType::IXFR)
+}
+
+#[async_trait]
+impl DnsHandler for AuthoritativeHandler {
+    async fn handle(&self, request: &DnsRequest) -> Result<Option<DnsMessage>> {
+        let mut reply = request.message.clone().as_reply();
+
+        // Zone transfers need a stream. An IXFR over UDP gets just our SOA, which tells the
+        // client to try again over TCP if it's out of date (RFC 1995 S2).
+        if let Some(question) = request.message.question()
+            && is_transfer(&question.qtype)
+        {
+            if request.transport != Transport::Udp {
+                return Ok(self.transfer(request, question)?.into_iter().next());
+            }
+
+            return Ok(Some(match self.transferable(request, question) {
+                Some(zone) if question.qtype == QuestionType::IXFR => {
+                    let soa = zone.soa_record().cloned().into_iter().collect();
+                    transfer_messages(&request.message, soa)?.remove(0)
+                }
+                _ => reply.with_response_code(ResponseCode::Refused),
+            }));
+        }
+
+        // only standard queries here
+        if request.message.header.opcode != Opcode::Query as u8 {
+            return Ok(Some(reply.with_response_code(ResponseCode::NotImp)));
+        }
+
+        let Some(question) = request.message.question() else {
+            return Ok(Some(reply.with_response_code(ResponseCode::FormErr)));
+        };
+
+        let Some(zone) = self.zones.find(&question.name) else {
+            if let Some(fallback) = &self.fallback {
+                return fallback.handle(request).await;
+            }
+            debug!("refusing {}, not in any of our zones", question.name);
+            return Ok(Some(reply.with_response_code(ResponseCode::Refused)));
+        };
+
+        // the DO bit asks for the DNSSEC records, and we echo it back to say we sent them
+        // (RFC 3225 S3)
+        let edns = request.message.edns();
+        let dnssec_ok = edns.as_ref().is_some_and(|e| e.dnssec_ok);
+
+        let answer = zone.answer(question, dnssec_ok);
+        reply.header.auth_answer = answer.authoritative;
+        let reply = reply
+            .with_response_code(answer.response_code)
+            .with_answers(DnsAnswerSet {
+                answers: answer.answers,
+            })?
+            .with_authority(DnsAnswerSet {
+                answers: answer.authority,
+            })?
+            .with_additional(DnsAnswerSet {
+                answers: answer.additional,
+            })?;
+        Ok(Some(match edns {
+            Some(_) => reply.with_edns(Edns {
+                dnssec_ok,
+                ..Edns::default()
+            })?,
+            None => reply,
+        }))
+    }
+
+    async fn handle_stream(&self, request: &DnsRequest) -> Result<Vec<DnsMessage>> {
+        match request.message.question() {
+            Some(question) if is_transfer(&question.qtype) => self.transfer(request, question),
+            _ => Ok(self.handle(request).await?.into_iter().collect()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::Nsec3Param;
+    use crate::dnssec::{
+        Denial, SignerConfig, SigningKey, ZoneSigner, prove_denial, prove_wildcard,
+    };
+    use crate::zone::ZoneParser;
+    use bytes::Bytes;
+
+    const ZONE: &str = r#"
+$TTL 300
+@           SOA ns1 hostmaster 1 3600 600 86400 60
+            NS  ns1
+            MX  10 mail
+ns1         A   192.0.2.1
+mail        A   192.0.2.2
+www         A   192.0.2.3
+alias       CNAME www
+outside     CNAME www.example.org.
+loop1       CNAME loop2
+loop2       CNAME loop1
+dangling    CNAME missing
+*.wild      TXT "wildcard"
+host.wild   A   192.0.2.4
+a.b.c       A   192.0.2.5
+child       NS  ns.child
+ns.child    A   192.0.2.6
+ns.child    AAAA 2001:db8::6
+"#;
+
+    fn zone() -> Zone {
+        let origin: Domain = "example.com".parse().unwrap();
+        Zone::new(
+            origin.clone(),
+            ZoneParser::parse_str(origin, ZONE, "zone").unwrap(),
+        )
+        .unwrap()
+    }
+
+    fn ask(zone: &Zone, name: &str, qtype: QuestionType) -> ZoneAnswer {
+        zone.answer(&DnsQuestion::new(name.parse().unwrap(), qtype), false)
+    }
+
+    fn names(records: &[DnsAnswer]) -> Vec<String> {
+        records.iter().map(|r| r.name.to_string()).collect()
+    }
+
+    #[test]
+    fn answers_with_additional_addresses() {
+        let zone = zone();
+        let answer = ask(&zone, "example.com", QuestionType::MX);
+        assert!(answer.authoritative);
+        assert_eq!(answer.response_code, ResponseCode::NoError);
+        assert_eq!(answer.answers.len(), 1);
+        assert_eq!(names(&answer.additional), vec!["mail.example.com."]);
+
+        let answer = ask(&zone, "WWW.example.com", QuestionType::A);
+        assert_eq!(answer.answers.len(), 1);
+        assert!(answer.authority.is_empty());
+    }
+
+    #[test]
+    fn nxdomain_and_nodata() {
+        let zone = zone();
+        let answer = ask(&zone, "nope.example.com", QuestionType::A);
+        assert_eq!(answer.response_code, ResponseCode::NxDomain);
+        assert!(answer.answers.is_empty());
+        assert_eq!(answer.authority[0].qtype, QuestionType::SOA);
+
+        // the SOA TTL is capped at the minimum field
+        assert_eq!(answer.authority[0].ttl, 60);
+
+        let answer = ask(&zone, "www.example.com", QuestionType::MX);
+        assert_eq!(answer.response_code, ResponseCode::NoError);
+        assert!(answer.answers.is_empty());
+        assert_eq!(answer.authority[0].qtype, QuestionType::SOA);
+
+        // b.c exists because a.b.c does, so it's NODATA rather than NXDOMAIN
+        let answer = ask(&zone, "b.c.example.com", QuestionType::A);
+        assert_eq!(answer.response_code, ResponseCode::NoError);
+        assert_eq!(answer.authority[0].qtype, QuestionType::SOA);
+    }
+
+    #[test]
+    fn follows_cnames() {
+        let zone = zone();
+        let answer = ask(&zone, "alias.example.com", QuestionType::A);
+        assert_eq!(names(&answer.answers), vec![
+            "alias.example.com.",
+            "www.example.com."
+        ]);
+
+        // asking for the CNAME itself doesn't follow it
+        let answer = ask(&zone, "alias.example.com", QuestionType::CNAME);
+        assert_eq!(answer.answers.len(), 1);
+
+        let answer = ask(&zone, "outside.example.com", QuestionType::A);
+        assert_eq!(answer.answers.len(), 1);
+        assert_eq!(answer.response_code, ResponseCode::NoError);
+
+        // RFC 6604, the rcode is for the last name in the chain
+        let answer = ask(&zone, "dangling.example.com", QuestionType::A);
+        assert_eq!(answer.answers.len(), 1);
+        assert_eq!(answer.response_code, ResponseCode::NxDomain);
+
+        let answer = ask(&zone, "loop1.example.com", QuestionType::A);
+        assert_eq!(answer.response_code, ResponseCode::ServFail);
+    }
+
+    #[test]
+    fn expands_wildcards() {
+        let zone = zone();
+        let answer = ask(&zone, "anything.wild.example.com", QuestionType::TXT);
+        assert_eq!(names(&answer.answers), vec!["anything.wild.example.com."]);
+
+        // wildcards only cover names that don't exist
+        let answer = ask(&zone, "host.wild.example.com", QuestionType::TXT);
+        assert!(answer.answers.is_empty());
+        assert_eq!(answer.response_code, ResponseCode::NoError);
+
+        // a name below one that exists isn't covered, its closest encloser has no wildcard
+        // (RFC 4592 S2.2.1)
+        let answer = ask(&zone, "x.host.wild.example.com", QuestionType::TXT);
+        assert_eq!(answer.response_code, ResponseCode::NxDomain);
+
+        // but any number of labels when the closest encloser is the wildcard's parent
+        let answer = ask(&zone, "a.b.wild.example.com", QuestionType::TXT);
+        assert_eq!(names(&answer.answers), vec!["a.b.wild.example.com."]);
+
+        let answer = ask(&zone, "anything.wild.example.com", QuestionType::A);
+        assert!(answer.answers.is_empty());
+        assert_eq!(answer.authority[0].qtype, QuestionType::SOA);
+    }
+
+    #[test]
+    fn wildcard_cnames_are_followed() {
+        let origin: Domain = "example.com".parse().unwrap();
+        let text = "$TTL 60\n@ SOA ns hm 1 2 3 4 5\n* CNAME www\nwww A 192.0.2.1\n";
+        let zone = Zone::new(
+            origin.clone(),
+            ZoneParser::parse_str(origin, text, "zone").unwrap(),
+        )
+        .unwrap();
+
+        let answer = ask(&zone, "foo.example.com", QuestionType::A);
+        assert_eq!(names(&answer.answers), vec![
+            "foo.example.com.",
+            "www.example.com."
+        ]);
+    }
+
+    #[test]
+    fn refers_to_child_zones_with_glue() {
+        let zone = zone();
+        for name in ["child.example.com", "www.child.example.com"] {
+            let answer = ask(&zone, name, QuestionType::A);
+            assert!(!answer.authoritative);
+            assert_eq!(answer.response_code, ResponseCode::NoError);
+            assert!(answer.answers.is_empty());
+            assert_eq!(names(&answer.authority), vec!["child.example.com."]);
+
+            // glue for both address families
+            assert_eq!(names(&answer.additional), vec![
+                "ns.child.example.com.",
+                "ns.child.example.com."
+            ]);
+            let types: Vec<QuestionType> =
+                answer.additional.iter().map(|r| r.qtype.clone()).collect();
+            assert_eq!(types, vec![QuestionType::A, QuestionType::AAAA]);
+        }
+
+        // the apex NS records aren't a cut
+        let answer = ask(&zone, "example.com", QuestionType::NS);
+        assert!(answer.authoritative);
+        assert_eq!(answer.answers.len(), 1);
+    }
+
+    fn signed(nsec3: Option<Nsec3Param>) -> Zone {
+        let origin: Domain = "example.com".parse().unwrap();
+        let key = SigningKey::generate(origin, 257, 15).unwrap();
+        ZoneSigner::new(vec![key])
+            .unwrap()
+            .with_config(SignerConfig {
+                nsec3,
+                ..SignerConfig::default()
+            })
+            .sign(&zone())
+            .unwrap()
+    }
+
+    fn ask_dnssec(zone: &Zone, name: &str, qtype: QuestionType) -> ZoneAnswer {
+        zone.answer(&DnsQuestion::new(name.parse().unwrap(), qtype), true)
+    }
+
+    fn signatures(records: &[DnsAnswer], qtype: QuestionType) -> Vec<(String, u8)> {
+        records
+            .iter()
+            .filter_map(|r| match r.rdata() {
+                Ok(RecordData::RRSIG(rrsig)) if rrsig.type_covered == qtype.code() => {
+                    Some((r.name.to_string(), rrsig.labels))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn signed_zones_only_send_dnssec_records_when_asked() {
+        let zone = signed(None);
+        let answer = ask(&zone, "www.example.com", QuestionType::A);
+        assert_eq!(answer.answers.len(), 1);
+
+        let answer = ask(&zone, "nope.example.com", QuestionType::A);
+        assert_eq!(answer.authority.len(), 1);
+
+        let answer = ask_dnssec(&zone, "www.example.com", QuestionType::A);
+        assert_eq!(signatures(&answer.answers, QuestionType::A), vec![(
+            "www.example.com.".to_string(),
+            3
+        )]);
+
+        let answer = ask_dnssec(&zone, "example.com", QuestionType::DNSKEY);
+        assert_eq!(answer.answers.len(), 2);
+    }
+
+    #[test]
+    fn proves_denials_with_nsec_and_nsec3() {
+        let origin: Domain = "example.com".parse().unwrap();
+        let nsec3 = Nsec3Param {
+            hash_algorithm: 1,
+            flags: 0,
+            iterations: 0,
+            salt: Bytes::from_static(&[0xab]),
+        };
+
+        for zone in [signed(None), signed(Some(nsec3))] {
+            let deny = |name: &str, qtype: QuestionType, nxdomain: bool| {
+                let answer = ask_dnssec(&zone, name, qtype.clone());
+                assert_eq!(signatures(&answer.authority, QuestionType::SOA).len(), 1);
+                prove_denial(
+                    &name.parse().unwrap(),
+                    &qtype,
+                    &origin,
+                    &answer.authority,
+                    nxdomain,
+                )
+                .unwrap()
+            };
+            assert_eq!(
+                deny("nope.example.com", QuestionType::A, true),
+                Denial::NxDomain
+            );
+            assert_eq!(
+                deny("x.host.wild.example.com", QuestionType::A, true),
+                Denial::NxDomain
+            );
+            assert_eq!(
+                deny("www.example.com", QuestionType::MX, false),
+                Denial::NoData { delegation: false }
+            );
+            assert_eq!(
+                deny("b.c.example.com", QuestionType::A, false),
+                Denial::NoData { delegation: false }
+            );
+
+            // wildcard answers are signed as the wildcard, with proof the name doesn't exist
+            let name: Domain = "a.b.wild.example.com".parse().unwrap();
+            let answer = ask_dnssec(&zone, "a.b.wild.example.com", QuestionType::TXT);
+            assert_eq!(signatures(&answer.answers, QuestionType::TXT), vec![(
+                "a.b.wild.example.com.".to_string(),
+                3
+            )]);
+            prove_wildcard(&name, 3, &origin, &answer.authority).unwrap();
+
+            // the child zone isn't signed, so the referral proves there's no DS
+            let answer = ask_dnssec(&zone, "www.child.example.com", QuestionType::A);
+            assert!(!answer.authoritative);
+            let child: Domain = "child.example.com".parse().unwrap();
+            assert_eq!(
+                prove_denial(&child, &QuestionType::DS, &origin, &answer.authority, false).unwrap(),
+                Denial::NoData { delegation: true }
+            );
+
+            // and DS queries for the cut are ours to answer
+            let answer = ask_dnssec(&zone, "child.example.com", QuestionType::DS);
+            assert!(answer.authoritative);
+            assert_eq!(answer.authority[0].qtype, QuestionType::SOA);
+        }
+    }
+}
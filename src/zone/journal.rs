@@ -0,0 +1,158 @@
+use crate::dns::{DnsAnswer, DnsAnswerSet, DnsMessage, QuestionType, RecordData};
+use crate::parse::DnsData;
+use anyhow::{Context, Result, bail, ensure};
+use bytes::{Buf, Bytes};
+use std::collections::{HashMap, VecDeque};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+// how many changes we remember per zone for IXFR, older ones mean a full transfer
+pub const MAX_JOURNAL_DIFFS: usize = 1000;
+
+// One change to a zone, in the same shape as an IXFR difference sequence (RFC 1995 S4): the SOA
+// it applies to, what it removes, the SOA it results in and what it adds.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diff {
+    pub from: DnsAnswer,
+    pub removed: Vec<DnsAnswer>,
+    pub to: DnsAnswer,
+    pub added: Vec<DnsAnswer>,
+}
+
+impl Diff {
+    pub fn from_serial(&self) -> Result<u32> {
+        soa_serial(&self.from)
+    }
+
+    pub fn to_serial(&self) -> Result<u32> {
+        soa_serial(&self.to)
+    }
+}
+
+// A diff is stored as a message whose answers are the old SOA and the records removed, and whose
+// authority section is the new SOA and the records added.
+fn encode_diff(diff: &Diff) -> Result<Bytes> {
+    let before = std::iter::once(&diff.from).chain(&diff.removed).cloned();
+    let after = std::iter::once(&diff.to).chain(&diff.added).cloned();
+    DnsMessage::default()
+        .with_answers(DnsAnswerSet {
+            answers: before.collect(),
+        })?
+        .with_authority(DnsAnswerSet {
+            answers: after.collect(),
+        })?
+        .encode(0, &mut HashMap::new())
+}
+
+fn decode_diff(buf: &Bytes) -> Result<Diff> {
+    let (_, message) = DnsMessage::decode(buf, 0, &mut HashMap::new())?;
+    let mut before = message.answers.answers.into_iter();
+    let mut after = message.authority.answers.into_iter();
+    let (Some(from), Some(to)) = (before.next(), after.next()) else {
+        bail!("journal entry is missing an SOA");
+    };
+    ensure!(
+        from.qtype == QuestionType::SOA && to.qtype == QuestionType::SOA,
+        "journal entry doesn't start with an SOA"
+    );
+
+    Ok(Diff {
+        from,
+        removed: before.collect(),
+        to,
+        added: after.collect(),
+    })
+}
+
+// the journal kept alongside a zone file, so changes made while we're running aren't lost
+pub fn journal_path(zone_file: &Path) -> PathBuf {
+    let mut path = zone_file.as_os_str().to_owned();
+    path.push(".jnl");
+    path.into()
+}
+
+// Every change in a journal file, oldest first, each one stored with a 4 byte length in front.
+// A missing file is an empty journal, and a half written change at the end (we stopped part way
+// through saving it) is dropped.
+pub fn read_journal(path: &Path) -> Result<Vec<Diff>> {
+    let mut buf = match std::fs::read(path) {
+        Ok(buf) => Bytes::from(buf),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).with_context(|| format!("reading {}", path.display())),
+    };
+
+    let mut diffs = Vec::new();
+    while buf.has_remaining() {
+        if buf.remaining() < 4 || buf.remaining() - 4 < (&buf[..4]).get_u32() as usize {
+            warn!(
+                "{} ends with an incomplete change, ignoring it",
+                path.display()
+            );
+            break;
+        }
+        let len = buf.get_u32() as usize;
+        let entry = buf.split_to(len);
+        diffs.push(decode_diff(&entry).with_context(|| format!("{}", path.display()))?);
+    }
+    Ok(diffs)
+}
+
+pub fn append_journal(path: &Path, diff: &Diff) -> Result<()> {
+    let entry = encode_diff(diff)?;
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("opening {}", path.display()))?;
+
+    let mut buf = u32::try_from(entry.len())?.to_be_bytes().to_vec();
+    buf.extend_from_slice(&entry);
+    file.write_all(&buf)?;
+    file.sync_data()?;
+    Ok(())
+}
+
+pub fn soa_serial(record: &DnsAnswer) -> Result<u32> {
+    match record.rdata()? {
+        RecordData::SOA(soa) => Ok(soa.serial),
+        _ => bail!("{} is not an SOA record", record.name),
+    }
+}
+
+// the recent history of a zone, oldest change first
+#[derive(Debug, Clone, Default)]
+pub struct Journal {
+    diffs: VecDeque<Diff>,
+}
+
+impl Journal {
+    pub fn push(&mut self, diff: Diff) {
+        if self.diffs.len() == MAX_JOURNAL_DIFFS {
+            self.diffs.pop_front();
+        }
+        self.diffs.push_back(diff);
+    }
+
+    // the changes that take a zone at `serial` up to date, None if we don't go back that far
+    pub fn since(&self, serial: u32) -> Option<Vec<&Diff>> {
+        let start = self
+            .diffs
+            .iter()
+            .position(|d| d.from_serial().is_ok_and(|s| s == serial))?;
+        Some(self.diffs.range(start..).collect())
+    }
+
+    pub fn diffs(&self) -> impl Iterator<Item = &Diff> {
+        self.diffs.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.diffs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.diffs.is_empty()
+    }
+}
@@ -0,0 +1,21 @@
+mod authoritative;
+mod journal;
+mod notify;
+mod parser;
+mod secondary;
+mod serial;
+mod store;
+mod transfer;
+mod tree;
+mod update;
+
+pub use authoritative::*;
+pub use journal::*;
+pub use notify::*;
+pub use parser::*;
+pub use secondary::*;
+pub use serial::*;
+pub use store::*;
+pub use transfer::*;
+pub use tree::*;
+pub use update::*;
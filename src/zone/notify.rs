@@ -0,0 +1,197 @@
+use crate::dns::{
+    DnsAnswerSet, DnsMessage, DnsQuestion, Domain, Opcode, QuestionType, ResponseCode,
+};
+use crate::handler::{DnsHandler, DnsRequest};
+use crate::resolver::{exchange_raw, random_id};
+use crate::tsig::{TsigKey, TsigSession};
+use crate::zone::{Secondary, ZoneStore};
+use anyhow::{Context, Result, ensure};
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{debug, info, warn};
+
+// how many times we tell a secondary about a change before giving up on it (RFC 1996 S3.6)
+const NOTIFY_ATTEMPTS: u32 = 3;
+
+// Tell `secondary` that our copy of `origin` has changed. It answers with an empty NOTIFY
+// response and then comes and asks for the SOA itself (RFC 1996 S3.7).
+pub async fn send_notify(
+    secondary: SocketAddr,
+    zones: &ZoneStore,
+    origin: &Domain,
+    key: Option<&Arc<TsigKey>>,
+    wait: Duration,
+) -> Result<()> {
+    let zone = zones
+        .get(origin)
+        .with_context(|| format!("{origin} isn't one of our zones"))?;
+
+    let mut request = DnsMessage::query(
+        random_id(),
+        DnsQuestion::new(zone.origin.clone(), QuestionType::SOA),
+    );
+    request.header.opcode = Opcode::Notify as u8;
+    request.header.auth_answer = true;
+    let mut request = request.with_answers(DnsAnswerSet {
+        answers: zone.soa_record().into_iter().cloned().collect(),
+    })?;
+    let mut session = key.map(|key| TsigSession::new(key.clone()));
+    if let Some(session) = &mut session {
+        request = session.sign(request)?;
+    }
+
+    let (raw, response) = exchange_raw(secondary, &request, wait).await?;
+    if let Some(session) = &mut session {
+        session
+            .verify(&raw)
+            .with_context(|| format!("NOTIFY response from {secondary}"))?;
+    }
+    ensure!(
+        response.header.opcode == Opcode::Notify as u8,
+        "{secondary} didn't answer with a NOTIFY response"
+    );
+    ensure!(
+        response.response_code() == ResponseCode::NoError as u8,
+        "{secondary} rejected our NOTIFY with rcode {}",
+        response.response_code()
+    );
+    Ok(())
+}
+
+// Watches the zone store and sends a NOTIFY to a zone's secondaries whenever it changes.
+#[derive(Debug)]
+pub struct Notifier {
+    zones: Arc<ZoneStore>,
+    secondaries: HashMap<Domain, Vec<SocketAddr>>,
+    timeout: Duration,
+
+    // signs every NOTIFY when set
+    key: Option<Arc<TsigKey>>,
+}
+
+impl Notifier {
+    pub fn new(zones: Arc<ZoneStore>) -> Self {
+        Self {
+            zones,
+            secondaries: HashMap::new(),
+            timeout: Duration::from_secs(2),
+            key: None,
+        }
+    }
+
+    pub fn with_secondaries(mut self, origin: Domain, secondaries: Vec<SocketAddr>) -> Self {
+        self.secondaries.insert(origin.to_lowercase(), secondaries);
+        self
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn with_key(mut self, key: Arc<TsigKey>) -> Self {
+        self.key = Some(key);
+        self
+    }
+
+    pub fn start(self: Arc<Self>) {
+        let mut changes = self.zones.subscribe();
+        tokio::spawn(async move {
+            loop {
+                let origin = match changes.recv().await {
+                    Ok(origin) => origin,
+                    Err(RecvError::Lagged(missed)) => {
+                        warn!("missed {missed} zone changes, secondaries may be behind");
+                        continue;
+                    }
+                    Err(RecvError::Closed) => return,
+                };
+                self.notify_all(&origin);
+            }
+        });
+    }
+
+    fn notify_all(&self, origin: &Domain) {
+        let Some(secondaries) = self.secondaries.get(origin) else {
+            return;
+        };
+
+        for secondary in secondaries.iter().copied() {
+            let zones = self.zones.clone();
+            let origin = origin.clone();
+            let timeout = self.timeout;
+            let key = self.key.clone();
+            tokio::spawn(async move {
+                for attempt in 1..=NOTIFY_ATTEMPTS {
+                    match send_notify(secondary, &zones, &origin, key.as_ref(), timeout).await {
+                        Ok(()) => {
+                            info!("notified {secondary} about {origin}");
+                            return;
+                        }
+                        Err(e) => debug!("NOTIFY {attempt} for {origin} to {secondary}: {e:#}"),
+                    }
+                }
+                warn!("gave up notifying {secondary} about {origin}");
+            });
+        }
+    }
+}
+
+// Takes NOTIFY messages for our secondary zones and passes everything else on.
+#[derive(Debug)]
+pub struct NotifyHandler {
+    secondary: Arc<Secondary>,
+    inner: Arc<dyn DnsHandler>,
+}
+
+impl NotifyHandler {
+    pub fn new(secondary: Arc<Secondary>, inner: Arc<dyn DnsHandler>) -> Self {
+        Self { secondary, inner }
+    }
+}
+
+fn is_notify(request: &DnsRequest) -> bool {
+    request.message.header.opcode == Opcode::Notify as u8
+}
+
+#[async_trait]
+impl DnsHandler for NotifyHandler {
+    async fn handle(&self, request: &DnsRequest) -> Result<Option<DnsMessage>> {
+        if !is_notify(request) {
+            return self.inner.handle(request).await;
+        }
+
+        // the response is the request with QR set and nothing in the answer section
+        let mut reply = request
+            .message
+            .clone()
+            .as_reply()
+            .with_answers(DnsAnswerSet::default())?;
+        reply.header.auth_answer = true;
+
+        let Some(question) = request.message.question() else {
+            return Ok(Some(reply.with_response_code(ResponseCode::FormErr)));
+        };
+
+        let from = request.client.ip();
+        let code = match self
+            .secondary
+            .notify(&question.name, from, request.key.as_ref())
+        {
+            true => ResponseCode::NoError,
+            false => ResponseCode::Refused,
+        };
+        Ok(Some(reply.with_response_code(code)))
+    }
+
+    async fn handle_stream(&self, request: &DnsRequest) -> Result<Vec<DnsMessage>> {
+        if is_notify(request) {
+            return Ok(self.handle(request).await?.into_iter().collect());
+        }
+        self.inner.handle_stream(request).await
+    }
+}
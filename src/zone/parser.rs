@@ -0,0 +1,1083 @@
+use crate::dns::{
+    CLASS_IN, DnsAnswer, DnsKey, Domain, Ds, Label, Nsec, Nsec3, Nsec3Param, QuestionType,
+    RecordData, Rrsig, Soa,
+};
+use crate::dnssec::base32hex_decode;
+use anyhow::{Context, Result, bail, ensure};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use bytes::Bytes;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+
+// $INCLUDE can nest, but not forever
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+// $GENERATE ranges are capped so a typo can't produce millions of records
+const MAX_GENERATE_RECORDS: u32 = 65536;
+
+// a single whitespace separated token, quoted strings can contain whitespace
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Token {
+    text: String,
+    quoted: bool,
+}
+
+// One logical line of a master file. Parentheses let an entry span several physical lines, the
+// line number is always the one the entry started on.
+#[derive(Debug)]
+struct Entry {
+    line: usize,
+
+    // an entry that starts with whitespace has the same owner as the one before it
+    inherits_owner: bool,
+    tokens: Vec<Token>,
+}
+
+// split a master file into entries, dealing with comments, quoting and parentheses
+fn tokenize(text: &str) -> Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    let mut tokens: Vec<Token> = Vec::new();
+    let mut start_line = 1;
+    let mut inherits_owner = false;
+    let mut depth = 0;
+
+    for (num, line) in text.lines().enumerate() {
+        let line_num = num + 1;
+        if depth == 0 {
+            start_line = line_num;
+            inherits_owner = line.starts_with([' ', '\t']);
+        }
+
+        let mut chars = line.chars().peekable();
+        let mut current: Option<Token> = None;
+        let mut in_quotes = false;
+
+        while let Some(c) = chars.next() {
+            if in_quotes {
+                let token = current.get_or_insert(Token {
+                    text: String::new(),
+                    quoted: true,
+                });
+                match c {
+                    '"' => {
+                        in_quotes = false;
+                        tokens.extend(current.take());
+                    }
+                    '\\' => {
+                        token.text.push(c);
+                        if let Some(next) = chars.next() {
+                            token.text.push(next);
+                        }
+                    }
+                    _ => token.text.push(c),
+                }
+                continue;
+            }
+
+            match c {
+                ';' => break,
+                '"' => {
+                    tokens.extend(current.take());
+                    in_quotes = true;
+                    current = Some(Token {
+                        text: String::new(),
+                        quoted: true,
+                    });
+                }
+                '(' => {
+                    tokens.extend(current.take());
+                    depth += 1;
+                }
+                ')' => {
+                    tokens.extend(current.take());
+                    ensure!(depth > 0, "line {line_num}: unbalanced parentheses");
+                    depth -= 1;
+                }
+                c if c.is_whitespace() => tokens.extend(current.take()),
+                '\\' => {
+                    let token = current.get_or_insert(Token {
+                        text: String::new(),
+                        quoted: false,
+                    });
+                    token.text.push(c);
+                    if let Some(next) = chars.next() {
+                        token.text.push(next);
+                    }
+                }
+                c => current
+                    .get_or_insert(Token {
+                        text: String::new(),
+                        quoted: false,
+                    })
+                    .text
+                    .push(c),
+            }
+        }
+
+        ensure!(!in_quotes, "line {line_num}: unterminated quoted string");
+        tokens.extend(current.take());
+
+        if depth == 0 && !tokens.is_empty() {
+            entries.push(Entry {
+                line: start_line,
+                inherits_owner,
+                tokens: std::mem::take(&mut tokens),
+            });
+        }
+    }
+
+    ensure!(depth == 0, "line {start_line}: unbalanced parentheses");
+    Ok(entries)
+}
+
+// Master file parsing (RFC 1035 S5) with the usual extensions: $TTL (RFC 2308), BIND style TTL
+// units, $GENERATE and RFC 3597 unknown RDATA (\# <length> <hex>).
+#[derive(Debug)]
+pub struct ZoneParser {
+    origin: Domain,
+    default_ttl: Option<u32>,
+    last_owner: Option<Domain>,
+    last_ttl: Option<u32>,
+    records: Vec<DnsAnswer>,
+}
+
+impl ZoneParser {
+    pub fn new(origin: Domain) -> Self {
+        Self {
+            origin,
+            default_ttl: None,
+            last_owner: None,
+            last_ttl: None,
+            records: Vec::new(),
+        }
+    }
+
+    // records that don't give a TTL get this one, as if the file started with $TTL
+    pub fn with_default_ttl(mut self, ttl: u32) -> Self {
+        self.default_ttl = Some(ttl);
+        self
+    }
+
+    // parse a zone file, any $INCLUDEd paths are relative to the file that includes them
+    pub fn parse_file(origin: Domain, path: impl AsRef<Path>) -> Result<Vec<DnsAnswer>> {
+        Self::new(origin).load(path)
+    }
+
+    pub fn load(mut self, path: impl AsRef<Path>) -> Result<Vec<DnsAnswer>> {
+        self.include(path.as_ref(), 0)?;
+        Ok(self.records)
+    }
+
+    // parse zone file contents, `name` is only used in error messages
+    pub fn parse_str(origin: Domain, text: &str, name: &str) -> Result<Vec<DnsAnswer>> {
+        let mut parser = Self::new(origin);
+        parser.parse(text, Path::new(name), None, 0)?;
+        Ok(parser.records)
+    }
+
+    fn include(&mut self, path: &Path, depth: usize) -> Result<()> {
+        ensure!(depth <= MAX_INCLUDE_DEPTH, "$INCLUDE nested too deeply");
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read zone file {}", path.display()))?;
+        self.parse(&text, path, path.parent(), depth)
+    }
+
+    fn parse(&mut self, text: &str, file: &Path, dir: Option<&Path>, depth: usize) -> Result<()> {
+        for entry in tokenize(text).with_context(|| format!("{}", file.display()))? {
+            self.parse_entry(&entry, dir, depth)
+                .with_context(|| format!("{}:{}", file.display(), entry.line))?;
+        }
+        Ok(())
+    }
+
+    fn parse_entry(&mut self, entry: &Entry, dir: Option<&Path>, depth: usize) -> Result<()> {
+        let tokens = &entry.tokens;
+        let first = tokens[0].text.as_str();
+
+        if !entry.inherits_owner && !tokens[0].quoted && first.starts_with('$') {
+            return match first.to_ascii_uppercase().as_str() {
+                "$ORIGIN" => {
+                    ensure!(tokens.len() == 2, "$ORIGIN takes exactly one name");
+                    self.origin = parse_name(&tokens[1].text, &self.origin)?;
+                    Ok(())
+                }
+                "$TTL" => {
+                    ensure!(tokens.len() == 2, "$TTL takes exactly one TTL");
+                    self.default_ttl = Some(parse_ttl(&tokens[1].text)?);
+                    Ok(())
+                }
+                "$INCLUDE" => {
+                    ensure!(
+                        tokens.len() == 2 || tokens.len() == 3,
+                        "usage: $INCLUDE <file> [origin]"
+                    );
+                    let mut path = PathBuf::from(&tokens[1].text);
+                    if path.is_relative()
+                        && let Some(dir) = dir
+                    {
+                        path = dir.join(path);
+                    }
+
+                    // the included file can have its own origin, but ours comes back afterwards
+                    let saved = self.origin.clone();
+                    if let Some(origin) = tokens.get(2) {
+                        self.origin = parse_name(&origin.text, &self.origin)?;
+                    }
+                    let result = self.include(&path, depth + 1);
+                    self.origin = saved;
+                    result
+                }
+                "$GENERATE" => self.generate(&tokens[1..]),
+                directive => bail!("unknown directive {directive}"),
+            };
+        }
+
+        let (owner, rest) = if entry.inherits_owner {
+            let owner = self
+                .last_owner
+                .clone()
+                .context("record without an owner name")?;
+            (owner, &tokens[..])
+        } else {
+            (parse_name(first, &self.origin)?, &tokens[1..])
+        };
+
+        self.parse_record(owner, rest)
+    }
+
+    // the rest of a record after the owner: [ttl] [class] type rdata, TTL and class can come in
+    // either order
+    fn parse_record(&mut self, owner: Domain, tokens: &[Token]) -> Result<()> {
+        let mut ttl = None;
+        let mut class = None;
+        let mut rest = tokens;
+
+        while let Some(token) = rest.first() {
+            if ttl.is_none()
+                && let Ok(t) = parse_ttl(&token.text)
+            {
+                ttl = Some(t);
+            } else if class.is_none()
+                && let Some(c) = parse_class(&token.text)
+            {
+                class = Some(c);
+            } else {
+                break;
+            }
+            rest = &rest[1..];
+        }
+
+        let rtype = rest.first().context("missing record type")?;
+        let qtype = parse_type(&rtype.text)?;
+        let rdata = parse_rdata(&qtype, &rest[1..], &self.origin)?;
+
+        // RFC 1035 says a missing TTL means the last one used, RFC 2308 adds $TTL, and for the
+        // SOA itself the minimum field is the last resort
+        let ttl = match (ttl, self.default_ttl, self.last_ttl, &rdata) {
+            (Some(ttl), _, _, _) => ttl,
+            (None, Some(ttl), _, _) => ttl,
+            (None, None, Some(ttl), _) => ttl,
+            (None, None, None, RecordData::SOA(soa)) => soa.minimum,
+            _ => bail!("no TTL given and no $TTL to fall back on"),
+        };
+
+        let mut record = DnsAnswer::new(owner.clone(), ttl, rdata)?;
+        record.class = class.unwrap_or(CLASS_IN);
+
+        self.last_owner = Some(owner);
+        self.last_ttl = Some(ttl);
+        self.records.push(record);
+        Ok(())
+    }
+
+    // $GENERATE start-stop[/step] lhs [ttl] [class] type rhs
+    fn generate(&mut self, tokens: &[Token]) -> Result<()> {
+        ensure!(
+            tokens.len() >= 4,
+            "usage: $GENERATE <range> <lhs> [ttl] [class] <type> <rhs>"
+        );
+
+        let (range, step) = match tokens[0].text.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>().context("invalid step")?),
+            None => (tokens[0].text.as_str(), 1),
+        };
+        let (start, stop) = range.split_once('-').context("invalid range")?;
+        let start: u32 = start.parse().context("invalid range start")?;
+        let stop: u32 = stop.parse().context("invalid range stop")?;
+        ensure!(
+            start <= stop && step > 0,
+            "invalid range {}",
+            tokens[0].text
+        );
+        ensure!(
+            (stop - start) / step < MAX_GENERATE_RECORDS,
+            "$GENERATE range is too large"
+        );
+
+        let lhs = &tokens[1];
+        let middle = &tokens[2..tokens.len() - 1];
+        let rhs = &tokens[tokens.len() - 1];
+
+        for i in (start..=stop).step_by(step as usize) {
+            let owner = parse_name(&substitute(&lhs.text, i)?, &self.origin)?;
+            let mut rest: Vec<Token> = middle.to_vec();
+            rest.push(Token {
+                text: substitute(&rhs.text, i)?,
+                quoted: rhs.quoted,
+            });
+            self.parse_record(owner, &rest)?;
+        }
+
+        Ok(())
+    }
+}
+
+// $GENERATE substitution: `$` is the iterator, `${offset,width,base}` formats it, `\$` is a
+// literal dollar sign
+fn substitute(template: &str, value: u32) -> Result<String> {
+    let mut out = String::new();
+    let mut chars = template.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if chars.peek() == Some(&'$') => {
+                out.push('$');
+                chars.next();
+            }
+            '$' if chars.peek() == Some(&'{') => {
+                chars.next();
+                let spec: String = chars.by_ref().take_while(|c| *c != '}').collect();
+                let mut parts = spec.split(',');
+                let offset: i64 = parts
+                    .next()
+                    .filter(|p| !p.is_empty())
+                    .map(str::parse)
+                    .transpose()?
+                    .unwrap_or(0);
+                let width: usize = parts.next().map(str::parse).transpose()?.unwrap_or(0);
+                let base = parts.next().unwrap_or("d");
+
+                let n = u64::try_from(value as i64 + offset)
+                    .context("$GENERATE offset makes the value negative")?;
+                out.push_str(&match base {
+                    "d" => format!("{n:0width$}"),
+                    "o" => format!("{n:0width$o}"),
+                    "x" => format!("{n:0width$x}"),
+                    "X" => format!("{n:0width$X}"),
+                    "n" | "N" => {
+                        // reversed nibbles separated by dots, as used in ip6.arpa names
+                        let hex = format!("{n:0width$x}");
+                        let hex = if base == "N" { hex.to_uppercase() } else { hex };
+                        hex.chars()
+                            .rev()
+                            .map(|c| c.to_string())
+                            .collect::<Vec<_>>()
+                            .join(".")
+                    }
+                    other => bail!("unknown $GENERATE base {other}"),
+                });
+            }
+            '$' => out.push_str(&value.to_string()),
+            c => out.push(c),
+        }
+    }
+
+    Ok(out)
+}
+
+// a name as written in a master file, relative names are completed with the origin
+pub fn parse_name(text: &str, origin: &Domain) -> Result<Domain> {
+    if text == "@" {
+        return Ok(origin.clone());
+    }
+    if text == "." {
+        return Ok(Domain::root());
+    }
+
+    // split on dots that aren't escaped
+    let mut labels = Vec::new();
+    let mut label = Vec::new();
+    let mut chars = text.chars();
+    let mut absolute = false;
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => unescape(&mut chars, &mut label).context("invalid name")?,
+            '.' => {
+                ensure!(!label.is_empty(), "empty label in {text}");
+                labels.push(Label(String::from_utf8(std::mem::take(&mut label))?));
+                absolute = chars.as_str().is_empty();
+            }
+            c => label.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+        }
+    }
+    if !label.is_empty() {
+        labels.push(Label(String::from_utf8(label)?));
+        absolute = false;
+    }
+
+    for l in &labels {
+        ensure!(
+            l.0.len() <= crate::dns::MAX_LABEL_LENGTH,
+            "label {} is too long",
+            l.0
+        );
+    }
+
+    if !absolute {
+        labels.extend(origin.labels.iter().cloned());
+    }
+
+    Ok(Domain { labels })
+}
+
+// TTLs are either plain seconds or BIND style units, e.g. 1h30m
+pub fn parse_ttl(text: &str) -> Result<u32> {
+    if let Ok(ttl) = text.parse::<u32>() {
+        return Ok(ttl);
+    }
+
+    let mut total: u64 = 0;
+    let mut number = String::new();
+    for c in text.chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+
+        ensure!(!number.is_empty(), "invalid TTL {text}");
+        let multiplier = match c.to_ascii_lowercase() {
+            's' => 1,
+            'm' => 60,
+            'h' => 3600,
+            'd' => 86400,
+            'w' => 604800,
+            _ => bail!("invalid TTL {text}"),
+        };
+        total += number.parse::<u64>()? * multiplier;
+        number.clear();
+    }
+
+    ensure!(number.is_empty() && total > 0, "invalid TTL {text}");
+    Ok(u32::try_from(total)?)
+}
+
+fn parse_class(text: &str) -> Option<u16> {
+    match text.to_ascii_uppercase().as_str() {
+        "IN" => Some(CLASS_IN),
+        "CH" => Some(3),
+        "HS" => Some(4),
+        _ => None,
+    }
+}
+
+pub fn parse_type(text: &str) -> Result<QuestionType> {
+    Ok(match text.to_ascii_uppercase().as_str() {
+        "A" => QuestionType::A,
+        "NS" => QuestionType::NS,
+        "MD" => QuestionType::MD,
+        "MF" => QuestionType::MF,
+        "CNAME" => QuestionType::CNAME,
+        "SOA" => QuestionType::SOA,
+        "MB" => QuestionType::MB,
+        "MG" => QuestionType::MG,
+        "MR" => QuestionType::MR,
+        "NULL" => QuestionType::NULL,
+        "WKS" => QuestionType::WKS,
+        "PTR" => QuestionType::PTR,
+        "HINFO" => QuestionType::HINFO,
+        "MINFO" => QuestionType::MINFO,
+        "MX" => QuestionType::MX,
+        "TXT" => QuestionType::TXT,
+        "AAAA" => QuestionType::AAAA,
+        "DS" => QuestionType::DS,
+        "RRSIG" => QuestionType::RRSIG,
+        "NSEC" => QuestionType::NSEC,
+        "DNSKEY" => QuestionType::DNSKEY,
+        "NSEC3" => QuestionType::NSEC3,
+        "NSEC3PARAM" => QuestionType::NSEC3PARAM,
+        // RFC 3597 TYPEnnn
+        t if t.starts_with("TYPE") => t[4..].parse::<u16>()?.into(),
+        t => bail!("unsupported record type {t}"),
+    })
+}
+
+// the <character-string> in a token, with \X and \DDD escapes resolved
+fn parse_string(token: &Token) -> Result<String> {
+    let mut out: Vec<u8> = Vec::new();
+    let mut chars = token.text.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut buf = [0; 4];
+            out.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+            continue;
+        }
+        unescape(&mut chars, &mut out).context("invalid string")?;
+    }
+
+    ensure!(out.len() <= 255, "string is longer than 255 bytes");
+    Ok(String::from_utf8(out)?)
+}
+
+// whatever follows a backslash, \DDD is a byte by its decimal value and anything else stands for
+// itself
+fn unescape(chars: &mut std::str::Chars, out: &mut Vec<u8>) -> Result<()> {
+    let next = chars.next().context("ends with a backslash")?;
+    if next.is_ascii_digit() {
+        let digits: String = std::iter::once(next)
+            .chain(chars.by_ref().take(2))
+            .collect();
+        ensure!(digits.len() == 3, "invalid escape \\{digits}");
+        out.push(digits.parse::<u8>().context("invalid escape")?);
+    } else {
+        let mut buf = [0; 4];
+        out.extend_from_slice(next.encode_utf8(&mut buf).as_bytes());
+    }
+    Ok(())
+}
+
+fn parse_u8(token: &Token) -> Result<u8> {
+    token
+        .text
+        .parse()
+        .with_context(|| format!("invalid number {}", token.text))
+}
+
+fn parse_u16(token: &Token) -> Result<u16> {
+    token
+        .text
+        .parse()
+        .with_context(|| format!("invalid number {}", token.text))
+}
+
+// the type code for a mnemonic, NSEC bitmaps can name types we have no other use for as TYPEnnn
+fn parse_type_code(text: &str) -> Result<u16> {
+    match text.to_ascii_uppercase().strip_prefix("TYPE") {
+        Some(n) => n.parse().with_context(|| format!("invalid type {text}")),
+        None => Ok(parse_type(text)?.code()),
+    }
+}
+
+// RRSIG times are either seconds since the epoch or YYYYMMDDHHmmSS in UTC (RFC 4034 S3.2)
+fn parse_timestamp(text: &str) -> Result<u32> {
+    if text.len() != 14 {
+        return text
+            .parse()
+            .with_context(|| format!("invalid timestamp {text}"));
+    }
+    ensure!(
+        text.bytes().all(|b| b.is_ascii_digit()),
+        "invalid timestamp {text}"
+    );
+
+    let field = |range: std::ops::Range<usize>| text[range].parse::<i64>().unwrap();
+    let (year, month, day) = (field(0..4), field(4..6), field(6..8));
+    let (hour, minute, second) = (field(8..10), field(10..12), field(12..14));
+    ensure!(
+        (1..=12).contains(&month) && (1..=31).contains(&day),
+        "invalid date in {text}"
+    );
+    ensure!(
+        hour < 24 && minute < 60 && second < 61,
+        "invalid time in {text}"
+    );
+
+    // days since the epoch for a proleptic Gregorian date, with March as the first month so
+    // the leap day comes last
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+
+    // serial arithmetic makes the times wrap rather than run out (RFC 4034 S3.1.5)
+    Ok((days * 86400 + hour * 3600 + minute * 60 + second) as u32)
+}
+
+// the rest of the tokens as one base64 blob, keys and signatures are often split over lines
+fn parse_base64(tokens: &[Token]) -> Result<Bytes> {
+    let text: String = tokens.iter().map(|t| t.text.as_str()).collect();
+    Ok(STANDARD.decode(text).context("invalid base64")?.into())
+}
+
+fn parse_hex(tokens: &[Token]) -> Result<Bytes> {
+    let text: String = tokens.iter().map(|t| t.text.as_str()).collect();
+    Ok(hex::decode(text).context("invalid hex")?.into())
+}
+
+// NSEC3 salts are hex, or - for none
+fn parse_salt(token: &Token) -> Result<Bytes> {
+    match token.text.as_str() {
+        "-" => Ok(Bytes::new()),
+        _ => parse_hex(std::slice::from_ref(token)),
+    }
+}
+
+// ports for the handful of services people actually list in WKS records
+fn service_port(name: &str) -> Result<u16> {
+    if let Ok(port) = name.parse() {
+        return Ok(port);
+    }
+
+    Ok(match name.to_ascii_lowercase().as_str() {
+        "ftp" => 21,
+        "ssh" => 22,
+        "telnet" => 23,
+        "smtp" => 25,
+        "domain" => 53,
+        "http" => 80,
+        "pop3" => 110,
+        "ntp" => 123,
+        "imap" => 143,
+        "https" => 443,
+        s => bail!("unknown service {s}"),
+    })
+}
+
+fn expect_count(tokens: &[Token], n: usize, qtype: &QuestionType) -> Result<()> {
+    ensure!(
+        tokens.len() == n,
+        "{qtype:?} record takes {n} fields, got {}",
+        tokens.len()
+    );
+    Ok(())
+}
+
+fn parse_rdata(qtype: &QuestionType, tokens: &[Token], origin: &Domain) -> Result<RecordData> {
+    // RFC 3597 generic encoding works for any type
+    if tokens.first().is_some_and(|t| t.text == "\\#") {
+        ensure!(tokens.len() >= 2, "\\# needs a length");
+        let len: usize = tokens[1].text.parse().context("invalid \\# length")?;
+        let hex: String = tokens[2..].iter().map(|t| t.text.as_str()).collect();
+        let data = hex::decode(hex).context("invalid hex in \\# RDATA")?;
+        ensure!(data.len() == len, "\\# length doesn't match the data");
+        return RecordData::from_bytes(&Bytes::from(data), qtype);
+    }
+
+    let name = |i: usize| parse_name(&tokens[i].text, origin);
+
+    Ok(match qtype {
+        QuestionType::A => {
+            expect_count(tokens, 1, qtype)?;
+            RecordData::A(
+                tokens[0]
+                    .text
+                    .parse::<Ipv4Addr>()
+                    .with_context(|| format!("invalid address {}", tokens[0].text))?,
+            )
+        }
+        QuestionType::AAAA => {
+            expect_count(tokens, 1, qtype)?;
+            RecordData::AAAA(
+                tokens[0]
+                    .text
+                    .parse::<Ipv6Addr>()
+                    .with_context(|| format!("invalid address {}", tokens[0].text))?,
+            )
+        }
+        QuestionType::NS => {
+            expect_count(tokens, 1, qtype)?;
+            RecordData::NS(name(0)?)
+        }
+        QuestionType::MD => {
+            expect_count(tokens, 1, qtype)?;
+            RecordData::MD(name(0)?)
+        }
+        QuestionType::MF => {
+            expect_count(tokens, 1, qtype)?;
+            RecordData::MF(name(0)?)
+        }
+        QuestionType::CNAME => {
+            expect_count(tokens, 1, qtype)?;
+            RecordData::CNAME(name(0)?)
+        }
+        QuestionType::MB => {
+            expect_count(tokens, 1, qtype)?;
+            RecordData::MB(name(0)?)
+        }
+        QuestionType::MG => {
+            expect_count(tokens, 1, qtype)?;
+            RecordData::MG(name(0)?)
+        }
+        QuestionType::MR => {
+            expect_count(tokens, 1, qtype)?;
+            RecordData::MR(name(0)?)
+        }
+        QuestionType::PTR => {
+            expect_count(tokens, 1, qtype)?;
+            RecordData::PTR(name(0)?)
+        }
+        QuestionType::SOA => {
+            expect_count(tokens, 7, qtype)?;
+            RecordData::SOA(Soa {
+                mname: name(0)?,
+                rname: name(1)?,
+                serial: tokens[2].text.parse().context("invalid serial")?,
+                refresh: parse_ttl(&tokens[3].text)?,
+                retry: parse_ttl(&tokens[4].text)?,
+                expire: parse_ttl(&tokens[5].text)?,
+                minimum: parse_ttl(&tokens[6].text)?,
+            })
+        }
+        QuestionType::NULL => bail!("NULL records can only be written as \\# <length> <hex>"),
+        QuestionType::WKS => {
+            ensure!(
+                tokens.len() >= 2,
+                "WKS record needs an address and protocol"
+            );
+            let address = tokens[0]
+                .text
+                .parse::<Ipv4Addr>()
+                .with_context(|| format!("invalid address {}", tokens[0].text))?;
+            let protocol = match tokens[1].text.to_ascii_lowercase().as_str() {
+                "tcp" => 6,
+                "udp" => 17,
+                p => p.parse().context("invalid protocol")?,
+            };
+
+            let mut bitmap: Vec<u8> = Vec::new();
+            for service in &tokens[2..] {
+                let port = service_port(&service.text)? as usize;
+                if bitmap.len() <= port / 8 {
+                    bitmap.resize(port / 8 + 1, 0);
+                }
+                bitmap[port / 8] |= 0x80 >> (port % 8);
+            }
+
+            RecordData::WKS {
+                address,
+                protocol,
+                bitmap: bitmap.into(),
+            }
+        }
+        QuestionType::HINFO => {
+            expect_count(tokens, 2, qtype)?;
+            RecordData::HINFO {
+                cpu: parse_string(&tokens[0])?,
+                os: parse_string(&tokens[1])?,
+            }
+        }
+        QuestionType::MINFO => {
+            expect_count(tokens, 2, qtype)?;
+            RecordData::MINFO {
+                rmailbx: name(0)?,
+                emailbx: name(1)?,
+            }
+        }
+        QuestionType::MX => {
+            expect_count(tokens, 2, qtype)?;
+            RecordData::MX {
+                preference: parse_u16(&tokens[0])?,
+                exchange: name(1)?,
+            }
+        }
+        QuestionType::TXT => {
+            ensure!(!tokens.is_empty(), "TXT record needs at least one string");
+            RecordData::TXT(tokens.iter().map(parse_string).collect::<Result<_>>()?)
+        }
+        QuestionType::DS => {
+            ensure!(
+                tokens.len() >= 4,
+                "DS record needs a tag, algorithm, type and digest"
+            );
+            RecordData::DS(Ds {
+                key_tag: parse_u16(&tokens[0])?,
+                algorithm: parse_u8(&tokens[1])?,
+                digest_type: parse_u8(&tokens[2])?,
+                digest: parse_hex(&tokens[3..])?,
+            })
+        }
+        QuestionType::DNSKEY => {
+            ensure!(
+                tokens.len() >= 4,
+                "DNSKEY record needs flags, protocol, algorithm and key"
+            );
+            RecordData::DNSKEY(DnsKey {
+                flags: parse_u16(&tokens[0])?,
+                protocol: parse_u8(&tokens[1])?,
+                algorithm: parse_u8(&tokens[2])?,
+                public_key: parse_base64(&tokens[3..])?,
+            })
+        }
+        QuestionType::RRSIG => {
+            ensure!(tokens.len() >= 9, "RRSIG record takes at least 9 fields");
+            RecordData::RRSIG(Rrsig {
+                type_covered: parse_type_code(&tokens[0].text)?,
+                algorithm: parse_u8(&tokens[1])?,
+                labels: parse_u8(&tokens[2])?,
+                original_ttl: parse_ttl(&tokens[3].text)?,
+                expiration: parse_timestamp(&tokens[4].text)?,
+                inception: parse_timestamp(&tokens[5].text)?,
+                key_tag: parse_u16(&tokens[6])?,
+                signer: name(7)?,
+                signature: parse_base64(&tokens[8..])?,
+            })
+        }
+        QuestionType::NSEC => {
+            ensure!(!tokens.is_empty(), "NSEC record needs the next name");
+            RecordData::NSEC(Nsec {
+                next: name(0)?,
+                types: tokens[1..]
+                    .iter()
+                    .map(|t| parse_type_code(&t.text))
+                    .collect::<Result<_>>()?,
+            })
+        }
+        QuestionType::NSEC3 => {
+            ensure!(tokens.len() >= 5, "NSEC3 record takes at least 5 fields");
+            RecordData::NSEC3(Nsec3 {
+                hash_algorithm: parse_u8(&tokens[0])?,
+                flags: parse_u8(&tokens[1])?,
+                iterations: parse_u16(&tokens[2])?,
+                salt: parse_salt(&tokens[3])?,
+                next_hashed: base32hex_decode(&tokens[4].text)?.into(),
+                types: tokens[5..]
+                    .iter()
+                    .map(|t| parse_type_code(&t.text))
+                    .collect::<Result<_>>()?,
+            })
+        }
+        QuestionType::NSEC3PARAM => {
+            expect_count(tokens, 4, qtype)?;
+            RecordData::NSEC3PARAM(Nsec3Param {
+                hash_algorithm: parse_u8(&tokens[0])?,
+                flags: parse_u8(&tokens[1])?,
+                iterations: parse_u16(&tokens[2])?,
+                salt: parse_salt(&tokens[3])?,
+            })
+        }
+        QuestionType::OPT
+        | QuestionType::TSIG
+        | QuestionType::IXFR
+        | QuestionType::AXFR
+        | QuestionType::ANY => {
+            bail!("{qtype:?} records can't appear in zone files")
+        }
+        QuestionType::Unknown(_) => {
+            bail!(
+                "{} records can only be written in the \\# form",
+                qtype.name()
+            )
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn origin() -> Domain {
+        "example.com".parse().unwrap()
+    }
+
+    fn name(s: &str) -> Domain {
+        s.parse().unwrap()
+    }
+
+    const ZONE: &str = r#"
+$TTL 1h
+@   IN  SOA ns1 hostmaster (
+            2024010101 ; serial
+            2h 15m 2w
+            300 )
+        NS  ns1
+        NS  ns1.example.net.
+        MX  10 mail
+ns1     A   192.0.2.1
+mail 600 IN A 192.0.2.2
+www IN 300 CNAME @
+info    TXT "hello world" "with \"quotes\"" plain
+        HINFO "PC" "Linux"
+$ORIGIN sub
+host    A   192.0.2.3
+        WKS 192.0.2.3 tcp smtp 80
+blob    TYPE10 \# 3 abcdef
+v6      AAAA 2001:db8::3
+svc     TYPE65 \# 4 0001 0000
+"#;
+
+    #[test]
+    fn parses_a_zone() {
+        let records = ZoneParser::parse_str(origin(), ZONE, "example.com.zone").unwrap();
+        assert_eq!(records.len(), 14);
+
+        let soa = &records[0];
+        assert_eq!(soa.name, origin());
+        assert_eq!(soa.ttl, 3600);
+        assert_eq!(
+            soa.rdata().unwrap(),
+            RecordData::SOA(Soa {
+                mname: name("ns1.example.com"),
+                rname: name("hostmaster.example.com"),
+                serial: 2024010101,
+                refresh: 7200,
+                retry: 900,
+                expire: 1209600,
+                minimum: 300,
+            })
+        );
+
+        // blank owners carry on from the record before
+        assert_eq!(records[1].name, origin());
+        assert_eq!(
+            records[2].rdata().unwrap(),
+            RecordData::NS(name("ns1.example.net"))
+        );
+        assert_eq!(records[3].rdata().unwrap(), RecordData::MX {
+            preference: 10,
+            exchange: name("mail.example.com")
+        });
+
+        // TTL and class in either order
+        assert_eq!(records[5].ttl, 600);
+        assert_eq!(records[6].ttl, 300);
+        assert_eq!(records[6].rdata().unwrap(), RecordData::CNAME(origin()));
+
+        assert_eq!(
+            records[7].rdata().unwrap(),
+            RecordData::TXT(vec![
+                "hello world".to_string(),
+                "with \"quotes\"".to_string(),
+                "plain".to_string()
+            ])
+        );
+        assert_eq!(records[8].name, name("info.example.com"));
+
+        // $ORIGIN is relative to the previous origin
+        assert_eq!(records[9].name, name("host.sub.example.com"));
+        match records[10].rdata().unwrap() {
+            RecordData::WKS {
+                protocol, bitmap, ..
+            } => {
+                assert_eq!(protocol, 6);
+                assert_eq!(bitmap.len(), 11);
+                assert_eq!(bitmap[3], 0x40);
+                assert_eq!(bitmap[10], 0x80);
+            }
+            other => panic!("expected WKS, got {other:?}"),
+        }
+        assert_eq!(records[11].qtype, QuestionType::NULL);
+        assert_eq!(records[11].data.as_ref(), [0xab, 0xcd, 0xef]);
+        assert_eq!(records[11].ttl, 3600);
+        assert_eq!(
+            records[12].rdata().unwrap(),
+            RecordData::AAAA("2001:db8::3".parse().unwrap())
+        );
+
+        // types we don't know are kept as they were written
+        assert_eq!(records[13].qtype, QuestionType::Unknown(65));
+        assert_eq!(
+            records[13].rdata().unwrap(),
+            RecordData::Unknown(65, Bytes::from_static(&[0, 1, 0, 0]))
+        );
+        let err = ZoneParser::parse_str(origin(), "svc 60 TYPE65 1 .\n", "zone").unwrap_err();
+        assert!(format!("{err:#}").contains("TYPE65 records can only be written"));
+    }
+
+    #[test]
+    fn ttl_falls_back_to_the_previous_record() {
+        let zone = "a 120 A 192.0.2.1\nb A 192.0.2.2\n";
+        let records = ZoneParser::parse_str(origin(), zone, "zone").unwrap();
+        assert_eq!(records[1].ttl, 120);
+
+        let err = ZoneParser::parse_str(origin(), "a A 192.0.2.1\n", "zone").unwrap_err();
+        assert!(format!("{err:#}").contains("no TTL"));
+    }
+
+    #[test]
+    fn errors_point_at_the_file_and_line() {
+        let zone = "$TTL 60\n\nwww A 192.0.2.1\nbad A 300.0.0.1\n";
+        let err = ZoneParser::parse_str(origin(), zone, "db.example").unwrap_err();
+        assert!(format!("{err:#}").starts_with("db.example:4:"), "{err:#}");
+
+        let err = ZoneParser::parse_str(origin(), "$TTL 60\na ( A\n", "db.example").unwrap_err();
+        assert!(format!("{err:#}").contains("unbalanced"), "{err:#}");
+
+        let err =
+            ZoneParser::parse_str(origin(), "$TTL 60\na BOGUS x\n", "db.example").unwrap_err();
+        assert!(format!("{err:#}").starts_with("db.example:2:"), "{err:#}");
+    }
+
+    #[test]
+    fn generates_records() {
+        let zone = "$TTL 60\n$GENERATE 1-10/3 host-$ A 192.0.2.$\n\
+                    $GENERATE 0-1 ${10,3,x} PTR ${0,2,n}.rev.\n\
+                    $GENERATE 5-5 \\$x CNAME t$\n";
+        let records = ZoneParser::parse_str(origin(), zone, "zone").unwrap();
+        let names: Vec<String> = records.iter().map(|r| r.name.to_string()).collect();
+        assert_eq!(names, vec![
+            "host-1.example.com.",
+            "host-4.example.com.",
+            "host-7.example.com.",
+            "host-10.example.com.",
+            "00a.example.com.",
+            "00b.example.com.",
+            "$x.example.com.",
+        ]);
+        assert_eq!(
+            records[3].rdata().unwrap(),
+            RecordData::A("192.0.2.10".parse().unwrap())
+        );
+        assert_eq!(
+            records[5].rdata().unwrap(),
+            RecordData::PTR(name("1.0.rev"))
+        );
+        assert_eq!(
+            records[6].rdata().unwrap(),
+            RecordData::CNAME(name("t5.example.com"))
+        );
+    }
+
+    #[test]
+    fn includes_files_relative_to_the_parent() {
+        let dir = std::env::temp_dir().join(format!("zone-include-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("main.zone"),
+            "$TTL 60\nwww A 192.0.2.1\n$INCLUDE hosts.inc lab\nafter A 192.0.2.3\n",
+        )
+        .unwrap();
+        std::fs::write(dir.join("hosts.inc"), "box A 192.0.2.2\noops A 1\n").unwrap();
+
+        let err = ZoneParser::parse_file(origin(), dir.join("main.zone")).unwrap_err();
+        assert!(format!("{err:#}").contains("hosts.inc:2:"), "{err:#}");
+
+        std::fs::write(dir.join("hosts.inc"), "box A 192.0.2.2\n").unwrap();
+        let records = ZoneParser::parse_file(origin(), dir.join("main.zone")).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let names: Vec<String> = records.iter().map(|r| r.name.to_string()).collect();
+        assert_eq!(names, vec![
+            "www.example.com.",
+            "box.lab.example.com.",
+            "after.example.com."
+        ]);
+    }
+
+    #[test]
+    fn parses_names() {
+        assert_eq!(parse_name("@", &origin()).unwrap(), origin());
+        assert_eq!(parse_name(".", &origin()).unwrap(), Domain::root());
+        assert_eq!(
+            parse_name("a.b", &origin()).unwrap(),
+            name("a.b.example.com")
+        );
+        assert_eq!(parse_name("a.b.", &origin()).unwrap(), name("a.b"));
+        assert_eq!(
+            parse_name("a\\.b", &origin()).unwrap().labels[0],
+            Label("a.b".to_string())
+        );
+        assert_eq!(
+            parse_name("a\\046b\\032c", &origin()).unwrap().labels[0],
+            Label("a.b c".to_string())
+        );
+        assert!(parse_name("a\\04", &origin()).is_err());
+        assert!(parse_name("a\\256", &origin()).is_err());
+        assert!(parse_name("a..b", &origin()).is_err());
+        assert_eq!(parse_ttl("1w2d3h4m5s").unwrap(), 788645);
+        assert!(parse_ttl("5x").is_err());
+    }
+}
@@ -0,0 +1,237 @@
+use crate::dns::{DnsQuestion, Domain, QuestionType, RecordData, ResponseCode};
+use crate::resolver::query_nameserver;
+use crate::tsig::TsigKey;
+use crate::zone::{Serial, Transfer, ZoneStore, transfer_zone};
+use anyhow::{Result, bail};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+use tracing::{debug, info, warn};
+
+// a zone we keep a copy of, the primaries we get it from and the key they expect, if any
+#[derive(Debug, Clone)]
+pub struct SecondaryZone {
+    pub origin: Domain,
+    pub primaries: Vec<SocketAddr>,
+    pub key: Option<Arc<TsigKey>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct SecondaryConfig {
+    // how long to wait for a primary
+    pub timeout: Duration,
+
+    // how often to retry before we have a copy of the zone, after that its SOA says
+    pub initial_retry: Duration,
+
+    // the SOA timers are clamped to at least this, so a zone can't have us polling constantly
+    pub min_refresh: Duration,
+}
+
+impl Default for SecondaryConfig {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(5),
+            initial_retry: Duration::from_secs(10),
+            min_refresh: Duration::from_secs(1),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Refresher {
+    primaries: Vec<SocketAddr>,
+
+    // signs our transfers, and the primaries' NOTIFYs have to be signed with it too
+    key: Option<Arc<TsigKey>>,
+
+    // wakes the refresh loop early, i.e. when a primary sends a NOTIFY
+    wake: Arc<Notify>,
+}
+
+// Keeps our copies of secondary zones up to date. Each zone polls its primaries for the SOA every
+// refresh interval (or straight away on a NOTIFY), transfers the zone when the serial has moved
+// on, retries on the retry interval when the primaries can't be reached, and stops serving the
+// zone once the expire interval passes without hearing from any of them (RFC 1034 S4.3.5).
+#[derive(Debug)]
+pub struct Secondary {
+    zones: Arc<ZoneStore>,
+    refreshers: HashMap<Domain, Refresher>,
+    config: SecondaryConfig,
+}
+
+impl Secondary {
+    pub fn new(zones: Arc<ZoneStore>, secondaries: Vec<SecondaryZone>) -> Self {
+        let refreshers = secondaries
+            .into_iter()
+            .map(|z| {
+                (z.origin.to_lowercase(), Refresher {
+                    primaries: z.primaries,
+                    key: z.key,
+                    wake: Arc::new(Notify::new()),
+                })
+            })
+            .collect();
+
+        Self {
+            zones,
+            refreshers,
+            config: SecondaryConfig::default(),
+        }
+    }
+
+    pub fn with_config(mut self, config: SecondaryConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    // start a refresh loop for every zone
+    pub fn start(self: &Arc<Self>) {
+        for origin in self.refreshers.keys() {
+            let secondary = self.clone();
+            let origin = origin.clone();
+            tokio::spawn(async move { secondary.run(origin).await });
+        }
+    }
+
+    // A primary says the zone has changed. It only counts if it's one of the zone's primaries,
+    // anyone else could use it to have us hammer the real primaries (RFC 1996 S3.10). Zones
+    // transferred with a key also need the NOTIFY signed with it.
+    pub fn notify(&self, origin: &Domain, from: IpAddr, key: Option<&Domain>) -> bool {
+        let Some(refresher) = self.refreshers.get(&origin.to_lowercase()) else {
+            return false;
+        };
+
+        if !refresher
+            .primaries
+            .iter()
+            .any(|p| p.ip().to_canonical() == from.to_canonical())
+        {
+            warn!("ignoring NOTIFY for {origin} from {from}, not one of its primaries");
+            return false;
+        }
+
+        if let Some(expected) = &refresher.key
+            && !key.is_some_and(|k| k.eq_ignore_case(&expected.name))
+        {
+            warn!(
+                "ignoring NOTIFY for {origin} from {from}, not signed with {}",
+                expected.name
+            );
+            return false;
+        }
+
+        info!("NOTIFY for {origin} from {from}");
+        refresher.wake.notify_one();
+        true
+    }
+
+    async fn run(&self, origin: Domain) {
+        let refresher = &self.refreshers[&origin];
+        let mut last_success: Option<Instant> = None;
+
+        loop {
+            let result = self.refresh(&origin, refresher).await;
+
+            let timers = self.zones.get(&origin).and_then(|z| z.soa().ok());
+            let wait = match (result, timers) {
+                (Ok(()), Some(soa)) => {
+                    last_success = Some(Instant::now());
+                    Duration::from_secs(soa.refresh.into())
+                }
+                (Ok(()), None) => self.config.initial_retry,
+                (Err(e), Some(soa)) => {
+                    warn!("failed to refresh {origin}: {e:#}");
+
+                    // without a primary for long enough our copy can't be trusted any more
+                    let expire = Duration::from_secs(soa.expire.into());
+                    if last_success.is_some_and(|t| t.elapsed() >= expire) {
+                        warn!("{origin} has expired, no longer serving it");
+                        self.zones.remove(&origin);
+                        last_success = None;
+                    }
+                    Duration::from_secs(soa.retry.into())
+                }
+                (Err(e), None) => {
+                    warn!("failed to load {origin}: {e:#}");
+                    self.config.initial_retry
+                }
+            };
+
+            let wait = wait.max(self.config.min_refresh);
+            debug!("next refresh of {origin} in {wait:?}");
+            tokio::select! {
+                _ = tokio::time::sleep(wait) => {}
+                _ = refresher.wake.notified() => {}
+            }
+        }
+    }
+
+    // bring our copy up to date from the first primary that can help
+    async fn refresh(&self, origin: &Domain, refresher: &Refresher) -> Result<()> {
+        for primary in &refresher.primaries {
+            match self
+                .refresh_from(origin, *primary, refresher.key.as_ref())
+                .await
+            {
+                Ok(()) => return Ok(()),
+                Err(e) => warn!("failed to refresh {origin} from {primary}: {e:#}"),
+            }
+        }
+        bail!("none of the primaries for {origin} could be reached")
+    }
+
+    async fn refresh_from(
+        &self,
+        origin: &Domain,
+        primary: SocketAddr,
+        key: Option<&Arc<TsigKey>>,
+    ) -> Result<()> {
+        let current = self.zones.get(origin);
+
+        if let Some(zone) = &current {
+            let question = DnsQuestion::new(origin.clone(), QuestionType::SOA);
+            let response = query_nameserver(primary, &question, self.config.timeout).await?;
+            if response.response_code() != ResponseCode::NoError as u8 {
+                bail!("SOA query got rcode {}", response.response_code());
+            }
+
+            let Some(RecordData::SOA(soa)) = response
+                .answers
+                .answers
+                .iter()
+                .find(|r| r.qtype == QuestionType::SOA)
+                .and_then(|r| r.rdata().ok())
+            else {
+                bail!("SOA query didn't get an SOA back");
+            };
+
+            let ours = zone.serial()?;
+            if !Serial(soa.serial).is_newer_than(Serial(ours)) {
+                debug!("{origin} is up to date at serial {ours}");
+                return Ok(());
+            }
+            info!("{origin} has moved on from {ours} to {}", soa.serial);
+        }
+
+        let wait = self.config.timeout;
+        let transfer = match transfer_zone(primary, origin, current.as_deref(), key, wait).await {
+            // if the changes don't apply to our copy, start again from scratch
+            Err(e) if current.is_some() => {
+                warn!("incremental transfer of {origin} failed, trying a full one: {e:#}");
+                transfer_zone(primary, origin, None, key, wait).await?
+            }
+            result => result?,
+        };
+
+        match transfer {
+            Transfer::UpToDate => {}
+            Transfer::Full(zone) | Transfer::Incremental(zone) => {
+                self.zones.insert(zone);
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,52 @@
+use std::cmp::Ordering;
+
+// the most a serial can be increased by in one go (RFC 1982 S3.1)
+pub const MAX_SERIAL_INCREMENT: u32 = (1 << 31) - 1;
+
+// Zone serials wrap around, so they're compared with RFC 1982 sequence space arithmetic: 1 comes
+// after 4294967295. Two serials exactly 2^31 apart can't be ordered at all.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Serial(pub u32);
+
+impl Serial {
+    pub fn increment(self, n: u32) -> Self {
+        debug_assert!(n <= MAX_SERIAL_INCREMENT);
+        Self(self.0.wrapping_add(n))
+    }
+
+    // false when the two can't be compared as well as when `other` is newer
+    pub fn is_newer_than(self, other: Self) -> bool {
+        self > other
+    }
+}
+
+impl PartialOrd for Serial {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        match other.0.wrapping_sub(self.0) {
+            0 => Some(Ordering::Equal),
+            d if d < 1 << 31 => Some(Ordering::Less),
+            d if d > 1 << 31 => Some(Ordering::Greater),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compares_across_the_wrap() {
+        assert!(Serial(1) < Serial(2));
+        assert!(Serial(u32::MAX) < Serial(0));
+        assert!(Serial(u32::MAX).increment(5) > Serial(u32::MAX));
+        assert_eq!(Serial(u32::MAX).increment(5), Serial(4));
+        assert!(Serial(100) > Serial(100u32.wrapping_sub(MAX_SERIAL_INCREMENT)));
+        assert!(Serial(7) >= Serial(7));
+
+        // exactly half way round is undefined
+        assert_eq!(Serial(0).partial_cmp(&Serial(1 << 31)), None);
+        assert!(!Serial(0).is_newer_than(Serial(1 << 31)));
+        assert!(!Serial(1 << 31).is_newer_than(Serial(0)));
+    }
+}
@@ -0,0 +1,554 @@
+use crate::dns::{DnsAnswer, Domain, Nsec3Param, QuestionType, RecordData, Soa};
+use crate::dnssec::{ZoneSigner, base32hex_decode};
+use crate::zone::{
+    Diff, Journal, NameNode, NameTree, Serial, ZoneParser, append_journal, journal_path,
+    read_journal,
+};
+use anyhow::{Context, Result, bail, ensure};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tracing::{error, info, warn};
+
+// the records of a single zone we're authoritative for
+#[derive(Debug, Clone)]
+pub struct Zone {
+    pub origin: Domain,
+    tree: NameTree,
+
+    // NSEC3 records and their signatures, keyed on the hash in the owner name. Their owners
+    // aren't real names, so they stay out of the tree where they would be found by queries.
+    nsec3: BTreeMap<Vec<u8>, Vec<DnsAnswer>>,
+
+    // the owners of the NSEC records in canonical order (RFC 4034 S6.1), to find the one that
+    // covers a name that doesn't exist
+    nsec: BTreeMap<Vec<String>, Domain>,
+
+    // the changes that led to this version of the zone, for IXFR
+    journal: Journal,
+}
+
+impl Zone {
+    // Build a zone, checking the things that would make it unservable: exactly one SOA at the
+    // apex, nothing outside the origin and nothing sharing a name with a CNAME.
+    pub fn new(origin: Domain, records: Vec<DnsAnswer>) -> Result<Self> {
+        let mut zone = Self {
+            origin,
+            tree: NameTree::default(),
+            nsec3: BTreeMap::new(),
+            nsec: BTreeMap::new(),
+            journal: Journal::default(),
+        };
+        for record in records {
+            zone.insert(record);
+        }
+        zone.check()?;
+        Ok(zone)
+    }
+
+    fn insert(&mut self, record: DnsAnswer) {
+        match nsec3_hash_of(&record) {
+            Some(hash) => {
+                let records = self.nsec3.entry(hash).or_default();
+                if !records.contains(&record) {
+                    records.push(record);
+                }
+            }
+            None => {
+                if record.qtype == QuestionType::NSEC {
+                    self.nsec
+                        .insert(canonical_key(&record.name), record.name.clone());
+                }
+                self.tree.insert(record);
+            }
+        }
+    }
+
+    fn remove(&mut self, record: &DnsAnswer) {
+        let same = |r: &DnsAnswer| {
+            r.qtype == record.qtype && r.class == record.class && r.data == record.data
+        };
+        match nsec3_hash_of(record) {
+            Some(hash) => {
+                if let Some(records) = self.nsec3.get_mut(&hash) {
+                    records.retain(|r| !same(r));
+                    if records.is_empty() {
+                        self.nsec3.remove(&hash);
+                    }
+                }
+            }
+            None => {
+                self.tree.remove(&record.name, same);
+                if record.qtype == QuestionType::NSEC
+                    && !self
+                        .node(&record.name)
+                        .is_some_and(|n| n.has(&QuestionType::NSEC))
+                {
+                    self.nsec.remove(&canonical_key(&record.name));
+                }
+            }
+        }
+    }
+
+    fn check(&self) -> Result<()> {
+        let origin = &self.origin;
+        let records = self.records();
+        let soas = records
+            .iter()
+            .filter(|r| r.qtype == QuestionType::SOA)
+            .collect::<Vec<_>>();
+        ensure!(soas.len() == 1, "zone {origin} must have exactly one SOA");
+        ensure!(
+            soas[0].name.eq_ignore_case(origin),
+            "zone {origin} has its SOA at {}",
+            soas[0].name
+        );
+
+        for record in records {
+            ensure!(
+                record.name.is_subdomain_of(origin),
+                "{} is outside zone {origin}",
+                record.name
+            );
+
+            // the DNSSEC records for a CNAME are the only thing allowed next to it (RFC 4035 S2.5)
+            if record.qtype == QuestionType::CNAME
+                && self.node(&record.name).is_some_and(|node| {
+                    node.records().iter().any(|r| {
+                        !matches!(
+                            r.qtype,
+                            QuestionType::CNAME | QuestionType::RRSIG | QuestionType::NSEC
+                        )
+                    }) || node.rrset(&QuestionType::CNAME).len() > 1
+                })
+            {
+                bail!("{} has a CNAME and other data", record.name);
+            }
+        }
+
+        Ok(())
+    }
+
+    // the zone with `diff` applied, which is also added to its journal
+    pub fn apply(&self, diff: Diff) -> Result<Self> {
+        let serial = self.serial()?;
+        ensure!(
+            diff.from_serial()? == serial,
+            "change to {} starts at serial {} but the zone is at {serial}",
+            self.origin,
+            diff.from_serial()?
+        );
+
+        let mut zone = self.clone();
+        zone.tree
+            .remove(&self.origin, |r| r.qtype == QuestionType::SOA);
+        for record in &diff.removed {
+            zone.remove(record);
+        }
+        zone.insert(diff.to.clone());
+        for record in &diff.added {
+            zone.insert(record.clone());
+        }
+
+        zone.check()?;
+        zone.journal.push(diff);
+        Ok(zone)
+    }
+
+    pub fn from_file(origin: Domain, path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let records = ZoneParser::parse_file(origin.clone(), path)?;
+        Self::new(origin, records).with_context(|| format!("{}", path.display()))
+    }
+
+    pub fn soa(&self) -> Result<Soa> {
+        self.soa_record()
+            .context("zone has no SOA")
+            .and_then(|r| match r.rdata()? {
+                RecordData::SOA(soa) => Ok(soa),
+                _ => bail!("SOA record without SOA data"),
+            })
+    }
+
+    pub fn serial(&self) -> Result<u32> {
+        Ok(self.soa()?.serial)
+    }
+
+    pub fn journal(&self) -> &Journal {
+        &self.journal
+    }
+
+    pub fn soa_record(&self) -> Option<&DnsAnswer> {
+        self.node(&self.origin)?
+            .records()
+            .iter()
+            .find(|r| r.qtype == QuestionType::SOA)
+    }
+
+    pub fn node(&self, name: &Domain) -> Option<&NameNode> {
+        self.tree.get(name)
+    }
+
+    pub fn tree(&self) -> &NameTree {
+        &self.tree
+    }
+
+    // every record in the zone, starting with the apex and ending with the NSEC3 chain
+    pub fn records(&self) -> Vec<&DnsAnswer> {
+        let mut records = self.tree.records();
+        records.extend(self.nsec3.values().flatten());
+        records
+    }
+
+    pub fn len(&self) -> usize {
+        self.tree.len() + self.nsec3.values().map(Vec::len).sum::<usize>()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // signed zones have an RRSIG over their SOA
+    pub fn is_signed(&self) -> bool {
+        self.node(&self.origin)
+            .is_some_and(|n| n.has(&QuestionType::RRSIG))
+    }
+
+    // how the zone's NSEC3 owner names are hashed, None for zones using NSEC
+    pub fn nsec3_param(&self) -> Option<Nsec3Param> {
+        if self.nsec3.is_empty() {
+            return None;
+        }
+        self.node(&self.origin)?
+            .rrset(&QuestionType::NSEC3PARAM)
+            .into_iter()
+            .find_map(|r| match r.rdata() {
+                Ok(RecordData::NSEC3PARAM(param)) => Some(param),
+                _ => None,
+            })
+    }
+
+    // the NSEC3 record with this owner hash, along with its signatures
+    pub fn nsec3_matching(&self, hash: &[u8]) -> Vec<DnsAnswer> {
+        self.nsec3.get(hash).cloned().unwrap_or_default()
+    }
+
+    // the NSEC3 record whose span covers the hash, the last one wrapping round to the first
+    pub fn nsec3_covering(&self, hash: &[u8]) -> Vec<DnsAnswer> {
+        self.nsec3
+            .range(..hash.to_vec())
+            .next_back()
+            .or_else(|| self.nsec3.iter().next_back())
+            .map(|(_, records)| records.clone())
+            .unwrap_or_default()
+    }
+
+    // the owner of the NSEC record at or before `name` in canonical order, which is the one that
+    // matches or covers it
+    pub fn nsec_owner(&self, name: &Domain) -> Option<&Domain> {
+        self.nsec
+            .range(..=canonical_key(name))
+            .next_back()
+            .or_else(|| self.nsec.iter().next_back())
+            .map(|(_, owner)| owner)
+    }
+}
+
+// Names in canonical order sort like their labels lowercased and read from the right.
+fn canonical_key(name: &Domain) -> Vec<String> {
+    name.labels
+        .iter()
+        .rev()
+        .map(|l| l.0.to_ascii_lowercase())
+        .collect()
+}
+
+// the owner hash of an NSEC3 record or a signature over one
+fn nsec3_hash_of(record: &DnsAnswer) -> Option<Vec<u8>> {
+    let nsec3 = match record.qtype {
+        QuestionType::NSEC3 => true,
+        QuestionType::RRSIG => matches!(
+            record.rdata(),
+            Ok(RecordData::RRSIG(rrsig)) if rrsig.type_covered == QuestionType::NSEC3.code()
+        ),
+        _ => false,
+    };
+    if !nsec3 {
+        return None;
+    }
+    base32hex_decode(&record.name.labels.first()?.0).ok()
+}
+
+// how many zone changes can queue up for a subscriber that isn't keeping up
+const CHANGE_QUEUE_LENGTH: usize = 256;
+
+// every zone we serve, keyed on the lowercased origin
+#[derive(Debug)]
+pub struct ZoneStore {
+    zones: RwLock<HashMap<Domain, Arc<Zone>>>,
+
+    // the origin of every zone that's added or replaced
+    changes: broadcast::Sender<Domain>,
+
+    // where changes to zones loaded from files are saved
+    journals: RwLock<HashMap<Domain, PathBuf>>,
+
+    // the zones we sign ourselves, and what with
+    signers: RwLock<HashMap<Domain, Arc<ZoneSigner>>>,
+
+    // changes are made one at a time so each sees the result of the last
+    updating: Mutex<()>,
+}
+
+impl Default for ZoneStore {
+    fn default() -> Self {
+        Self {
+            zones: RwLock::new(HashMap::new()),
+            changes: broadcast::channel(CHANGE_QUEUE_LENGTH).0,
+            journals: RwLock::new(HashMap::new()),
+            signers: RwLock::new(HashMap::new()),
+            updating: Mutex::new(()),
+        }
+    }
+}
+
+impl ZoneStore {
+    // Add or replace a zone, signing it first if it's one we sign. A zone that can't be signed
+    // is still served, validators will treat it as bogus rather than us not answering at all.
+    pub fn insert(&self, zone: Zone) -> Option<Arc<Zone>> {
+        let origin = zone.origin.to_lowercase();
+        let signer = self.signers.read().unwrap().get(&origin).cloned();
+        let zone = match signer.map(|s| s.sign(&zone)) {
+            Some(Ok(signed)) => signed,
+            Some(Err(e)) => {
+                error!("failed to sign {origin}: {e:#}");
+                zone
+            }
+            None => zone,
+        };
+
+        info!("loaded zone {} with {} records", zone.origin, zone.len());
+        let previous = self
+            .zones
+            .write()
+            .unwrap()
+            .insert(origin.clone(), Arc::new(zone));
+
+        // nobody listening is fine
+        let _ = self.changes.send(origin);
+        previous
+    }
+
+    // hear about every zone that's added or replaced from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<Domain> {
+        self.changes.subscribe()
+    }
+
+    // Load a zone file along with the changes in its journal. Changes the file already has (it was
+    // edited and its serial bumped) are skipped, and replaying stops at the first gap.
+    pub fn load_file(&self, origin: Domain, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        let mut zone = Zone::from_file(origin, path)?;
+
+        let journal = journal_path(path);
+        for diff in read_journal(&journal)? {
+            let serial = Serial(zone.serial()?);
+            let from = Serial(diff.from_serial()?);
+            if serial.is_newer_than(from) {
+                continue;
+            }
+            if from != serial {
+                warn!(
+                    "{} skips from serial {} to {}, ignoring the rest of it",
+                    journal.display(),
+                    serial.0,
+                    from.0
+                );
+                break;
+            }
+            zone = zone
+                .apply(diff)
+                .with_context(|| format!("{}", journal.display()))?;
+        }
+
+        self.journals
+            .write()
+            .unwrap()
+            .insert(zone.origin.to_lowercase(), journal);
+        self.insert(zone);
+        Ok(())
+    }
+
+    // Change one of our zones, with `change` working out the diff from the zone as it is now or
+    // returning None to leave it alone. The change is saved to the zone's journal first if it has
+    // one, and nothing else changes the zone in between.
+    pub fn update(
+        &self,
+        origin: &Domain,
+        change: impl FnOnce(&Zone) -> Result<Option<Diff>>,
+    ) -> Result<Option<Arc<Zone>>> {
+        let _guard = self.updating.lock().unwrap();
+        let zone = self
+            .get(origin)
+            .with_context(|| format!("{origin} isn't one of our zones"))?;
+        let Some(diff) = change(&zone)? else {
+            return Ok(None);
+        };
+        let zone = zone.apply(diff.clone())?;
+
+        if let Some(path) = self.journals.read().unwrap().get(&origin.to_lowercase()) {
+            append_journal(path, &diff)?;
+        }
+        self.insert(zone);
+        self.get(origin)
+            .context("zone went away while updating it")
+            .map(Some)
+    }
+
+    // Sign `origin` with `signer` from now on, which has to be set up before the zone is loaded.
+    pub fn set_signer(&self, origin: &Domain, signer: Arc<ZoneSigner>) {
+        self.signers
+            .write()
+            .unwrap()
+            .insert(origin.to_lowercase(), signer);
+    }
+
+    // Sign a zone again if its signatures are getting old, returning whether it was. The serial
+    // goes up, otherwise secondaries would never fetch the new signatures.
+    pub fn resign(&self, origin: &Domain) -> Result<bool> {
+        let signer = self
+            .signers
+            .read()
+            .unwrap()
+            .get(&origin.to_lowercase())
+            .cloned()
+            .with_context(|| format!("we don't sign {origin}"))?;
+        let updated = self.update(origin, |zone| {
+            if !signer.needs_refresh(zone) {
+                return Ok(None);
+            }
+
+            let from = zone.soa_record().context("zone has no SOA")?.clone();
+            let mut soa = zone.soa()?;
+            soa.serial = soa.serial.wrapping_add(1);
+            let to = DnsAnswer::new(from.name.clone(), from.ttl, RecordData::SOA(soa))?;
+            Ok(Some(Diff {
+                from,
+                removed: vec![],
+                to,
+                added: vec![],
+            }))
+        })?;
+        Ok(updated.is_some())
+    }
+
+    // check every signed zone's signatures once per `interval`, signing again as needed
+    pub fn start_resigning(self: &Arc<Self>, interval: Duration) {
+        let zones = self.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                let origins: Vec<Domain> = zones.signers.read().unwrap().keys().cloned().collect();
+                for origin in origins {
+                    match zones.resign(&origin) {
+                        Ok(true) => info!("signed {origin} again"),
+                        Ok(false) => {}
+                        Err(e) => warn!("failed to sign {origin} again: {e:#}"),
+                    }
+                }
+            }
+        });
+    }
+
+    pub fn remove(&self, origin: &Domain) -> Option<Arc<Zone>> {
+        self.zones.write().unwrap().remove(&origin.to_lowercase())
+    }
+
+    pub fn get(&self, origin: &Domain) -> Option<Arc<Zone>> {
+        self.zones
+            .read()
+            .unwrap()
+            .get(&origin.to_lowercase())
+            .cloned()
+    }
+
+    // the most specific zone that `name` falls within
+    pub fn find(&self, name: &Domain) -> Option<Arc<Zone>> {
+        let zones = self.zones.read().unwrap();
+        let mut current = Some(name.to_lowercase());
+        while let Some(name) = current {
+            if let Some(zone) = zones.get(&name) {
+                return Some(zone.clone());
+            }
+            current = name.parent();
+        }
+        None
+    }
+
+    pub fn origins(&self) -> Vec<Domain> {
+        self.zones.read().unwrap().keys().cloned().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.zones.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn zone(origin: &str, text: &str) -> Result<Zone> {
+        let origin: Domain = origin.parse().unwrap();
+        Zone::new(origin.clone(), ZoneParser::parse_str(origin, text, "zone")?)
+    }
+
+    const SOA: &str = "$TTL 60\n@ SOA ns hostmaster 1 2 3 4 5\n";
+
+    #[test]
+    fn finds_the_closest_zone() {
+        let store = ZoneStore::default();
+        store.insert(zone("example.com", SOA).unwrap());
+        store.insert(zone("sub.Example.com", SOA).unwrap());
+
+        let find = |n: &str| {
+            store
+                .find(&n.parse().unwrap())
+                .map(|z| z.origin.to_string())
+        };
+        assert_eq!(find("www.example.com").as_deref(), Some("example.com."));
+        assert_eq!(
+            find("a.SUB.example.com").as_deref(),
+            Some("sub.Example.com.")
+        );
+        assert_eq!(find("example.org"), None);
+        assert_eq!(store.len(), 2);
+    }
+
+    #[test]
+    fn rejects_broken_zones() {
+        assert!(zone("example.com", "$TTL 60\nwww A 192.0.2.1\n").is_err());
+        assert!(zone("example.com", &format!("{SOA}other.org. A 192.0.2.1\n")).is_err());
+        assert!(
+            zone(
+                "example.com",
+                &format!("{SOA}www CNAME @\nwww A 192.0.2.1\n")
+            )
+            .is_err()
+        );
+        assert_eq!(
+            zone("example.com", &format!("{SOA}www A 192.0.2.1\n"))
+                .unwrap()
+                .soa()
+                .unwrap()
+                .serial,
+            1
+        );
+    }
+}
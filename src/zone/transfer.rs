@@ -0,0 +1,431 @@
+use crate::dns::{
+    DnsAnswer, DnsAnswerSet, DnsMessage, DnsQuestion, Domain, QuestionType, ResponseCode,
+    read_frame, write_frame,
+};
+use crate::parse::DnsData;
+use crate::resolver::random_id;
+use crate::tsig::{TsigKey, TsigSession};
+use crate::zone::{Diff, Serial, Zone, soa_serial};
+use anyhow::{Context, Result, bail, ensure};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::time::{Instant, timeout, timeout_at};
+use tracing::{debug, info};
+
+// Transfers are split across messages of about this size. Anything up to 64k would do, but
+// smaller messages mean the receiver can start working sooner.
+const TRANSFER_MESSAGE_SIZE: usize = 16384;
+
+// A transfer that goes past either of these is given up on, so a primary that keeps sending, or
+// sends just often enough to never time out, can't keep us busy for good.
+pub const MAX_TRANSFER_SIZE: usize = 256 * 1024 * 1024;
+pub const MAX_TRANSFER_TIME: Duration = Duration::from_secs(900);
+
+// the records of a full transfer: the SOA, everything else, then the SOA again (RFC 5936 S2.2)
+pub fn axfr_records(zone: &Zone) -> Result<Vec<DnsAnswer>> {
+    let soa = zone.soa_record().context("zone has no SOA")?.clone();
+    let mut records = vec![soa.clone()];
+    records.extend(
+        zone.records()
+            .into_iter()
+            .filter(|r| r.qtype != QuestionType::SOA)
+            .cloned(),
+    );
+    records.push(soa);
+    Ok(records)
+}
+
+// The records of an incremental transfer for a client at `serial` (RFC 1995 S4). A client that's
+// up to date just gets our SOA, and None means the journal doesn't go back far enough so it'll
+// have to make do with a full transfer.
+pub fn ixfr_records(zone: &Zone, serial: u32) -> Result<Option<Vec<DnsAnswer>>> {
+    let soa = zone.soa_record().context("zone has no SOA")?.clone();
+    if Serial(serial) >= Serial(zone.serial()?) {
+        return Ok(Some(vec![soa]));
+    }
+
+    let Some(diffs) = zone.journal().since(serial) else {
+        return Ok(None);
+    };
+
+    let mut records = vec![soa.clone()];
+    for diff in diffs {
+        records.push(diff.from.clone());
+        records.extend(diff.removed.iter().cloned());
+        records.push(diff.to.clone());
+        records.extend(diff.added.iter().cloned());
+    }
+    records.push(soa);
+    Ok(Some(records))
+}
+
+// spread records across as many replies to `request` as it takes
+pub fn transfer_messages(request: &DnsMessage, records: Vec<DnsAnswer>) -> Result<Vec<DnsMessage>> {
+    let mut messages = Vec::new();
+    let mut chunk = Vec::new();
+    let mut size = 0;
+
+    for record in records {
+        let record_size = record.name.wire_len() + 10 + record.data.len();
+        if size + record_size > TRANSFER_MESSAGE_SIZE && !chunk.is_empty() {
+            messages.push(transfer_message(request, std::mem::take(&mut chunk))?);
+            size = 0;
+        }
+        size += record_size;
+        chunk.push(record);
+    }
+    if !chunk.is_empty() || messages.is_empty() {
+        messages.push(transfer_message(request, chunk)?);
+    }
+
+    Ok(messages)
+}
+
+fn transfer_message(request: &DnsMessage, answers: Vec<DnsAnswer>) -> Result<DnsMessage> {
+    let mut reply = request.clone().as_reply();
+    reply.header.auth_answer = true;
+    reply
+        .with_answers(DnsAnswerSet { answers })?
+        .with_authority(DnsAnswerSet::default())?
+        .with_additional(DnsAnswerSet::default())
+}
+
+// what a transfer from the primary got us
+#[derive(Debug)]
+pub enum Transfer {
+    // our copy is already the latest
+    UpToDate,
+
+    // the whole zone
+    Full(Zone),
+
+    // our copy with the primary's changes applied
+    Incremental(Zone),
+}
+
+// Pull a zone from its primary over TCP. With a copy of the zone we ask for just the changes
+// since (IXFR), which the primary may answer with the whole zone anyway, otherwise we ask for
+// everything (AXFR). With a key the request is signed and so must every reply be. `wait` is how
+// long we wait for each message, the transfer as a whole has MAX_TRANSFER_TIME.
+pub async fn transfer_zone(
+    primary: SocketAddr,
+    origin: &Domain,
+    current: Option<&Zone>,
+    key: Option<&Arc<TsigKey>>,
+    wait: Duration,
+) -> Result<Transfer> {
+    let qtype = match current {
+        Some(_) => QuestionType::IXFR,
+        None => QuestionType::AXFR,
+    };
+    let mut request = DnsMessage::query(random_id(), DnsQuestion::new(origin.clone(), qtype));
+    if let Some(zone) = current {
+        let soa = zone.soa_record().context("zone has no SOA")?.clone();
+        request = request.with_authority(DnsAnswerSet { answers: vec![soa] })?;
+    }
+    let mut session = key.map(|key| TsigSession::new(key.clone()));
+    if let Some(session) = &mut session {
+        request = session.sign(request)?;
+    }
+
+    let deadline = Instant::now() + MAX_TRANSFER_TIME;
+    let mut stream = timeout(wait, TcpStream::connect(primary))
+        .await
+        .with_context(|| format!("timed out connecting to {primary}"))??;
+    write_frame(&mut stream, &request.encode(0, &mut HashMap::new())?).await?;
+
+    let mut received = Received::default();
+    let mut size = 0;
+    loop {
+        let frame = timeout_at(deadline.min(Instant::now() + wait), read_frame(&mut stream)).await;
+        let Ok(frame) = frame else {
+            ensure!(
+                Instant::now() < deadline,
+                "transfer of {origin} from {primary} took longer than {MAX_TRANSFER_TIME:?}"
+            );
+            bail!("timed out waiting for {primary}");
+        };
+        let buf =
+            frame?.with_context(|| format!("{primary} closed the connection part way through"))?;
+        size += buf.len();
+        ensure!(
+            size <= MAX_TRANSFER_SIZE,
+            "transfer of {origin} from {primary} is over {MAX_TRANSFER_SIZE} bytes"
+        );
+        if let Some(session) = &mut session {
+            session
+                .verify(&buf)
+                .with_context(|| format!("transfer of {origin} from {primary}"))?;
+        }
+        let (_, message) = DnsMessage::decode(&buf, 0, &mut HashMap::new())?;
+
+        ensure!(
+            message.header.packet_id == request.header.packet_id,
+            "transfer message from {primary} has the wrong ID"
+        );
+        ensure!(
+            message.response_code() == ResponseCode::NoError as u8,
+            "{primary} refused to transfer {origin} (rcode {})",
+            message.response_code()
+        );
+        let first = received.records.is_empty();
+        for record in message.answers.answers {
+            received.push(record)?;
+        }
+        let records = &received.records;
+
+        // A single message with a lone SOA no newer than ours means there's nothing to transfer
+        // (RFC 1995 S2). A newer one is only the start of a transfer that carries on in the
+        // messages after it.
+        let up_to_date = match current {
+            Some(zone) if first && records.len() == 1 => {
+                !Serial(soa_serial(&records[0])?).is_newer_than(Serial(zone.serial()?))
+            }
+            _ => false,
+        };
+        if up_to_date || received.complete()? {
+            break;
+        }
+    }
+    if let Some(session) = &session {
+        session.finish()?;
+    }
+
+    let mut records = received.records;
+    let serial = soa_serial(&records[0])?;
+    if records.len() == 1 {
+        debug!("{origin} is up to date at serial {serial}");
+        return Ok(Transfer::UpToDate);
+    }
+
+    match (current, is_incremental(&records)?) {
+        (Some(zone), true) => {
+            let mut zone = zone.clone();
+            for diff in diffs(&records)? {
+                zone = zone.apply(diff)?;
+            }
+            info!("applied incremental transfer of {origin} up to serial {serial}");
+            Ok(Transfer::Incremental(zone))
+        }
+        (None, true) => bail!("{primary} sent an incremental transfer we didn't ask for"),
+        (_, false) => {
+            records.pop();
+            let zone = Zone::new(origin.clone(), records)?;
+            info!(
+                "transferred {origin} at serial {serial}, {} records",
+                zone.len()
+            );
+            Ok(Transfer::Full(zone))
+        }
+    }
+}
+
+// An incremental transfer has the old SOA straight after the new one, a full transfer goes on to
+// the rest of the zone (or straight to the closing SOA for a zone with nothing else in it).
+fn is_incremental(records: &[DnsAnswer]) -> Result<bool> {
+    Ok(records.len() > 2
+        && records[1].qtype == QuestionType::SOA
+        && soa_serial(&records[1])? != soa_serial(&records[0])?)
+}
+
+// the records of a transfer so far, counting the SOAs as they arrive
+#[derive(Debug, Default)]
+struct Received {
+    records: Vec<DnsAnswer>,
+
+    // the serial the transfer started with, and how many times it has turned up
+    serial: Option<u32>,
+    appearances: usize,
+}
+
+impl Received {
+    fn push(&mut self, record: DnsAnswer) -> Result<()> {
+        if record.qtype == QuestionType::SOA {
+            let serial = soa_serial(&record)?;
+            if *self.serial.get_or_insert(serial) == serial {
+                self.appearances += 1;
+            }
+        }
+        ensure!(self.serial.is_some(), "transfer didn't start with an SOA");
+        self.records.push(record);
+        Ok(())
+    }
+
+    // A transfer ends with the SOA it started with. That's its second appearance in a full
+    // transfer, and its third in an incremental one, where it's also the result of the last change.
+    fn complete(&self) -> Result<bool> {
+        let needed = if is_incremental(&self.records)? { 3 } else { 2 };
+        Ok(self.appearances >= needed
+            && self
+                .records
+                .last()
+                .is_some_and(|r| r.qtype == QuestionType::SOA))
+    }
+}
+
+// split the body of an incremental transfer back into the changes it's made of
+fn diffs(records: &[DnsAnswer]) -> Result<Vec<Diff>> {
+    let body = &records[1..records.len() - 1];
+    let mut diffs = Vec::new();
+    let mut i = 0;
+
+    // each change is: old SOA, removed records, new SOA, added records
+    let take_until_soa = |i: &mut usize| {
+        let start = *i;
+        while *i < body.len() && body[*i].qtype != QuestionType::SOA {
+            *i += 1;
+        }
+        body[start..*i].to_vec()
+    };
+
+    while i < body.len() {
+        let from = body[i].clone();
+        i += 1;
+        let removed = take_until_soa(&mut i);
+        let to = body
+            .get(i)
+            .context("incremental transfer is missing an SOA")?
+            .clone();
+        i += 1;
+        let added = take_until_soa(&mut i);
+        diffs.push(Diff {
+            from,
+            removed,
+            to,
+            added,
+        });
+    }
+
+    Ok(diffs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::{RecordData, Soa};
+    use crate::zone::ZoneParser;
+
+    fn zone() -> Zone {
+        let origin: Domain = "example.com".parse().unwrap();
+        let text = "$TTL 60\n@ SOA ns hm 1 2 3 4 5\n@ NS ns\nns A 192.0.2.1\n\
+                    $GENERATE 1-250 host$ A 10.0.0.$\n\
+                    $GENERATE 1-250 text$ TXT \"padding for record number $\"\n";
+        Zone::new(
+            origin.clone(),
+            ZoneParser::parse_str(origin, text, "zone").unwrap(),
+        )
+        .unwrap()
+    }
+
+    fn soa(serial: u32) -> DnsAnswer {
+        DnsAnswer::new(
+            "example.com".parse().unwrap(),
+            60,
+            RecordData::SOA(Soa {
+                mname: "ns.example.com".parse().unwrap(),
+                rname: "hm.example.com".parse().unwrap(),
+                serial,
+                refresh: 2,
+                retry: 3,
+                expire: 4,
+                minimum: 5,
+            }),
+        )
+        .unwrap()
+    }
+
+    fn a(name: &str, ip: [u8; 4]) -> DnsAnswer {
+        DnsAnswer::new(name.parse().unwrap(), 60, RecordData::A(ip.into())).unwrap()
+    }
+
+    fn complete(records: &[DnsAnswer]) -> bool {
+        let mut received = Received::default();
+        for record in records {
+            received.push(record.clone()).unwrap();
+        }
+        received.complete().unwrap()
+    }
+
+    fn change(zone: &Zone, to: u32, removed: Vec<DnsAnswer>, added: Vec<DnsAnswer>) -> Zone {
+        zone.apply(Diff {
+            from: zone.soa_record().unwrap().clone(),
+            removed,
+            to: soa(to),
+            added,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn full_transfers_span_several_messages() {
+        let zone = zone();
+        let records = axfr_records(&zone).unwrap();
+        assert_eq!(records.len(), zone.len() + 1);
+        assert_eq!(records[0], records[records.len() - 1]);
+        assert!(complete(&records));
+        assert!(!complete(&records[..records.len() - 1]));
+
+        // and one that doesn't start with the SOA isn't a transfer at all
+        assert!(Received::default().push(records[1].clone()).is_err());
+
+        let request =
+            DnsMessage::query(1, DnsQuestion::new(zone.origin.clone(), QuestionType::AXFR));
+        let messages = transfer_messages(&request, records.clone()).unwrap();
+        assert!(messages.len() > 1);
+        let received: Vec<DnsAnswer> = messages
+            .into_iter()
+            .flat_map(|m| {
+                let encoded = m.encode(0, &mut HashMap::new()).unwrap();
+                assert!(encoded.len() < u16::MAX as usize);
+                let (_, m) = DnsMessage::decode(&encoded, 0, &mut HashMap::new()).unwrap();
+                m.answers.answers
+            })
+            .collect();
+        assert_eq!(received, records);
+    }
+
+    #[test]
+    fn incremental_transfers_replay_the_journal() {
+        let v1 = zone();
+        let v2 = change(&v1, 2, vec![a("host1.example.com", [10, 0, 0, 1])], vec![]);
+        let v3 = change(&v2, 3, vec![], vec![a("new.example.com", [10, 9, 9, 9])]);
+        assert!(
+            v1.apply(v3.journal().diffs().next().unwrap().clone())
+                .is_ok()
+        );
+        assert!(
+            v2.apply(v3.journal().diffs().next().unwrap().clone())
+                .is_err()
+        );
+
+        assert_eq!(ixfr_records(&v3, 3).unwrap().unwrap(), vec![soa(3)]);
+        assert!(ixfr_records(&v3, 0).unwrap().is_none());
+
+        // a client somehow ahead of us has nothing to catch up on
+        assert_eq!(ixfr_records(&v3, 7).unwrap().unwrap(), vec![soa(3)]);
+
+        let records = ixfr_records(&v3, 1).unwrap().unwrap();
+        assert!(is_incremental(&records).unwrap());
+        assert!(complete(&records));
+        assert!(!complete(&records[..records.len() - 1]));
+
+        let mut replayed = v1.clone();
+        for diff in diffs(&records).unwrap() {
+            replayed = replayed.apply(diff).unwrap();
+        }
+        assert_eq!(replayed.records(), v3.records());
+        assert_eq!(replayed.serial().unwrap(), 3);
+        assert!(
+            replayed
+                .node(&"host1.example.com".parse().unwrap())
+                .is_none()
+        );
+
+        // from the middle of the history
+        let records = ixfr_records(&v3, 2).unwrap().unwrap();
+        assert_eq!(diffs(&records).unwrap().len(), 1);
+    }
+}
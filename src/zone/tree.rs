@@ -0,0 +1,212 @@
+use crate::dns::{DnsAnswer, Domain, QuestionType};
+use std::collections::BTreeMap;
+
+// One name in the tree, holding the records owned by that name. A node with no records but with
+// children is an empty non-terminal: the name exists, it just has no data of its own.
+#[derive(Debug, Default, Clone)]
+pub struct NameNode {
+    children: BTreeMap<String, NameNode>,
+    records: Vec<DnsAnswer>,
+}
+
+impl NameNode {
+    pub fn records(&self) -> &[DnsAnswer] {
+        &self.records
+    }
+
+    pub fn rrset(&self, qtype: &QuestionType) -> Vec<&DnsAnswer> {
+        self.records.iter().filter(|r| r.qtype == *qtype).collect()
+    }
+
+    pub fn has(&self, qtype: &QuestionType) -> bool {
+        self.records.iter().any(|r| r.qtype == *qtype)
+    }
+
+    pub fn child(&self, label: &str) -> Option<&NameNode> {
+        self.children.get(&label.to_ascii_lowercase())
+    }
+
+    fn is_empty(&self) -> bool {
+        self.records.is_empty() && self.children.is_empty()
+    }
+
+    fn collect<'a>(&'a self, out: &mut Vec<&'a DnsAnswer>) {
+        out.extend(self.records.iter());
+        for child in self.children.values() {
+            child.collect(out);
+        }
+    }
+}
+
+// How far down the tree a name got.
+#[derive(Debug)]
+pub struct Walk<'a> {
+    // the node for every label of the name that exists, starting with the root
+    pub path: Vec<&'a NameNode>,
+
+    // whether the full name exists in the tree
+    pub exact: bool,
+}
+
+impl<'a> Walk<'a> {
+    // the deepest existing ancestor of the name, or the name itself when it exists
+    pub fn closest_encloser(&self) -> &'a NameNode {
+        self.path[self.path.len() - 1]
+    }
+}
+
+// Records indexed by name, one level of the tree per label and walked from the root down, so
+// finding a name or its closest encloser only costs as many steps as the name has labels.
+// Labels are compared case insensitively.
+#[derive(Debug, Default, Clone)]
+pub struct NameTree {
+    root: NameNode,
+    len: usize,
+}
+
+impl NameTree {
+    // add a record, returns false if an identical one was already there
+    pub fn insert(&mut self, record: DnsAnswer) -> bool {
+        let mut node = &mut self.root;
+        for label in record.name.labels.iter().rev() {
+            node = node
+                .children
+                .entry(label.0.to_ascii_lowercase())
+                .or_default();
+        }
+
+        if node
+            .records
+            .iter()
+            .any(|r| r.qtype == record.qtype && r.class == record.class && r.data == record.data)
+        {
+            return false;
+        }
+
+        node.records.push(record);
+        self.len += 1;
+        true
+    }
+
+    // remove the records at `name` that match, pruning any nodes left empty
+    pub fn remove(
+        &mut self,
+        name: &Domain,
+        mut matches: impl FnMut(&DnsAnswer) -> bool,
+    ) -> Vec<DnsAnswer> {
+        fn remove_at(
+            node: &mut NameNode,
+            labels: &[String],
+            matches: &mut dyn FnMut(&DnsAnswer) -> bool,
+        ) -> Vec<DnsAnswer> {
+            let Some((label, rest)) = labels.split_last() else {
+                let (removed, kept) = std::mem::take(&mut node.records)
+                    .into_iter()
+                    .partition(|r| matches(r));
+                node.records = kept;
+                return removed;
+            };
+
+            let Some(child) = node.children.get_mut(label) else {
+                return Vec::new();
+            };
+            let removed = remove_at(child, rest, matches);
+            if child.is_empty() {
+                node.children.remove(label);
+            }
+            removed
+        }
+
+        let labels: Vec<String> = name
+            .labels
+            .iter()
+            .map(|l| l.0.to_ascii_lowercase())
+            .collect();
+        let removed = remove_at(&mut self.root, &labels, &mut matches);
+        self.len -= removed.len();
+        removed
+    }
+
+    pub fn get(&self, name: &Domain) -> Option<&NameNode> {
+        let mut node = &self.root;
+        for label in name.labels.iter().rev() {
+            node = node.child(&label.0)?;
+        }
+        Some(node)
+    }
+
+    // follow `name` down from the root as far as it goes
+    pub fn walk(&self, name: &Domain) -> Walk<'_> {
+        let mut path = vec![&self.root];
+        for label in name.labels.iter().rev() {
+            match path[path.len() - 1].child(&label.0) {
+                Some(child) => path.push(child),
+                None => return Walk { path, exact: false },
+            }
+        }
+        Walk { path, exact: true }
+    }
+
+    // every record, parents before children and siblings in label order
+    pub fn records(&self) -> Vec<&DnsAnswer> {
+        let mut out = Vec::with_capacity(self.len);
+        self.root.collect(&mut out);
+        out
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::RecordData;
+
+    fn a(name: &str) -> DnsAnswer {
+        DnsAnswer::new(
+            name.parse().unwrap(),
+            60,
+            RecordData::A([192, 0, 2, 1].into()),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn walks_to_the_closest_encloser() {
+        let mut tree = NameTree::default();
+        assert!(tree.insert(a("www.example.com")));
+        assert!(!tree.insert(a("WWW.example.com")));
+        assert!(tree.insert(a("a.b.example.com")));
+        assert_eq!(tree.len(), 2);
+
+        let walk = tree.walk(&"x.WWW.example.com".parse().unwrap());
+        assert!(!walk.exact);
+        assert_eq!(walk.path.len(), 4);
+        assert_eq!(walk.closest_encloser().records().len(), 1);
+
+        // b.example.com is an empty non-terminal
+        let walk = tree.walk(&"b.example.com".parse().unwrap());
+        assert!(walk.exact);
+        assert!(walk.closest_encloser().records().is_empty());
+    }
+
+    #[test]
+    fn removing_prunes_empty_nodes() {
+        let mut tree = NameTree::default();
+        tree.insert(a("a.b.example.com"));
+        tree.insert(a("example.com"));
+
+        let removed = tree.remove(&"A.b.example.com".parse().unwrap(), |_| true);
+        assert_eq!(removed.len(), 1);
+        assert_eq!(tree.len(), 1);
+        assert!(tree.get(&"b.example.com".parse().unwrap()).is_none());
+        assert!(tree.get(&"example.com".parse().unwrap()).is_some());
+        assert_eq!(tree.records().len(), 1);
+    }
+}
@@ -0,0 +1,562 @@
+use crate::dns::{
+    CLASS_ANY, CLASS_IN, CLASS_NONE, DnsAnswer, DnsAnswerSet, DnsMessage, Domain, Opcode,
+    QuestionType, RRset, RecordData, ResponseCode,
+};
+use crate::handler::{DnsHandler, DnsRequest};
+use crate::zone::{Diff, NameTree, Serial, Zone, ZoneStore};
+use anyhow::{Result, bail};
+use async_trait::async_trait;
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::sync::Arc;
+use tracing::{debug, info, warn};
+
+// types that only make sense in questions, so can't be added to a zone
+fn is_meta(qtype: &QuestionType) -> bool {
+    matches!(
+        qtype,
+        QuestionType::ANY
+            | QuestionType::AXFR
+            | QuestionType::IXFR
+            | QuestionType::OPT
+            | QuestionType::TSIG
+    )
+}
+
+fn same_record(a: &DnsAnswer, b: &DnsAnswer) -> bool {
+    a.qtype == b.qtype && a.class == b.class && a.data == b.data
+}
+
+// Check the prerequisite section of an UPDATE against the zone (RFC 2136 S3.2), giving the
+// response code for the first one that doesn't hold.
+pub fn check_prerequisites(zone: &Zone, prerequisites: &[DnsAnswer]) -> Option<ResponseCode> {
+    // records with the zone's class are compared as whole RRsets once they've all been seen
+    let mut rrsets: Vec<DnsAnswer> = Vec::new();
+
+    for pr in prerequisites {
+        if pr.ttl != 0 {
+            return Some(ResponseCode::FormErr);
+        }
+        if !pr.name.is_subdomain_of(&zone.origin) {
+            return Some(ResponseCode::NotZone);
+        }
+
+        let node = zone.node(&pr.name);
+        let in_use = node.is_some_and(|n| !n.records().is_empty());
+        let rrset_exists = node.is_some_and(|n| n.has(&pr.qtype));
+
+        let failed = match (pr.class, &pr.qtype) {
+            (CLASS_ANY | CLASS_NONE, _) if !pr.data.is_empty() => ResponseCode::FormErr,
+            (CLASS_ANY, QuestionType::ANY) if !in_use => ResponseCode::NxDomain,
+            (CLASS_ANY, QuestionType::ANY) => continue,
+            (CLASS_ANY, _) if !rrset_exists => ResponseCode::NxRrset,
+            (CLASS_ANY, _) => continue,
+            (CLASS_NONE, QuestionType::ANY) if in_use => ResponseCode::YxDomain,
+            (CLASS_NONE, QuestionType::ANY) => continue,
+            (CLASS_NONE, _) if rrset_exists => ResponseCode::YxRrset,
+            (CLASS_NONE, _) => continue,
+            (CLASS_IN, qtype) if !is_meta(qtype) => {
+                rrsets.push(pr.clone());
+                continue;
+            }
+            _ => ResponseCode::FormErr,
+        };
+        return Some(failed);
+    }
+
+    // each RRset has to match the zone's exactly, in any order
+    for expected in RRset::group(&rrsets) {
+        let actual = zone
+            .node(expected.name())
+            .map(|n| n.rrset(expected.qtype()))
+            .unwrap_or_default();
+        let matches = RRset::from_records(actual.into_iter().cloned())
+            .and_then(|actual| actual.same_data(&expected))
+            .unwrap_or(false);
+        if !matches {
+            return Some(ResponseCode::NxRrset);
+        }
+    }
+
+    None
+}
+
+// Check the update section before touching anything (RFC 2136 S3.4.1.3), an UPDATE either
+// applies in full or not at all.
+fn prescan(zone: &Zone, updates: &[DnsAnswer]) -> Option<ResponseCode> {
+    for rr in updates {
+        if !rr.name.is_subdomain_of(&zone.origin) {
+            return Some(ResponseCode::NotZone);
+        }
+
+        let valid = match rr.class {
+            CLASS_IN => !is_meta(&rr.qtype) && rr.rdata().is_ok(),
+            CLASS_ANY => {
+                rr.ttl == 0
+                    && rr.data.is_empty()
+                    && !matches!(rr.qtype, QuestionType::AXFR | QuestionType::IXFR)
+            }
+            CLASS_NONE => rr.ttl == 0 && !is_meta(&rr.qtype),
+            _ => false,
+        };
+        if !valid {
+            return Some(ResponseCode::FormErr);
+        }
+    }
+    None
+}
+
+// Apply the update section to a copy of the zone's records (RFC 2136 S3.4.2). Additions that would
+// break the zone, like a second SOA or data alongside a CNAME, and deletions of the apex SOA or
+// last NS are silently skipped, as the RFC says.
+fn apply_updates(zone: &Zone, updates: &[DnsAnswer]) -> Result<NameTree> {
+    let mut tree = zone.tree().clone();
+    let origin = &zone.origin;
+
+    for rr in updates {
+        let at_apex = rr.name.eq_ignore_case(origin);
+        match rr.class {
+            CLASS_IN => {
+                let existing = tree.get(&rr.name).map(|n| n.records()).unwrap_or_default();
+                let has_cname = existing.iter().any(|r| r.qtype == QuestionType::CNAME);
+                let has_other = existing.iter().any(|r| r.qtype != QuestionType::CNAME);
+
+                match rr.qtype {
+                    QuestionType::SOA => {
+                        // only a newer SOA for the apex replaces the one we have
+                        let newer = match (rr.rdata()?, zone.soa()) {
+                            (RecordData::SOA(new), Ok(old)) => {
+                                Serial(new.serial).is_newer_than(Serial(old.serial))
+                            }
+                            _ => false,
+                        };
+                        if !at_apex || !newer {
+                            continue;
+                        }
+                        tree.remove(&rr.name, |r| r.qtype == QuestionType::SOA);
+                    }
+                    QuestionType::CNAME if has_other => continue,
+                    QuestionType::CNAME => {
+                        tree.remove(&rr.name, |r| r.qtype == QuestionType::CNAME);
+                    }
+                    _ if has_cname => continue,
+
+                    // the same record again replaces it, which is how its TTL gets changed
+                    _ => {
+                        tree.remove(&rr.name, |r| same_record(r, rr));
+                    }
+                }
+                tree.insert(rr.clone());
+            }
+            CLASS_ANY => match rr.qtype {
+                // the apex keeps its SOA and NS records whatever happens
+                QuestionType::ANY if at_apex => {
+                    tree.remove(&rr.name, |r| {
+                        !matches!(r.qtype, QuestionType::SOA | QuestionType::NS)
+                    });
+                }
+                QuestionType::ANY => {
+                    tree.remove(&rr.name, |_| true);
+                }
+                QuestionType::SOA | QuestionType::NS if at_apex => {}
+                _ => {
+                    tree.remove(&rr.name, |r| r.qtype == rr.qtype);
+                }
+            },
+            CLASS_NONE => {
+                if rr.qtype == QuestionType::SOA {
+                    continue;
+                }
+                let last_ns = at_apex
+                    && rr.qtype == QuestionType::NS
+                    && tree
+                        .get(&rr.name)
+                        .is_some_and(|n| n.rrset(&QuestionType::NS).len() == 1);
+                if last_ns {
+                    continue;
+                }
+                tree.remove(&rr.name, |r| {
+                    r.qtype == rr.qtype && r.class == CLASS_IN && r.data == rr.data
+                });
+            }
+            class => bail!("update record with class {class} got past the prescan"),
+        }
+    }
+
+    Ok(tree)
+}
+
+// The change that takes `zone` to the records in `tree`, None if there isn't one. The serial goes
+// up by one unless the update already moved it on itself.
+fn diff(zone: &Zone, tree: &NameTree) -> Result<Option<Diff>> {
+    let is_soa = |r: &&DnsAnswer| r.qtype == QuestionType::SOA;
+    let before: HashSet<&DnsAnswer> = zone.records().into_iter().filter(|r| !is_soa(r)).collect();
+    let after: HashSet<&DnsAnswer> = tree.records().into_iter().filter(|r| !is_soa(r)).collect();
+
+    let from = zone.soa_record().cloned().unwrap_or_default();
+    let new_soa = tree.records().into_iter().find(is_soa).cloned();
+    let soa_changed = new_soa.as_ref().is_some_and(|soa| *soa != from);
+    if before == after && !soa_changed {
+        return Ok(None);
+    }
+
+    let to = match new_soa {
+        Some(soa) if soa_changed => soa,
+        _ => {
+            let RecordData::SOA(mut soa) = from.rdata()? else {
+                bail!("{} has no SOA", zone.origin);
+            };
+            soa.serial = Serial(soa.serial).increment(1).0;
+            DnsAnswer {
+                data: RecordData::SOA(soa).encode()?,
+                ..from.clone()
+            }
+        }
+    };
+
+    Ok(Some(Diff {
+        from,
+        removed: before.difference(&after).map(|r| (*r).clone()).collect(),
+        to,
+        added: after.difference(&before).map(|r| (*r).clone()).collect(),
+    }))
+}
+
+// Takes dynamic UPDATEs (RFC 2136) for our zones and passes everything else on. The message
+// sections carry the update: the question is the zone, the answers are the prerequisites and the
+// authority section the changes to make. Each zone has its own list of clients and TSIG keys
+// allowed to change it, and a zone without either can't be updated at all.
+#[derive(Debug)]
+pub struct UpdateHandler {
+    zones: Arc<ZoneStore>,
+    inner: Arc<dyn DnsHandler>,
+    access: HashMap<Domain, Vec<IpAddr>>,
+
+    // TSIG keys that allow a zone to be updated from anywhere
+    keys: HashMap<Domain, Vec<Domain>>,
+}
+
+impl UpdateHandler {
+    pub fn new(zones: Arc<ZoneStore>, inner: Arc<dyn DnsHandler>) -> Self {
+        Self {
+            zones,
+            inner,
+            access: HashMap::new(),
+            keys: HashMap::new(),
+        }
+    }
+
+    pub fn with_zone_access(mut self, origin: Domain, allowed: Vec<IpAddr>) -> Self {
+        self.access.insert(origin.to_lowercase(), allowed);
+        self
+    }
+
+    pub fn with_zone_keys(mut self, origin: Domain, keys: Vec<Domain>) -> Self {
+        self.keys.insert(origin.to_lowercase(), keys);
+        self
+    }
+
+    fn allowed(&self, origin: &Domain, request: &DnsRequest) -> bool {
+        let origin = origin.to_lowercase();
+        let client = request.client.ip().to_canonical();
+        let signed = request.key.as_ref().is_some_and(|key| {
+            self.keys
+                .get(&origin)
+                .is_some_and(|keys| keys.iter().any(|k| k.eq_ignore_case(key)))
+        });
+
+        signed
+            || self
+                .access
+                .get(&origin)
+                .is_some_and(|allowed| allowed.iter().any(|a| a.to_canonical() == client))
+    }
+
+    async fn update(&self, request: &DnsRequest) -> Result<ResponseCode> {
+        let message = &request.message;
+        let [zone_section] = message.questions.questions.as_slice() else {
+            return Ok(ResponseCode::FormErr);
+        };
+        if zone_section.qtype != QuestionType::SOA {
+            return Ok(ResponseCode::FormErr);
+        }
+
+        let origin = &zone_section.name;
+        if self.zones.get(origin).is_none() {
+            debug!("UPDATE for {origin}, which isn't one of our zones");
+            return Ok(ResponseCode::NotAuth);
+        }
+        if !self.allowed(origin, request) {
+            warn!("refusing UPDATE for {origin} from {}", request.client);
+            return Ok(ResponseCode::Refused);
+        }
+
+        // the prerequisites are checked against the zone the changes are made to
+        let mut code = ResponseCode::NoError;
+        let updated = self.zones.update(origin, |zone| {
+            if let Some(failed) = check_prerequisites(zone, &message.answers.answers) {
+                debug!("UPDATE for {origin} failed its prerequisites: {failed:?}");
+                code = failed;
+                return Ok(None);
+            }
+
+            let updates = &message.authority.answers;
+            if let Some(failed) = prescan(zone, updates) {
+                code = failed;
+                return Ok(None);
+            }
+
+            let tree = apply_updates(zone, updates)?;
+            let diff = diff(zone, &tree)?;
+            if diff.is_none() {
+                debug!("UPDATE for {origin} didn't change anything");
+            }
+            Ok(diff)
+        })?;
+
+        if let Some(zone) = updated {
+            info!(
+                "{} updated {origin} to serial {}",
+                request.client.ip(),
+                zone.serial()?
+            );
+        }
+        Ok(code)
+    }
+}
+
+fn is_update(request: &DnsRequest) -> bool {
+    request.message.header.opcode == Opcode::Update as u8
+}
+
+#[async_trait]
+impl DnsHandler for UpdateHandler {
+    async fn handle(&self, request: &DnsRequest) -> Result<Option<DnsMessage>> {
+        if !is_update(request) {
+            return self.inner.handle(request).await;
+        }
+
+        // the response only carries the zone section back (RFC 2136 S3.8)
+        let code = self.update(request).await?;
+        Ok(Some(
+            request
+                .message
+                .clone()
+                .as_reply()
+                .with_answers(DnsAnswerSet::default())?
+                .with_authority(DnsAnswerSet::default())?
+                .with_additional(DnsAnswerSet::default())?
+                .with_response_code(code),
+        ))
+    }
+
+    async fn handle_stream(&self, request: &DnsRequest) -> Result<Vec<DnsMessage>> {
+        if is_update(request) {
+            return Ok(self.handle(request).await?.into_iter().collect());
+        }
+        self.inner.handle_stream(request).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::zone::ZoneParser;
+
+    const ZONE: &str = r#"
+$TTL 300
+@       SOA ns1 hostmaster 7 3600 600 86400 60
+        NS  ns1
+ns1     A   192.0.2.1
+www     A   192.0.2.2
+        A   192.0.2.3
+alias   CNAME www
+"#;
+
+    fn zone() -> Zone {
+        let origin: Domain = "example.com".parse().unwrap();
+        Zone::new(
+            origin.clone(),
+            ZoneParser::parse_str(origin, ZONE, "zone").unwrap(),
+        )
+        .unwrap()
+    }
+
+    fn rr(
+        name: &str,
+        class: u16,
+        ttl: u32,
+        rdata: Option<RecordData>,
+        qtype: QuestionType,
+    ) -> DnsAnswer {
+        DnsAnswer {
+            name: name.parse().unwrap(),
+            qtype,
+            class,
+            ttl,
+            data: rdata.map(|d| d.encode().unwrap()).unwrap_or_default(),
+        }
+    }
+
+    fn a(name: &str, class: u16, ttl: u32, ip: [u8; 4]) -> DnsAnswer {
+        rr(
+            name,
+            class,
+            ttl,
+            Some(RecordData::A(ip.into())),
+            QuestionType::A,
+        )
+    }
+
+    fn update(zone: &Zone, updates: &[DnsAnswer]) -> Option<Zone> {
+        assert_eq!(prescan(zone, updates), None);
+        let tree = apply_updates(zone, updates).unwrap();
+        diff(zone, &tree)
+            .unwrap()
+            .map(|diff| zone.apply(diff).unwrap())
+    }
+
+    #[test]
+    fn checks_prerequisites() {
+        let zone = zone();
+        let check = |prs: &[DnsAnswer]| check_prerequisites(&zone, prs);
+        let www = "www.example.com";
+
+        assert_eq!(
+            check(&[rr(www, CLASS_ANY, 0, None, QuestionType::ANY)]),
+            None
+        );
+        assert_eq!(
+            check(&[rr("new.example.com", CLASS_ANY, 0, None, QuestionType::ANY)]),
+            Some(ResponseCode::NxDomain)
+        );
+        assert_eq!(
+            check(&[rr(www, CLASS_NONE, 0, None, QuestionType::ANY)]),
+            Some(ResponseCode::YxDomain)
+        );
+        assert_eq!(
+            check(&[rr(www, CLASS_ANY, 0, None, QuestionType::MX)]),
+            Some(ResponseCode::NxRrset)
+        );
+        assert_eq!(
+            check(&[rr(www, CLASS_NONE, 0, None, QuestionType::A)]),
+            Some(ResponseCode::YxRrset)
+        );
+
+        // value dependent prerequisites need the whole RRset
+        let both = [
+            a(www, CLASS_IN, 0, [192, 0, 2, 3]),
+            a(www, CLASS_IN, 0, [192, 0, 2, 2]),
+        ];
+        assert_eq!(check(&both), None);
+        assert_eq!(check(&both[..1]), Some(ResponseCode::NxRrset));
+
+        assert_eq!(
+            check(&[a(www, CLASS_IN, 60, [192, 0, 2, 2])]),
+            Some(ResponseCode::FormErr)
+        );
+        assert_eq!(
+            check(&[rr("www.example.org", CLASS_ANY, 0, None, QuestionType::ANY)]),
+            Some(ResponseCode::NotZone)
+        );
+    }
+
+    #[test]
+    fn adds_and_deletes_records() {
+        let v7 = zone();
+
+        // adding bumps the serial
+        let v8 = update(&v7, &[a("new.example.com", CLASS_IN, 60, [192, 0, 2, 9])]).unwrap();
+        assert_eq!(v8.serial().unwrap(), 8);
+        assert!(v8.node(&"new.example.com".parse().unwrap()).is_some());
+        assert_eq!(v8.journal().len(), 1);
+
+        // adding what's already there changes nothing
+        assert!(update(&v8, &[a("new.example.com", CLASS_IN, 60, [192, 0, 2, 9])]).is_none());
+
+        // delete one record, then an RRset, then a whole name
+        let www: Domain = "www.example.com".parse().unwrap();
+        let v9 = update(&v8, &[a("www.example.com", CLASS_NONE, 0, [192, 0, 2, 2])]).unwrap();
+        assert_eq!(v9.node(&www).unwrap().records().len(), 1);
+        let v10 = update(&v9, &[rr(
+            "www.example.com",
+            CLASS_ANY,
+            0,
+            None,
+            QuestionType::A,
+        )])
+        .unwrap();
+        assert!(v10.node(&www).is_none());
+        let v11 = update(&v10, &[rr(
+            "alias.example.com",
+            CLASS_ANY,
+            0,
+            None,
+            QuestionType::ANY,
+        )])
+        .unwrap();
+        assert_eq!(v11.serial().unwrap(), 11);
+        assert_eq!(v11.len(), 4);
+
+        // the apex keeps its SOA and NS, and data can't be added next to a CNAME
+        assert!(
+            update(&v7, &[rr(
+                "example.com",
+                CLASS_ANY,
+                0,
+                None,
+                QuestionType::ANY
+            )])
+            .is_none()
+        );
+        assert!(
+            update(&v7, &[rr(
+                "example.com",
+                CLASS_ANY,
+                0,
+                None,
+                QuestionType::NS
+            )])
+            .is_none()
+        );
+        assert!(update(&v7, &[a("alias.example.com", CLASS_IN, 60, [192, 0, 2, 9])]).is_none());
+    }
+
+    #[test]
+    fn an_explicit_soa_sets_the_serial() {
+        let zone = zone();
+        let soa = |serial| {
+            let RecordData::SOA(mut soa) = zone.soa().map(RecordData::SOA).unwrap() else {
+                unreachable!()
+            };
+            soa.serial = serial;
+            rr(
+                "example.com",
+                CLASS_IN,
+                300,
+                Some(RecordData::SOA(soa)),
+                QuestionType::SOA,
+            )
+        };
+
+        assert_eq!(update(&zone, &[soa(100)]).unwrap().serial().unwrap(), 100);
+
+        // an older serial is ignored, so the other change just bumps it
+        let updated = update(&zone, &[
+            soa(3),
+            a("new.example.com", CLASS_IN, 60, [192, 0, 2, 9]),
+        ]);
+        assert_eq!(updated.unwrap().serial().unwrap(), 8);
+
+        assert_eq!(
+            prescan(&zone, &[rr(
+                "example.com",
+                CLASS_IN,
+                0,
+                None,
+                QuestionType::ANY
+            )]),
+            Some(ResponseCode::FormErr)
+        );
+    }
+}
@@ -1,6 +1,20 @@
 use anyhow::Result;
-use dns::dns::DnsServer;
-use std::sync::LazyLock;
+use async_trait::async_trait;
+use bytes::Bytes;
+use dns::dns::{
+    DnsAnswer, DnsAnswerSet, DnsMessage, DnsQuestion, DnsServer, Domain, QuestionType, RecordData,
+    ResponseCode, send_request,
+};
+use dns::handler::{DnsHandler, DnsRequest};
+use dns::parse::DnsData;
+use rustls::crypto::ring::default_provider;
+use rustls::{ClientConfig, RootCertStore};
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::{Arc, LazyLock};
+use tokio::net::UdpSocket;
 
 static TRACING: LazyLock<()> = LazyLock::new(|| {
     if std::env::var("TESTING_LOG").is_ok() {
@@ -15,3 +29,195 @@ pub async fn spawn_app() -> Result<String> {
     tokio::spawn(async move { server.run_until_stopped().await });
     Ok(address)
 }
+
+pub async fn spawn_app_with_handler(
+    address: &str,
+    handler: Arc<dyn DnsHandler>,
+) -> Result<SocketAddr> {
+    LazyLock::force(&TRACING);
+    let server = DnsServer::build(address).await?.with_handler(handler);
+    let address = server.address()?.parse()?;
+    tokio::spawn(async move { server.run_until_stopped().await });
+    Ok(address)
+}
+
+// sends a request over UDP and decodes the reply, which has to be the one to this request
+pub async fn query(server: impl Display, request: DnsMessage) -> Result<DnsMessage> {
+    let reply = send_request(&server.to_string(), request.encode(0, &mut HashMap::new())?).await?;
+    let (_, reply) = DnsMessage::decode(&reply, 0, &mut HashMap::new())?;
+    assert_eq!(reply.header.packet_id, request.header.packet_id);
+    Ok(reply)
+}
+
+// a plain question, without EDNS or recursion desired
+pub async fn ask(server: impl Display, name: &str, qtype: QuestionType) -> Result<DnsMessage> {
+    query(
+        server,
+        DnsMessage::query(1, DnsQuestion::new(name.parse()?, qtype)),
+    )
+    .await
+}
+
+// send_request always goes from 127.0.0.1, this picks the address the request comes from
+pub async fn send_request_from(
+    client: &str,
+    server: &str,
+    request: DnsMessage,
+) -> Result<DnsMessage> {
+    let socket = UdpSocket::bind(format!("{client}:0")).await?;
+    socket.connect(server).await?;
+    socket
+        .send(&request.encode(0, &mut HashMap::new())?)
+        .await?;
+
+    let mut buf = [0; 4096];
+    let len = socket.recv(&mut buf).await?;
+    let (_, reply) =
+        DnsMessage::decode(&Bytes::copy_from_slice(&buf[..len]), 0, &mut HashMap::new())?;
+    Ok(reply)
+}
+
+// A self-signed certificate for localhost, written out as PEM files the way an operator would
+// have them, and a client config that trusts it.
+pub fn self_signed_certificate(test: &str) -> Result<(PathBuf, PathBuf, ClientConfig)> {
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()])?;
+    let dir = std::env::temp_dir().join(format!("{test}-{}", std::process::id()));
+    std::fs::create_dir_all(&dir)?;
+    let (cert_path, key_path) = (dir.join("cert.pem"), dir.join("key.pem"));
+    std::fs::write(&cert_path, cert.cert.pem())?;
+    std::fs::write(&key_path, cert.key_pair.serialize_pem())?;
+
+    let mut roots = RootCertStore::empty();
+    roots.add(cert.cert.der().clone())?;
+    let client = ClientConfig::builder_with_provider(Arc::new(default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok((cert_path, key_path, client))
+}
+
+pub fn record(name: &str, ttl: u32, rdata: RecordData) -> DnsAnswer {
+    DnsAnswer::new(name.parse().unwrap(), ttl, rdata).unwrap()
+}
+
+// the addresses in a reply's answer section, without any CNAMEs on the way to them
+pub fn addresses(reply: &DnsMessage) -> Vec<RecordData> {
+    reply
+        .answers
+        .answers
+        .iter()
+        .filter(|a| matches!(a.qtype, QuestionType::A | QuestionType::AAAA))
+        .map(|a| a.rdata().unwrap())
+        .collect()
+}
+
+// www.example.com with two addresses, the second with a shorter TTL
+pub fn example() -> StubZone {
+    StubZone::new("example.com.", vec![
+        record(
+            "www.example.com.",
+            300,
+            RecordData::A(Ipv4Addr::new(192, 0, 2, 80)),
+        ),
+        record(
+            "www.example.com.",
+            60,
+            RecordData::A(Ipv4Addr::new(192, 0, 2, 81)),
+        ),
+    ])
+}
+
+// A very small authoritative server for a single zone. Just enough to build a fake hierarchy of
+// servers for the resolver to walk: it answers from its records, hands out referrals (with
+// whatever glue it has) for NS records below the apex, and otherwise says NXDOMAIN or NODATA.
+#[derive(Debug)]
+pub struct StubZone {
+    pub apex: Domain,
+    pub records: Vec<DnsAnswer>,
+}
+
+impl StubZone {
+    pub fn new(apex: &str, records: Vec<DnsAnswer>) -> Self {
+        Self {
+            apex: apex.parse().unwrap(),
+            records,
+        }
+    }
+
+    fn soa(&self) -> DnsAnswerSet {
+        DnsAnswerSet {
+            answers: self
+                .records
+                .iter()
+                .filter(|r| r.qtype == QuestionType::SOA)
+                .cloned()
+                .collect(),
+        }
+    }
+}
+
+#[async_trait]
+impl DnsHandler for StubZone {
+    async fn handle(&self, request: &DnsRequest) -> Result<Option<DnsMessage>> {
+        let question = request.message.question().unwrap().clone();
+        let mut reply = request.message.clone().as_reply();
+
+        // delegations
+        if let Some(cut) = self.records.iter().find(|r| {
+            r.qtype == QuestionType::NS
+                && !r.name.eq_ignore_case(&self.apex)
+                && question.name.is_subdomain_of(&r.name)
+        }) {
+            let ns: Vec<DnsAnswer> = self
+                .records
+                .iter()
+                .filter(|r| r.qtype == QuestionType::NS && r.name.eq_ignore_case(&cut.name))
+                .cloned()
+                .collect();
+            let glue: Vec<DnsAnswer> = self
+                .records
+                .iter()
+                .filter(|r| {
+                    matches!(r.qtype, QuestionType::A | QuestionType::AAAA)
+                        && ns
+                            .iter()
+                            .any(|n| n.rdata().unwrap().target() == Some(&r.name))
+                })
+                .cloned()
+                .collect();
+
+            return Ok(Some(
+                reply
+                    .with_authority(DnsAnswerSet { answers: ns })?
+                    .with_additional(DnsAnswerSet { answers: glue })?,
+            ));
+        }
+
+        reply.header.auth_answer = true;
+        let owned: Vec<&DnsAnswer> = self
+            .records
+            .iter()
+            .filter(|r| r.name.eq_ignore_case(&question.name))
+            .collect();
+
+        if owned.is_empty() {
+            return Ok(Some(
+                reply
+                    .with_response_code(ResponseCode::NxDomain)
+                    .with_authority(self.soa())?,
+            ));
+        }
+
+        let answers: Vec<DnsAnswer> = owned
+            .into_iter()
+            .filter(|r| r.qtype == question.qtype || r.qtype == QuestionType::CNAME)
+            .cloned()
+            .collect();
+
+        if answers.is_empty() {
+            return Ok(Some(reply.with_authority(self.soa())?));
+        }
+
+        Ok(Some(reply.with_answers(DnsAnswerSet { answers })?))
+    }
+}
@@ -1,7 +1,32 @@
+// the tests build requests up field by field from a default message
+#![allow(clippy::field_reassign_with_default)]
+
 mod helpers;
 mod simple;
+mod test_acl;
 mod test_answer_label_fail_1;
 mod test_answer_label_fail_2;
 mod test_answer_label_fail_3;
+mod test_authoritative;
+mod test_cache;
+mod test_dnssec;
+mod test_doh;
+mod test_doq;
+mod test_dot;
 mod test_encode_decode_message_with_question;
+mod test_filter;
 mod test_forwarding;
+mod test_hosts;
+mod test_malformed;
+mod test_recursive;
+mod test_rpz;
+mod test_rrl;
+mod test_secondary;
+mod test_serve_stale;
+mod test_throttle;
+mod test_transfer;
+mod test_truncation;
+mod test_tsig;
+mod test_update;
+mod test_upstreams;
+mod test_views;
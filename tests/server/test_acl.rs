@@ -0,0 +1,87 @@
+use crate::helpers::{StubZone, record, send_request_from, spawn_app_with_handler};
+use anyhow::Result;
+use dns::acl::{Access, AclHandler, STAT_ACL_ALLOWED, STAT_ACL_REFUSED};
+use dns::dns::*;
+use dns::zone::{AuthoritativeHandler, Zone, ZoneParser, ZoneStore};
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+
+const ZONE: &str = "
+$TTL 300
+@    SOA ns1 hostmaster 1 3600 600 86400 60
+     NS  ns1
+ns1  A   192.0.2.53
+www  A   192.0.2.80
+";
+
+async fn ask_from(client: &str, server: &str, name: &str, qtype: QuestionType) -> Result<u8> {
+    let request = DnsMessage::query(9, DnsQuestion::new(name.parse()?, qtype));
+    Ok(send_request_from(client, server, request)
+        .await?
+        .response_code())
+}
+
+#[tokio::test]
+async fn test_acls_refuse_queries_recursion_and_transfers() -> Result<()> {
+    let origin: Domain = "example.com".parse()?;
+    let zones = Arc::new(ZoneStore::default());
+    zones.insert(Zone::new(
+        origin.clone(),
+        ZoneParser::parse_str(origin, ZONE, "example.com")?,
+    )?);
+
+    // recursion is only for 127.0.0.2, everything else only gets our own zone
+    let resolver = StubZone::new("example.net.", vec![record(
+        "www.example.net.",
+        300,
+        RecordData::A(Ipv4Addr::new(198, 51, 100, 80)),
+    )]);
+    let recursion =
+        AclHandler::new(Arc::new(resolver)).with_acl(Access::Recursion, "127.0.0.2".parse()?);
+    let authoritative = AuthoritativeHandler::new(zones)
+        .with_fallback(Arc::new(recursion))
+        .with_transfer_allowed(vec!["127.0.0.2".parse()?, "127.0.0.3".parse()?]);
+
+    let acls = Arc::new(
+        AclHandler::new(Arc::new(authoritative))
+            .with_acl(Access::Query, "!127.0.0.4, 127.0.0.0/8".parse()?)
+            .with_acl(Access::Transfer, "127.0.0.2".parse()?),
+    );
+    let server = spawn_app_with_handler("127.0.0.1:0", acls.clone())
+        .await?
+        .to_string();
+
+    let refused = ResponseCode::Refused as u8;
+    let ok = ResponseCode::NoError as u8;
+    assert_eq!(
+        ask_from("127.0.0.3", &server, "www.example.com", QuestionType::A).await?,
+        ok
+    );
+    assert_eq!(
+        ask_from("127.0.0.4", &server, "www.example.com", QuestionType::A).await?,
+        refused
+    );
+
+    assert_eq!(
+        ask_from("127.0.0.2", &server, "www.example.net", QuestionType::A).await?,
+        ok
+    );
+    assert_eq!(
+        ask_from("127.0.0.3", &server, "www.example.net", QuestionType::A).await?,
+        refused
+    );
+
+    // both are on the zone's own transfer list, but only one gets past the ACL
+    assert_eq!(
+        ask_from("127.0.0.2", &server, "example.com", QuestionType::IXFR).await?,
+        ok
+    );
+    assert_eq!(
+        ask_from("127.0.0.3", &server, "example.com", QuestionType::IXFR).await?,
+        refused
+    );
+
+    assert_eq!(acls.stats().get(STAT_ACL_ALLOWED), 4);
+    assert_eq!(acls.stats().get(STAT_ACL_REFUSED), 2);
+    Ok(())
+}
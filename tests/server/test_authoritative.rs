@@ -0,0 +1,80 @@
+use crate::helpers::{ask, spawn_app_with_handler};
+use anyhow::Result;
+use dns::dns::*;
+use dns::zone::{AuthoritativeHandler, ZoneStore};
+use std::sync::Arc;
+
+const ZONE: &str = r#"
+$ORIGIN example.com.
+$TTL 1h
+@       IN SOA ns1 hostmaster (
+            2024060101 ; serial
+            1h         ; refresh
+            10m        ; retry
+            1w         ; expire
+            5m )       ; minimum
+        IN NS ns1
+ns1     IN A 192.0.2.1
+www     IN A 192.0.2.2
+alias   IN CNAME www
+*.users IN A 192.0.2.3
+shop    IN NS ns.shop
+ns.shop IN A 192.0.2.4
+"#;
+
+#[tokio::test]
+async fn test_serves_zone_from_master_file() -> Result<()> {
+    let path = std::env::temp_dir().join(format!("db.example.{}", std::process::id()));
+    std::fs::write(&path, ZONE)?;
+    let zones = Arc::new(ZoneStore::default());
+    zones.load_file("example.com".parse()?, &path)?;
+    std::fs::remove_file(&path)?;
+
+    let server = spawn_app_with_handler("127.0.0.1:0", Arc::new(AuthoritativeHandler::new(zones)))
+        .await?
+        .to_string();
+
+    let reply = ask(&server, "alias.example.com", QuestionType::A).await?;
+    assert!(reply.header.auth_answer);
+    assert_eq!(reply.response_code(), ResponseCode::NoError as u8);
+    let answers: Vec<RecordData> = reply
+        .answers
+        .answers
+        .iter()
+        .map(|a| a.rdata().unwrap())
+        .collect();
+    assert_eq!(answers, vec![
+        RecordData::CNAME("www.example.com".parse()?),
+        RecordData::A([192, 0, 2, 2].into()),
+    ]);
+
+    let reply = ask(&server, "bob.users.example.com", QuestionType::A).await?;
+    assert_eq!(
+        reply.answers.answers[0].name.to_string(),
+        "bob.users.example.com."
+    );
+
+    let reply = ask(&server, "missing.example.com", QuestionType::A).await?;
+    assert!(reply.header.auth_answer);
+    assert_eq!(reply.response_code(), ResponseCode::NxDomain as u8);
+    assert_eq!(reply.authority.answers[0].qtype, QuestionType::SOA);
+    assert_eq!(reply.authority.answers[0].ttl, 300);
+
+    let reply = ask(&server, "www.example.com", QuestionType::MX).await?;
+    assert_eq!(reply.response_code(), ResponseCode::NoError as u8);
+    assert!(reply.answers.answers.is_empty());
+    assert_eq!(reply.authority.answers[0].qtype, QuestionType::SOA);
+
+    let reply = ask(&server, "www.shop.example.com", QuestionType::A).await?;
+    assert!(!reply.header.auth_answer);
+    assert_eq!(reply.authority.answers[0].qtype, QuestionType::NS);
+    assert_eq!(
+        reply.additional.answers[0].name.to_string(),
+        "ns.shop.example.com."
+    );
+
+    let reply = ask(&server, "www.example.org", QuestionType::A).await?;
+    assert_eq!(reply.response_code(), ResponseCode::Refused as u8);
+
+    Ok(())
+}
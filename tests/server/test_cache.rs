@@ -0,0 +1,150 @@
+use crate::helpers::{StubZone, ask, record, spawn_app_with_handler};
+use anyhow::Result;
+use async_trait::async_trait;
+use dns::cache::{CacheConfig, CachingHandler, DnsCache, ManualClock, STAT_CACHE_PREFETCHES};
+use dns::dns::*;
+use dns::handler::{DnsHandler, DnsRequest};
+use dns::resolver::ForwardHandler;
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+// counts how many requests make it through to the upstream
+#[derive(Debug)]
+struct Counting {
+    count: Arc<AtomicUsize>,
+    inner: StubZone,
+}
+
+#[async_trait]
+impl DnsHandler for Counting {
+    async fn handle(&self, request: &DnsRequest) -> Result<Option<DnsMessage>> {
+        self.count.fetch_add(1, Ordering::SeqCst);
+        self.inner.handle(request).await
+    }
+}
+
+#[tokio::test]
+async fn test_cache_in_front_of_forwarder() -> Result<()> {
+    let count = Arc::new(AtomicUsize::new(0));
+    let upstream = spawn_app_with_handler(
+        "127.0.0.1:0",
+        Arc::new(Counting {
+            count: count.clone(),
+            inner: StubZone::new("example.com.", vec![
+                record(
+                    "example.com.",
+                    3600,
+                    RecordData::SOA(Soa {
+                        mname: "ns.example.com".parse()?,
+                        rname: "hostmaster.example.com".parse()?,
+                        minimum: 60,
+                        ..Soa::default()
+                    }),
+                ),
+                record(
+                    "www.example.com.",
+                    300,
+                    RecordData::A(Ipv4Addr::new(10, 0, 0, 1)),
+                ),
+            ]),
+        }),
+    )
+    .await?;
+
+    let clock = Arc::new(ManualClock::default());
+    let cache = Arc::new(DnsCache::with_clock(CacheConfig::default(), clock.clone()));
+    let forwarder =
+        ForwardHandler::new(vec![upstream.into()]).with_timeout(Duration::from_millis(500));
+    let server = spawn_app_with_handler(
+        "127.0.0.1:0",
+        Arc::new(CachingHandler::new(cache, Arc::new(forwarder))),
+    )
+    .await?
+    .to_string();
+
+    // the first query goes upstream
+    let reply = ask(&server, "www.example.com", QuestionType::A).await?;
+    assert_eq!(reply.answers.answers[0].ttl, 300);
+    assert_eq!(count.load(Ordering::SeqCst), 1);
+
+    // the second one doesn't, and the TTL has counted down
+    clock.advance(Duration::from_secs(120));
+    let reply = ask(&server, "www.example.com", QuestionType::A).await?;
+    assert_eq!(reply.answers.answers[0].ttl, 180);
+    assert_eq!(count.load(Ordering::SeqCst), 1);
+
+    // once it expires we go back upstream
+    clock.advance(Duration::from_secs(180));
+    ask(&server, "www.example.com", QuestionType::A).await?;
+    assert_eq!(count.load(Ordering::SeqCst), 2);
+
+    // negative answers are cached too, for the SOA minimum
+    let reply = ask(&server, "missing.example.com", QuestionType::A).await?;
+    assert_eq!(reply.response_code(), ResponseCode::NxDomain as u8);
+    let reply = ask(&server, "missing.example.com", QuestionType::A).await?;
+    assert_eq!(reply.response_code(), ResponseCode::NxDomain as u8);
+    assert_eq!(reply.authority.answers[0].ttl, 60);
+    assert_eq!(count.load(Ordering::SeqCst), 3);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_cache_prefetches_popular_entries() -> Result<()> {
+    let count = Arc::new(AtomicUsize::new(0));
+    let upstream = spawn_app_with_handler(
+        "127.0.0.1:0",
+        Arc::new(Counting {
+            count: count.clone(),
+            inner: StubZone::new("example.com.", vec![record(
+                "www.example.com.",
+                100,
+                RecordData::A(Ipv4Addr::new(10, 0, 0, 1)),
+            )]),
+        }),
+    )
+    .await?;
+
+    let clock = Arc::new(ManualClock::default());
+    let cache = Arc::new(DnsCache::with_clock(
+        CacheConfig {
+            prefetch_threshold: 20,
+            prefetch_min_hits: 2,
+            ..CacheConfig::default()
+        },
+        clock.clone(),
+    ));
+    let forwarder =
+        ForwardHandler::new(vec![upstream.into()]).with_timeout(Duration::from_millis(500));
+    let handler = CachingHandler::new(cache, Arc::new(forwarder));
+    let stats = handler.stats();
+    let server = spawn_app_with_handler("127.0.0.1:0", Arc::new(handler))
+        .await?
+        .to_string();
+
+    // fill the cache and make the entry popular
+    for _ in 0..3 {
+        ask(&server, "www.example.com", QuestionType::A).await?;
+    }
+    assert_eq!(count.load(Ordering::SeqCst), 1);
+
+    // close to expiry the client still gets the cached answer, but a refresh goes out behind it
+    clock.advance(Duration::from_secs(85));
+    let reply = ask(&server, "www.example.com", QuestionType::A).await?;
+    assert_eq!(reply.answers.answers[0].ttl, 15);
+
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(count.load(Ordering::SeqCst), 2);
+    assert_eq!(stats.get(STAT_CACHE_PREFETCHES), 1);
+
+    // so by the time the old entry would have expired there's already a new one, fetched 15
+    // seconds ago
+    clock.advance(Duration::from_secs(15));
+    let reply = ask(&server, "www.example.com", QuestionType::A).await?;
+    assert_eq!(reply.answers.answers[0].ttl, 85);
+    assert_eq!(count.load(Ordering::SeqCst), 2);
+
+    Ok(())
+}
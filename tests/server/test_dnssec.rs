@@ -0,0 +1,592 @@
+use crate::helpers::{StubZone, record, spawn_app_with_handler};
+use anyhow::Result;
+use async_trait::async_trait;
+use dns::cache::{CacheConfig, CachingHandler, DnsCache, STAT_CACHE_HITS};
+use dns::dns::*;
+use dns::dnssec::{
+    ALGORITHM_ED25519, DIGEST_SHA256, SignerConfig, SigningKey, TrustAnchor, ZoneSigner, ds_for,
+    signed_data,
+};
+use dns::handler::{DnsHandler, DnsRequest};
+use dns::parse::DnsData;
+use dns::resolver::{NameServer, RecursiveHandler, Resolver, ResolverConfig, RootHints};
+use dns::zone::{AuthoritativeHandler, Zone, ZoneParser, ZoneStore};
+use ring::rand::SystemRandom;
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+fn soa(zone: &str) -> DnsAnswer {
+    record(
+        zone,
+        300,
+        RecordData::SOA(Soa {
+            mname: "ns.test.".parse().unwrap(),
+            rname: "hostmaster.test.".parse().unwrap(),
+            serial: 1,
+            refresh: 3600,
+            retry: 600,
+            expire: 86400,
+            minimum: 60,
+        }),
+    )
+}
+
+fn a(name: &str, ip: [u8; 4]) -> DnsAnswer {
+    record(name, 300, RecordData::A(Ipv4Addr::from(ip)))
+}
+
+fn ns(name: &str, target: &str) -> DnsAnswer {
+    record(name, 300, RecordData::NS(target.parse().unwrap()))
+}
+
+// A signing key for one zone, used as both KSK and ZSK.
+#[derive(Debug)]
+struct ZoneKey {
+    pair: Ed25519KeyPair,
+    dnskey: DnsKey,
+}
+
+impl ZoneKey {
+    fn generate() -> Self {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        let pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+        let dnskey = DnsKey {
+            flags: DnsKey::ZONE | DnsKey::SEP,
+            protocol: 3,
+            algorithm: ALGORITHM_ED25519,
+            public_key: pair.public_key().as_ref().to_vec().into(),
+        };
+        Self { pair, dnskey }
+    }
+
+    fn ds(&self, zone: &str) -> DnsAnswer {
+        let ds = ds_for(&zone.parse().unwrap(), &self.dnskey, DIGEST_SHA256)
+            .unwrap()
+            .unwrap();
+        record(zone, 300, RecordData::DS(ds))
+    }
+
+    fn sign(&self, apex: &Domain, rrset: &[DnsAnswer]) -> DnsAnswer {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as u32;
+        let mut rrsig = Rrsig {
+            type_covered: rrset[0].qtype.code(),
+            algorithm: ALGORITHM_ED25519,
+            labels: rrset[0].name.labels.len() as u8,
+            original_ttl: rrset[0].ttl,
+            expiration: now + 3600,
+            inception: now - 3600,
+            key_tag: self.dnskey.key_tag(),
+            signer: apex.clone(),
+            signature: Default::default(),
+        };
+        let data = signed_data(&rrsig, rrset).unwrap();
+        rrsig.signature = self.pair.sign(&data).as_ref().to_vec().into();
+        DnsAnswer::new(
+            rrset[0].name.clone(),
+            rrset[0].ttl,
+            RecordData::RRSIG(rrsig),
+        )
+        .unwrap()
+    }
+}
+
+// Like StubZone, but signed: every authoritative RRset comes with its RRSIG, delegations carry
+// their DS records or the NSEC showing there aren't any, and negative answers come with NSECs.
+#[derive(Debug)]
+struct SignedZone {
+    apex: Domain,
+    records: Vec<DnsAnswer>,
+}
+
+impl SignedZone {
+    fn new(apex: &str, key: &ZoneKey, mut records: Vec<DnsAnswer>) -> Self {
+        let apex: Domain = apex.parse().unwrap();
+        records.push(
+            DnsAnswer::new(apex.clone(), 300, RecordData::DNSKEY(key.dnskey.clone())).unwrap(),
+        );
+
+        // NS records below the apex are delegations, anything under them is glue
+        let cuts: Vec<Domain> = records
+            .iter()
+            .filter(|r| r.qtype == QuestionType::NS && r.name != apex)
+            .map(|r| r.name.clone())
+            .collect();
+        let glue = |name: &Domain| cuts.iter().any(|c| name.is_subdomain_of(c) && name != c);
+
+        let mut owners: Vec<Domain> = records
+            .iter()
+            .map(|r| r.name.clone())
+            .filter(|n| !glue(n))
+            .collect();
+        owners.sort_by(|a, b| a.canonical_cmp(b));
+        owners.dedup();
+
+        // the NSEC chain, each owner pointing at the next and the last back at the apex
+        for (i, owner) in owners.iter().enumerate() {
+            let mut types: Vec<u16> = records
+                .iter()
+                .filter(|r| r.name == *owner)
+                .map(|r| r.qtype.code())
+                .chain([QuestionType::RRSIG.code(), QuestionType::NSEC.code()])
+                .collect();
+            types.sort();
+            types.dedup();
+            let next = owners[(i + 1) % owners.len()].clone();
+            records.push(
+                DnsAnswer::new(owner.clone(), 60, RecordData::NSEC(Nsec { next, types })).unwrap(),
+            );
+        }
+
+        // sign everything we're authoritative for, which excludes the NS records at a cut
+        let mut rrsets: Vec<Vec<DnsAnswer>> = Vec::new();
+        for r in records.iter().filter(|r| !glue(&r.name)) {
+            if r.qtype == QuestionType::NS && r.name != apex {
+                continue;
+            }
+            match rrsets
+                .iter_mut()
+                .find(|s| s[0].name == r.name && s[0].qtype == r.qtype)
+            {
+                Some(set) => set.push(r.clone()),
+                None => rrsets.push(vec![r.clone()]),
+            }
+        }
+        for rrset in rrsets {
+            records.push(key.sign(&apex, &rrset));
+        }
+
+        Self { apex, records }
+    }
+
+    // replace the data of a record after it was signed
+    fn tamper(mut self, name: &str, rdata: RecordData) -> Self {
+        let name: Domain = name.parse().unwrap();
+        for r in self.records.iter_mut().filter(|r| r.name == name) {
+            if r.qtype == rdata.qtype() {
+                *r = DnsAnswer::new(name.clone(), r.ttl, rdata.clone()).unwrap();
+            }
+        }
+        self
+    }
+
+    // the records of a type at a name, along with their signatures
+    fn signed(&self, name: &Domain, qtype: &QuestionType) -> Vec<DnsAnswer> {
+        self.records
+            .iter()
+            .filter(|r| r.name.eq_ignore_case(name))
+            .filter(|r| {
+                r.qtype == *qtype
+                    || matches!(r.rdata(), Ok(RecordData::RRSIG(s)) if s.type_covered == qtype.code())
+            })
+            .cloned()
+            .collect()
+    }
+
+    // NSECs that match or cover the name, or the wildcards that could have answered for it
+    fn nsecs(&self, name: &Domain) -> Vec<DnsAnswer> {
+        let mut names = vec![name.clone()];
+        for len in self.apex.labels.len()..name.labels.len() {
+            names.push(name.suffix(len).prepend("*"));
+        }
+
+        let mut proof = Vec::new();
+        for r in self
+            .records
+            .iter()
+            .filter(|r| r.qtype == QuestionType::NSEC)
+        {
+            let Ok(RecordData::NSEC(nsec)) = r.rdata() else {
+                continue;
+            };
+            let relevant = names.iter().any(|n| {
+                r.name.canonical_cmp(n) != Ordering::Greater
+                    && (n.canonical_cmp(&nsec.next) == Ordering::Less || nsec.next == self.apex)
+            });
+            if relevant && !proof.contains(r) {
+                proof.extend(self.signed(&r.name, &QuestionType::NSEC));
+            }
+        }
+        proof
+    }
+}
+
+#[async_trait]
+impl DnsHandler for SignedZone {
+    async fn handle(&self, request: &DnsRequest) -> Result<Option<DnsMessage>> {
+        let question = request.message.question().unwrap().clone();
+        let mut reply = request.message.clone().as_reply();
+        let soa = self.signed(&self.apex, &QuestionType::SOA);
+
+        if let Some(cut) = self.records.iter().find(|r| {
+            r.qtype == QuestionType::NS
+                && r.name != self.apex
+                && question.name.is_subdomain_of(&r.name)
+        }) {
+            // the DS at a cut is ours to answer, or to deny
+            let mut ds = self.signed(&cut.name, &QuestionType::DS);
+            if ds.is_empty() {
+                ds = self.signed(&cut.name, &QuestionType::NSEC);
+            }
+            if question.name == cut.name && question.qtype == QuestionType::DS {
+                reply.header.auth_answer = true;
+                return Ok(Some(match ds[0].qtype {
+                    QuestionType::DS => reply.with_answers(DnsAnswerSet { answers: ds })?,
+                    _ => reply.with_authority(DnsAnswerSet {
+                        answers: [soa, ds].concat(),
+                    })?,
+                }));
+            }
+
+            let mut authority = self.signed(&cut.name, &QuestionType::NS);
+            authority.extend(ds);
+            let glue: Vec<DnsAnswer> = self
+                .records
+                .iter()
+                .filter(|r| r.qtype == QuestionType::A && r.name.is_subdomain_of(&cut.name))
+                .cloned()
+                .collect();
+            return Ok(Some(
+                reply
+                    .with_authority(DnsAnswerSet { answers: authority })?
+                    .with_additional(DnsAnswerSet { answers: glue })?,
+            ));
+        }
+
+        reply.header.auth_answer = true;
+        if !self.records.iter().any(|r| r.name == question.name) {
+            return Ok(Some(
+                reply
+                    .with_response_code(ResponseCode::NxDomain)
+                    .with_authority(DnsAnswerSet {
+                        answers: [soa, self.nsecs(&question.name)].concat(),
+                    })?,
+            ));
+        }
+
+        let mut answers = self.signed(&question.name, &question.qtype);
+        answers.extend(self.signed(&question.name, &QuestionType::CNAME));
+        if answers.is_empty() {
+            return Ok(Some(reply.with_authority(DnsAnswerSet {
+                answers: [soa, self.nsecs(&question.name)].concat(),
+            })?));
+        }
+
+        Ok(Some(reply.with_answers(DnsAnswerSet { answers })?))
+    }
+}
+
+// A signed root delegating to a signed test. zone, which in turn delegates to insecure.test.
+// without a DS:
+//
+//  127.0.0.20  .               signed, test. delegated with a DS
+//  127.0.0.21  test.           signed, with a tampered record at bad.test.
+//  127.0.0.22  insecure.test.  not signed
+//
+// Returns a validating recursive handler anchored at the root key.
+async fn signed_hierarchy() -> Result<Arc<dyn DnsHandler>> {
+    let root_key = ZoneKey::generate();
+    let test_key = ZoneKey::generate();
+
+    let root = spawn_app_with_handler(
+        "127.0.0.20:0",
+        Arc::new(SignedZone::new(".", &root_key, vec![
+            soa("."),
+            ns("test.", "ns.test."),
+            a("ns.test.", [127, 0, 0, 21]),
+            test_key.ds("test."),
+        ])),
+    )
+    .await?;
+    let port = root.port();
+
+    spawn_app_with_handler(
+        &format!("127.0.0.21:{port}"),
+        Arc::new(
+            SignedZone::new("test.", &test_key, vec![
+                soa("test."),
+                ns("test.", "ns.test."),
+                a("ns.test.", [127, 0, 0, 21]),
+                a("www.test.", [10, 0, 0, 1]),
+                record("alias.test.", 300, RecordData::CNAME("www.test.".parse()?)),
+                a("bad.test.", [10, 0, 0, 2]),
+                ns("insecure.test.", "ns.insecure.test."),
+                a("ns.insecure.test.", [127, 0, 0, 22]),
+            ])
+            .tamper("bad.test.", RecordData::A(Ipv4Addr::new(10, 6, 6, 6))),
+        ),
+    )
+    .await?;
+
+    spawn_app_with_handler(
+        &format!("127.0.0.22:{port}"),
+        Arc::new(StubZone::new("insecure.test.", vec![
+            soa("insecure.test."),
+            a("www.insecure.test.", [10, 0, 0, 3]),
+        ])),
+    )
+    .await?;
+
+    let resolver = Resolver::new(ResolverConfig {
+        root_hints: RootHints {
+            servers: vec![NameServer {
+                name: "a.root-servers.test.".parse()?,
+                address: IpAddr::V4(Ipv4Addr::new(127, 0, 0, 20)),
+            }],
+        },
+        port,
+        timeout: Duration::from_millis(500),
+        trust_anchor: Some(TrustAnchor {
+            zone: Domain::root(),
+            ds: vec![],
+            keys: vec![root_key.dnskey.clone()],
+        }),
+        ..ResolverConfig::default()
+    });
+
+    Ok(Arc::new(RecursiveHandler::new(Arc::new(resolver))))
+}
+
+// the address of a server answering with the signed hierarchy's resolver
+async fn spawn_signed_hierarchy() -> Result<String> {
+    let server = spawn_app_with_handler("127.0.0.1:0", signed_hierarchy().await?).await?;
+    Ok(server.to_string())
+}
+
+async fn ask(
+    server: &str,
+    name: &str,
+    qtype: QuestionType,
+    dnssec_ok: bool,
+    checking_disabled: bool,
+) -> Result<DnsMessage> {
+    let mut request =
+        DnsMessage::query(4242, DnsQuestion::new(name.parse()?, qtype)).with_edns(Edns {
+            dnssec_ok,
+            ..Edns::default()
+        })?;
+    request.header.recursion_desired = true;
+    request.header.set_checking_disabled(checking_disabled);
+
+    let reply = send_request(server, request.encode(0, &mut HashMap::new())?).await?;
+    let (_, reply) = DnsMessage::decode(&reply, 0, &mut HashMap::new())?;
+    Ok(reply)
+}
+
+fn has(reply: &DnsMessage, qtype: QuestionType) -> bool {
+    reply.answers.answers.iter().any(|a| a.qtype == qtype)
+}
+
+#[tokio::test]
+async fn test_dnssec_secure_answers_are_authenticated() -> Result<()> {
+    let server = spawn_signed_hierarchy().await?;
+
+    let reply = ask(&server, "www.test", QuestionType::A, true, false).await?;
+    assert_eq!(reply.response_code(), ResponseCode::NoError as u8);
+    assert!(reply.header.authentic_data());
+    assert!(has(&reply, QuestionType::A));
+    assert!(has(&reply, QuestionType::RRSIG));
+
+    // through a CNAME, and without DO the signatures are left out
+    let reply = ask(&server, "alias.test", QuestionType::A, false, false).await?;
+    assert_eq!(reply.response_code(), ResponseCode::NoError as u8);
+    assert!(!reply.header.authentic_data());
+    assert!(has(&reply, QuestionType::CNAME));
+    assert!(!has(&reply, QuestionType::RRSIG));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_dnssec_denials_are_authenticated() -> Result<()> {
+    let server = spawn_signed_hierarchy().await?;
+
+    let reply = ask(&server, "missing.test", QuestionType::A, true, false).await?;
+    assert_eq!(reply.response_code(), ResponseCode::NxDomain as u8);
+    assert!(reply.header.authentic_data());
+
+    let reply = ask(&server, "www.test", QuestionType::MX, true, false).await?;
+    assert_eq!(reply.response_code(), ResponseCode::NoError as u8);
+    assert!(reply.header.authentic_data());
+    assert!(reply.answers.answers.is_empty());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_dnssec_unsigned_delegation_is_insecure() -> Result<()> {
+    let server = spawn_signed_hierarchy().await?;
+
+    let reply = ask(&server, "www.insecure.test", QuestionType::A, true, false).await?;
+    assert_eq!(reply.response_code(), ResponseCode::NoError as u8);
+    assert!(!reply.header.authentic_data());
+    assert!(has(&reply, QuestionType::A));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_dnssec_bogus_answers_fail_unless_checking_disabled() -> Result<()> {
+    let server = spawn_signed_hierarchy().await?;
+
+    let reply = ask(&server, "bad.test", QuestionType::A, true, false).await?;
+    assert_eq!(reply.response_code(), ResponseCode::ServFail as u8);
+    assert!(reply.answers.answers.is_empty());
+    assert_eq!(reply.edns().unwrap().extended_errors(), vec![
+        EDE_DNSSEC_BOGUS
+    ]);
+
+    let reply = ask(&server, "bad.test", QuestionType::A, true, true).await?;
+    assert_eq!(reply.response_code(), ResponseCode::NoError as u8);
+    assert!(!reply.header.authentic_data());
+    assert!(reply.header.checking_disabled());
+    assert!(has(&reply, QuestionType::A));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_dnssec_answers_are_cached_for_do_queries() -> Result<()> {
+    let cache = Arc::new(DnsCache::new(CacheConfig::default()));
+    let handler = CachingHandler::new(cache, signed_hierarchy().await?);
+    let stats = handler.stats();
+    let server = spawn_app_with_handler("127.0.0.1:0", Arc::new(handler))
+        .await?
+        .to_string();
+
+    // the second time it comes from the cache, still with its signatures and AD
+    for hits in [0, 1] {
+        let reply = ask(&server, "www.test", QuestionType::A, true, false).await?;
+        assert!(reply.header.authentic_data());
+        assert!(has(&reply, QuestionType::A));
+        assert!(has(&reply, QuestionType::RRSIG));
+        assert_eq!(stats.get(STAT_CACHE_HITS), hits);
+    }
+
+    // denials keep their proofs
+    for hits in [1, 2] {
+        let reply = ask(&server, "missing.test", QuestionType::A, true, false).await?;
+        assert_eq!(reply.response_code(), ResponseCode::NxDomain as u8);
+        assert!(reply.header.authentic_data());
+        assert!(
+            reply
+                .authority
+                .answers
+                .iter()
+                .any(|a| a.qtype == QuestionType::NSEC)
+        );
+        assert_eq!(stats.get(STAT_CACHE_HITS), hits);
+    }
+
+    // clients without DO get the same entry without the signatures
+    let reply = ask(&server, "www.test", QuestionType::A, false, false).await?;
+    assert!(!reply.header.authentic_data());
+    assert!(has(&reply, QuestionType::A));
+    assert!(!has(&reply, QuestionType::RRSIG));
+    assert_eq!(stats.get(STAT_CACHE_HITS), 3);
+
+    // and CD goes upstream every time
+    let reply = ask(&server, "www.test", QuestionType::A, true, true).await?;
+    assert!(has(&reply, QuestionType::A));
+    assert_eq!(stats.get(STAT_CACHE_HITS), 3);
+
+    Ok(())
+}
+
+const ROOT_ZONE: &str = r#"
+$TTL 300
+@               SOA ns.root. hostmaster.root. 1 3600 600 86400 60
+                NS  ns.root.
+ns.root.        A   127.0.0.23
+www.example.    A   10.0.0.1
+alias.example.  CNAME www.example.
+*.wild.example. TXT "wildcard"
+"#;
+
+// A root zone signed by us and served by the authoritative handler on 127.0.0.23, behind a
+// validating recursive server anchored at its key.
+async fn spawn_signed_root(nsec3: Option<Nsec3Param>) -> Result<String> {
+    let key = SigningKey::generate(Domain::root(), 257, ALGORITHM_ED25519)?;
+    let anchor = TrustAnchor {
+        zone: Domain::root(),
+        ds: vec![],
+        keys: vec![key.dnskey.clone()],
+    };
+
+    let zones = Arc::new(ZoneStore::default());
+    let signer = ZoneSigner::new(vec![key])?.with_config(SignerConfig {
+        nsec3,
+        ..SignerConfig::default()
+    });
+    zones.set_signer(&Domain::root(), Arc::new(signer));
+    let records = ZoneParser::parse_str(Domain::root(), ROOT_ZONE, "root")?;
+    zones.insert(Zone::new(Domain::root(), records)?);
+
+    let root =
+        spawn_app_with_handler("127.0.0.23:0", Arc::new(AuthoritativeHandler::new(zones))).await?;
+
+    let resolver = Resolver::new(ResolverConfig {
+        root_hints: RootHints {
+            servers: vec![NameServer {
+                name: "ns.root.".parse()?,
+                address: IpAddr::V4(Ipv4Addr::new(127, 0, 0, 23)),
+            }],
+        },
+        port: root.port(),
+        timeout: Duration::from_millis(500),
+        trust_anchor: Some(anchor),
+        ..ResolverConfig::default()
+    });
+    let server = spawn_app_with_handler(
+        "127.0.0.1:0",
+        Arc::new(RecursiveHandler::new(Arc::new(resolver))),
+    )
+    .await?;
+
+    Ok(server.to_string())
+}
+
+#[tokio::test]
+async fn test_dnssec_validates_zones_we_sign() -> Result<()> {
+    let nsec3 = Nsec3Param {
+        hash_algorithm: 1,
+        flags: 0,
+        iterations: 0,
+        salt: vec![0xab, 0xcd].into(),
+    };
+
+    for nsec3 in [None, Some(nsec3)] {
+        let server = spawn_signed_root(nsec3).await?;
+
+        for (name, qtype) in [
+            ("www.example", QuestionType::A),
+            ("alias.example", QuestionType::A),
+            ("anything.wild.example", QuestionType::TXT),
+        ] {
+            let reply = ask(&server, name, qtype.clone(), true, false).await?;
+            assert_eq!(reply.response_code(), ResponseCode::NoError as u8, "{name}");
+            assert!(reply.header.authentic_data(), "{name}");
+            assert!(has(&reply, qtype));
+            assert!(has(&reply, QuestionType::RRSIG));
+        }
+
+        let reply = ask(&server, "missing.example", QuestionType::A, true, false).await?;
+        assert_eq!(reply.response_code(), ResponseCode::NxDomain as u8);
+        assert!(reply.header.authentic_data());
+
+        for name in ["www.example", "example", "anything.wild.example"] {
+            let reply = ask(&server, name, QuestionType::MX, true, false).await?;
+            assert_eq!(reply.response_code(), ResponseCode::NoError as u8, "{name}");
+            assert!(reply.header.authentic_data(), "{name}");
+            assert!(reply.answers.answers.is_empty());
+        }
+    }
+
+    Ok(())
+}
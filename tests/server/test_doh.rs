@@ -0,0 +1,149 @@
+use crate::helpers::{example, self_signed_certificate};
+use anyhow::Result;
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use bytes::Bytes;
+use dns::dns::*;
+use dns::parse::DnsData;
+use http_body_util::{BodyExt, Full};
+use hyper::header::{CACHE_CONTROL, CONTENT_TYPE, HOST};
+use hyper::{Request, StatusCode};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use rustls::pki_types::ServerName;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio_rustls::TlsConnector;
+
+fn query() -> Result<Bytes> {
+    let query = DnsMessage::query(
+        0,
+        DnsQuestion::new("www.example.com".parse()?, QuestionType::A),
+    );
+    query.encode(0, &mut HashMap::new())
+}
+
+#[tokio::test]
+async fn test_doh_over_http2_with_get_and_post() -> Result<()> {
+    let (cert, key, mut client) = self_signed_certificate("doh")?;
+    let server = DnsServer::build("127.0.0.1:0")
+        .await?
+        .with_handler(Arc::new(example()))
+        .with_https("127.0.0.1:0", Some(tls_server_config(cert, key)?))
+        .await?;
+    let address = server.https_address()?.unwrap();
+    tokio::spawn(async move { server.run_until_stopped().await });
+
+    client.alpn_protocols = vec![b"h2".to_vec()];
+    let stream = TcpStream::connect(&address).await?;
+    let stream = TlsConnector::from(Arc::new(client))
+        .connect(ServerName::try_from("localhost")?, stream)
+        .await?;
+    assert_eq!(stream.get_ref().1.alpn_protocol(), Some(&b"h2"[..]));
+    let (mut sender, connection) =
+        hyper::client::conn::http2::handshake(TokioExecutor::new(), TokioIo::new(stream)).await?;
+    tokio::spawn(connection);
+
+    let url = format!("https://localhost{DOH_PATH}");
+    let get = Request::get(format!("{url}?dns={}", URL_SAFE_NO_PAD.encode(query()?)))
+        .body(Full::new(Bytes::new()))?;
+    let post = Request::post(&url)
+        .header(CONTENT_TYPE, DNS_MESSAGE)
+        .body(Full::new(query()?))?;
+    for request in [get, post] {
+        let response = sender.send_request(request).await?;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[CONTENT_TYPE], DNS_MESSAGE);
+
+        // cacheable for as long as the shortest TTL
+        assert_eq!(response.headers()[CACHE_CONTROL], "max-age=60");
+        let body = response.into_body().collect().await?.to_bytes();
+        let (_, reply) = DnsMessage::decode(&body, 0, &mut HashMap::new())?;
+        assert_eq!(reply.answers.answers.len(), 2);
+    }
+
+    // POSTs have to say they're DNS messages
+    let post = Request::post(&url)
+        .header(CONTENT_TYPE, "text/plain")
+        .body(Full::new(query()?))?;
+    let response = sender.send_request(post).await?;
+    assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_doh_json_api_over_plain_http() -> Result<()> {
+    let server = DnsServer::build("127.0.0.1:0")
+        .await?
+        .with_handler(Arc::new(example()))
+        .with_https("127.0.0.1:0", None)
+        .await?;
+    let address = server.https_address()?.unwrap();
+    tokio::spawn(async move { server.run_until_stopped().await });
+
+    let stream = TcpStream::connect(&address).await?;
+    let (mut sender, connection) =
+        hyper::client::conn::http1::handshake(TokioIo::new(stream)).await?;
+    tokio::spawn(connection);
+
+    let request = Request::get(format!("{DOH_PATH}?name=www.example.com&type=A"))
+        .header(HOST, "localhost")
+        .body(Full::new(Bytes::new()))?;
+    let response = sender.send_request(request).await?;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()[CONTENT_TYPE], DNS_JSON);
+    let body = response.into_body().collect().await?.to_bytes();
+    let json: serde_json::Value = serde_json::from_slice(&body)?;
+    assert_eq!(json["Status"], 0);
+    assert_eq!(json["Question"][0]["name"], "www.example.com.");
+    assert_eq!(json["Answer"][0]["type"], 1);
+    assert_eq!(json["Answer"][0]["TTL"], 300);
+    assert_eq!(json["Answer"][0]["data"], "192.0.2.80");
+
+    let request = Request::get("/elsewhere")
+        .header(HOST, "localhost")
+        .body(Full::new(Bytes::new()))?;
+    assert_eq!(
+        sender.send_request(request).await?.status(),
+        StatusCode::NOT_FOUND
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_doh_closes_idle_connections() -> Result<()> {
+    let server = DnsServer::build("127.0.0.1:0")
+        .await?
+        .with_handler(Arc::new(example()))
+        .with_https("127.0.0.1:0", None)
+        .await?
+        .with_tls_idle_timeout(Duration::from_millis(200));
+    let address = server.https_address()?.unwrap();
+    tokio::spawn(async move { server.run_until_stopped().await });
+    let get = || {
+        let dns = URL_SAFE_NO_PAD.encode(query()?);
+        Ok::<_, anyhow::Error>(
+            Request::get(format!("http://localhost{DOH_PATH}?dns={dns}"))
+                .header(HOST, "localhost")
+                .body(Full::new(Bytes::new()))?,
+        )
+    };
+
+    // the server hangs up on us rather than waiting for another request, in either version
+    let stream = TcpStream::connect(&address).await?;
+    let (mut sender, connection) =
+        hyper::client::conn::http1::handshake(TokioIo::new(stream)).await?;
+    let connection = tokio::spawn(connection);
+    assert_eq!(sender.send_request(get()?).await?.status(), StatusCode::OK);
+    tokio::time::timeout(Duration::from_secs(2), connection).await???;
+
+    let stream = TcpStream::connect(&address).await?;
+    let (mut sender, connection) =
+        hyper::client::conn::http2::handshake(TokioExecutor::new(), TokioIo::new(stream)).await?;
+    let connection = tokio::spawn(connection);
+    assert_eq!(sender.send_request(get()?).await?.status(), StatusCode::OK);
+    tokio::time::timeout(Duration::from_secs(2), connection).await???;
+
+    Ok(())
+}
@@ -0,0 +1,83 @@
+use crate::helpers::{StubZone, record, self_signed_certificate};
+use anyhow::Result;
+use dns::dns::*;
+use dns::parse::DnsData;
+use quinn::crypto::rustls::{HandshakeData, QuicClientConfig};
+use quinn::{Connection, ConnectionError, Endpoint, VarInt};
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+
+async fn connect(test: &str) -> Result<Connection> {
+    let (cert, key, mut client) = self_signed_certificate(&format!("doq-{test}"))?;
+    let zone = StubZone::new("example.com.", vec![record(
+        "www.example.com.",
+        300,
+        RecordData::A(Ipv4Addr::new(192, 0, 2, 80)),
+    )]);
+    let server = DnsServer::build("127.0.0.1:0")
+        .await?
+        .with_handler(Arc::new(zone))
+        .with_quic("127.0.0.1:0", tls_server_config(cert, key)?)?;
+    let address = server.quic_address()?.unwrap();
+    tokio::spawn(async move { server.run_until_stopped().await });
+
+    client.alpn_protocols = vec![DOQ_ALPN.to_vec()];
+    let mut endpoint = Endpoint::client("127.0.0.1:0".parse()?)?;
+    endpoint.set_default_client_config(quinn::ClientConfig::new(Arc::new(
+        QuicClientConfig::try_from(client)?,
+    )));
+    Ok(endpoint.connect(address.parse()?, "localhost")?.await?)
+}
+
+// a query on a stream of its own, and the one response to it
+async fn ask(connection: &Connection, id: u16) -> Result<DnsMessage> {
+    let request = DnsMessage::query(
+        id,
+        DnsQuestion::new("www.example.com".parse()?, QuestionType::A),
+    );
+    let (mut send, mut recv) = connection.open_bi().await?;
+    write_frame(&mut send, &request.encode(0, &mut HashMap::new())?).await?;
+    send.finish()?;
+    let reply = read_frame(&mut recv).await?.unwrap();
+    Ok(DnsMessage::decode(&reply, 0, &mut HashMap::new())?.1)
+}
+
+#[tokio::test]
+async fn test_doq_answers_a_query_per_stream() -> Result<()> {
+    let connection = connect("answer").await?;
+    assert_eq!(
+        connection
+            .handshake_data()
+            .unwrap()
+            .downcast::<HandshakeData>()
+            .unwrap()
+            .protocol,
+        Some(DOQ_ALPN.to_vec())
+    );
+
+    // the streams are independent, so the queries can all be in flight at once
+    let (first, second) = tokio::join!(ask(&connection, 0), ask(&connection, 0));
+    for reply in [first?, second?] {
+        assert_eq!(reply.header.packet_id, 0);
+        assert_eq!(
+            reply.answers.answers[0].rdata()?,
+            RecordData::A(Ipv4Addr::new(192, 0, 2, 80))
+        );
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_doq_closes_the_connection_for_a_non_zero_id() -> Result<()> {
+    let connection = connect("id").await?;
+    assert!(ask(&connection, 1234).await.is_err());
+
+    match connection.closed().await {
+        ConnectionError::ApplicationClosed(close) => {
+            assert_eq!(close.error_code, VarInt::from_u32(DOQ_PROTOCOL_ERROR));
+        }
+        other => panic!("expected the server to close the connection, got {other}"),
+    }
+    Ok(())
+}
@@ -0,0 +1,91 @@
+use crate::helpers::{StubZone, record, self_signed_certificate};
+use anyhow::Result;
+use dns::dns::*;
+use dns::parse::DnsData;
+use rustls::pki_types::ServerName;
+use rustls::{ClientConfig, HandshakeKind};
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpStream;
+use tokio_rustls::TlsConnector;
+use tokio_rustls::client::TlsStream;
+
+async fn spawn_dot(test: &str, idle: Duration) -> Result<(String, Arc<ClientConfig>)> {
+    let (cert, key, client) = self_signed_certificate(&format!("dot-{test}"))?;
+    let zone = StubZone::new("example.com.", vec![record(
+        "www.example.com.",
+        300,
+        RecordData::A(Ipv4Addr::new(192, 0, 2, 80)),
+    )]);
+    let server = DnsServer::build("127.0.0.1:0")
+        .await?
+        .with_handler(Arc::new(zone))
+        .with_tls("127.0.0.1:0", tls_server_config(cert, key)?)
+        .await?
+        .with_tls_idle_timeout(idle);
+    let address = server.tls_address()?.unwrap();
+    tokio::spawn(async move { server.run_until_stopped().await });
+    Ok((address, Arc::new(client)))
+}
+
+async fn connect(address: &str, client: Arc<ClientConfig>) -> Result<TlsStream<TcpStream>> {
+    let stream = TcpStream::connect(address).await?;
+    let name = ServerName::try_from("localhost")?;
+    Ok(TlsConnector::from(client).connect(name, stream).await?)
+}
+
+async fn ask(stream: &mut TlsStream<TcpStream>, id: u16) -> Result<DnsMessage> {
+    let request = DnsMessage::query(
+        id,
+        DnsQuestion::new("www.example.com".parse()?, QuestionType::A),
+    );
+    write_frame(stream, &request.encode(0, &mut HashMap::new())?).await?;
+    let reply = read_frame(stream).await?.unwrap();
+    Ok(DnsMessage::decode(&reply, 0, &mut HashMap::new())?.1)
+}
+
+#[tokio::test]
+async fn test_dot_answers_over_tls_and_resumes_sessions() -> Result<()> {
+    let (address, client) = spawn_dot("resume", TLS_IDLE_TIMEOUT).await?;
+
+    // several queries down the one connection
+    let mut stream = connect(&address, client.clone()).await?;
+    assert_eq!(
+        stream.get_ref().1.handshake_kind(),
+        Some(HandshakeKind::Full)
+    );
+    for id in 1..=2 {
+        let reply = ask(&mut stream, id).await?;
+        assert_eq!(reply.header.packet_id, id);
+        assert_eq!(
+            reply.answers.answers[0].rdata()?,
+            RecordData::A(Ipv4Addr::new(192, 0, 2, 80))
+        );
+    }
+    drop(stream);
+
+    // coming back, the client gets to skip the full handshake
+    let mut stream = connect(&address, client).await?;
+    assert_eq!(
+        stream.get_ref().1.handshake_kind(),
+        Some(HandshakeKind::Resumed)
+    );
+    assert_eq!(ask(&mut stream, 3).await?.header.packet_id, 3);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_dot_closes_idle_connections() -> Result<()> {
+    let (address, client) = spawn_dot("idle", Duration::from_millis(200)).await?;
+    let mut stream = connect(&address, client).await?;
+    ask(&mut stream, 1).await?;
+
+    // the server hangs up on us rather than waiting for another query
+    let mut buf = [0; 1];
+    let read = tokio::time::timeout(Duration::from_secs(2), stream.read(&mut buf)).await?;
+    assert!(matches!(read, Ok(0) | Err(_)));
+    Ok(())
+}
@@ -0,0 +1,134 @@
+use crate::helpers::{StubZone, addresses, ask, query, record, spawn_app_with_handler};
+use anyhow::Result;
+use dns::dns::*;
+use dns::filter::{
+    BlockResponse, Blocklists, FilterHandler, STAT_FILTER_ALLOWED, STAT_FILTER_BLOCKED,
+};
+use dns::stats::Stats;
+use std::net::Ipv4Addr;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+const BLOCKLIST: &str = "
+0.0.0.0 ads.example.com
+||tracker.example.com^
+";
+
+const ALLOWLIST: &str = "ok.tracker.example.com\n";
+
+// blocklists in a temp directory, for a filter in front of a zone where every name resolves
+fn lists(test: &str) -> Result<(PathBuf, Arc<Blocklists>)> {
+    let dir = std::env::temp_dir().join(format!("filter-{test}-{}", std::process::id()));
+    std::fs::create_dir_all(&dir)?;
+    std::fs::write(dir.join("block.txt"), BLOCKLIST)?;
+    std::fs::write(dir.join("allow.txt"), ALLOWLIST)?;
+    let lists = Blocklists::new(vec![dir.join("block.txt")], vec![dir.join("allow.txt")])?;
+    Ok((dir, Arc::new(lists)))
+}
+
+async fn spawn_filter(
+    lists: Arc<Blocklists>,
+    response: BlockResponse,
+    stats: Arc<Stats>,
+) -> Result<String> {
+    let zone = StubZone::new("example.com.", vec![
+        record(
+            "ads.example.com.",
+            300,
+            RecordData::A(Ipv4Addr::new(10, 0, 0, 1)),
+        ),
+        record(
+            "ok.tracker.example.com.",
+            300,
+            RecordData::A(Ipv4Addr::new(10, 0, 0, 2)),
+        ),
+        record(
+            "www.example.com.",
+            300,
+            RecordData::A(Ipv4Addr::new(10, 0, 0, 3)),
+        ),
+    ]);
+    let filter = FilterHandler::new(lists, Arc::new(zone))
+        .with_response(response)
+        .with_stats(stats);
+    Ok(spawn_app_with_handler("127.0.0.1:0", Arc::new(filter))
+        .await?
+        .to_string())
+}
+
+#[tokio::test]
+async fn test_filter_blocks_with_null_addresses() -> Result<()> {
+    let (dir, lists) = lists("null")?;
+    let stats = Arc::new(Stats::default());
+    let server = spawn_filter(lists, BlockResponse::NullAddress, stats.clone()).await?;
+
+    let request = DnsMessage::query(
+        99,
+        DnsQuestion::new("ads.example.com".parse()?, QuestionType::A),
+    )
+    .with_edns(Edns::default())?;
+    let reply = query(&server, request).await?;
+    assert_eq!(reply.response_code(), ResponseCode::NoError as u8);
+    assert_eq!(addresses(&reply), vec![RecordData::A(
+        Ipv4Addr::UNSPECIFIED
+    )]);
+    assert_eq!(reply.edns().unwrap().extended_errors(), vec![EDE_BLOCKED]);
+
+    let reply = ask(&server, "cdn.tracker.example.com", QuestionType::AAAA).await?;
+    assert_eq!(addresses(&reply), vec![RecordData::AAAA("::".parse()?)]);
+
+    // other types get an empty answer
+    let reply = ask(&server, "ads.example.com", QuestionType::MX).await?;
+    assert_eq!(reply.response_code(), ResponseCode::NoError as u8);
+    assert!(reply.answers.answers.is_empty());
+
+    // the allowlist wins, and names on neither list aren't touched
+    let reply = ask(&server, "ok.tracker.example.com", QuestionType::A).await?;
+    assert_eq!(addresses(&reply), vec![RecordData::A(Ipv4Addr::new(
+        10, 0, 0, 2
+    ))]);
+    let reply = ask(&server, "www.example.com", QuestionType::A).await?;
+    assert_eq!(addresses(&reply), vec![RecordData::A(Ipv4Addr::new(
+        10, 0, 0, 3
+    ))]);
+
+    assert_eq!(stats.get(STAT_FILTER_BLOCKED), 3);
+    assert_eq!(stats.get(STAT_FILTER_ALLOWED), 1);
+
+    std::fs::remove_dir_all(dir)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_filter_block_responses() -> Result<()> {
+    let (dir, lists) = lists("responses")?;
+
+    let server = spawn_filter(
+        lists.clone(),
+        BlockResponse::NxDomain,
+        Arc::new(Stats::default()),
+    )
+    .await?;
+    let reply = ask(&server, "ads.example.com", QuestionType::A).await?;
+    assert_eq!(reply.response_code(), ResponseCode::NxDomain as u8);
+
+    let server = spawn_filter(
+        lists.clone(),
+        BlockResponse::Refused,
+        Arc::new(Stats::default()),
+    )
+    .await?;
+    let reply = ask(&server, "ads.example.com", QuestionType::A).await?;
+    assert_eq!(reply.response_code(), ResponseCode::Refused as u8);
+
+    let server = spawn_filter(lists, "192.0.2.53".parse()?, Arc::new(Stats::default())).await?;
+    let reply = ask(&server, "ads.example.com", QuestionType::A).await?;
+    assert_eq!(addresses(&reply), vec![RecordData::A(Ipv4Addr::new(
+        192, 0, 2, 53
+    ))]);
+    let reply = ask(&server, "ads.example.com", QuestionType::AAAA).await?;
+    assert!(reply.answers.answers.is_empty());
+
+    std::fs::remove_dir_all(dir)?;
+    Ok(())
+}
@@ -1,8 +1,16 @@
-use crate::helpers::spawn_app;
+use crate::helpers::{StubZone, record, spawn_app, spawn_app_with_handler};
 use anyhow::Result;
+use async_trait::async_trait;
+use bytes::Bytes;
 use dns::dns::*;
+use dns::handler::{DnsHandler, DnsRequest, Transport};
+use dns::resolver::{ForwardHandler, exchange};
 use dns::{dns::DnsMessage, parse::DnsData};
 use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::UdpSocket;
 
 #[tokio::test]
 async fn test_forwarding() -> Result<()> {
@@ -29,3 +37,83 @@ async fn test_forwarding() -> Result<()> {
     let _reply = send_request(&server_addr, dns_bytes).await?;
     Ok(())
 }
+
+// answers over UDP are cut short, as if they were too big to fit
+#[derive(Debug)]
+struct Truncating(StubZone);
+
+#[async_trait]
+impl DnsHandler for Truncating {
+    async fn handle(&self, request: &DnsRequest) -> Result<Option<DnsMessage>> {
+        if request.transport != Transport::Udp {
+            return self.0.handle(request).await;
+        }
+        let mut reply = request.message.clone().as_reply();
+        reply.header.truncation = true;
+        Ok(Some(reply))
+    }
+}
+
+#[tokio::test]
+async fn test_forwarding_retries_truncated_responses_over_tcp() -> Result<()> {
+    let zone = StubZone::new("example.com.", vec![record(
+        "www.example.com.",
+        300,
+        RecordData::A(Ipv4Addr::new(192, 0, 2, 80)),
+    )]);
+    let upstream = spawn_app_with_handler("127.0.0.1:0", Arc::new(Truncating(zone))).await?;
+
+    let forwarder = ForwardHandler::new(vec![upstream.into()]);
+    let query = DnsMessage::query(
+        1,
+        DnsQuestion::new("www.example.com".parse()?, QuestionType::A),
+    );
+    let reply = forwarder.forward(&query).await?;
+    assert!(!reply.header.truncation);
+    assert_eq!(
+        reply.answers.answers[0].rdata()?,
+        RecordData::A(Ipv4Addr::new(192, 0, 2, 80))
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_responses_with_the_wrong_id_are_ignored() -> Result<()> {
+    let upstream = UdpSocket::bind("127.0.0.1:0").await?;
+    let address = upstream.local_addr()?;
+
+    // someone racing the real server with a guessed ID gets there first
+    tokio::spawn(async move {
+        let mut buf = [0; 512];
+        let (len, client) = upstream.recv_from(&mut buf).await?;
+        let raw = Bytes::copy_from_slice(&buf[..len]);
+        let (_, query) = DnsMessage::decode(&raw, 0, &mut HashMap::new())?;
+        let mut spoofed = query.clone().as_reply();
+        spoofed.header.packet_id = query.header.packet_id.wrapping_add(1);
+        let answer = record(
+            "www.example.com.",
+            300,
+            RecordData::A(Ipv4Addr::new(6, 6, 6, 6)),
+        );
+        let spoofed = spoofed.with_answers(DnsAnswerSet {
+            answers: vec![answer],
+        })?;
+        upstream
+            .send_to(&spoofed.encode(0, &mut HashMap::new())?, client)
+            .await?;
+        let reply = query.as_reply().with_response_code(ResponseCode::NxDomain);
+        upstream
+            .send_to(&reply.encode(0, &mut HashMap::new())?, client)
+            .await?;
+        Ok::<_, anyhow::Error>(())
+    });
+
+    let query = DnsMessage::query(
+        1,
+        DnsQuestion::new("www.example.com".parse()?, QuestionType::A),
+    );
+    let reply = exchange(address, &query, Duration::from_secs(2)).await?;
+    assert_eq!(reply.response_code(), ResponseCode::NxDomain as u8);
+    assert!(reply.answers.answers.is_empty());
+    Ok(())
+}
@@ -0,0 +1,82 @@
+use crate::helpers::{StubZone, ask, record, spawn_app_with_handler};
+use anyhow::Result;
+use dns::dns::*;
+use dns::hosts::{Hosts, HostsHandler};
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::Arc;
+
+const HOSTS: &str = "
+127.0.0.1       localhost
+192.0.2.10      nas.home.arpa nas
+2001:db8::10    nas.home.arpa
+192.0.2.11      printer.home.arpa   # upstairs
+";
+
+fn rdata(reply: &DnsMessage) -> Vec<RecordData> {
+    reply
+        .answers
+        .answers
+        .iter()
+        .map(|a| a.rdata().unwrap())
+        .collect()
+}
+
+#[tokio::test]
+async fn test_hosts_file_answers_before_upstream() -> Result<()> {
+    let dir = std::env::temp_dir().join(format!("hosts-handler-{}", std::process::id()));
+    std::fs::create_dir_all(&dir)?;
+    std::fs::write(dir.join("hosts"), HOSTS)?;
+
+    let upstream = StubZone::new("example.com.", vec![record(
+        "www.example.com.",
+        300,
+        RecordData::A(Ipv4Addr::new(198, 51, 100, 1)),
+    )]);
+    let hosts = Arc::new(Hosts::new(dir.join("hosts"))?);
+    let handler = HostsHandler::new(hosts, Arc::new(upstream));
+    let server = spawn_app_with_handler("127.0.0.1:0", Arc::new(handler))
+        .await?
+        .to_string();
+
+    let reply = ask(&server, "NAS.home.arpa", QuestionType::A).await?;
+    assert!(reply.header.auth_answer);
+    assert_eq!(rdata(&reply), vec![RecordData::A(Ipv4Addr::new(
+        192, 0, 2, 10
+    ))]);
+    let reply = ask(&server, "nas.home.arpa", QuestionType::AAAA).await?;
+    assert_eq!(rdata(&reply), vec![RecordData::AAAA(
+        "2001:db8::10".parse()?
+    )]);
+    let reply = ask(&server, "nas", QuestionType::A).await?;
+    assert_eq!(rdata(&reply), vec![RecordData::A(Ipv4Addr::new(
+        192, 0, 2, 10
+    ))]);
+
+    // names in the file only have what the file gives them
+    let reply = ask(&server, "printer.home.arpa", QuestionType::AAAA).await?;
+    assert_eq!(reply.response_code(), ResponseCode::NoError as u8);
+    assert!(reply.answers.answers.is_empty());
+
+    // reverse lookups come from the same entries, with the canonical name
+    let v4: IpAddr = "192.0.2.10".parse()?;
+    let reply = ask(&server, &reverse_name(&v4).to_string(), QuestionType::PTR).await?;
+    assert_eq!(rdata(&reply), vec![RecordData::PTR(
+        "nas.home.arpa".parse()?
+    )]);
+    let v6: IpAddr = "2001:db8::10".parse()?;
+    let reply = ask(&server, &reverse_name(&v6).to_string(), QuestionType::PTR).await?;
+    assert_eq!(rdata(&reply), vec![RecordData::PTR(
+        "nas.home.arpa".parse()?
+    )]);
+
+    // everything else goes upstream
+    let reply = ask(&server, "www.example.com", QuestionType::A).await?;
+    assert_eq!(rdata(&reply), vec![RecordData::A(Ipv4Addr::new(
+        198, 51, 100, 1
+    ))]);
+    let reply = ask(&server, "99.2.0.192.in-addr.arpa", QuestionType::PTR).await?;
+    assert_eq!(reply.response_code(), ResponseCode::NxDomain as u8);
+
+    std::fs::remove_dir_all(dir)?;
+    Ok(())
+}
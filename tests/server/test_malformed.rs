@@ -0,0 +1,148 @@
+use crate::helpers::{StubZone, example, query, spawn_app_with_handler};
+use anyhow::{Result, bail};
+use async_trait::async_trait;
+use bytes::{BufMut, Bytes, BytesMut};
+use dns::dns::*;
+use dns::handler::{DnsHandler, DnsRequest};
+use dns::parse::DnsData;
+use http_body_util::{BodyExt, Full};
+use hyper::header::{CONTENT_TYPE, HOST};
+use hyper::{Request, StatusCode};
+use hyper_util::rt::TokioIo;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+async fn app() -> Result<String> {
+    Ok(spawn_app_with_handler("127.0.0.1:0", Arc::new(example()))
+        .await?
+        .to_string())
+}
+
+// errors out on anything under fail.example.com
+#[derive(Debug)]
+struct Failing(StubZone);
+
+#[async_trait]
+impl DnsHandler for Failing {
+    async fn handle(&self, request: &DnsRequest) -> Result<Option<DnsMessage>> {
+        let fail: Domain = "fail.example.com".parse()?;
+        match request.message.question() {
+            Some(q) if q.name.is_subdomain_of(&fail) => bail!("something went wrong"),
+            _ => self.0.handle(request).await,
+        }
+    }
+}
+
+fn question(id: u16, name: &str) -> Result<Bytes> {
+    DnsMessage::query(id, DnsQuestion::new(name.parse()?, QuestionType::A))
+        .encode(0, &mut HashMap::new())
+}
+
+#[tokio::test]
+async fn test_queries_for_unknown_types_are_answered() -> Result<()> {
+    let server = app().await?;
+
+    // HTTPS records, which we have no type of our own for
+    let request = DnsMessage::query(
+        7,
+        DnsQuestion::new("www.example.com".parse()?, QuestionType::from(65)),
+    );
+    let reply = query(&server, request).await?;
+    assert_eq!(
+        reply.questions.questions[0].qtype,
+        QuestionType::Unknown(65)
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_undecodable_queries_get_a_format_error() -> Result<()> {
+    let server = app().await?;
+
+    // a header promising a question that isn't there
+    let mut header = DnsHeader::default();
+    header.packet_id = 99;
+    header.question_count = 1;
+    let mut buf = BytesMut::from(&header.encode(0, &mut HashMap::new())?[..]);
+    buf.put_u8(63);
+    let reply = send_request(&server, buf.freeze()).await?;
+    let (_, reply) = DnsMessage::decode(&reply, 0, &mut HashMap::new())?;
+    assert_eq!(reply.header.packet_id, 99);
+    assert_eq!(reply.header.query_type, DnsPacketType::Response);
+    assert_eq!(reply.response_code(), ResponseCode::FormErr as u8);
+
+    // and over TCP the connection carries on afterwards
+    let mut stream = tokio::net::TcpStream::connect(&server).await?;
+    let garbage = Bytes::from_static(&[0, 1, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 63]);
+    write_frame(&mut stream, &garbage).await?;
+    let query = DnsMessage::query(
+        2,
+        DnsQuestion::new("www.example.com".parse()?, QuestionType::A),
+    );
+    write_frame(&mut stream, &query.encode(0, &mut HashMap::new())?).await?;
+    for (id, rcode) in [(1, ResponseCode::FormErr), (2, ResponseCode::NoError)] {
+        let raw = read_frame(&mut stream).await?.unwrap();
+        let (_, reply) = DnsMessage::decode(&raw, 0, &mut HashMap::new())?;
+        assert_eq!(reply.header.packet_id, id);
+        assert_eq!(reply.response_code(), rcode as u8);
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_handler_errors_get_a_server_failure() -> Result<()> {
+    let server = DnsServer::build("127.0.0.1:0")
+        .await?
+        .with_handler(Arc::new(Failing(example())))
+        .with_https("127.0.0.1:0", None)
+        .await?;
+    let address = server.address()?;
+    let https = server.https_address()?.unwrap();
+    tokio::spawn(async move { server.run_until_stopped().await });
+
+    // over UDP the client hears back rather than waiting out its timeout
+    let request = DnsMessage::query(
+        3,
+        DnsQuestion::new("fail.example.com".parse()?, QuestionType::A),
+    );
+    let reply = query(&address, request).await?;
+    assert_eq!(reply.response_code(), ResponseCode::ServFail as u8);
+
+    // over TCP the connection carries on afterwards
+    let mut stream = tokio::net::TcpStream::connect(&address).await?;
+    write_frame(&mut stream, &question(1, "fail.example.com")?).await?;
+    write_frame(&mut stream, &question(2, "www.example.com")?).await?;
+    for (id, rcode) in [(1, ResponseCode::ServFail), (2, ResponseCode::NoError)] {
+        let raw = read_frame(&mut stream).await?.unwrap();
+        let (_, reply) = DnsMessage::decode(&raw, 0, &mut HashMap::new())?;
+        assert_eq!(reply.header.packet_id, id);
+        assert_eq!(reply.response_code(), rcode as u8);
+    }
+
+    // and over HTTPS it's still a DNS answer
+    let stream = tokio::net::TcpStream::connect(&https).await?;
+    let (mut sender, connection) =
+        hyper::client::conn::http1::handshake(TokioIo::new(stream)).await?;
+    tokio::spawn(connection);
+    let post = |body: Bytes| {
+        Request::post(DOH_PATH)
+            .header(HOST, "localhost")
+            .header(CONTENT_TYPE, DNS_MESSAGE)
+            .body(Full::new(body))
+    };
+    let response = sender
+        .send_request(post(question(0, "fail.example.com")?)?)
+        .await?;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = response.into_body().collect().await?.to_bytes();
+    let (_, reply) = DnsMessage::decode(&body, 0, &mut HashMap::new())?;
+    assert_eq!(reply.response_code(), ResponseCode::ServFail as u8);
+
+    // a body that isn't a DNS message at all is the only thing that isn't
+    let response = sender
+        .send_request(post(Bytes::from_static(&[1, 2, 3]))?)
+        .await?;
+    assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+    Ok(())
+}
@@ -0,0 +1,300 @@
+use crate::helpers::{StubZone, addresses, query, record, spawn_app_with_handler};
+use anyhow::Result;
+use async_trait::async_trait;
+use dns::dns::*;
+use dns::handler::{DnsHandler, DnsRequest};
+use dns::resolver::{NameServer, RecursiveHandler, Resolver, ResolverConfig, RootHints};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::Arc;
+use std::time::Duration;
+
+fn soa(zone: &str) -> DnsAnswer {
+    record(
+        zone,
+        300,
+        RecordData::SOA(Soa {
+            mname: format!("ns.{}", zone.trim_start_matches('.'))
+                .parse()
+                .unwrap(),
+            rname: format!("hostmaster.{}", zone.trim_start_matches('.'))
+                .parse()
+                .unwrap(),
+            serial: 1,
+            refresh: 3600,
+            retry: 600,
+            expire: 86400,
+            minimum: 60,
+        }),
+    )
+}
+
+fn a(name: &str, ip: [u8; 4]) -> DnsAnswer {
+    record(name, 300, RecordData::A(Ipv4Addr::from(ip)))
+}
+
+fn aaaa(name: &str, ip: Ipv6Addr) -> DnsAnswer {
+    record(name, 300, RecordData::AAAA(ip))
+}
+
+fn ns(name: &str, target: &str) -> DnsAnswer {
+    record(name, 300, RecordData::NS(target.parse().unwrap()))
+}
+
+fn cname(name: &str, target: &str) -> DnsAnswer {
+    record(name, 300, RecordData::CNAME(target.parse().unwrap()))
+}
+
+// a zone whose server tacks records for other people's names onto everything it says
+#[derive(Debug)]
+struct Poisoning(StubZone);
+
+#[async_trait]
+impl DnsHandler for Poisoning {
+    async fn handle(&self, request: &DnsRequest) -> Result<Option<DnsMessage>> {
+        let Some(mut reply) = self.0.handle(request).await? else {
+            return Ok(None);
+        };
+        let mut answers = reply.answers.clone();
+        answers.answers.push(a("www.example.com.", [6, 6, 6, 6]));
+        let mut authority = reply.authority.clone();
+        authority.answers.push(ns("example.com.", "ns.evil.com."));
+        authority.answers.push(a("ns.example.com.", [6, 6, 6, 6]));
+        reply = reply.with_answers(answers)?.with_authority(authority)?;
+        Ok(Some(reply))
+    }
+}
+
+// Builds a small hierarchy on the loopback network, every server listens on the same port but on
+// its own address:
+//
+//  127.0.0.10  .             com. and org. delegated, org. has no glue
+//  127.0.0.11  com.          example.com. and evil.com. delegated with glue, v6.com. with AAAA glue
+//  127.0.0.12  example.com.
+//  127.0.0.13  org.          v6.org. delegated to ns.v6.com., which has no A record
+//  127.0.0.14  evil.com.     tries to poison us with records for example.com.
+//  ::1         v6.com.       and v6.org. as well, reachable over IPv6 only
+//
+// Returns the address of a recursive server that resolves from the root at 127.0.0.10.
+async fn spawn_hierarchy() -> Result<String> {
+    let root = spawn_app_with_handler(
+        "127.0.0.10:0",
+        Arc::new(StubZone::new(".", vec![
+            soa("."),
+            ns("com.", "ns.com."),
+            a("ns.com.", [127, 0, 0, 11]),
+            ns("org.", "ns.example.com."),
+        ])),
+    )
+    .await?;
+    let port = root.port();
+
+    spawn_app_with_handler(
+        &format!("127.0.0.11:{port}"),
+        Arc::new(StubZone::new("com.", vec![
+            soa("com."),
+            ns("example.com.", "ns1.example.com."),
+            a("ns1.example.com.", [127, 0, 0, 12]),
+            ns("evil.com.", "ns.evil.com."),
+            a("ns.evil.com.", [127, 0, 0, 14]),
+            ns("v6.com.", "ns.v6.com."),
+            aaaa("ns.v6.com.", Ipv6Addr::LOCALHOST),
+        ])),
+    )
+    .await?;
+
+    spawn_app_with_handler(
+        &format!("127.0.0.12:{port}"),
+        Arc::new(StubZone::new("example.com.", vec![
+            soa("example.com."),
+            a("ns1.example.com.", [127, 0, 0, 12]),
+            a("ns.example.com.", [127, 0, 0, 13]),
+            a("www.example.com.", [10, 0, 0, 1]),
+            cname("alias.example.com.", "www.example.org."),
+            cname("local.example.com.", "www.example.com."),
+            cname("loop1.example.com.", "loop2.example.com."),
+            cname("loop2.example.com.", "loop1.example.com."),
+        ])),
+    )
+    .await?;
+
+    spawn_app_with_handler(
+        &format!("127.0.0.13:{port}"),
+        Arc::new(StubZone::new("org.", vec![
+            soa("org."),
+            a("www.example.org.", [10, 0, 0, 2]),
+            ns("v6.org.", "ns.v6.com."),
+        ])),
+    )
+    .await?;
+
+    spawn_app_with_handler(
+        &format!("[::1]:{port}"),
+        Arc::new(StubZone::new("v6.com.", vec![
+            soa("v6.com."),
+            aaaa("ns.v6.com.", Ipv6Addr::LOCALHOST),
+            a("www.v6.com.", [10, 0, 0, 4]),
+            a("www.v6.org.", [10, 0, 0, 5]),
+        ])),
+    )
+    .await?;
+
+    spawn_app_with_handler(
+        &format!("127.0.0.14:{port}"),
+        Arc::new(Poisoning(StubZone::new("evil.com.", vec![
+            soa("evil.com."),
+            a("www.evil.com.", [10, 0, 0, 3]),
+        ]))),
+    )
+    .await?;
+
+    let resolver = Resolver::new(ResolverConfig {
+        root_hints: RootHints {
+            servers: vec![NameServer {
+                name: "a.root-servers.test.".parse()?,
+                address: IpAddr::V4(Ipv4Addr::new(127, 0, 0, 10)),
+            }],
+        },
+        port,
+        timeout: Duration::from_millis(500),
+        ..ResolverConfig::default()
+    });
+
+    let server = spawn_app_with_handler(
+        "127.0.0.1:0",
+        Arc::new(RecursiveHandler::new(Arc::new(resolver))),
+    )
+    .await?;
+
+    Ok(server.to_string())
+}
+
+async fn ask(server: &str, name: &str, qtype: QuestionType) -> Result<DnsMessage> {
+    let mut request = DnsMessage::query(4242, DnsQuestion::new(name.parse()?, qtype));
+    request.header.recursion_desired = true;
+    let reply = query(server, request).await?;
+    assert!(reply.header.recursion_available);
+    Ok(reply)
+}
+
+#[tokio::test]
+async fn test_recursive_follows_referrals_with_glue() -> Result<()> {
+    let server = spawn_hierarchy().await?;
+
+    let reply = ask(&server, "www.example.com", QuestionType::A).await?;
+
+    assert_eq!(reply.response_code(), ResponseCode::NoError as u8);
+    assert_eq!(addresses(&reply), vec![RecordData::A(Ipv4Addr::new(
+        10, 0, 0, 1
+    ))]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_recursive_chases_cname_across_zones_without_glue() -> Result<()> {
+    let server = spawn_hierarchy().await?;
+
+    // example.com hands us a CNAME into org., whose name server has no glue
+    let reply = ask(&server, "alias.example.com", QuestionType::A).await?;
+
+    assert_eq!(reply.response_code(), ResponseCode::NoError as u8);
+    assert_eq!(reply.answers.answers[0].qtype, QuestionType::CNAME);
+    assert_eq!(addresses(&reply), vec![RecordData::A(Ipv4Addr::new(
+        10, 0, 0, 2
+    ))]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_recursive_reaches_ipv6_only_name_servers() -> Result<()> {
+    let server = spawn_hierarchy().await?;
+
+    // com. hands out AAAA glue for v6.com.
+    let reply = ask(&server, "www.v6.com", QuestionType::A).await?;
+    assert_eq!(addresses(&reply), vec![RecordData::A(Ipv4Addr::new(
+        10, 0, 0, 4
+    ))]);
+
+    // org. has no glue for v6.org., and looking up its name server's A record finds nothing
+    let reply = ask(&server, "www.v6.org", QuestionType::A).await?;
+    assert_eq!(addresses(&reply), vec![RecordData::A(Ipv4Addr::new(
+        10, 0, 0, 5
+    ))]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_recursive_cname_within_zone() -> Result<()> {
+    let server = spawn_hierarchy().await?;
+
+    let reply = ask(&server, "local.example.com", QuestionType::A).await?;
+
+    assert_eq!(reply.answers.answers.len(), 2);
+    assert_eq!(addresses(&reply), vec![RecordData::A(Ipv4Addr::new(
+        10, 0, 0, 1
+    ))]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_recursive_nxdomain_and_nodata() -> Result<()> {
+    let server = spawn_hierarchy().await?;
+
+    let reply = ask(&server, "missing.example.com", QuestionType::A).await?;
+    assert_eq!(reply.response_code(), ResponseCode::NxDomain as u8);
+    assert_eq!(reply.authority.answers[0].qtype, QuestionType::SOA);
+
+    let reply = ask(&server, "www.example.com", QuestionType::MX).await?;
+    assert_eq!(reply.response_code(), ResponseCode::NoError as u8);
+    assert!(reply.answers.answers.is_empty());
+    assert_eq!(reply.authority.answers[0].qtype, QuestionType::SOA);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_recursive_cname_loop_fails() -> Result<()> {
+    let server = spawn_hierarchy().await?;
+
+    let reply = ask(&server, "loop1.example.com", QuestionType::A).await?;
+    assert_eq!(reply.response_code(), ResponseCode::ServFail as u8);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_recursive_drops_out_of_bailiwick_records() -> Result<()> {
+    let server = spawn_hierarchy().await?;
+
+    let reply = ask(&server, "www.evil.com", QuestionType::A).await?;
+    assert_eq!(reply.response_code(), ResponseCode::NoError as u8);
+    assert_eq!(addresses(&reply), vec![RecordData::A(Ipv4Addr::new(
+        10, 0, 0, 3
+    ))]);
+    let example: Domain = "example.com".parse()?;
+    assert!(
+        reply
+            .answers
+            .answers
+            .iter()
+            .chain(&reply.authority.answers)
+            .all(|r| !r.name.is_subdomain_of(&example))
+    );
+
+    // the same goes for a negative answer's authority section
+    let reply = ask(&server, "missing.evil.com", QuestionType::A).await?;
+    assert_eq!(reply.response_code(), ResponseCode::NxDomain as u8);
+    assert!(reply.answers.answers.is_empty());
+    assert!(
+        reply
+            .authority
+            .answers
+            .iter()
+            .all(|r| r.qtype == QuestionType::SOA && r.name.to_string() == "evil.com.")
+    );
+
+    Ok(())
+}
@@ -0,0 +1,181 @@
+use crate::helpers::{StubZone, query, record, spawn_app_with_handler};
+use anyhow::Result;
+use bytes::Bytes;
+use dns::dns::*;
+use dns::filter::{PolicyHandler, PolicyZone, STAT_RPZ_HITS};
+use dns::handler::{DnsHandler, DnsRequest, Transport};
+use dns::zone::ZoneParser;
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+
+fn example_zone() -> Arc<StubZone> {
+    let soa = Soa {
+        mname: "ns1.example.com.".parse().unwrap(),
+        rname: "admin.example.com.".parse().unwrap(),
+        serial: 1,
+        refresh: 3600,
+        retry: 600,
+        expire: 86400,
+        minimum: 60,
+    };
+    let a = |name, ip| record(name, 300, RecordData::A(ip));
+    Arc::new(StubZone::new("example.com.", vec![
+        record("example.com.", 300, RecordData::SOA(soa)),
+        record(
+            "example.com.",
+            300,
+            RecordData::NS("ns1.example.com.".parse().unwrap()),
+        ),
+        a("ns1.example.com.", Ipv4Addr::new(192, 0, 2, 53)),
+        a("www.example.com.", Ipv4Addr::new(192, 0, 2, 1)),
+        a("mail.example.com.", Ipv4Addr::new(192, 0, 2, 25)),
+        a("bad-ip.example.com.", Ipv4Addr::new(198, 51, 100, 7)),
+    ]))
+}
+
+fn policy(origin: &str, text: &str) -> Result<Arc<PolicyZone>> {
+    let text = format!("$TTL 60\n@ SOA ns.{origin}. admin.{origin}. 1 3600 600 86400 60\n{text}");
+    let records = ZoneParser::parse_str(origin.parse()?, &text, origin)?;
+    Ok(Arc::new(PolicyZone::new(origin.parse()?, records)?))
+}
+
+fn recursive_query(name: &str, qtype: QuestionType) -> Result<DnsMessage> {
+    let mut message = DnsMessage::query(7, DnsQuestion::new(name.parse()?, qtype));
+    message.header.recursion_desired = true;
+    Ok(message)
+}
+
+async fn ask(server: &str, name: &str, qtype: QuestionType) -> Result<DnsMessage> {
+    query(
+        server,
+        recursive_query(name, qtype)?.with_edns(Edns::default())?,
+    )
+    .await
+}
+
+fn rdata(reply: &DnsMessage) -> Vec<RecordData> {
+    reply
+        .answers
+        .answers
+        .iter()
+        .map(|a| a.rdata().unwrap())
+        .collect()
+}
+
+#[tokio::test]
+async fn test_rpz_query_and_response_policies() -> Result<()> {
+    let zone = policy(
+        "rpz.test",
+        "
+bad.example.com                 CNAME .
+*.nodata.example.com            CNAME *.
+local.example.com               A     10.9.9.9
+garden.example.com              CNAME www.example.com.
+tcp.example.com                 CNAME rpz-tcp-only.
+24.0.100.51.198.rpz-ip          CNAME .
+",
+    )?;
+    let handler = Arc::new(PolicyHandler::new(vec![zone], example_zone()));
+    let server = spawn_app_with_handler("127.0.0.1:0", handler.clone())
+        .await?
+        .to_string();
+
+    // query time, the name alone decides
+    let reply = ask(&server, "bad.example.com", QuestionType::A).await?;
+    assert_eq!(reply.response_code(), ResponseCode::NxDomain as u8);
+    assert_eq!(reply.authority.answers[0].name, "rpz.test".parse()?);
+    assert_eq!(reply.edns().unwrap().extended_errors(), vec![EDE_FILTERED]);
+
+    let reply = ask(&server, "a.nodata.example.com", QuestionType::A).await?;
+    assert_eq!(reply.response_code(), ResponseCode::NoError as u8);
+    assert!(reply.answers.answers.is_empty());
+
+    let reply = ask(&server, "local.example.com", QuestionType::A).await?;
+    assert_eq!(rdata(&reply), vec![RecordData::A(Ipv4Addr::new(
+        10, 9, 9, 9
+    ))]);
+    assert_eq!(reply.answers.answers[0].name, "local.example.com".parse()?);
+    let reply = ask(&server, "local.example.com", QuestionType::MX).await?;
+    assert!(reply.answers.answers.is_empty());
+
+    // a rewrite gets followed to the answer it points at
+    let reply = ask(&server, "garden.example.com", QuestionType::A).await?;
+    assert_eq!(rdata(&reply), vec![
+        RecordData::CNAME("www.example.com".parse()?),
+        RecordData::A(Ipv4Addr::new(192, 0, 2, 1)),
+    ]);
+
+    let reply = ask(&server, "tcp.example.com", QuestionType::A).await?;
+    assert!(reply.header.truncation);
+
+    // response time, the address in the answer decides
+    let reply = ask(&server, "bad-ip.example.com", QuestionType::A).await?;
+    assert_eq!(reply.response_code(), ResponseCode::NxDomain as u8);
+
+    let reply = ask(&server, "www.example.com", QuestionType::A).await?;
+    assert_eq!(rdata(&reply), vec![RecordData::A(Ipv4Addr::new(
+        192, 0, 2, 1
+    ))]);
+
+    assert_eq!(handler.stats().get(STAT_RPZ_HITS), 7);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_rpz_name_server_policies() -> Result<()> {
+    // the first zone lets www through whatever the second one says
+    let exceptions = policy("allow.rpz.test", "www.example.com CNAME rpz-passthru.")?;
+    let nsdname = policy("ns.rpz.test", "ns1.example.com.rpz-nsdname CNAME .")?;
+    let handler = PolicyHandler::new(vec![exceptions.clone(), nsdname], example_zone());
+
+    let request = |name, transport| -> Result<DnsRequest> {
+        Ok(DnsRequest {
+            message: recursive_query(name, QuestionType::A)?,
+            client: "127.0.0.1:5353".parse()?,
+            transport,
+            raw: Bytes::new(),
+            key: None,
+        })
+    };
+    let reply = handler
+        .handle(&request("www.example.com", Transport::Udp)?)
+        .await?
+        .unwrap();
+    assert_eq!(rdata(&reply), vec![RecordData::A(Ipv4Addr::new(
+        192, 0, 2, 1
+    ))]);
+    let reply = handler
+        .handle(&request("mail.example.com", Transport::Udp)?)
+        .await?
+        .unwrap();
+    assert_eq!(reply.response_code(), ResponseCode::NxDomain as u8);
+
+    // dropped queries get no answer, and TCP-only ones are answered over TCP
+    let nsip = policy(
+        "nsip.rpz.test",
+        "
+32.53.2.0.192.rpz-nsip   CNAME rpz-drop.
+tcp.example.com          CNAME rpz-tcp-only.
+",
+    )?;
+    let handler = PolicyHandler::new(vec![exceptions, nsip], example_zone());
+    assert!(
+        handler
+            .handle(&request("mail.example.com", Transport::Udp)?)
+            .await?
+            .is_none()
+    );
+    assert!(
+        handler
+            .handle(&request("www.example.com", Transport::Udp)?)
+            .await?
+            .is_some()
+    );
+    let reply = handler
+        .handle(&request("tcp.example.com", Transport::Tcp)?)
+        .await?
+        .unwrap();
+    assert!(!reply.header.truncation);
+    assert_eq!(reply.response_code(), ResponseCode::NxDomain as u8);
+    Ok(())
+}
@@ -0,0 +1,98 @@
+use crate::helpers::{send_request_from, spawn_app_with_handler};
+use anyhow::Result;
+use dns::dns::*;
+use dns::rrl::{ResponseLimiter, RrlConfig, RrlHandler, STAT_RRL_DROPPED, STAT_RRL_SLIPPED};
+use dns::zone::{AuthoritativeHandler, Zone, ZoneParser, ZoneStore};
+use std::sync::Arc;
+use std::time::Duration;
+
+const ZONE: &str = "
+$TTL 300
+@    SOA ns1 hostmaster 1 3600 600 86400 60
+     NS  ns1
+ns1  A   192.0.2.53
+www  A   192.0.2.80
+";
+
+// the reply, or None if the server didn't send one
+async fn ask_from(client: &str, server: &str, name: &str) -> Result<Option<DnsMessage>> {
+    let request = DnsMessage::query(5, DnsQuestion::new(name.parse()?, QuestionType::A));
+    let reply = send_request_from(client, server, request);
+    match tokio::time::timeout(Duration::from_millis(100), reply).await {
+        Ok(reply) => Ok(Some(reply?)),
+        Err(_) => Ok(None),
+    }
+}
+
+#[tokio::test]
+async fn test_rrl_drops_and_slips_identical_responses() -> Result<()> {
+    let origin: Domain = "example.com".parse()?;
+    let zones = Arc::new(ZoneStore::default());
+    zones.insert(Zone::new(
+        origin.clone(),
+        ZoneParser::parse_str(origin, ZONE, "example.com")?,
+    )?);
+
+    // a rate low enough that the test can't refill the buckets by being slow
+    let limiter = Arc::new(ResponseLimiter::new(RrlConfig {
+        responses_per_second: 2,
+        nxdomains_per_second: 1,
+        slip: 2,
+        ..Default::default()
+    }));
+    let rrl = Arc::new(
+        RrlHandler::new(limiter, Arc::new(AuthoritativeHandler::new(zones)))
+            .with_exempt("127.0.0.3".parse()?),
+    );
+    let server = spawn_app_with_handler("127.0.0.1:0", rrl.clone())
+        .await?
+        .to_string();
+
+    for _ in 0..2 {
+        let reply = ask_from("127.0.0.2", &server, "www.example.com")
+            .await?
+            .unwrap();
+        assert!(!reply.header.truncation);
+        assert_eq!(reply.answers.answers.len(), 1);
+    }
+
+    // over the limit every other response is dropped, and the rest come back empty and truncated
+    assert!(
+        ask_from("127.0.0.2", &server, "www.example.com")
+            .await?
+            .is_none()
+    );
+    let slipped = ask_from("127.0.0.2", &server, "www.example.com")
+        .await?
+        .unwrap();
+    assert!(slipped.header.truncation);
+    assert!(slipped.answers.answers.is_empty());
+
+    // NXDOMAINs are counted apart from answers
+    let reply = ask_from("127.0.0.2", &server, "nope.example.com")
+        .await?
+        .unwrap();
+    assert_eq!(reply.response_code(), ResponseCode::NxDomain as u8);
+    assert!(
+        ask_from("127.0.0.2", &server, "other.example.com")
+            .await?
+            .is_none()
+    );
+
+    // the whole /24 shares the limit, apart from exempt clients
+    assert!(
+        ask_from("127.0.0.4", &server, "www.example.com")
+            .await?
+            .is_none()
+    );
+    for _ in 0..4 {
+        let reply = ask_from("127.0.0.3", &server, "www.example.com")
+            .await?
+            .unwrap();
+        assert_eq!(reply.answers.answers.len(), 1);
+    }
+
+    assert_eq!(rrl.stats().get(STAT_RRL_DROPPED), 3);
+    assert_eq!(rrl.stats().get(STAT_RRL_SLIPPED), 1);
+    Ok(())
+}
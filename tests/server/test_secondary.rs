@@ -0,0 +1,150 @@
+use crate::helpers::{ask, spawn_app_with_handler};
+use anyhow::Result;
+use dns::dns::*;
+use dns::zone::{
+    AuthoritativeHandler, Diff, Notifier, NotifyHandler, Secondary, SecondaryConfig, SecondaryZone,
+    Zone, ZoneParser, ZoneStore, send_notify,
+};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+fn zone(refresh: u32) -> Result<Zone> {
+    let origin: Domain = "example.com".parse()?;
+    let text =
+        format!("$TTL 300\n@ SOA ns hostmaster 1 {refresh} 1 86400 60\n@ NS ns\nns A 192.0.2.1\n");
+    Zone::new(
+        origin.clone(),
+        ZoneParser::parse_str(origin, &text, "zone")?,
+    )
+}
+
+// add a record to the zone in the store and bump its serial
+fn add_record(zones: &ZoneStore, name: &str) -> Result<()> {
+    let zone = zones.get(&"example.com".parse()?).unwrap();
+    let from = zone.soa_record().unwrap().clone();
+    let RecordData::SOA(mut soa) = from.rdata()? else {
+        unreachable!()
+    };
+    soa.serial += 1;
+    zones.insert(zone.apply(Diff {
+        to: DnsAnswer::new(from.name.clone(), from.ttl, RecordData::SOA(soa))?,
+        from,
+        removed: vec![],
+        added: vec![DnsAnswer::new(
+            name.parse()?,
+            300,
+            RecordData::A([192, 0, 2, 9].into()),
+        )?],
+    })?);
+    Ok(())
+}
+
+// keep asking until the secondary has the name, or give up after a few seconds
+async fn wait_for(server: SocketAddr, name: &str) -> Result<DnsMessage> {
+    for _ in 0..50 {
+        let reply = ask(server, name, QuestionType::A).await?;
+        if !reply.answers.answers.is_empty() {
+            return Ok(reply);
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    anyhow::bail!("{name} never showed up")
+}
+
+// a primary serving `zone` and a secondary pulling it from there
+async fn spawn_pair(zone: Zone) -> Result<(Arc<ZoneStore>, SocketAddr, SocketAddr)> {
+    let primary_zones = Arc::new(ZoneStore::default());
+    primary_zones.insert(zone);
+    let primary = spawn_app_with_handler(
+        "127.0.0.1:0",
+        Arc::new(
+            AuthoritativeHandler::new(primary_zones.clone())
+                .with_transfer_allowed(vec![IpAddr::V4(Ipv4Addr::LOCALHOST)]),
+        ),
+    )
+    .await?;
+
+    let secondary_zones = Arc::new(ZoneStore::default());
+    let secondary = Arc::new(
+        Secondary::new(secondary_zones.clone(), vec![SecondaryZone {
+            origin: "example.com".parse()?,
+            primaries: vec![primary],
+            key: None,
+        }])
+        .with_config(SecondaryConfig {
+            timeout: Duration::from_secs(1),
+            initial_retry: Duration::from_millis(100),
+            ..SecondaryConfig::default()
+        }),
+    );
+    secondary.start();
+    let secondary_addr = spawn_app_with_handler(
+        "127.0.0.1:0",
+        Arc::new(NotifyHandler::new(
+            secondary,
+            Arc::new(AuthoritativeHandler::new(secondary_zones)),
+        )),
+    )
+    .await?;
+
+    Ok((primary_zones, primary, secondary_addr))
+}
+
+#[tokio::test]
+async fn test_notify_triggers_refresh() -> Result<()> {
+    // with an hour between polls, only a NOTIFY can explain the secondary catching up
+    let (primary_zones, _, secondary) = spawn_pair(zone(3600)?).await?;
+    wait_for(secondary, "ns.example.com").await?;
+
+    Arc::new(
+        Notifier::new(primary_zones.clone())
+            .with_secondaries("example.com".parse()?, vec![secondary]),
+    )
+    .start();
+    add_record(&primary_zones, "fresh.example.com")?;
+
+    let reply = wait_for(secondary, "fresh.example.com").await?;
+    assert!(reply.header.auth_answer);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_secondary_polls_on_refresh_timer() -> Result<()> {
+    let (primary_zones, _, secondary) = spawn_pair(zone(1)?).await?;
+    wait_for(secondary, "ns.example.com").await?;
+
+    add_record(&primary_zones, "polled.example.com")?;
+    wait_for(secondary, "polled.example.com").await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_notify_only_accepted_from_primaries() -> Result<()> {
+    let zones = Arc::new(ZoneStore::default());
+    zones.insert(zone(3600)?);
+
+    // the secondary thinks its primary is somewhere we aren't
+    let secondary = Arc::new(Secondary::new(Arc::new(ZoneStore::default()), vec![
+        SecondaryZone {
+            origin: "example.com".parse()?,
+            primaries: vec!["192.0.2.53:53".parse()?],
+            key: None,
+        },
+    ]));
+    let server = spawn_app_with_handler(
+        "127.0.0.1:0",
+        Arc::new(NotifyHandler::new(
+            secondary,
+            Arc::new(AuthoritativeHandler::new(zones.clone())),
+        )),
+    )
+    .await?;
+
+    let origin: Domain = "example.com".parse()?;
+    let err = send_notify(server, &zones, &origin, None, Duration::from_secs(1))
+        .await
+        .unwrap_err();
+    assert!(format!("{err:#}").contains("rcode 5"), "{err:#}");
+    Ok(())
+}
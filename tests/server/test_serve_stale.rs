@@ -0,0 +1,104 @@
+use crate::helpers::{StubZone, query, record, spawn_app_with_handler};
+use anyhow::Result;
+use async_trait::async_trait;
+use dns::cache::{CacheConfig, CachingHandler, DnsCache, ManualClock};
+use dns::dns::*;
+use dns::handler::{DnsHandler, DnsRequest};
+use dns::resolver::ForwardHandler;
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+// an upstream we can take down, while it's down it just ignores every request
+#[derive(Debug)]
+struct Flaky {
+    up: Arc<AtomicBool>,
+    inner: StubZone,
+}
+
+#[async_trait]
+impl DnsHandler for Flaky {
+    async fn handle(&self, request: &DnsRequest) -> Result<Option<DnsMessage>> {
+        if !self.up.load(Ordering::SeqCst) {
+            return Ok(None);
+        }
+        self.inner.handle(request).await
+    }
+}
+
+async fn ask(server: &str) -> Result<DnsMessage> {
+    let request = DnsMessage::query(
+        99,
+        DnsQuestion::new("www.example.com".parse()?, QuestionType::A),
+    )
+    .with_edns(Edns::default())?;
+    query(server, request).await
+}
+
+fn extended_errors(reply: &DnsMessage) -> Vec<u16> {
+    reply
+        .edns()
+        .map(|e| e.extended_errors())
+        .unwrap_or_default()
+}
+
+#[tokio::test]
+async fn test_serve_stale_when_upstream_is_down() -> Result<()> {
+    let up = Arc::new(AtomicBool::new(true));
+    let upstream = spawn_app_with_handler(
+        "127.0.0.1:0",
+        Arc::new(Flaky {
+            up: up.clone(),
+            inner: StubZone::new("example.com.", vec![record(
+                "www.example.com.",
+                300,
+                RecordData::A(Ipv4Addr::new(10, 0, 0, 1)),
+            )]),
+        }),
+    )
+    .await?;
+
+    let clock = Arc::new(ManualClock::default());
+    let cache = Arc::new(DnsCache::with_clock(
+        CacheConfig {
+            serve_stale: 3600,
+            stale_answer_ttl: 30,
+            stale_refresh_interval: Duration::from_millis(50),
+            ..CacheConfig::default()
+        },
+        clock.clone(),
+    ));
+    let forwarder =
+        ForwardHandler::new(vec![upstream.into()]).with_timeout(Duration::from_millis(100));
+    let server = spawn_app_with_handler(
+        "127.0.0.1:0",
+        Arc::new(CachingHandler::new(cache, Arc::new(forwarder))),
+    )
+    .await?
+    .to_string();
+
+    let reply = ask(&server).await?;
+    assert_eq!(reply.answers.answers[0].ttl, 300);
+    assert!(extended_errors(&reply).is_empty());
+
+    // the entry expires while upstream is down, so we get the old answer back
+    clock.advance(Duration::from_secs(301));
+    up.store(false, Ordering::SeqCst);
+
+    let reply = ask(&server).await?;
+    assert_eq!(reply.response_code(), ResponseCode::NoError as u8);
+    assert_eq!(reply.answers.answers[0].ttl, 30);
+    assert_eq!(extended_errors(&reply), vec![EDE_STALE_ANSWER]);
+
+    // once upstream is back the entry gets refreshed behind the scenes
+    up.store(true, Ordering::SeqCst);
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    up.store(false, Ordering::SeqCst);
+
+    let reply = ask(&server).await?;
+    assert_eq!(reply.answers.answers[0].ttl, 300);
+    assert!(extended_errors(&reply).is_empty());
+
+    Ok(())
+}
@@ -0,0 +1,95 @@
+use crate::helpers::{StubZone, example, send_request_from, spawn_app_with_handler};
+use anyhow::Result;
+use async_trait::async_trait;
+use dns::dns::*;
+use dns::handler::{DnsHandler, DnsRequest};
+use dns::throttle::{
+    ClientLimiter, ClientLimits, OverLimit, STAT_THROTTLE_IN_FLIGHT_LIMITED,
+    STAT_THROTTLE_OFFENDERS, STAT_THROTTLE_RATE_LIMITED, ThrottleHandler,
+};
+use std::sync::Arc;
+use std::time::Duration;
+
+// an upstream that takes its time
+#[derive(Debug)]
+struct Slow(StubZone);
+
+#[async_trait]
+impl DnsHandler for Slow {
+    async fn handle(&self, request: &DnsRequest) -> Result<Option<DnsMessage>> {
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        self.0.handle(request).await
+    }
+}
+
+// the reply, or None if the server didn't send one
+async fn ask_from(client: &str, server: &str) -> Result<Option<DnsMessage>> {
+    let request = DnsMessage::query(
+        7,
+        DnsQuestion::new("www.example.com".parse()?, QuestionType::A),
+    );
+    let reply = send_request_from(client, server, request);
+    match tokio::time::timeout(Duration::from_millis(600), reply).await {
+        Ok(reply) => Ok(Some(reply?)),
+        Err(_) => Ok(None),
+    }
+}
+
+#[tokio::test]
+async fn test_throttle_refuses_clients_over_their_query_rate() -> Result<()> {
+    let limiter = Arc::new(ClientLimiter::new(ClientLimits {
+        queries_per_second: 3,
+        ..Default::default()
+    }));
+    let throttle = Arc::new(ThrottleHandler::new(limiter, Arc::new(example())));
+    let server = spawn_app_with_handler("127.0.0.1:0", throttle.clone())
+        .await?
+        .to_string();
+
+    for _ in 0..3 {
+        let reply = ask_from("127.0.0.2", &server).await?.unwrap();
+        assert_eq!(reply.response_code(), ResponseCode::NoError as u8);
+    }
+    let reply = ask_from("127.0.0.2", &server).await?.unwrap();
+    assert_eq!(reply.response_code(), ResponseCode::Refused as u8);
+
+    // every address has a limit of its own
+    let reply = ask_from("127.0.0.3", &server).await?.unwrap();
+    assert_eq!(reply.response_code(), ResponseCode::NoError as u8);
+
+    assert_eq!(throttle.stats().get(STAT_THROTTLE_RATE_LIMITED), 1);
+    assert_eq!(throttle.stats().top(STAT_THROTTLE_OFFENDERS, 10), vec![(
+        "127.0.0.2/32".to_string(),
+        1
+    )]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_throttle_drops_queries_over_the_in_flight_budget() -> Result<()> {
+    let limiter = Arc::new(ClientLimiter::new(ClientLimits {
+        queries_per_second: 0,
+        max_in_flight: 1,
+        over_limit: OverLimit::Drop,
+        ..Default::default()
+    }));
+    let throttle = Arc::new(ThrottleHandler::new(limiter, Arc::new(Slow(example()))));
+    let server = spawn_app_with_handler("127.0.0.1:0", throttle.clone())
+        .await?
+        .to_string();
+
+    // the second query arrives while the first is still being answered
+    let first = ask_from("127.0.0.2", &server);
+    let second = async {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        ask_from("127.0.0.2", &server).await
+    };
+    let (first, second) = tokio::join!(first, second);
+    assert!(first?.is_some());
+    assert!(second?.is_none());
+
+    // and once it has been, there's room again
+    assert!(ask_from("127.0.0.2", &server).await?.is_some());
+    assert_eq!(throttle.stats().get(STAT_THROTTLE_IN_FLIGHT_LIMITED), 1);
+    Ok(())
+}
@@ -0,0 +1,173 @@
+use crate::helpers::{ask, query, spawn_app_with_handler};
+use anyhow::Result;
+use dns::dns::*;
+use dns::parse::DnsData;
+use dns::zone::{
+    AuthoritativeHandler, Diff, Transfer, Zone, ZoneParser, ZoneStore, axfr_records, transfer_zone,
+};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+
+const WAIT: Duration = Duration::from_secs(2);
+
+fn zone(serial: u32) -> Result<Zone> {
+    let origin: Domain = "example.com".parse()?;
+    let text = format!(
+        "$TTL 300\n@ SOA ns hostmaster {serial} 3600 600 86400 60\n@ NS ns\nns A 192.0.2.1\n\
+         $GENERATE 1-200 host$ A 10.0.0.$\n\
+         $GENERATE 1-200 txt$ TXT \"enough text to need more than one message $\"\n"
+    );
+    Zone::new(
+        origin.clone(),
+        ZoneParser::parse_str(origin, &text, "zone")?,
+    )
+}
+
+fn a(name: &str, ip: [u8; 4]) -> Result<DnsAnswer> {
+    DnsAnswer::new(name.parse()?, 300, RecordData::A(ip.into()))
+}
+
+async fn spawn_server(zones: Arc<ZoneStore>, allowed: Vec<IpAddr>) -> Result<SocketAddr> {
+    spawn_app_with_handler(
+        "127.0.0.1:0",
+        Arc::new(AuthoritativeHandler::new(zones).with_transfer_allowed(allowed)),
+    )
+    .await
+}
+
+#[tokio::test]
+async fn test_secondary_pulls_zone_from_primary() -> Result<()> {
+    let origin: Domain = "example.com".parse()?;
+    let primary_zones = Arc::new(ZoneStore::default());
+    primary_zones.insert(zone(1)?);
+    let primary =
+        spawn_server(primary_zones.clone(), vec![IpAddr::V4(Ipv4Addr::LOCALHOST)]).await?;
+
+    // the first transfer is always a full one
+    let Transfer::Full(copy) = transfer_zone(primary, &origin, None, None, WAIT).await? else {
+        panic!("expected a full transfer");
+    };
+    assert_eq!(
+        copy.records(),
+        primary_zones.get(&origin).unwrap().records()
+    );
+
+    let secondary_zones = Arc::new(ZoneStore::default());
+    secondary_zones.insert(copy);
+    let secondary = spawn_server(secondary_zones.clone(), vec![]).await?;
+
+    let reply = ask(secondary, "host42.example.com", QuestionType::A).await?;
+    assert!(reply.header.auth_answer);
+    assert_eq!(
+        reply.answers.answers[0].rdata()?,
+        RecordData::A([10, 0, 0, 42].into())
+    );
+
+    // nothing has changed yet
+    let current = secondary_zones.get(&origin).unwrap();
+    assert!(matches!(
+        transfer_zone(primary, &origin, Some(&current), None, WAIT).await?,
+        Transfer::UpToDate
+    ));
+
+    // change the primary, the secondary only needs the difference
+    let v1 = primary_zones.get(&origin).unwrap();
+    let mut to = v1.soa_record().unwrap().clone();
+    let RecordData::SOA(mut soa) = to.rdata()? else {
+        unreachable!()
+    };
+    soa.serial = 2;
+    to = DnsAnswer::new(origin.clone(), 300, RecordData::SOA(soa))?;
+    primary_zones.insert(v1.apply(Diff {
+        from: v1.soa_record().unwrap().clone(),
+        removed: vec![a("host42.example.com", [10, 0, 0, 42])?],
+        to,
+        added: vec![a("new.example.com", [10, 1, 1, 1])?],
+    })?);
+
+    let Transfer::Incremental(updated) =
+        transfer_zone(primary, &origin, Some(&current), None, WAIT).await?
+    else {
+        panic!("expected an incremental transfer");
+    };
+    assert_eq!(updated.serial()?, 2);
+    secondary_zones.insert(updated);
+
+    let reply = ask(secondary, "host42.example.com", QuestionType::A).await?;
+    assert_eq!(reply.response_code(), ResponseCode::NxDomain as u8);
+    let reply = ask(secondary, "new.example.com", QuestionType::A).await?;
+    assert_eq!(reply.answers.answers.len(), 1);
+
+    // a secondary that's further behind than the journal goes gets the whole zone
+    let ancient = zone(0)?;
+    assert!(matches!(
+        transfer_zone(primary, &origin, Some(&ancient), None, WAIT).await?,
+        Transfer::Full(_)
+    ));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_transfers_need_permission_and_tcp() -> Result<()> {
+    let origin: Domain = "example.com".parse()?;
+    let zones = Arc::new(ZoneStore::default());
+    zones.insert(zone(1)?);
+    let server = spawn_server(zones.clone(), vec!["192.0.2.99".parse()?]).await?;
+
+    let err = transfer_zone(server, &origin, None, None, WAIT)
+        .await
+        .unwrap_err();
+    assert!(format!("{err:#}").contains("refused"), "{err:#}");
+
+    let allowed = spawn_server(zones, vec![IpAddr::V4(Ipv4Addr::LOCALHOST)]).await?;
+    let reply = ask(allowed, "example.com", QuestionType::AXFR).await?;
+    assert_eq!(reply.response_code(), ResponseCode::Refused as u8);
+
+    // over UDP an IXFR just gets the SOA
+    let mut request = DnsMessage::query(9, DnsQuestion::new(origin.clone(), QuestionType::IXFR));
+    request = request.with_authority(DnsAnswerSet {
+        answers: vec![zone(0)?.soa_record().unwrap().clone()],
+    })?;
+    let reply = query(allowed, request).await?;
+    assert_eq!(reply.answers.answers.len(), 1);
+    assert_eq!(reply.answers.answers[0].qtype, QuestionType::SOA);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_a_newer_soa_on_its_own_is_not_up_to_date() -> Result<()> {
+    let origin: Domain = "example.com".parse()?;
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let primary = listener.local_addr()?;
+
+    // a primary that sends the opening SOA of a full transfer in a message by itself
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await?;
+        let buf = read_frame(&mut stream).await?.unwrap();
+        let (_, request) = DnsMessage::decode(&buf, 0, &mut HashMap::new())?;
+        let mut records = axfr_records(&zone(2)?)?;
+        let rest = records.split_off(1);
+        for answers in [records, rest] {
+            let reply = request
+                .clone()
+                .as_reply()
+                .with_answers(DnsAnswerSet { answers })?;
+            write_frame(&mut stream, &reply.encode(0, &mut HashMap::new())?).await?;
+        }
+        anyhow::Ok(())
+    });
+
+    let Transfer::Full(copy) = transfer_zone(primary, &origin, Some(&zone(1)?), None, WAIT).await?
+    else {
+        panic!("expected a full transfer");
+    };
+    assert_eq!(copy.serial()?, 2);
+    assert_eq!(copy.records(), zone(2)?.records());
+
+    Ok(())
+}
@@ -0,0 +1,106 @@
+use crate::helpers::{StubZone, query, record, spawn_app_with_handler};
+use anyhow::Result;
+use dns::dns::*;
+use dns::parse::DnsData;
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+
+// a name with more addresses than fit in 512 bytes, and a delegation with more glue than that
+async fn app() -> Result<String> {
+    let mut records = vec![];
+    for i in 0..60 {
+        records.push(record(
+            "big.example.com.",
+            300,
+            RecordData::A(Ipv4Addr::new(192, 0, 2, i)),
+        ));
+    }
+    for n in 0..4 {
+        let server = format!("ns{n}.sub.example.com.");
+        records.push(record(
+            "sub.example.com.",
+            300,
+            RecordData::NS(server.parse()?),
+        ));
+        for i in 0..10 {
+            records.push(record(
+                &server,
+                300,
+                RecordData::A(Ipv4Addr::new(198, 51, 100, n * 10 + i)),
+            ));
+        }
+    }
+    let zone = StubZone::new("example.com.", records);
+    Ok(spawn_app_with_handler("127.0.0.1:0", Arc::new(zone))
+        .await?
+        .to_string())
+}
+
+// the reply and how many bytes it came in
+async fn ask_raw(server: &str, request: DnsMessage) -> Result<(usize, DnsMessage)> {
+    let reply = send_request(server, request.encode(0, &mut HashMap::new())?).await?;
+    let (_, message) = DnsMessage::decode(&reply, 0, &mut HashMap::new())?;
+    Ok((reply.len(), message))
+}
+
+fn question(name: &str) -> Result<DnsMessage> {
+    Ok(DnsMessage::query(
+        3,
+        DnsQuestion::new(name.parse()?, QuestionType::A),
+    ))
+}
+
+#[tokio::test]
+async fn test_udp_replies_are_truncated_to_512_bytes_without_edns() -> Result<()> {
+    let server = app().await?;
+
+    let (len, reply) = ask_raw(&server, question("big.example.com")?).await?;
+    assert!(len <= MIN_UDP_MESSAGE_SIZE);
+    assert!(reply.header.truncation);
+    assert!(reply.answers.answers.is_empty());
+    assert_eq!(reply.questions.questions.len(), 1);
+
+    // glue is the first thing to go
+    let (len, reply) = ask_raw(&server, question("www.sub.example.com")?).await?;
+    assert!(len <= MIN_UDP_MESSAGE_SIZE);
+    assert!(reply.header.truncation);
+    assert_eq!(reply.authority.answers.len(), 4);
+    assert!(reply.additional.answers.is_empty());
+
+    // TCP has room for everything
+    let mut stream = tokio::net::TcpStream::connect(&server).await?;
+    write_frame(
+        &mut stream,
+        &question("big.example.com")?.encode(0, &mut HashMap::new())?,
+    )
+    .await?;
+    let raw = read_frame(&mut stream).await?.unwrap();
+    let (_, reply) = DnsMessage::decode(&raw, 0, &mut HashMap::new())?;
+    assert!(!reply.header.truncation);
+    assert_eq!(reply.answers.answers.len(), 60);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_udp_replies_fit_the_edns_payload_size() -> Result<()> {
+    let server = app().await?;
+
+    let edns = |udp_payload_size| Edns {
+        udp_payload_size,
+        ..Edns::default()
+    };
+    let reply = query(&server, question("big.example.com")?.with_edns(edns(4096))?).await?;
+    assert!(!reply.header.truncation);
+    assert_eq!(reply.answers.answers.len(), 60);
+
+    // too small to fit, but the OPT still makes it
+    let (len, reply) = ask_raw(&server, question("big.example.com")?.with_edns(edns(600))?).await?;
+    assert!(len <= 600);
+    assert!(reply.header.truncation);
+    assert!(reply.answers.answers.is_empty());
+    assert!(reply.edns().is_some());
+
+    Ok(())
+}
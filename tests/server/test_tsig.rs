@@ -0,0 +1,179 @@
+use crate::helpers::{record, spawn_app_with_handler};
+use anyhow::Result;
+use dns::dns::*;
+use dns::parse::DnsData;
+use dns::tsig::{Keyring, TsigAlgorithm, TsigHandler, TsigKey, TsigSession};
+use dns::zone::{
+    AuthoritativeHandler, NotifyHandler, Secondary, SecondaryZone, Transfer, UpdateHandler, Zone,
+    ZoneParser, ZoneStore, send_notify, transfer_zone,
+};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+const WAIT: Duration = Duration::from_secs(2);
+
+const KEYS: &str = r#"
+key "transfer.example.com" {
+    algorithm hmac-sha256;
+    secret "dHJhbnNmZXIgc2VjcmV0";
+};
+key "update.example.com" {
+    algorithm hmac-sha512;
+    secret "dXBkYXRlIHNlY3JldA==";
+};
+"#;
+
+fn zone() -> Result<Zone> {
+    let origin: Domain = "example.com".parse()?;
+    let text = "$TTL 300\n@ SOA ns hostmaster 1 3600 600 86400 60\n@ NS ns\nns A 192.0.2.1\n\
+                $GENERATE 1-200 txt$ TXT \"enough text to need more than one message $\"\n";
+    Zone::new(origin.clone(), ZoneParser::parse_str(origin, text, "zone")?)
+}
+
+fn key(keyring: &Keyring, name: &str) -> Arc<TsigKey> {
+    keyring.get(&name.parse().unwrap()).unwrap()
+}
+
+// a primary that only lets the transfer key transfer and the update key update
+async fn spawn_primary(keyring: Keyring) -> Result<(Arc<ZoneStore>, SocketAddr)> {
+    let zones = Arc::new(ZoneStore::default());
+    zones.insert(zone()?);
+
+    let origin: Domain = "example.com".parse()?;
+    let authoritative = AuthoritativeHandler::new(zones.clone())
+        .with_transfer_keys(vec!["transfer.example.com".parse()?]);
+    let update = UpdateHandler::new(zones.clone(), Arc::new(authoritative))
+        .with_zone_keys(origin, vec!["update.example.com".parse()?]);
+    let handler = TsigHandler::new(Arc::new(keyring), Arc::new(update));
+    Ok((
+        zones,
+        spawn_app_with_handler("127.0.0.1:0", Arc::new(handler)).await?,
+    ))
+}
+
+#[tokio::test]
+async fn test_transfers_need_a_good_signature() -> Result<()> {
+    let keyring = Keyring::parse_str(KEYS)?;
+    let transfer = key(&keyring, "transfer.example.com");
+    let (zones, primary) = spawn_primary(keyring.clone()).await?;
+    let origin: Domain = "example.com".parse()?;
+
+    // every message of the transfer is checked
+    let Transfer::Full(copy) = transfer_zone(primary, &origin, None, Some(&transfer), WAIT).await?
+    else {
+        panic!("expected a full transfer");
+    };
+    assert_eq!(copy.records(), zones.get(&origin).unwrap().records());
+
+    let err = transfer_zone(primary, &origin, None, None, WAIT)
+        .await
+        .unwrap_err();
+    assert!(format!("{err:#}").contains("refused"), "{err:#}");
+
+    // the update key is good, just not for transfers
+    let update = key(&keyring, "update.example.com");
+    let err = transfer_zone(primary, &origin, None, Some(&update), WAIT)
+        .await
+        .unwrap_err();
+    assert!(format!("{err:#}").contains("refused"), "{err:#}");
+
+    let forged = Arc::new(TsigKey::new(
+        transfer.name.clone(),
+        TsigAlgorithm::HmacSha256,
+        b"guessed",
+    ));
+    let err = transfer_zone(primary, &origin, None, Some(&forged), WAIT)
+        .await
+        .unwrap_err();
+    assert!(format!("{err:#}").contains("BADSIG"), "{err:#}");
+
+    let unknown = Arc::new(TsigKey::new(
+        "unknown.example.com".parse()?,
+        TsigAlgorithm::HmacSha256,
+        b"secret",
+    ));
+    let err = transfer_zone(primary, &origin, None, Some(&unknown), WAIT)
+        .await
+        .unwrap_err();
+    assert!(format!("{err:#}").contains("BADKEY"), "{err:#}");
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_updates_are_signed_both_ways() -> Result<()> {
+    let keyring = Keyring::parse_str(KEYS)?;
+    let (zones, primary) = spawn_primary(keyring.clone()).await?;
+
+    let mut request = DnsMessage::query(
+        77,
+        DnsQuestion::new("example.com".parse()?, QuestionType::SOA),
+    )
+    .with_authority(DnsAnswerSet {
+        answers: vec![record(
+            "signed.example.com",
+            300,
+            RecordData::A([192, 0, 2, 77].into()),
+        )],
+    })?;
+    request.header.opcode = Opcode::Update as u8;
+
+    // without a signature the zone is off limits
+    let reply = send_request(
+        &primary.to_string(),
+        request.encode(0, &mut HashMap::new())?,
+    )
+    .await?;
+    let (_, reply) = DnsMessage::decode(&reply, 0, &mut HashMap::new())?;
+    assert_eq!(reply.response_code(), ResponseCode::Refused as u8);
+
+    let mut session = TsigSession::new(key(&keyring, "update.example.com"));
+    let request = session.sign(request)?;
+    let raw = send_request(
+        &primary.to_string(),
+        request.encode(0, &mut HashMap::new())?,
+    )
+    .await?;
+    session.verify(&raw)?;
+    let (_, reply) = DnsMessage::decode(&raw, 0, &mut HashMap::new())?;
+    assert_eq!(reply.response_code(), ResponseCode::NoError as u8);
+
+    let zone = zones.get(&"example.com".parse()?).unwrap();
+    assert!(zone.node(&"signed.example.com".parse()?).is_some());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_notify_must_be_signed_with_the_zone_key() -> Result<()> {
+    let keyring = Keyring::parse_str(KEYS)?;
+    let transfer = key(&keyring, "transfer.example.com");
+    let zones = Arc::new(ZoneStore::default());
+    zones.insert(zone()?);
+
+    // we're the primary as far as the secondary is concerned
+    let secondary = Arc::new(Secondary::new(Arc::new(ZoneStore::default()), vec![
+        SecondaryZone {
+            origin: "example.com".parse()?,
+            primaries: vec!["127.0.0.1:53".parse()?],
+            key: Some(transfer.clone()),
+        },
+    ]));
+    let handler = NotifyHandler::new(
+        secondary,
+        Arc::new(AuthoritativeHandler::new(zones.clone())),
+    );
+    let server = spawn_app_with_handler(
+        "127.0.0.1:0",
+        Arc::new(TsigHandler::new(Arc::new(keyring), Arc::new(handler))),
+    )
+    .await?;
+
+    let origin: Domain = "example.com".parse()?;
+    send_notify(server, &zones, &origin, Some(&transfer), WAIT).await?;
+    let err = send_notify(server, &zones, &origin, None, WAIT)
+        .await
+        .unwrap_err();
+    assert!(format!("{err:#}").contains("rcode 5"), "{err:#}");
+    Ok(())
+}
@@ -0,0 +1,140 @@
+use crate::helpers::{ask, query, record, spawn_app_with_handler};
+use anyhow::Result;
+use dns::dns::*;
+use dns::zone::{AuthoritativeHandler, UpdateHandler, ZoneStore};
+use std::sync::Arc;
+
+const ZONE: &str = r#"
+$ORIGIN example.com.
+$TTL 1h
+@       IN SOA ns1 hostmaster 2024060101 1h 10m 1w 5m
+        IN NS ns1
+ns1     IN A 192.0.2.1
+www     IN A 192.0.2.2
+"#;
+
+const LOCKED: &str = r#"
+$ORIGIN locked.org.
+@       3600 IN SOA ns1 hostmaster 1 1h 10m 1w 5m
+        3600 IN NS ns1
+ns1     3600 IN A 192.0.2.1
+"#;
+
+async fn update(
+    server: &str,
+    zone: &str,
+    prerequisites: Vec<DnsAnswer>,
+    updates: Vec<DnsAnswer>,
+) -> Result<u8> {
+    let mut request = DnsMessage::query(13, DnsQuestion::new(zone.parse()?, QuestionType::SOA))
+        .with_answers(DnsAnswerSet {
+            answers: prerequisites,
+        })?
+        .with_authority(DnsAnswerSet { answers: updates })?;
+    request.header.opcode = Opcode::Update as u8;
+
+    let reply = query(server, request).await?;
+    assert_eq!(reply.header.opcode, Opcode::Update as u8);
+    assert_eq!(reply.questions.questions.len(), 1);
+    Ok(reply.response_code())
+}
+
+// a record with no data, for prerequisites and deletes
+fn empty(name: &str, qtype: QuestionType, class: u16) -> DnsAnswer {
+    DnsAnswer {
+        name: name.parse().unwrap(),
+        qtype,
+        class,
+        ttl: 0,
+        data: Default::default(),
+    }
+}
+
+fn serial(reply: &DnsMessage) -> u32 {
+    match reply.answers.answers[0].rdata().unwrap() {
+        RecordData::SOA(soa) => soa.serial,
+        other => panic!("expected an SOA, got {other:?}"),
+    }
+}
+
+async fn serve(zones: Arc<ZoneStore>) -> Result<String> {
+    let handler = UpdateHandler::new(zones.clone(), Arc::new(AuthoritativeHandler::new(zones)))
+        .with_zone_access("example.com".parse()?, vec!["127.0.0.1".parse()?]);
+    Ok(spawn_app_with_handler("127.0.0.1:0", Arc::new(handler))
+        .await?
+        .to_string())
+}
+
+#[tokio::test]
+async fn test_updates_change_the_zone_and_survive_a_restart() -> Result<()> {
+    let dir = std::env::temp_dir().join(format!("dns-update-{}", std::process::id()));
+    std::fs::create_dir_all(&dir)?;
+    let path = dir.join("db.example");
+    std::fs::write(&path, ZONE)?;
+    let locked = dir.join("db.locked");
+    std::fs::write(&locked, LOCKED)?;
+
+    let zones = Arc::new(ZoneStore::default());
+    zones.load_file("example.com".parse()?, &path)?;
+    zones.load_file("locked.org".parse()?, &locked)?;
+    let server = serve(zones).await?;
+
+    let new = record("new.example.com", 300, RecordData::A([192, 0, 2, 9].into()));
+    let code = update(&server, "example.com", vec![], vec![new.clone()]).await?;
+    assert_eq!(code, ResponseCode::NoError as u8);
+
+    let reply = ask(&server, "new.example.com", QuestionType::A).await?;
+    assert_eq!(reply.answers.answers, vec![new.clone()]);
+    let reply = ask(&server, "example.com", QuestionType::SOA).await?;
+    assert_eq!(serial(&reply), 2024060102);
+
+    // only add new if it isn't there yet, which it is
+    let code = update(
+        &server,
+        "example.com",
+        vec![empty("new.example.com", QuestionType::ANY, CLASS_NONE)],
+        vec![new],
+    )
+    .await?;
+    assert_eq!(code, ResponseCode::YxDomain as u8);
+
+    // replace www's addresses, but only while it still has some
+    let code = update(
+        &server,
+        "example.com",
+        vec![empty("www.example.com", QuestionType::A, CLASS_ANY)],
+        vec![
+            empty("www.example.com", QuestionType::A, CLASS_ANY),
+            record("www.example.com", 60, RecordData::A([192, 0, 2, 7].into())),
+        ],
+    )
+    .await?;
+    assert_eq!(code, ResponseCode::NoError as u8);
+
+    // the other zone doesn't let anyone update it, and we don't have the last one at all
+    let code = update(&server, "locked.org", vec![], vec![]).await?;
+    assert_eq!(code, ResponseCode::Refused as u8);
+    let code = update(&server, "example.net", vec![], vec![]).await?;
+    assert_eq!(code, ResponseCode::NotAuth as u8);
+
+    // starting again from the zone file gets the changes back from the journal
+    let zones = Arc::new(ZoneStore::default());
+    zones.load_file("example.com".parse()?, &path)?;
+    let server = serve(zones).await?;
+
+    let reply = ask(&server, "example.com", QuestionType::SOA).await?;
+    assert_eq!(serial(&reply), 2024060103);
+    let reply = ask(&server, "www.example.com", QuestionType::A).await?;
+    let addresses: Vec<RecordData> = reply
+        .answers
+        .answers
+        .iter()
+        .map(|a| a.rdata().unwrap())
+        .collect();
+    assert_eq!(addresses, vec![RecordData::A([192, 0, 2, 7].into())]);
+    let reply = ask(&server, "new.example.com", QuestionType::A).await?;
+    assert_eq!(reply.answers.answers.len(), 1);
+
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
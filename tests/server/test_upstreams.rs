@@ -0,0 +1,184 @@
+use crate::helpers::{StubZone, record, self_signed_certificate};
+use anyhow::Result;
+use async_trait::async_trait;
+use dns::dns::*;
+use dns::handler::{DnsHandler, DnsRequest, Transport};
+use dns::resolver::{ForwardHandler, Stamp, StampProtocol, Upstream};
+use std::collections::HashSet;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::task::JoinSet;
+
+// a zone that remembers where every query came from, to count the connections they came over
+#[derive(Debug)]
+struct Recording {
+    zone: StubZone,
+    clients: Mutex<Vec<(Transport, SocketAddr)>>,
+}
+
+#[async_trait]
+impl DnsHandler for Recording {
+    async fn handle(&self, request: &DnsRequest) -> Result<Option<DnsMessage>> {
+        self.clients
+            .lock()
+            .unwrap()
+            .push((request.transport, request.client));
+        self.zone.handle(request).await
+    }
+}
+
+impl Recording {
+    fn connections(&self, transport: Transport) -> usize {
+        let clients = self.clients.lock().unwrap();
+        let addresses: HashSet<&SocketAddr> = clients
+            .iter()
+            .filter(|(t, _)| *t == transport)
+            .map(|(_, client)| client)
+            .collect();
+        addresses.len()
+    }
+}
+
+#[tokio::test]
+async fn test_forwarding_over_encrypted_upstreams() -> Result<()> {
+    let (cert, key, client) = self_signed_certificate("upstreams")?;
+    let tls = tls_server_config(cert, key)?;
+    let recording = Arc::new(Recording {
+        zone: StubZone::new("example.com.", vec![record(
+            "www.example.com.",
+            300,
+            RecordData::A(Ipv4Addr::new(192, 0, 2, 80)),
+        )]),
+        clients: Mutex::new(Vec::new()),
+    });
+    let server = DnsServer::build("127.0.0.1:0")
+        .await?
+        .with_handler(recording.clone())
+        .with_tls("127.0.0.1:0", tls.clone())
+        .await?
+        .with_https("127.0.0.1:0", Some(tls.clone()))
+        .await?
+        .with_quic("127.0.0.1:0", tls)?;
+    let port = |address: Option<String>| address.unwrap().parse::<SocketAddr>().unwrap().port();
+    let upstreams = [
+        (
+            Transport::Tls,
+            format!("tls://localhost:{}", port(server.tls_address()?)),
+        ),
+        (
+            Transport::Https,
+            format!(
+                "https://localhost:{}/dns-query",
+                port(server.https_address()?)
+            ),
+        ),
+        (
+            Transport::Quic,
+            format!("quic://localhost:{}", port(server.quic_address()?)),
+        ),
+    ];
+    tokio::spawn(async move { server.run_until_stopped().await });
+
+    let client = Arc::new(client);
+    for (transport, url) in upstreams {
+        let forwarder = Arc::new(
+            ForwardHandler::new(vec![url.parse::<Upstream>()?])
+                .with_tls_config(client.clone())
+                .with_timeout(Duration::from_secs(2)),
+        );
+
+        // a burst of queries at once, and then another to check the connection is kept
+        let mut tasks = JoinSet::new();
+        for id in 1..=10 {
+            let forwarder = forwarder.clone();
+            tasks.spawn(async move {
+                let query = DnsMessage::query(
+                    id,
+                    DnsQuestion::new("www.example.com".parse()?, QuestionType::A),
+                );
+                Ok::<_, anyhow::Error>((id, forwarder.forward(&query).await?))
+            });
+        }
+        for result in tasks.join_all().await {
+            let (id, reply) = result?;
+            assert_eq!(reply.header.packet_id, id, "{url}");
+            assert_eq!(
+                reply.answers.answers[0].rdata()?,
+                RecordData::A(Ipv4Addr::new(192, 0, 2, 80))
+            );
+        }
+        let query = DnsMessage::query(
+            11,
+            DnsQuestion::new("www.example.com".parse()?, QuestionType::A),
+        );
+        forwarder.forward(&query).await?;
+
+        // they were all pipelined or multiplexed over the one connection
+        assert_eq!(recording.connections(transport), 1, "{url}");
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_upstream_certificates_are_checked_against_the_host_name() -> Result<()> {
+    let (cert, key, client) = self_signed_certificate("upstreams-name")?;
+    let zone = StubZone::new("example.com.", vec![]);
+    let server = DnsServer::build("127.0.0.1:0")
+        .await?
+        .with_handler(Arc::new(zone))
+        .with_tls("127.0.0.1:0", tls_server_config(cert, key)?)
+        .await?;
+    let address = server.tls_address()?.unwrap();
+    tokio::spawn(async move { server.run_until_stopped().await });
+
+    // the certificate is for localhost, not the address
+    let forwarder = ForwardHandler::new(vec![format!("tls://{address}").parse()?])
+        .with_tls_config(Arc::new(client))
+        .with_timeout(Duration::from_secs(2));
+    let query = DnsMessage::query(
+        1,
+        DnsQuestion::new("www.example.com".parse()?, QuestionType::A),
+    );
+    assert!(forwarder.forward(&query).await.is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_forwarding_to_a_stamp() -> Result<()> {
+    let (cert, key, client) = self_signed_certificate("upstreams-stamp")?;
+    let zone = StubZone::new("example.com.", vec![record(
+        "www.example.com.",
+        300,
+        RecordData::A(Ipv4Addr::new(192, 0, 2, 80)),
+    )]);
+    let server = DnsServer::build("127.0.0.1:0")
+        .await?
+        .with_handler(Arc::new(zone))
+        .with_tls("127.0.0.1:0", tls_server_config(cert, key)?)
+        .await?;
+    let address = server.tls_address()?.unwrap();
+    tokio::spawn(async move { server.run_until_stopped().await });
+
+    // the stamp has the address to connect to, so the name is only for the certificate
+    let stamp = Stamp {
+        protocol: StampProtocol::DoT,
+        address,
+        hostname: "localhost".to_string(),
+        ..Stamp::default()
+    };
+    let upstream: Upstream = stamp.encode()?.parse()?;
+    let forwarder = ForwardHandler::new(vec![upstream])
+        .with_tls_config(Arc::new(client))
+        .with_timeout(Duration::from_secs(2));
+    let query = DnsMessage::query(
+        1,
+        DnsQuestion::new("www.example.com".parse()?, QuestionType::A),
+    );
+    let reply = forwarder.forward(&query).await?;
+    assert_eq!(
+        reply.answers.answers[0].rdata()?,
+        RecordData::A(Ipv4Addr::new(192, 0, 2, 80))
+    );
+    Ok(())
+}
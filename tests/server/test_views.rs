@@ -0,0 +1,68 @@
+use crate::helpers::{StubZone, addresses, record, send_request_from, spawn_app_with_handler};
+use anyhow::Result;
+use dns::dns::*;
+use dns::view::{View, ViewHandler};
+use dns::zone::{AuthoritativeHandler, Zone, ZoneParser, ZoneStore};
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+
+fn zone(www: &str) -> Result<Arc<ZoneStore>> {
+    let text = format!(
+        "$TTL 300
+@    SOA ns1 hostmaster 1 3600 600 86400 60
+     NS  ns1
+ns1  A   192.0.2.53
+www  A   {www}
+"
+    );
+    let origin: Domain = "example.com".parse()?;
+    let records = ZoneParser::parse_str(origin.clone(), &text, "example.com")?;
+    let zones = Arc::new(ZoneStore::default());
+    zones.insert(Zone::new(origin, records)?);
+    Ok(zones)
+}
+
+async fn ask_from(client: &str, server: &str, name: &str) -> Result<DnsMessage> {
+    let request = DnsMessage::query(3, DnsQuestion::new(name.parse()?, QuestionType::A));
+    send_request_from(client, server, request).await
+}
+
+#[tokio::test]
+async fn test_views_split_the_horizon_by_client_address() -> Result<()> {
+    // inside, our zone has private addresses and everything else gets resolved
+    let resolver = StubZone::new("example.net.", vec![record(
+        "www.example.net.",
+        300,
+        RecordData::A(Ipv4Addr::new(198, 51, 100, 80)),
+    )]);
+    let internal = AuthoritativeHandler::new(zone("10.0.0.80")?).with_fallback(Arc::new(resolver));
+
+    // outside, there's just the public zone
+    let external = AuthoritativeHandler::new(zone("203.0.113.80")?);
+
+    let views = ViewHandler::new(vec![
+        View::new("internal", Arc::new(internal)).with_clients(vec!["127.0.0.2/32".parse()?]),
+        View::new("external", Arc::new(external)).with_clients(vec!["127.0.0.0/8".parse()?]),
+    ]);
+    let server = spawn_app_with_handler("127.0.0.1:0", Arc::new(views))
+        .await?
+        .to_string();
+
+    let reply = ask_from("127.0.0.2", &server, "www.example.com").await?;
+    assert_eq!(addresses(&reply), vec![RecordData::A(Ipv4Addr::new(
+        10, 0, 0, 80
+    ))]);
+    let reply = ask_from("127.0.0.3", &server, "www.example.com").await?;
+    assert_eq!(addresses(&reply), vec![RecordData::A(Ipv4Addr::new(
+        203, 0, 113, 80
+    ))]);
+
+    // only the internal view does recursion
+    let reply = ask_from("127.0.0.2", &server, "www.example.net").await?;
+    assert_eq!(addresses(&reply), vec![RecordData::A(Ipv4Addr::new(
+        198, 51, 100, 80
+    ))]);
+    let reply = ask_from("127.0.0.3", &server, "www.example.net").await?;
+    assert_eq!(reply.response_code(), ResponseCode::Refused as u8);
+    Ok(())
+}